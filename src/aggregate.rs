@@ -0,0 +1,181 @@
+//! Aggregate functions, accumulated over the rows of each group.
+
+use crate::expr::BoundExpr;
+use crate::{Affinity, Collation, Column, Row};
+use anyhow::{bail, Result};
+use std::cmp::Ordering;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AggregateFunction {
+    Count,
+    Sum,
+    Total,
+    Avg,
+    Min,
+    Max,
+}
+
+impl AggregateFunction {
+    pub fn from_name(name: &str) -> Option<AggregateFunction> {
+        match name.to_lowercase().as_str() {
+            "count" => Some(AggregateFunction::Count),
+            "sum" => Some(AggregateFunction::Sum),
+            "total" => Some(AggregateFunction::Total),
+            "avg" => Some(AggregateFunction::Avg),
+            "min" => Some(AggregateFunction::Min),
+            "max" => Some(AggregateFunction::Max),
+            _ => None,
+        }
+    }
+}
+
+/// A call to an aggregate function. `arg` is `None` only for `count(*)`.
+#[derive(Debug, Clone)]
+pub struct Aggregate {
+    pub function: AggregateFunction,
+    pub arg: Option<BoundExpr>,
+}
+
+impl Aggregate {
+    pub fn accumulator(&self) -> Accumulator {
+        match self.function {
+            AggregateFunction::Count => Accumulator::Count(0),
+            AggregateFunction::Sum | AggregateFunction::Total | AggregateFunction::Avg => {
+                Accumulator::Sum {
+                    function: self.function,
+                    integer: 0,
+                    real: 0.0,
+                    approximate: false,
+                    overflow: false,
+                    count: 0,
+                }
+            }
+            AggregateFunction::Min | AggregateFunction::Max => Accumulator::Extreme {
+                ordering: if self.function == AggregateFunction::Min {
+                    Ordering::Less
+                } else {
+                    Ordering::Greater
+                },
+                collation: self
+                    .arg
+                    .as_ref()
+                    .and_then(BoundExpr::collation)
+                    .unwrap_or_default(),
+                best: None,
+            },
+        }
+    }
+
+    /// Adds `row` to the group being accumulated in `accumulator`.
+    pub fn step(&self, accumulator: &mut Accumulator, row: &Row) {
+        let value = self.arg.as_ref().map(|arg| arg.eval(row));
+        // Every aggregate but count(*) skips NULL
+        if value == Some(Column::Null) {
+            return;
+        }
+        accumulator.step(value.unwrap_or(Column::Null), row);
+    }
+}
+
+/// The running state of one aggregate over one group.
+#[derive(Debug)]
+pub enum Accumulator {
+    Count(i64),
+    /// `sum`, `total` and `avg`: the sum is kept both exactly, for as long
+    /// as every value is an integer, and as a real.
+    Sum {
+        function: AggregateFunction,
+        integer: i64,
+        real: f64,
+        /// Some value was a real (or text that isn't an integer)
+        approximate: bool,
+        overflow: bool,
+        count: i64,
+    },
+    /// `min` and `max`: the best value so far, with the row it came from
+    Extreme {
+        ordering: Ordering,
+        collation: Collation,
+        best: Option<(Column, Row)>,
+    },
+}
+
+impl Accumulator {
+    fn step(&mut self, value: Column, row: &Row) {
+        match self {
+            Accumulator::Count(n) => *n += 1,
+            Accumulator::Sum {
+                integer,
+                real,
+                approximate,
+                overflow,
+                count,
+                ..
+            } => {
+                *count += 1;
+                match Affinity::Numeric.apply(value) {
+                    Column::Integer(i) => {
+                        *real += i as f64;
+                        match integer.checked_add(i) {
+                            Some(sum) => *integer = sum,
+                            None => *overflow = true,
+                        }
+                    }
+                    Column::Real(r) => {
+                        *real += r;
+                        *approximate = true;
+                    }
+                    // Text that isn't a number counts as 0.0
+                    _ => *approximate = true,
+                }
+            }
+            Accumulator::Extreme {
+                ordering,
+                collation,
+                best,
+            } => {
+                let better = match best {
+                    Some((b, _)) => value.compare(b, *collation) == *ordering,
+                    None => true,
+                };
+                if better {
+                    *best = Some((value, row.clone()));
+                }
+            }
+        }
+    }
+
+    /// The row a lone `min()` or `max()` took its result from, which is
+    /// where SQLite takes the other columns of the result row from as well.
+    pub fn extreme_row(&self) -> Option<&Row> {
+        match self {
+            Accumulator::Extreme {
+                best: Some((_, row)),
+                ..
+            } => Some(row),
+            _ => None,
+        }
+    }
+
+    pub fn finish(self) -> Result<Column> {
+        Ok(match self {
+            Accumulator::Count(n) => Column::Integer(n),
+            Accumulator::Sum {
+                function,
+                integer,
+                real,
+                approximate,
+                overflow,
+                count,
+            } => match function {
+                AggregateFunction::Total => Column::Real(real),
+                _ if count == 0 => Column::Null,
+                AggregateFunction::Avg => Column::Real(real / count as f64),
+                _ if approximate => Column::Real(real),
+                _ if overflow => bail!("integer overflow"),
+                _ => Column::Integer(integer),
+            },
+            Accumulator::Extreme { best, .. } => best.map_or(Column::Null, |(value, _)| value),
+        })
+    }
+}
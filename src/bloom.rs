@@ -0,0 +1,48 @@
+//! A Bloom filter: a set that can tell for sure that a value isn't in it,
+//! in a few bits per value.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+/// Bits per value, which with three probes makes about one in twenty values
+/// that aren't in the filter look like they are.
+const BITS_PER_VALUE: usize = 8;
+const PROBES: u64 = 3;
+
+#[derive(Debug)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+}
+
+impl BloomFilter {
+    /// An empty filter sized for `capacity` values.
+    pub fn new(capacity: usize) -> BloomFilter {
+        let bits = (capacity * BITS_PER_VALUE).max(64).next_power_of_two();
+        BloomFilter {
+            bits: vec![0; bits / 64],
+        }
+    }
+
+    pub fn insert<T: Hash>(&mut self, value: &T) {
+        for bit in self.positions(value) {
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+    }
+
+    /// Whether `value` may have been inserted. False means it certainly wasn't.
+    pub fn may_contain<T: Hash>(&self, value: &T) -> bool {
+        self.positions(value)
+            .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    /// The bits for `value`, from two halves of one hash combined as
+    /// `h1 + i * h2`.
+    fn positions<T: Hash>(&self, value: &T) -> impl Iterator<Item = usize> {
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        let hash = hasher.finish();
+        let (h1, h2) = (hash & 0xffff_ffff, (hash >> 32) | 1);
+        let mask = self.bits.len() as u64 * 64 - 1;
+        (0..PROBES).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) & mask) as usize)
+    }
+}
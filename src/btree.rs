@@ -0,0 +1,860 @@
+//! Reading the b-trees a database is made of: the rows of tables, by
+//! rowid or in order, and the entries of indexes, by key or by range.
+//!
+//! A page that isn't what the b-tree says it is, or whose cells don't fit on
+//! it, fails with [`Error::CorruptPage`] rather than being read past.
+
+use crate::header::TextEncoding;
+use crate::pager::{Page, Pager};
+use crate::record::{self, Format, TableLayout};
+use crate::{teach, try_variant, Collation, Column, Error, Row};
+use anyhow::Result;
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::ops::Bound;
+use std::rc::Rc;
+use std::vec;
+
+/// The row with rowid `row_id`, decoded as `layout` says.
+pub fn select(
+    row_id: u64,
+    page: &Page,
+    pager: &mut Pager,
+    format: Format,
+    layout: &TableLayout,
+) -> Result<Row> {
+    select_at(row_id, page, pager, format, layout, 0)
+}
+
+/// How deep a b-tree can be: SQLite's own limit, which no valid database
+/// comes near. A corrupt b-tree whose pages lead back to one another goes
+/// past it, rather than being walked forever.
+const MAX_DEPTH: usize = 20;
+
+/// Fails if `page`, at `depth` in its b-tree, is deeper than any b-tree goes.
+fn check_depth(page: &Page, depth: usize) -> Result<()> {
+    if depth > MAX_DEPTH {
+        return Err(Error::corrupt(
+            page.number(),
+            0,
+            "the b-tree is deeper than any valid one: its pages lead back to one another",
+        )
+        .into());
+    }
+    Ok(())
+}
+
+fn select_at(
+    row_id: u64,
+    page: &Page,
+    pager: &mut Pager,
+    format: Format,
+    layout: &TableLayout,
+    depth: usize,
+) -> Result<Row> {
+    check_depth(page, depth)?;
+    match page.page_type() {
+        0x05 => {
+            // internal page
+            let right_most_pointer = page.right_most_pointer();
+            for i in cells(page)? {
+                let (left_page, cell) = child(page, i)?;
+                let (key, _) = varint(page, cell)?;
+
+                if row_id <= key {
+                    let page = pager.get_page(left_page)?;
+                    return select_at(row_id, &page, pager, format, layout, depth + 1);
+                }
+            }
+            let page = pager.get_page(right_most_pointer)?;
+            select_at(row_id, &page, pager, format, layout, depth + 1)
+        }
+        0x0d => {
+            // leaf page
+            for i in cells(page)? {
+                let (_payload_length, cell) = varint(page, &page[i..])?;
+                let (k, _) = varint(page, cell)?;
+                if row_id == k {
+                    return Ok(table_cell(page, i, pager, format, layout)?.1);
+                }
+            }
+            Err(Error::corrupt(
+                page.number(),
+                0,
+                format!("no row {} where the table b-tree leads", row_id),
+            )
+            .into())
+        }
+        _ => Err(unexpected_type(page, "table")),
+    }
+}
+
+/// Number of rows in a table b-tree, found by adding up the cell counts of its
+/// leaf pages without decoding any records.
+pub fn count_rows(page: &Page, pager: &mut Pager) -> Result<u64> {
+    count_rows_at(page, pager, 0)
+}
+
+fn count_rows_at(page: &Page, pager: &mut Pager, depth: usize) -> Result<u64> {
+    check_depth(page, depth)?;
+    match page.page_type() {
+        0x05 => {
+            let mut count = 0;
+            for next_page in children(page, Direction::Forward)? {
+                let page = pager.get_page(next_page)?;
+                count += count_rows_at(&page, pager, depth + 1)?;
+            }
+            Ok(count)
+        }
+        0x0d => Ok(page.number_of_cells() as u64),
+        _ => Err(unexpected_type(page, "table")),
+    }
+}
+
+/// Order in which a b-tree scan visits its entries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Forward,
+    Backward,
+}
+
+/// Child page numbers of an interior page, in key order (the right-most
+/// pointer last), or reversed when scanning backward.
+pub fn children(page: &Page, direction: Direction) -> Result<Vec<u32>> {
+    let right_most_pointer = page.right_most_pointer();
+
+    let mut children = cells(page)?
+        .into_iter()
+        .map(|i| Ok(child(page, i)?.0))
+        .chain(std::iter::once(Ok(right_most_pointer)))
+        .collect::<Result<Vec<_>>>()?;
+    if direction == Direction::Backward {
+        children.reverse();
+    }
+    Ok(children)
+}
+
+/// Every row of a table b-tree, decoded as `layout` says.
+pub fn rows(
+    page: Rc<Page>,
+    pager: &mut Pager,
+    format: Format,
+    direction: Direction,
+    layout: &TableLayout,
+) -> Result<Vec<Row>> {
+    TableCursor::new(page, pager, format, direction, layout)?
+        .map(|row| Ok(row?.1))
+        .collect()
+}
+
+/// The rows of a table b-tree along with their rowids, read one at a time
+/// as the b-tree is walked, so that only the pages on the path to the
+/// current leaf are held rather than every row.
+pub struct TableCursor<'a> {
+    pager: &'a mut Pager,
+    format: Format,
+    direction: Direction,
+    layout: TableLayout,
+    /// For each interior page above the current leaf, its children yet to
+    /// be visited
+    stack: Vec<vec::IntoIter<u32>>,
+    /// The current leaf page, and the offsets of its cells yet to be read
+    leaf: Option<(Rc<Page>, vec::IntoIter<usize>)>,
+}
+
+impl<'a> TableCursor<'a> {
+    /// A cursor before the first row of the b-tree whose root is `page`, in
+    /// the order of `direction`.
+    pub fn new(
+        page: Rc<Page>,
+        pager: &'a mut Pager,
+        format: Format,
+        direction: Direction,
+        layout: &TableLayout,
+    ) -> Result<TableCursor<'a>> {
+        let mut cursor = TableCursor {
+            pager,
+            format,
+            direction,
+            layout: layout.clone(),
+            stack: vec![],
+            leaf: None,
+        };
+        cursor.enter(page)?;
+        Ok(cursor)
+    }
+
+    /// Moves down to `page`, the next page of the walk.
+    fn enter(&mut self, page: Rc<Page>) -> Result<()> {
+        check_depth(&page, self.stack.len())?;
+        match page.page_type() {
+            0x05 => self
+                .stack
+                .push(children(&page, self.direction)?.into_iter()),
+            0x0d => {
+                let mut cell_indices = cells(&page)?;
+                if self.direction == Direction::Backward {
+                    cell_indices.reverse();
+                }
+                self.leaf = Some((page, cell_indices.into_iter()));
+            }
+            _ => return Err(unexpected_type(&page, "table")),
+        }
+        Ok(())
+    }
+
+    /// The next row, if any.
+    fn step(&mut self) -> Result<Option<(u64, Row)>> {
+        loop {
+            if let Some((page, cells)) = &mut self.leaf {
+                if let Some(i) = cells.next() {
+                    let cell = table_cell(page, i, self.pager, self.format, &self.layout)?;
+                    return Ok(Some(cell));
+                }
+                self.leaf = None;
+            }
+            // Up the stack to the nearest page with children left, and down
+            // to the next of them
+            let Some(children) = self.stack.last_mut() else {
+                return Ok(None);
+            };
+            match children.next() {
+                Some(child) => {
+                    let page = self.pager.get_page(child)?;
+                    self.enter(page)?;
+                }
+                None => {
+                    self.stack.pop();
+                }
+            }
+        }
+    }
+}
+
+impl Iterator for TableCursor<'_> {
+    type Item = Result<(u64, Row)>;
+
+    /// The next row, or the error that stopped the walk, after which there
+    /// are no more.
+    fn next(&mut self) -> Option<Result<(u64, Row)>> {
+        match self.step() {
+            Ok(row) => row.map(Ok),
+            Err(e) => {
+                self.stack.clear();
+                self.leaf = None;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Decodes the cell at offset `i` of the table leaf page `page` into its
+/// rowid and row.
+pub fn table_cell(
+    page: &Page,
+    i: usize,
+    pager: &mut Pager,
+    format: Format,
+    layout: &TableLayout,
+) -> Result<(u64, Row)> {
+    let (payload_length, cell) = varint(page, &page[i..])?;
+    let (row_id, cell) = varint(page, cell)?;
+    let payload = cell_payload(page, i, pager, format, payload_length, cell, false)?;
+    let row = record::decode_row(&payload, row_id, layout, format.encoding)
+        .ok_or_else(|| malformed_record(page, i))?;
+    Ok((row_id, row))
+}
+
+/// The rows of a table b-tree whose rowid is greater than `after`, or all of
+/// them, in rowid order and along with their rowids, until `budget` rows have
+/// been read.
+/// Subtrees holding only smaller rowids are skipped without being read.
+pub fn rows_after(
+    page: &Page,
+    pager: &mut Pager,
+    format: Format,
+    layout: &TableLayout,
+    after: Option<u64>,
+    budget: &mut usize,
+) -> Result<Vec<(u64, Row)>> {
+    rows_after_at(page, pager, format, layout, after, budget, 0)
+}
+
+fn rows_after_at(
+    page: &Page,
+    pager: &mut Pager,
+    format: Format,
+    layout: &TableLayout,
+    after: Option<u64>,
+    budget: &mut usize,
+    depth: usize,
+) -> Result<Vec<(u64, Row)>> {
+    check_depth(page, depth)?;
+    let mut rows = vec![];
+    match page.page_type() {
+        0x05 => {
+            // internal page: every rowid under a cell's child is at most its key
+            let right_most_pointer = page.right_most_pointer();
+            let children = cells(page)?
+                .into_iter()
+                .map(|i| {
+                    let (child, cell) = child(page, i)?;
+                    let (key, _) = varint(page, cell)?;
+                    Ok((child, Some(key)))
+                })
+                .chain(std::iter::once(Ok((right_most_pointer, None))))
+                .collect::<Result<Vec<_>>>()?;
+            for (child, key) in children {
+                if *budget == 0 {
+                    break;
+                }
+                if let (Some(key), Some(after)) = (key, after) {
+                    if key <= after {
+                        continue;
+                    }
+                }
+                let page = pager.get_page(child)?;
+                rows.extend(rows_after_at(
+                    &page,
+                    pager,
+                    format,
+                    layout,
+                    after,
+                    budget,
+                    depth + 1,
+                )?);
+            }
+        }
+        0x0d => {
+            // leaf page
+            for i in cells(page)? {
+                if *budget == 0 {
+                    break;
+                }
+                let (_payload_length, rest) = varint(page, &page[i..])?;
+                let (row_id, _) = varint(page, rest)?;
+                if after.map_or(true, |after| row_id > after) {
+                    rows.push(table_cell(page, i, pager, format, layout)?);
+                    *budget -= 1;
+                }
+            }
+        }
+        _ => return Err(unexpected_type(page, "table")),
+    }
+    Ok(rows)
+}
+
+/// Looks up the index entries whose first columns equal those of `key`, each
+/// in the order `orders` gives for it. For a UNIQUE index whose columns the
+/// key covers there is at most one such entry, so the search stops at the
+/// first hit.
+pub fn index(
+    pager: &mut Pager,
+    page: &Page,
+    format: Format,
+    key: &[Column],
+    orders: &[KeyOrder],
+    unique: bool,
+) -> Result<Vec<Row>> {
+    index_at(pager, page, format, key, orders, unique, 0)
+}
+
+fn index_at(
+    pager: &mut Pager,
+    page: &Page,
+    format: Format,
+    key: &[Column],
+    orders: &[KeyOrder],
+    unique: bool,
+    depth: usize,
+) -> Result<Vec<Row>> {
+    check_depth(page, depth)?;
+    // How an entry's prefix compares with the key
+    let compare = |entry: &[Column]| compare_key(entry, key, orders, format.encoding);
+    match page.page_type() {
+        0x02 => {
+            // internal page
+            let right_most_pointer = page.right_most_pointer();
+
+            let mut result = vec![];
+            // The child left of each divider holds the entries after the
+            // previous divider, up to this one. Entries equal to the key can
+            // span several children, each divider between them being one of
+            // them, so the search goes on until a divider is past the key,
+            // or else into the right-most child.
+            let mut past_key = false;
+            for i in cells(page)? {
+                let (next_page, _) = child(page, i)?;
+                let divider = index_cell(page, i + 4, pager, format)?;
+                let ordering = compare(&divider);
+                if ordering != Ordering::Less {
+                    let page = pager.get_page(next_page)?;
+                    result.extend(index_at(
+                        pager,
+                        &page,
+                        format,
+                        key,
+                        orders,
+                        unique,
+                        depth + 1,
+                    )?);
+                }
+                if unique && !result.is_empty() {
+                    return Ok(result);
+                }
+                if ordering == Ordering::Equal {
+                    result.push(divider);
+                    if unique {
+                        return Ok(result);
+                    }
+                }
+                if ordering == Ordering::Greater {
+                    past_key = true;
+                    break;
+                }
+            }
+            if !past_key {
+                let page = pager.get_page(right_most_pointer)?;
+                result.extend(index_at(
+                    pager,
+                    &page,
+                    format,
+                    key,
+                    orders,
+                    unique,
+                    depth + 1,
+                )?);
+            }
+
+            Ok(result)
+        }
+        0x0a => {
+            let mut result = vec![];
+
+            for i in cells(page)? {
+                let row = index_cell(page, i, pager, format)?;
+                if compare(&row) == Ordering::Equal {
+                    result.push(row);
+                    if unique {
+                        break;
+                    }
+                }
+            }
+
+            Ok(result)
+        }
+        _ => Err(unexpected_type(page, "index")),
+    }
+}
+
+/// The index entries whose first column is within `lower` and `upper` under
+/// `collation`, in key order. Subtrees holding only keys outside the range
+/// are skipped without being read.
+pub fn index_range(
+    pager: &mut Pager,
+    page: &Page,
+    format: Format,
+    lower: Bound<&Column>,
+    upper: Bound<&Column>,
+    collation: Collation,
+) -> Result<Vec<Row>> {
+    index_range_at(pager, page, format, lower, upper, collation, 0)
+}
+
+fn index_range_at(
+    pager: &mut Pager,
+    page: &Page,
+    format: Format,
+    lower: Bound<&Column>,
+    upper: Bound<&Column>,
+    collation: Collation,
+    depth: usize,
+) -> Result<Vec<Row>> {
+    check_depth(page, depth)?;
+    let above = |key: &Column| match lower {
+        Bound::Unbounded => true,
+        Bound::Included(l) => key.compare(l, collation) != Ordering::Less,
+        Bound::Excluded(l) => key.compare(l, collation) == Ordering::Greater,
+    };
+    let below = |key: &Column| match upper {
+        Bound::Unbounded => true,
+        Bound::Included(u) => key.compare(u, collation) != Ordering::Greater,
+        Bound::Excluded(u) => key.compare(u, collation) == Ordering::Less,
+    };
+    // Bounds are compared in the order of characters, as the filters of a
+    // query are. Only when the index is kept in that order can it be cut
+    // short; otherwise every entry is looked at.
+    let ordered = format.encoding == TextEncoding::Utf8 || collation != Collation::Binary;
+    let mut result = vec![];
+    match page.page_type() {
+        0x02 => {
+            // internal page: a cell's child holds the keys up to its own, and
+            // the right-most child those after the last
+            let right_most_pointer = page.right_most_pointer();
+            for i in cells(page)? {
+                let (child, _) = child(page, i)?;
+                let divider = index_cell(page, i + 4, pager, format)?;
+                let Some(first) = divider.first() else {
+                    return Err(malformed_record(page, i));
+                };
+                if !ordered || above(first) {
+                    let page = pager.get_page(child)?;
+                    result.extend(index_range_at(
+                        pager,
+                        &page,
+                        format,
+                        lower,
+                        upper,
+                        collation,
+                        depth + 1,
+                    )?);
+                }
+                if ordered && !below(first) {
+                    return Ok(result);
+                }
+                if above(first) && below(first) {
+                    result.push(divider);
+                }
+            }
+            let page = pager.get_page(right_most_pointer)?;
+            result.extend(index_range_at(
+                pager,
+                &page,
+                format,
+                lower,
+                upper,
+                collation,
+                depth + 1,
+            )?);
+        }
+        0x0a => {
+            for i in cells(page)? {
+                let row = index_cell(page, i, pager, format)?;
+                let Some(first) = row.first() else {
+                    return Err(malformed_record(page, i));
+                };
+                if ordered && !below(first) {
+                    break;
+                }
+                if above(first) && below(first) {
+                    result.push(row);
+                }
+            }
+        }
+        _ => return Err(unexpected_type(page, "index")),
+    }
+    Ok(result)
+}
+
+/// Compares two keys in the order an index keeps them. Under BINARY that is
+/// the order of the bytes text is stored as, which in a UTF-16 database
+/// isn't the order of its characters.
+pub fn index_order(
+    a: &Column,
+    b: &Column,
+    collation: Collation,
+    encoding: TextEncoding,
+) -> Ordering {
+    match (a, b, collation) {
+        (Column::Text(a), Column::Text(b), Collation::Binary) => encoding.compare(a, b),
+        _ => a.compare(b, collation),
+    }
+}
+
+/// How an index orders one of its columns.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KeyOrder {
+    pub collation: Collation,
+    pub descending: bool,
+}
+
+/// Compares the first `key.len()` columns of the index entry `entry` with
+/// `key`, column by column in the order the index keeps each.
+pub fn compare_key(
+    entry: &[Column],
+    key: &[Column],
+    orders: &[KeyOrder],
+    encoding: TextEncoding,
+) -> Ordering {
+    for ((a, b), order) in entry.iter().zip(key).zip(orders) {
+        let ordering = index_order(a, b, order.collation, encoding);
+        let ordering = if order.descending {
+            ordering.reverse()
+        } else {
+            ordering
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    Ordering::Equal
+}
+
+/// Every entry of an index b-tree, in key order or reversed.
+pub fn index_rows(
+    page: &Page,
+    pager: &mut Pager,
+    format: Format,
+    direction: Direction,
+) -> Result<Vec<Row>> {
+    index_rows_at(page, pager, format, direction, 0)
+}
+
+fn index_rows_at(
+    page: &Page,
+    pager: &mut Pager,
+    format: Format,
+    direction: Direction,
+    depth: usize,
+) -> Result<Vec<Row>> {
+    check_depth(page, depth)?;
+    match page.page_type() {
+        0x02 => {
+            // internal page: each cell's key sorts between its own child and the next one
+            let mut result = vec![];
+            let children = children(page, direction)?;
+            let mut keys = cells(page)?
+                .into_iter()
+                .map(|i| index_cell(page, i + 4, pager, format))
+                .collect::<Result<Vec<_>>>()?;
+            if direction == Direction::Backward {
+                keys.reverse();
+            }
+
+            let mut keys = keys.into_iter();
+            for (i, next_page) in children.into_iter().enumerate() {
+                let child = pager.get_page(next_page)?;
+
+                if direction == Direction::Backward && i > 0 {
+                    result.extend(keys.next());
+                }
+                result.extend(index_rows_at(&child, pager, format, direction, depth + 1)?);
+                if direction == Direction::Forward {
+                    result.extend(keys.next());
+                }
+            }
+
+            Ok(result)
+        }
+        0x0a => {
+            let mut cell_indices = cells(page)?;
+            if direction == Direction::Backward {
+                cell_indices.reverse();
+            }
+
+            cell_indices
+                .into_iter()
+                .map(|i| index_cell(page, i, pager, format))
+                .collect()
+        }
+        _ => Err(unexpected_type(page, "index")),
+    }
+}
+
+/// The offsets of the cells of `page`, in the order of its cell pointers,
+/// each checked to be on the page.
+fn cells(page: &Page) -> Result<Vec<usize>> {
+    let pointers = page.cell_pointers();
+    let number_of_cells = page.number_of_cells();
+    let content = pointers + 2 * number_of_cells;
+    if content > page.len() {
+        return Err(Error::corrupt(
+            page.number(),
+            page.btree_start() + 3,
+            format!("{} cell pointers don't fit on the page", number_of_cells),
+        )
+        .into());
+    }
+    (0..number_of_cells)
+        .map(|i| {
+            let pointer = pointers + 2 * i;
+            let offset = u16::from_be_bytes([page[pointer], page[pointer + 1]]) as usize;
+            if offset < content || offset >= page.len() {
+                return Err(Error::corrupt(
+                    page.number(),
+                    pointer,
+                    format!("cell pointer {} is outside the cell content area", offset),
+                )
+                .into());
+            }
+            Ok(offset)
+        })
+        .collect()
+}
+
+/// The child page number a cell of an interior page starts with, and the
+/// rest of the cell.
+fn child(page: &Page, i: usize) -> Result<(u32, &[u8])> {
+    match page.get(i..i + 4) {
+        Some(pointer) => Ok((
+            u32::from_be_bytes(pointer.try_into().unwrap()),
+            &page[i + 4..],
+        )),
+        None => Err(Error::corrupt(page.number(), i, "cell runs off the page").into()),
+    }
+}
+
+/// The varint `cell`, a part of `page`, starts with, and the rest of it.
+fn varint<'a>(page: &Page, cell: &'a [u8]) -> Result<(u64, &'a [u8])> {
+    try_variant(cell).ok_or_else(|| {
+        Error::corrupt(
+            page.number(),
+            page.len() - cell.len(),
+            "cell runs off the page",
+        )
+        .into()
+    })
+}
+
+/// The payload of `payload_length` bytes of the cell at offset `i` of
+/// `page`, whose part on the page is `local`, checked to fit on the page
+/// before it is read.
+fn cell_payload<'a>(
+    page: &Page,
+    i: usize,
+    pager: &mut Pager,
+    format: Format,
+    payload_length: u64,
+    local: &'a [u8],
+    index: bool,
+) -> Result<Cow<'a, [u8]>> {
+    let local_size = record::local_size(format, payload_length, index);
+    let on_page = if local_size as u64 == payload_length {
+        local_size
+    } else {
+        // followed by the first overflow page
+        local_size + 4
+    };
+    if on_page > local.len() {
+        return Err(Error::corrupt(page.number(), i, "cell runs off the page").into());
+    }
+    let payload = record::payload(pager, format, payload_length, local, index)?;
+    if pager.teaches() {
+        teach::record(&payload);
+    }
+    Ok(payload)
+}
+
+/// Decodes the index entry in the cell at offset `i` of `page`, past the
+/// child pointer of an interior cell.
+fn index_cell(page: &Page, i: usize, pager: &mut Pager, format: Format) -> Result<Row> {
+    let (payload_length, cell) = varint(page, page.get(i..).unwrap_or_default())?;
+    let payload = cell_payload(page, i, pager, format, payload_length, cell, true)?;
+    record::decode(&payload, format.encoding).ok_or_else(|| malformed_record(page, i))
+}
+
+fn malformed_record(page: &Page, i: usize) -> anyhow::Error {
+    Error::corrupt(page.number(), i, "malformed record").into()
+}
+
+fn unexpected_type(page: &Page, tree: &str) -> anyhow::Error {
+    Error::corrupt(
+        page.number(),
+        page.btree_start(),
+        format!(
+            "unexpected page type {:#04x} in {} b-tree",
+            page.page_type(),
+            tree
+        ),
+    )
+    .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Database;
+    use crate::load::load;
+    use crate::testing::TempDatabase;
+
+    /// The row ids of the entries of index `name` that start with `key`.
+    fn lookup(database: &TempDatabase, name: &str, key: &[Column], unique: bool) -> Vec<i64> {
+        let mut database = Database::open(database.filename()).unwrap();
+        let rootpage = database.schemas[0]
+            .tables
+            .iter()
+            .find(|t| t.name == name)
+            .unwrap()
+            .rootpage;
+        let connection = &mut database.connections[0];
+        let page = connection.pager.get_page(rootpage).unwrap();
+        // Leaves alone would never try the children after a divider
+        assert_eq!(page.page_type(), 0x02);
+        let orders = [KeyOrder {
+            collation: Collation::Binary,
+            descending: false,
+        }; 2];
+        index(
+            &mut connection.pager,
+            &page,
+            connection.format,
+            key,
+            &orders,
+            unique,
+        )
+        .unwrap()
+        .iter()
+        .map(|entry| match entry.last() {
+            Some(Column::Integer(row_id)) => *row_id,
+            _ => panic!("no row id in {:?}", entry),
+        })
+        .collect()
+    }
+
+    #[test]
+    fn equal_keys_across_pages() {
+        let database = TempDatabase::new("index-equal-keys");
+        // Few keys, each in many entries, so that dividers on interior
+        // pages equal the keys looked up
+        let rows = (1..=3000).map(|i| Ok(vec![Column::Integer(i % 3), Column::Integer(i)]));
+        load(
+            &database.0,
+            "CREATE TABLE t (a, b)",
+            &["CREATE INDEX a ON t (a)".to_string()],
+            rows,
+        )
+        .unwrap();
+        for k in 0..3 {
+            let expected = (1..=3000).filter(|i| i % 3 == k).collect::<Vec<_>>();
+            assert_eq!(
+                lookup(&database, "a", &[Column::Integer(k)], false),
+                expected
+            );
+        }
+        // Past every divider, through the right-most pointer
+        assert!(lookup(&database, "a", &[Column::Integer(3)], false).is_empty());
+        assert!(lookup(&database, "a", &[Column::Integer(-1)], false).is_empty());
+    }
+
+    #[test]
+    fn composite_key_prefixes() {
+        let database = TempDatabase::new("index-composite");
+        let rows = (1..=2000).map(|i| {
+            Ok(vec![
+                Column::Integer(i % 10),
+                Column::Text(format!("{:03}", i % 100)),
+            ])
+        });
+        load(
+            &database.0,
+            "CREATE TABLE t (a, b)",
+            &["CREATE INDEX ab ON t (a, b)".to_string()],
+            rows,
+        )
+        .unwrap();
+        let matching = |f: &dyn Fn(i64) -> bool| (1..=2000).filter(|&i| f(i)).collect::<Vec<_>>();
+        // The first column alone, whose entries are in the order of the
+        // second, then both
+        let mut found = lookup(&database, "ab", &[Column::Integer(7)], false);
+        found.sort();
+        assert_eq!(found, matching(&|i| i % 10 == 7));
+        let key = [Column::Integer(7), Column::Text("017".to_string())];
+        assert_eq!(
+            lookup(&database, "ab", &key, false),
+            matching(&|i| i % 100 == 17)
+        );
+        // A second column no entry of the first has
+        let key = [Column::Integer(7), Column::Text("018".to_string())];
+        assert!(lookup(&database, "ab", &key, false).is_empty());
+    }
+}
@@ -0,0 +1,132 @@
+//! Defaults for some of the options, read before the arguments, which
+//! override them. `~/.codecrafters-sqliterc` holds them as the sqlite3
+//! shell's `~/.sqliterc` would, one per line:
+//!
+//! ```text
+//! .mode table
+//! .headers on
+//! .nullvalue NULL
+//! .timer on
+//! PRAGMA cache_size = -8000;
+//! PRAGMA temp_store = memory;
+//! ```
+//!
+//! and the environment variables `CODECRAFTERS_SQLITE_MODE`, `_HEADERS`,
+//! `_NULLVALUE`, `_CACHE_SIZE`, `_TEMP_STORE` and `_TIMER` override those in
+//! turn.
+
+use anyhow::{bail, Context, Result};
+use sqlite_starter_rust::db::TempStore;
+use sqlite_starter_rust::output::Mode;
+use std::env;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+const RC_FILE: &str = ".codecrafters-sqliterc";
+const ENV_PREFIX: &str = "CODECRAFTERS_SQLITE_";
+
+/// The options the rc file and the environment set, None where neither does.
+#[derive(Debug, Default)]
+pub struct Defaults {
+    pub mode: Option<Mode>,
+    pub headers: Option<bool>,
+    pub null: Option<String>,
+    pub cache_size: Option<i64>,
+    pub temp_store: Option<TempStore>,
+    pub timer: Option<bool>,
+}
+
+impl Defaults {
+    /// Reads the rc file in the home directory, if there is one, and then
+    /// the environment.
+    pub fn load() -> Result<Defaults> {
+        let mut defaults = Defaults::default();
+        if let Some(path) = env::var_os("HOME").map(|home| PathBuf::from(home).join(RC_FILE)) {
+            match fs::read_to_string(&path) {
+                Ok(rc) => {
+                    for (i, line) in rc.lines().enumerate() {
+                        defaults
+                            .rc_line(line)
+                            .with_context(|| format!("{}:{}", path.display(), i + 1))?;
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e).with_context(|| format!("cannot read {}", path.display())),
+            }
+        }
+        for (name, setting) in [
+            ("MODE", ".mode"),
+            ("HEADERS", ".headers"),
+            ("NULLVALUE", ".nullvalue"),
+            ("CACHE_SIZE", ".cache_size"),
+            ("TEMP_STORE", ".temp_store"),
+            ("TIMER", ".timer"),
+        ] {
+            let name = format!("{}{}", ENV_PREFIX, name);
+            if let Ok(value) = env::var(&name) {
+                defaults.set(setting, &value).context(name)?;
+            }
+        }
+        Ok(defaults)
+    }
+
+    /// Applies a line of the rc file: a dot-command, or the `cache_size` or
+    /// `temp_store` pragma. Blank lines and `--` comments are skipped.
+    fn rc_line(&mut self, line: &str) -> Result<()> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with("--") {
+            return Ok(());
+        }
+        if line.starts_with('.') {
+            let (setting, value) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            return self.set(setting, value.trim());
+        }
+        let words = line.trim_end_matches(';').to_ascii_lowercase();
+        let words = words.split(|c: char| c.is_whitespace() || c == '=');
+        match words.filter(|w| !w.is_empty()).collect::<Vec<_>>()[..] {
+            ["pragma", "cache_size", value] => self.set(".cache_size", value),
+            ["pragma", "temp_store", value] => self.set(".temp_store", value),
+            _ => bail!(
+                "only dot-commands and PRAGMA cache_size and temp_store are supported: {}",
+                line
+            ),
+        }
+    }
+
+    fn set(&mut self, setting: &str, value: &str) -> Result<()> {
+        match setting {
+            ".mode" => {
+                self.mode = Some(
+                    Mode::from_name(value).with_context(|| format!("unknown mode: {}", value))?,
+                )
+            }
+            ".headers" => self.headers = Some(switch(value)?),
+            ".nullvalue" => self.null = Some(value.to_string()),
+            ".cache_size" => {
+                self.cache_size = Some(
+                    value
+                        .parse()
+                        .with_context(|| format!("cache_size expects a number, not {}", value))?,
+                )
+            }
+            ".temp_store" => {
+                self.temp_store = Some(TempStore::from_name(value).with_context(|| {
+                    format!("temp_store expects default, file or memory, not {}", value)
+                })?)
+            }
+            ".timer" => self.timer = Some(switch(value)?),
+            _ => bail!("unknown command: {}", setting),
+        }
+        Ok(())
+    }
+}
+
+/// `on` or `off`, or any of the other ways sqlite3 spells a boolean.
+fn switch(value: &str) -> Result<bool> {
+    match value.to_ascii_lowercase().as_str() {
+        "on" | "yes" | "true" | "1" => Ok(true),
+        "off" | "no" | "false" | "0" => Ok(false),
+        _ => bail!("expected on or off, not {}", value),
+    }
+}
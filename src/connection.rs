@@ -0,0 +1,363 @@
+//! Opening database files.
+
+use crate::db::Options;
+use crate::header::DatabaseHeader;
+use crate::pager::Pager;
+use crate::ptrmap::PointerMap;
+use crate::record::Format;
+use crate::teach;
+use crate::wal::{self, Wal};
+use anyhow::{bail, Context, Result};
+use std::fs::{File, OpenOptions};
+use std::io::{prelude::*, SeekFrom};
+use std::path::{Path, PathBuf};
+
+/// A database file opened for reading.
+///
+/// Nothing is ever written through a connection: no journal, WAL or lock
+/// files are created next to the database, so files on read-only mounts or
+/// owned by other services can be read as long as the file itself is.
+#[derive(Debug)]
+pub struct Connection {
+    pub pager: Pager,
+    /// The page size less the bytes reserved at the end of every page
+    pub format: Format,
+    /// The header as it was when the file was opened
+    pub header: DatabaseHeader,
+    /// The file was declared unchanging with `immutable=1`
+    pub immutable: bool,
+    path: PathBuf,
+    /// The version of the database when it was opened
+    version: Version,
+}
+
+/// Tells the states of a database apart: a commit bumps the file change
+/// counter in rollback mode, and adds to the WAL in WAL mode.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Version {
+    change_counter: u32,
+    wal: Option<wal::Version>,
+}
+
+impl Version {
+    /// The version of the database at `path`, open as `file`, as it is now.
+    fn of(file: &File, path: &Path) -> Result<Version> {
+        Ok(Version {
+            change_counter: change_counter(file)?,
+            wal: wal::Version::of(path)?,
+        })
+    }
+}
+
+impl Connection {
+    /// Opens the database named by `filename`, a path or a `file:` URI.
+    pub fn open(filename: &str, options: &Options) -> Result<Connection> {
+        let filename = Filename::parse(filename)?;
+        Connection::open_readonly(&filename.path, filename.immutable, options)
+    }
+
+    /// Opens the database at `path` with a read-only file handle. An
+    /// `immutable` one is taken never to change, so there is no WAL to look
+    /// for and no change counter to check, then or later.
+    pub fn open_readonly(
+        path: impl AsRef<Path>,
+        immutable: bool,
+        options: &Options,
+    ) -> Result<Connection> {
+        let path = path.as_ref();
+        let mut file = OpenOptions::new().read(true).open(path)?;
+        let mut header = [0; 100];
+        file.read_exact(&mut header)
+            .with_context(|| format!("{}: file is not a database", path.display()))?;
+        // Until a checkpoint, the header in the file may be out of date, or
+        // not even filled in past the page size if the database was created
+        // in WAL mode: the rest comes from the WAL's page 1 if it has one
+        let page_size = DatabaseHeader::parse_page_size(&header)
+            .with_context(|| format!("{}: file is not a database", path.display()))?;
+        let wal = match immutable {
+            true => None,
+            false => Wal::open(path, page_size)
+                .with_context(|| format!("{}: cannot read the WAL", path.display()))?,
+        };
+        let mut pager = Pager::new(file, page_size, wal, options);
+        if pager.wal().map_or(false, |wal| wal.has_page(1)) {
+            let page = pager.get_any_page(1)?;
+            header = page[..100].try_into().unwrap();
+        }
+        let header = DatabaseHeader::parse(&header)
+            .with_context(|| format!("{}: file is not a database", path.display()))?;
+        if options.teach {
+            teach::header(&header);
+        }
+        pager.set_pointer_map(PointerMap::new(&header));
+
+        let version = Version {
+            change_counter: match immutable {
+                true => header.change_counter,
+                false => change_counter(pager.file())?,
+            },
+            wal: pager.wal().map(|wal| wal.version().clone()),
+        };
+        Ok(Connection {
+            pager,
+            format: header.format(),
+            header,
+            immutable,
+            path: path.to_owned(),
+            version,
+        })
+    }
+
+    /// Begins a read transaction: the statements run until it is ended all
+    /// see the database as it is now.
+    ///
+    /// Writers aren't held off, since taking SQLite's locks isn't possible
+    /// here. Instead every commit bumps the file change counter in the
+    /// header or adds to the WAL, and ending the transaction fails if either
+    /// changed, so
+    /// that a report whose statements saw different states of the database
+    /// is never trusted. An immutable database never changes, so neither
+    /// is checked, and the pages cached before are kept.
+    pub fn begin_read(&mut self) -> Result<ReadTransaction> {
+        if self.immutable {
+            return Ok(ReadTransaction {
+                version: self.version.clone(),
+                source: None,
+            });
+        }
+        // Pages cached before may have changed since
+        self.pager.clear()?;
+        let version = self.version()?;
+        // The WAL is read again if it was committed to, checkpointed or
+        // started over since it was last read
+        if version.wal.as_ref() != self.pager.wal().map(Wal::version) {
+            let wal = Wal::open(&self.path, self.pager.page_size())
+                .with_context(|| format!("{}: cannot read the WAL", self.path.display()))?;
+            self.pager.set_wal(wal);
+        }
+        Ok(ReadTransaction {
+            version: Version {
+                wal: self.pager.wal().map(|wal| wal.version().clone()),
+                ..version
+            },
+            source: Some((self.pager.file().try_clone()?, self.path.clone())),
+        })
+    }
+
+    /// Whether the database was written to since it was opened, so that the
+    /// header and schema read then may be out of date.
+    pub fn is_stale(&mut self) -> Result<bool> {
+        Ok(!self.immutable && self.version()? != self.version)
+    }
+
+    /// The version of the database as it is now.
+    fn version(&self) -> Result<Version> {
+        Version::of(self.pager.file(), &self.path)
+    }
+}
+
+/// The file change counter of `file`, at byte 24 of the header.
+fn change_counter(mut file: &File) -> Result<u32> {
+    let mut counter = [0; 4];
+    file.seek(SeekFrom::Start(24))?;
+    file.read_exact(&mut counter)?;
+    Ok(u32::from_be_bytes(counter))
+}
+
+/// A read transaction begun by `Connection::begin_read`, to be ended with
+/// `ReadTransaction::end`. It doesn't borrow the connection, so it can be
+/// ended after rows read lazily through the connection are done with.
+#[derive(Debug)]
+#[must_use]
+pub struct ReadTransaction {
+    version: Version,
+    /// The database file and its path, to read the version from again at
+    /// the end, unless the database is immutable
+    source: Option<(File, PathBuf)>,
+}
+
+impl ReadTransaction {
+    /// Ends the transaction, failing if the database was written to since
+    /// it began.
+    pub fn end(self) -> Result<()> {
+        if let Some((file, path)) = &self.source {
+            if Version::of(file, path)? != self.version {
+                bail!("the database was modified during the read: results may be inconsistent");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A database filename: a plain path, or a URI such as
+/// `file:data.db?mode=ro&immutable=1` whose parameters set how it is opened.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Filename {
+    pub path: PathBuf,
+    pub immutable: bool,
+}
+
+impl Filename {
+    pub fn parse(filename: &str) -> Result<Filename> {
+        let Some(uri) = filename.strip_prefix("file:") else {
+            return Ok(Filename {
+                path: PathBuf::from(filename),
+                immutable: false,
+            });
+        };
+        // Anything after `#` is a fragment, which SQLite ignores
+        let uri = uri.split('#').next().unwrap_or_default();
+        let (path, query) = uri.split_once('?').unwrap_or((uri, ""));
+        // `file://host/path` only names a local file if the host is empty or localhost
+        let path = match path.strip_prefix("//") {
+            Some(rest) => {
+                let (host, path) = rest.find('/').map_or((rest, ""), |i| rest.split_at(i));
+                if !host.is_empty() && host != "localhost" {
+                    bail!("invalid uri authority: {}", host);
+                }
+                path
+            }
+            None => path,
+        };
+
+        let mut immutable = false;
+        for parameter in query.split('&').filter(|p| !p.is_empty()) {
+            let (key, value) = parameter.split_once('=').unwrap_or((parameter, ""));
+            let value = percent_decode(value);
+            match percent_decode(key).as_str() {
+                "mode" => match value.as_str() {
+                    "ro" => {}
+                    "rw" | "rwc" | "memory" => {
+                        bail!(
+                            "access mode {} is not supported: databases are only read",
+                            value
+                        )
+                    }
+                    _ => bail!("no such access mode: {}", value),
+                },
+                "immutable" => immutable = boolean(&value)?,
+                // There is a single connection to share a cache with
+                "cache" if value == "shared" || value == "private" => {}
+                "cache" => bail!("no such cache mode: {}", value),
+                // Like SQLite, parameters it doesn't know about are ignored
+                _ => {}
+            }
+        }
+
+        Ok(Filename {
+            path: PathBuf::from(percent_decode(path)),
+            immutable,
+        })
+    }
+}
+
+/// Decodes the `%XX` escapes of a URI component.
+pub fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], escaped) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// A boolean URI parameter, spelled the ways `sqlite3_uri_boolean` accepts.
+fn boolean(value: &str) -> Result<bool> {
+    match value.to_lowercase().as_str() {
+        "1" | "yes" | "true" | "on" => Ok(true),
+        "0" | "no" | "false" | "off" => Ok(false),
+        _ => bail!("invalid boolean value: {}", value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::load::load;
+    use crate::testing::TempDatabase;
+    use std::iter;
+
+    /// Bumps the file change counter of the database at `path`, as a commit
+    /// by another process would.
+    fn commit(path: &Path) {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .unwrap();
+        let counter = change_counter(&file).unwrap();
+        file.seek(SeekFrom::Start(24)).unwrap();
+        file.write_all(&(counter + 1).to_be_bytes()).unwrap();
+    }
+
+    #[test]
+    fn immutable_databases_are_not_checked_for_changes() {
+        let database = TempDatabase::new("immutable");
+        load(&database.0, "CREATE TABLE t (a)", &[], iter::empty()).unwrap();
+        let options = Options::default();
+        let mut connection = Connection::open(database.filename(), &options).unwrap();
+        let uri = format!("file:{}?immutable=1", database.filename());
+        let mut immutable = Connection::open(&uri, &options).unwrap();
+        assert!(immutable.immutable);
+
+        let read = connection.begin_read().unwrap();
+        let immutable_read = immutable.begin_read().unwrap();
+        commit(&database.0);
+        assert!(read.end().is_err());
+        assert!(immutable_read.end().is_ok());
+        assert!(connection.is_stale().unwrap());
+        assert!(!immutable.is_stale().unwrap());
+    }
+
+    #[test]
+    fn uri_filenames() {
+        let parse = |filename| Filename::parse(filename).unwrap();
+        let file = |path: &str, immutable| Filename {
+            path: PathBuf::from(path),
+            immutable,
+        };
+        assert_eq!(parse("data.db"), file("data.db", false));
+        // Only a `file:` prefix makes a URI
+        assert_eq!(
+            parse("data.db?immutable=1"),
+            file("data.db?immutable=1", false)
+        );
+        assert_eq!(parse("file:data.db"), file("data.db", false));
+        assert_eq!(
+            parse("file:///tmp/my%20data.db?mode=ro&immutable=yes#fragment"),
+            file("/tmp/my data.db", true)
+        );
+        assert_eq!(
+            parse("file://localhost/data.db?immutable=0&cache=shared&other=x"),
+            file("/data.db", false)
+        );
+
+        for (filename, error) in [
+            ("file://host/data.db", "invalid uri authority: host"),
+            ("file:data.db?mode=rw", "access mode rw is not supported"),
+            ("file:data.db?mode=fast", "no such access mode: fast"),
+            (
+                "file:data.db?immutable=maybe",
+                "invalid boolean value: maybe",
+            ),
+            ("file:data.db?cache=none", "no such cache mode: none"),
+        ] {
+            let message = Filename::parse(filename).unwrap_err().to_string();
+            assert!(message.starts_with(error), "{}: {}", filename, message);
+        }
+    }
+}
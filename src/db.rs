@@ -0,0 +1,255 @@
+//! A database opened to be queried from a program, rather than from the
+//! command line.
+
+use crate::connection::{Connection, ReadTransaction};
+use crate::exec::Statement;
+use crate::output::{ColumnInfo, OutputWriter};
+use crate::{load_schemas, pragma, sql, Column, Row, Schema};
+use anyhow::Result;
+use std::env;
+use std::io;
+use std::path::PathBuf;
+
+/// How a database is opened and read. Each `Database` has its own, so that
+/// two opened differently in one process don't interfere.
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// How many pages each pager caches, read like SQLite's `cache_size`: a
+    /// number of pages, or when negative of KiB. The default is SQLite's,
+    /// 2000 KiB.
+    pub cache_size: i64,
+    /// Whether pagers check that pages read again haven't changed. Nothing
+    /// here takes the locks SQLite uses, so a page that reads back
+    /// differently was changed by a writer in the meantime, and results
+    /// mixing its old and new contents can't be trusted.
+    pub verify_pages: bool,
+    /// Virtual tables in `main`, by name: CSV files, or TSV by their `.tsv`
+    /// extension
+    pub vtabs: Vec<(String, PathBuf)>,
+    /// How many result sets `serve` keeps to answer the same queries again
+    pub result_cache_size: usize,
+    /// Whether what outgrows memory spills to files, as `PRAGMA temp_store`
+    /// says
+    pub temp_store: TempStore,
+    /// Where spill files go, with `--temp-dir`, rather than the system's
+    /// temporary directory
+    pub temp_dir: Option<PathBuf>,
+    /// Whether pagers read pages from a mapping of the file, with `--mmap`
+    pub mmap: bool,
+    /// Whether reading the file is narrated on stderr, with `--teach`
+    pub teach: bool,
+}
+
+/// Where what outgrows memory goes, like SQLite's `PRAGMA temp_store`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TempStore {
+    /// Files, as for `File`
+    #[default]
+    Default,
+    File,
+    /// Nowhere: it is all kept in memory, for systems whose temporary
+    /// directory is small or slow
+    Memory,
+}
+
+impl TempStore {
+    /// The store named `name`, or numbered as SQLite numbers them.
+    pub fn from_name(name: &str) -> Option<TempStore> {
+        match name.to_ascii_lowercase().as_str() {
+            "0" | "default" => Some(TempStore::Default),
+            "1" | "file" => Some(TempStore::File),
+            "2" | "memory" => Some(TempStore::Memory),
+            _ => None,
+        }
+    }
+
+    /// The number `PRAGMA temp_store` reports.
+    pub fn number(self) -> i64 {
+        match self {
+            TempStore::Default => 0,
+            TempStore::File => 1,
+            TempStore::Memory => 2,
+        }
+    }
+}
+
+impl Options {
+    /// The directory spill files go in, or None if `temp_store` keeps
+    /// everything in memory.
+    pub fn spill_dir(&self) -> Option<PathBuf> {
+        match self.temp_store {
+            TempStore::Memory => None,
+            TempStore::Default | TempStore::File => {
+                Some(self.temp_dir.clone().unwrap_or_else(env::temp_dir))
+            }
+        }
+    }
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            cache_size: -2000,
+            verify_pages: false,
+            vtabs: vec![],
+            result_cache_size: 0,
+            temp_store: TempStore::Default,
+            temp_dir: None,
+            mmap: false,
+            teach: false,
+        }
+    }
+}
+
+/// An open database and its schema, along with those attached to it.
+#[derive(Debug)]
+pub struct Database {
+    /// `main` first, then the databases attached as `aux1`, `aux2`...
+    pub connections: Vec<Connection>,
+    /// The schema of each, in the same order
+    pub schemas: Vec<Schema>,
+    /// What they were opened with
+    pub options: Options,
+}
+
+impl Database {
+    /// Opens the database named by `filename`, a path or a `file:` URI, and
+    /// reads its schema.
+    pub fn open(filename: &str) -> Result<Database> {
+        Database::open_all(&[filename], Options::default())
+    }
+
+    /// Opens the databases named by `filenames`, the first as `main` and the
+    /// others attached to it, so that one statement can join tables across
+    /// files, and reads their schemas.
+    pub fn open_all(filenames: &[impl AsRef<str>], options: Options) -> Result<Database> {
+        let mut connections = filenames
+            .iter()
+            .map(|filename| Connection::open(filename.as_ref(), &options))
+            .collect::<Result<Vec<_>>>()?;
+        let schemas = load_schemas(&mut connections, &options)?;
+        Ok(Database {
+            connections,
+            schemas,
+            options,
+        })
+    }
+
+    /// Whether any of the databases has been written to since it was
+    /// opened, so that the schemas read then may be out of date.
+    pub fn is_stale(&mut self) -> Result<bool> {
+        for connection in &mut self.connections {
+            if connection.is_stale()? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Runs `sql`, a `SELECT` statement or a PRAGMA, in a read transaction
+    /// of every database. The rows of a statement are read as they are
+    /// asked for, and the transactions last until `Rows::finish`.
+    pub fn query(&mut self, sql: &str) -> Result<Rows<'_>> {
+        let transactions = self
+            .connections
+            .iter_mut()
+            .map(Connection::begin_read)
+            .collect::<Result<Vec<_>>>()?;
+        let (columns, rows): (_, Box<dyn Iterator<Item = Result<Row>>>) = if sql
+            .split_whitespace()
+            .next()
+            .map_or(false, |word| word.eq_ignore_ascii_case("PRAGMA"))
+        {
+            let pragma = sql::parse_pragma(sql)?;
+            let mut results = Results::default();
+            pragma::execute(
+                &pragma,
+                &self.schemas,
+                &mut self.connections,
+                &mut self.options,
+                &mut results,
+            )?;
+            (results.columns, Box::new(results.rows.into_iter().map(Ok)))
+        } else {
+            let mut statement = Statement::prepare(sql, &self.schemas)?;
+            statement.set_spill_dir(self.options.spill_dir());
+            let columns = statement.columns().iter().map(|c| c.name.clone()).collect();
+            (columns, statement.query(&mut self.connections)?)
+        };
+        Ok(Rows {
+            columns,
+            rows,
+            transactions,
+            error: None,
+        })
+    }
+}
+
+/// The rows a query returns, in order. An error reading them ends them
+/// early, and `finish` returns it.
+pub struct Rows<'a> {
+    columns: Vec<String>,
+    rows: Box<dyn Iterator<Item = Result<Row>> + 'a>,
+    transactions: Vec<ReadTransaction>,
+    /// What ended the rows early, if anything did
+    error: Option<anyhow::Error>,
+}
+
+impl Rows<'_> {
+    /// The names of the columns of each row.
+    pub fn columns(&self) -> &[String] {
+        &self.columns
+    }
+
+    /// Ends the read transactions, failing if reading the rows did, or if
+    /// a database was written to while they were read.
+    pub fn finish(self) -> Result<()> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+        for transaction in self.transactions {
+            transaction.end()?;
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for Rows<'_> {
+    type Item = Row;
+
+    fn next(&mut self) -> Option<Row> {
+        if self.error.is_some() {
+            return None;
+        }
+        match self.rows.next()? {
+            Ok(row) => Some(row),
+            Err(error) => {
+                self.error = Some(error);
+                None
+            }
+        }
+    }
+}
+
+/// Collects what a PRAGMA writes out.
+#[derive(Debug, Default)]
+struct Results {
+    columns: Vec<String>,
+    rows: Vec<Row>,
+}
+
+impl OutputWriter for Results {
+    fn begin(&mut self, columns: &[ColumnInfo]) -> io::Result<()> {
+        self.columns = columns.iter().map(|c| c.name.clone()).collect();
+        Ok(())
+    }
+
+    fn write_row(&mut self, row: &[Column]) -> io::Result<()> {
+        self.rows.push(row.to_vec());
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,256 @@
+use crate::btree::{index_rows, rows, Direction};
+use crate::output::{sql_literal, InsertWriter, OutputWriter};
+use crate::pager::Pager;
+use crate::record::{Format, TableLayout};
+use crate::{sql, sql_columns, Column, Error, Schema, Table};
+use anyhow::{bail, Result};
+use std::borrow::Cow;
+use std::io::Write;
+
+/// `.dump [--schema-only | --data-only] [TABLE...]`: the database (or just
+/// the given tables and their indexes) as SQL text that recreates it.
+pub fn dump(
+    pager: &mut Pager,
+    format: Format,
+    tables: &[Table],
+    args: &[&str],
+    out: &mut dyn Write,
+) -> Result<()> {
+    let mut schema = true;
+    let mut data = true;
+    let mut selected = vec![];
+    for arg in args {
+        match *arg {
+            "--schema-only" => data = false,
+            "--data-only" => schema = false,
+            arg if arg.starts_with('-') => bail!("unknown .dump option: {}", arg),
+            table => selected.push(table),
+        }
+    }
+    if !schema && !data {
+        bail!("--schema-only and --data-only are mutually exclusive");
+    }
+    for name in &selected {
+        if !tables.iter().any(|t| t.ty == "table" && t.name == *name) {
+            return Err(Error::UnknownTable(name.to_string()).into());
+        }
+    }
+    let is_selected = |name: &str| selected.is_empty() || selected.contains(&name);
+
+    if schema {
+        writeln!(out, "PRAGMA foreign_keys=OFF;")?;
+        writeln!(out, "BEGIN TRANSACTION;")?;
+    }
+
+    let mut sequence = None;
+    let mut writable_schema = false;
+    // Virtual tables are the command's, not the database's
+    for table in tables
+        .iter()
+        .filter(|t| t.ty == "table" && t.vtab.is_none())
+    {
+        if !is_selected(&table.name) {
+            continue;
+        }
+        // sqlite_sequence is created implicitly, so its contents are restored
+        // separately once every AUTOINCREMENT table exists.
+        if table.name == "sqlite_sequence" {
+            sequence = Some(table);
+            continue;
+        }
+
+        // A virtual table of the database has no b-tree, only its SQL, which
+        // CREATE VIRTUAL TABLE would fail on for a module that isn't loaded:
+        // it goes straight into the schema, as sqlite3 puts it
+        if table.rootpage == 0 {
+            if schema {
+                if !writable_schema {
+                    writeln!(out, "PRAGMA writable_schema=ON;")?;
+                    writable_schema = true;
+                }
+                writeln!(
+                    out,
+                    "INSERT INTO sqlite_schema(type,name,tbl_name,rootpage,sql)VALUES('table',{},{},0,{});",
+                    sql_literal(&Column::Text(table.name.clone())),
+                    sql_literal(&Column::Text(table.name.clone())),
+                    sql_literal(&Column::Text(table.sql.clone()))
+                )?;
+            }
+            continue;
+        }
+
+        if schema {
+            writeln!(out, "{};", create_table(&table.sql))?;
+        }
+        if data {
+            dump_rows(pager, format, table, out)?;
+        }
+    }
+
+    if let Some(table) = sequence {
+        if !writable_schema {
+            writeln!(out, "PRAGMA writable_schema=ON;")?;
+            writable_schema = true;
+        }
+        writeln!(
+            out,
+            "CREATE TABLE IF NOT EXISTS {};",
+            &table.sql["CREATE TABLE ".len()..]
+        )?;
+        if data {
+            writeln!(out, "DELETE FROM sqlite_sequence;")?;
+            dump_rows(pager, format, table, out)?;
+        }
+    }
+    if writable_schema {
+        writeln!(out, "PRAGMA writable_schema=OFF;")?;
+    }
+
+    if schema {
+        // Indexes, views and triggers, skipping the ones created implicitly by
+        // constraints (which have no SQL)
+        for entry in tables
+            .iter()
+            .filter(|t| t.ty != "table" && !t.sql.is_empty())
+        {
+            if is_selected(&entry.tbl_name) {
+                writeln!(out, "{};", entry.sql)?;
+            }
+        }
+        writeln!(out, "COMMIT;")?;
+    }
+
+    Ok(())
+}
+
+/// `.schema [PATTERN]`: the SQL of every table, index, view and trigger, or
+/// of those on a table whose name matches `pattern`, a LIKE pattern, written
+/// as sqlite3 writes it.
+pub fn schema(tables: &[Table], pattern: Option<&str>, out: &mut dyn Write) -> Result<()> {
+    // A pattern naming the schema table itself gets its definition, which
+    // isn't stored anywhere
+    if let Some(pattern) = pattern {
+        if like(pattern, "sqlite_master") || like(pattern, "sqlite_schema") {
+            writeln!(
+                out,
+                "CREATE TABLE {} (\n  type text,\n  name text,\n  tbl_name text,\n  rootpage integer,\n  sql text\n);",
+                pattern
+            )?;
+        }
+    }
+    for entry in tables
+        .iter()
+        .filter(|t| !t.sql.is_empty() && t.vtab.is_none())
+        .filter(|t| pattern.map_or(true, |pattern| like(pattern, &t.tbl_name)))
+    {
+        let sql = &entry.sql;
+        if sql.contains("--") || sql.contains("/*") {
+            // A comment at the end would swallow the semicolon
+            writeln!(out, "{}\n;", sql)?;
+        } else {
+            writeln!(out, "{};", create_table(sql))?;
+        }
+    }
+    Ok(())
+}
+
+/// `sql` as sqlite3 writes it: a table whose name is quoted, such as the
+/// shadow tables of a virtual table, may already have been created by the
+/// time it is run, as the virtual table's are by the virtual table.
+fn create_table(sql: &str) -> Cow<'_, str> {
+    if sql.starts_with("CREATE TABLE \"") || sql.starts_with("CREATE TABLE '") {
+        Cow::Owned(format!(
+            "CREATE TABLE IF NOT EXISTS {}",
+            &sql["CREATE TABLE ".len()..]
+        ))
+    } else {
+        Cow::Borrowed(sql)
+    }
+}
+
+/// `.indexes [PATTERN]`: the names of the indexes, or of those on a table
+/// whose name matches `pattern`, a LIKE pattern, on one line as `.tables`
+/// writes its names. Those of attached databases are qualified with their
+/// schema.
+pub fn indexes(schemas: &[Schema], pattern: Option<&str>, out: &mut dyn Write) -> Result<()> {
+    let names = schemas.iter().flat_map(|schema| {
+        schema
+            .tables
+            .iter()
+            .filter(|t| t.ty == "index")
+            .filter(|t| pattern.map_or(true, |pattern| like(pattern, &t.tbl_name)))
+            .map(move |t| match schema.name.as_str() {
+                "main" => t.name.clone(),
+                name => format!("{}.{}", name, t.name),
+            })
+    });
+    writeln!(out, "{}", names.collect::<Vec<_>>().join(" "))?;
+    Ok(())
+}
+
+/// Whether `name` matches the LIKE pattern `pattern`, in which `%` stands
+/// for any run of characters and `_` for any one, ignoring ASCII case.
+fn like(pattern: &str, name: &str) -> bool {
+    let (pattern, name) = (
+        pattern.to_ascii_lowercase().chars().collect::<Vec<_>>(),
+        name.to_ascii_lowercase().chars().collect::<Vec<_>>(),
+    );
+    // Which prefixes of `name` the pattern so far matches
+    let mut matched = vec![false; name.len() + 1];
+    matched[0] = true;
+    for p in pattern {
+        matched = match p {
+            '%' => {
+                let first = matched.iter().position(|&m| m).unwrap_or(matched.len());
+                (0..=name.len()).map(|i| i >= first).collect()
+            }
+            p => (0..=name.len())
+                .map(|i| i > 0 && matched[i - 1] && (p == '_' || name[i - 1] == p))
+                .collect(),
+        };
+    }
+    matched[name.len()]
+}
+
+fn dump_rows(pager: &mut Pager, format: Format, table: &Table, out: &mut dyn Write) -> Result<()> {
+    let page = pager.get_page(table.rootpage)?;
+    let mut writer = InsertWriter::new(out, &table.name, false);
+    writer.begin(&[])?;
+    // Tables whose SQL isn't understood are still dumped, with their rows
+    // as stored
+    let layout = sql_columns(&table.sql).map_or_else(
+        |_| TableLayout::default(),
+        |columns| TableLayout::new(&columns),
+    );
+    match sql::parse_create_table(&table.sql) {
+        Ok(create) if create.without_rowid => {
+            // A WITHOUT ROWID table is an index b-tree whose entries are the
+            // primary key's columns followed by the others
+            let key = create.unique_keys().into_iter().next().unwrap_or_default();
+            let mut order = vec![];
+            for name in key.iter().chain(create.columns.iter().map(|c| &c.name)) {
+                let i = create
+                    .columns
+                    .iter()
+                    .position(|c| c.name.eq_ignore_ascii_case(name));
+                if let Some(i) = i.filter(|i| !order.contains(i)) {
+                    order.push(i);
+                }
+            }
+            for entry in index_rows(&page, pager, format, Direction::Forward)? {
+                let mut row = layout.defaults.clone();
+                for (value, &i) in entry.into_iter().zip(&order) {
+                    row[i] = value;
+                }
+                writer.write_row(&row)?;
+            }
+        }
+        _ => {
+            for row in rows(page, pager, format, Direction::Forward, &layout)? {
+                writer.write_row(&row)?;
+            }
+        }
+    }
+    writer.finish()?;
+    Ok(())
+}
@@ -0,0 +1,42 @@
+//! What can be wrong with a statement or with the database it runs on. I/O
+//! errors are passed on as they are, and these are what the rest fail with,
+//! so that a caller can tell them apart.
+
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("no such table: {0}")]
+    UnknownTable(String),
+
+    #[error("no such column: {0}")]
+    UnknownColumn(String),
+
+    /// A page that isn't what the b-tree it is part of says it is, or holds
+    /// a cell that can't be read
+    #[error("database disk image is malformed: {reason} (page {page}, offset {offset})")]
+    CorruptPage {
+        page: u32,
+        offset: usize,
+        reason: String,
+    },
+
+    /// SQL that is valid for SQLite, but that this crate doesn't run
+    #[error("{0} is not supported")]
+    UnsupportedFeature(String),
+
+    /// SQL that doesn't parse, and the byte offset in it where the tokenizer
+    /// or the parser gave up
+    #[error("{message}")]
+    ParseError { message: String, offset: usize },
+}
+
+impl Error {
+    pub fn corrupt(page: u32, offset: usize, reason: impl Into<String>) -> Error {
+        Error::CorruptPage {
+            page,
+            offset,
+            reason: reason.into(),
+        }
+    }
+}
@@ -0,0 +1,2331 @@
+use crate::aggregate::{Accumulator, Aggregate, AggregateFunction};
+use crate::bloom::BloomFilter;
+use crate::btree::{
+    count_rows, index, index_range, index_rows, rows, rows_after, select, Direction, KeyOrder,
+    TableCursor,
+};
+use crate::connection::Connection;
+use crate::expr::{strip_hints, BoundExpr, Scope, ScopeTable};
+use crate::header::TextEncoding;
+use crate::output::{ColumnInfo, OutputWriter};
+use crate::pager::{self, Pager};
+use crate::record::{self, Format, TableLayout};
+use crate::sql::{self, Expr, ResultColumn, SelectStatement, TableRef};
+use crate::vtab::VirtualTable;
+use crate::{
+    rowid_alias, table_columns, Affinity, Collation, Column, ColumnDef, Error, Row, Schema, Table,
+};
+use anyhow::{bail, Context, Result};
+use itertools::Itertools;
+use std::cell::Cell;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::iter;
+use std::mem::{self, size_of};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::sync::atomic::{self, AtomicUsize};
+
+/// A parsed and resolved `SELECT` statement, ready to be executed.
+#[derive(Debug, Clone)]
+pub struct Statement {
+    /// Root page of the first table in FROM, if any
+    rootpage: Option<u32>,
+    /// Where the first table's rows come from instead, if it is virtual
+    vtab: Option<Rc<dyn VirtualTable>>,
+    /// The database the first table is in, as an index into the schemas
+    database: usize,
+    /// How the first table's records are decoded
+    layout: TableLayout,
+    /// Tables joined to the first one, in the order they are written
+    joins: Vec<JoinedTable>,
+    /// Whether the tables may be joined in another order than written: only
+    /// inner joins may, and only when the order of the rows doesn't matter
+    reorder_joins: bool,
+    /// Number of columns in the joined row
+    width: usize,
+    columns: Vec<ColumnInfo>,
+    /// One expression per output column
+    projection: Vec<BoundExpr>,
+    /// Whether duplicate result rows are dropped
+    distinct: bool,
+    index_lookup: Option<IndexLookup>,
+    index_range: Option<IndexRange>,
+    /// The index used holds every column of the table the statement reads,
+    /// so rows are made from its entries without reading the table
+    covering: bool,
+    /// Conditions checked against every fetched row
+    filters: Vec<BoundExpr>,
+    /// The WHERE clause is false whatever the row, so nothing is scanned
+    no_rows: bool,
+    /// Whether the rows are combined into groups, one output row each
+    aggregate: bool,
+    /// Aggregate function calls, whose results follow the table columns in
+    /// each group's row
+    aggregates: Vec<Aggregate>,
+    group_by: Vec<SortKey>,
+    having: Option<BoundExpr>,
+    /// Direction of the table scan, or of the index range, when it already
+    /// yields rows in the requested order
+    direction: Direction,
+    /// Sort keys for the rows, if the scan doesn't produce them in order
+    order_by: Vec<SortKey>,
+    limit: Option<usize>,
+    offset: usize,
+    /// Bytes of rows and keys that execution may hold at once
+    memory_limit: Option<usize>,
+    /// Where sorts spill rows past the memory limit, or None to keep them
+    spill_dir: Option<PathBuf>,
+    /// Shared with the copies made to run subqueries, which do the work
+    join_stats: Rc<JoinStats>,
+    /// The SELECT it was made from, written out in full: the same for
+    /// subqueries of the same SQL, whose results are then reused
+    select: String,
+}
+
+#[derive(Debug, Clone)]
+struct SortKey {
+    key: BoundExpr,
+    collation: Collation,
+    descending: bool,
+    /// Whether NULLs come before the other values, whatever the direction
+    nulls_first: bool,
+}
+
+impl SortKey {
+    fn compare(keys: &[SortKey], a: &[Column], b: &[Column]) -> Ordering {
+        keys.iter()
+            .zip(a.iter().zip(b))
+            .map(|(key, (a, b))| match (a, b) {
+                (Column::Null, Column::Null) => Ordering::Equal,
+                (Column::Null, _) if key.nulls_first => Ordering::Less,
+                (Column::Null, _) => Ordering::Greater,
+                (_, Column::Null) if key.nulls_first => Ordering::Greater,
+                (_, Column::Null) => Ordering::Less,
+                _ if key.descending => a.compare(b, key.collation).reverse(),
+                _ => a.compare(b, key.collation),
+            })
+            .find(|o| o.is_ne())
+            .unwrap_or(Ordering::Equal)
+    }
+}
+
+/// Bytes of rows and keys held before sorts spill to files, without a
+/// memory limit
+const SPILL_THRESHOLD: usize = 64 << 20;
+
+/// Most runs merged at once, and so spill files open at once
+const MAX_MERGE_WIDTH: usize = 64;
+
+/// The memory held by the rows and keys materialized while executing a
+/// statement, so that a query that would need more than the limit fails with
+/// an error instead of exhausting the memory of the process.
+struct MemoryUsage {
+    limit: Option<usize>,
+    used: Cell<usize>,
+    /// Where sorts write the rows that don't fit, or None if they are all
+    /// kept in memory
+    spill_dir: Option<PathBuf>,
+}
+
+impl MemoryUsage {
+    fn new(limit: Option<usize>, spill_dir: Option<PathBuf>) -> MemoryUsage {
+        MemoryUsage {
+            limit,
+            used: Cell::new(0),
+            spill_dir,
+        }
+    }
+
+    /// The bytes `values` take up as a row.
+    fn size(values: &[Column]) -> usize {
+        size_of::<Row>()
+            + values
+                .iter()
+                .map(|value| match value {
+                    Column::Text(s) => size_of::<Column>() + s.capacity(),
+                    Column::Blob(b) => size_of::<Column>() + b.capacity(),
+                    _ => size_of::<Column>(),
+                })
+                .sum::<usize>()
+    }
+
+    /// Accounts for `values` being held until the end of execution.
+    fn charge(&self, values: &[Column]) -> Result<()> {
+        self.reserve(Self::size(values))
+    }
+
+    /// Accounts for `bytes` more being held, until they are released.
+    fn reserve(&self, bytes: usize) -> Result<()> {
+        self.used.set(self.used.get() + bytes);
+        match self.limit {
+            Some(limit) if self.used.get() > limit => {
+                bail!("query exceeds memory limit of {} bytes", limit)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn release(&self, bytes: usize) {
+        self.used.set(self.used.get() - bytes);
+    }
+
+    /// Bytes that can still be held before sorts spill: up to the limit, or
+    /// to `SPILL_THRESHOLD` without one.
+    fn room(&self) -> usize {
+        self.limit
+            .unwrap_or(SPILL_THRESHOLD)
+            .saturating_sub(self.used.get())
+    }
+}
+
+type Keyed = (Vec<Column>, Row);
+
+/// Sorts rows by `keys`, returning each along with its evaluated keys. Rows
+/// are kept in memory while they fit; then they are sorted and written out
+/// to a file as a run, and the runs are merged back together at the end.
+/// Rows with equal keys stay in the order they were added.
+struct Sorter<'m> {
+    keys: Vec<SortKey>,
+    memory: &'m MemoryUsage,
+    rows: Vec<Keyed>,
+    /// Bytes reserved for `rows`
+    bytes: usize,
+    runs: Vec<Run>,
+}
+
+impl<'m> Sorter<'m> {
+    fn new(keys: &[SortKey], memory: &'m MemoryUsage) -> Sorter<'m> {
+        Sorter {
+            keys: keys.to_vec(),
+            memory,
+            rows: vec![],
+            bytes: 0,
+            runs: vec![],
+        }
+    }
+
+    fn add(&mut self, row: Row) -> Result<()> {
+        let keys = self.keys.iter().map(|k| k.key.eval(&row)).collect();
+        self.push(keys, row)
+    }
+
+    /// Adds `row`, whose keys are already evaluated.
+    fn push(&mut self, keys: Vec<Column>, row: Row) -> Result<()> {
+        let size = MemoryUsage::size(&keys) + MemoryUsage::size(&row);
+        if size > self.memory.room() && !self.rows.is_empty() {
+            if let Some(dir) = &self.memory.spill_dir {
+                self.sort();
+                let run = Run::write(dir, self.keys.len(), self.rows.drain(..).map(Ok))?;
+                self.runs.push(run);
+                self.memory.release(self.bytes);
+                self.bytes = 0;
+            }
+        }
+        self.memory.reserve(size)?;
+        self.bytes += size;
+        self.rows.push((keys, row));
+        Ok(())
+    }
+
+    fn sort(&mut self) {
+        let keys = &self.keys;
+        self.rows
+            .sort_by(|(a, _), (b, _)| SortKey::compare(keys, a, b));
+    }
+
+    /// All the rows added, in order. Those still in memory stay charged
+    /// until the end of execution.
+    fn finish(mut self) -> Result<Box<dyn Iterator<Item = Result<Keyed>>>> {
+        self.sort();
+        let rows = mem::take(&mut self.rows);
+        let Some(dir) = self
+            .memory
+            .spill_dir
+            .clone()
+            .filter(|_| !self.runs.is_empty())
+        else {
+            return Ok(Box::new(rows.into_iter().map(Ok)));
+        };
+        // Runs merged together go first, as their rows were added before
+        // those of the runs after them
+        while self.runs.len() > MAX_MERGE_WIDTH {
+            let runs = self
+                .runs
+                .drain(..MAX_MERGE_WIDTH)
+                .map(Run::read)
+                .collect::<Result<_>>()?;
+            let run = Run::write(&dir, self.keys.len(), merge(self.keys.clone(), runs))?;
+            self.runs.insert(0, run);
+        }
+        let mut sources = mem::take(&mut self.runs)
+            .into_iter()
+            .map(Run::read)
+            .collect::<Result<Vec<_>>>()?;
+        sources.push(Box::new(rows.into_iter().map(Ok)));
+        Ok(Box::new(merge(self.keys, sources)))
+    }
+}
+
+/// Merges `sources`, each sorted by `keys`, into one sorted stream. Of rows
+/// with equal keys, those of earlier sources come first.
+fn merge(
+    keys: Vec<SortKey>,
+    sources: Vec<Box<dyn Iterator<Item = Result<Keyed>>>>,
+) -> impl Iterator<Item = Result<Keyed>> {
+    let mut heads = sources
+        .into_iter()
+        .map(|mut source| (source.next(), source))
+        .collect::<Vec<_>>();
+    iter::from_fn(move || {
+        let mut next: Option<usize> = None;
+        for (i, (head, _)) in heads.iter().enumerate() {
+            match (head, next) {
+                (None, _) => {}
+                (Some(Err(_)), _) => {
+                    next = Some(i);
+                    break;
+                }
+                (Some(Ok(_)), None) => next = Some(i),
+                (Some(Ok((a, _))), Some(j)) => {
+                    if let Some(Ok((b, _))) = &heads[j].0 {
+                        if SortKey::compare(&keys, a, b).is_lt() {
+                            next = Some(i);
+                        }
+                    }
+                }
+            }
+        }
+        let (head, source) = &mut heads[next?];
+        mem::replace(head, source.next())
+    })
+}
+
+/// Sorted rows spilled to a file, each with its keys as one record,
+/// preceded by its length. The file is removed when the run is dropped.
+struct Run {
+    path: PathBuf,
+    /// How many of the values of each record are keys
+    keys: usize,
+}
+
+impl Run {
+    fn write(dir: &Path, keys: usize, rows: impl Iterator<Item = Result<Keyed>>) -> Result<Run> {
+        static RUNS: AtomicUsize = AtomicUsize::new(0);
+        let path = dir.join(format!(
+            "sqlite-sort-{}-{}",
+            process::id(),
+            RUNS.fetch_add(1, atomic::Ordering::Relaxed)
+        ));
+        let file =
+            File::create(&path).with_context(|| format!("cannot create {}", path.display()))?;
+        let run = Run { path, keys };
+        let mut out = BufWriter::new(file);
+        for row in rows {
+            let (mut values, row) = row?;
+            values.extend(row);
+            let record = record::encode(&values);
+            out.write_all(&(record.len() as u64).to_be_bytes())?;
+            out.write_all(&record)?;
+        }
+        out.flush()?;
+        Ok(run)
+    }
+
+    /// The rows of the run, read back in order.
+    fn read(self) -> Result<Box<dyn Iterator<Item = Result<Keyed>>>> {
+        let mut input = BufReader::new(File::open(&self.path)?);
+        Ok(Box::new(iter::from_fn(move || {
+            let mut length = [0; 8];
+            match input.read(&mut length[..1]) {
+                Ok(0) => return None,
+                Ok(_) => {}
+                Err(e) => return Some(Err(e.into())),
+            }
+            let mut read = || -> Result<Keyed> {
+                input.read_exact(&mut length[1..])?;
+                let mut record = vec![0; u64::from_be_bytes(length) as usize];
+                input.read_exact(&mut record)?;
+                let mut values = record::decode(&record, TextEncoding::Utf8)
+                    .with_context(|| format!("corrupt spill file {}", self.path.display()))?;
+                let row = values.split_off(self.keys);
+                Ok((values, row))
+            };
+            Some(read())
+        })))
+    }
+}
+
+impl Drop for Run {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// A hashable form of a result value, equal for two values exactly when
+/// they compare equal, so that duplicates can be found with a hash set.
+#[derive(Debug, PartialEq, Eq, Hash)]
+pub enum DistinctKey {
+    Null,
+    Integer(i64),
+    /// The bits of a real that isn't equal to any integer
+    Real(u64),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+impl DistinctKey {
+    pub fn new(value: &Column, collation: Collation) -> DistinctKey {
+        match value {
+            Column::Null => DistinctKey::Null,
+            Column::Integer(i) => DistinctKey::Integer(*i),
+            // 1.0 and 1 are the same value
+            Column::Real(r) if r.fract() == 0.0 && r.abs() < 9.2e18 => {
+                DistinctKey::Integer(*r as i64)
+            }
+            Column::Real(r) => DistinctKey::Real(r.to_bits()),
+            Column::Text(s) => DistinctKey::Text(collation.canonical(s)),
+            Column::Blob(b) => DistinctKey::Blob(b.clone()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct JoinedTable {
+    database: usize,
+    rootpage: u32,
+    vtab: Option<Rc<dyn VirtualTable>>,
+    layout: TableLayout,
+    /// Number of columns in the table
+    width: usize,
+    /// Whether rows without a match are kept, padded with NULL
+    left: bool,
+    /// The ON clause, evaluated on the row joined so far
+    constraint: Option<BoundExpr>,
+    /// A `column = column` term of the constraint, between a column of the
+    /// tables before and one of this table: their positions in the joined
+    /// row and the collation they compare under
+    key: Option<(usize, usize, Collation)>,
+}
+
+impl JoinedTable {
+    /// Every row of the table.
+    fn rows(&self, pager: &mut Pager, format: Format) -> Result<Vec<Row>> {
+        let mut rows = match &self.vtab {
+            Some(vtab) => vtab.rows()?,
+            None => {
+                let page = pager.get_page(self.rootpage)?;
+                rows(page, pager, format, Direction::Forward, &self.layout)?
+            }
+        };
+        // Rows written before columns were added to the table are short
+        for r in &mut rows {
+            r.resize(self.width, Column::Null);
+        }
+        Ok(rows)
+    }
+
+    /// Extends each of `left` with every row of `right`, the rows of this
+    /// table, that satisfies the constraint, by a nested loop over both. For
+    /// a LEFT JOIN, a row that no row satisfies it for is extended with NULLs
+    /// instead.
+    fn join(
+        &self,
+        left: Vec<Row>,
+        right: Vec<Row>,
+        memory: &MemoryUsage,
+        stats: &JoinStats,
+    ) -> Result<Vec<Row>> {
+        // Like SQLite's automatic indexes: when the table is looped over more
+        // than once, the rows of each key are found through a hash index
+        // built on the first pass instead of by comparing against every row.
+        // NULL keys equal nothing, and are left out.
+        let automatic = match self.key {
+            Some((outer, inner, collation)) if left.len() > 1 => {
+                let offset = left[0].len();
+                let mut index: HashMap<DistinctKey, Vec<usize>> = HashMap::new();
+                for (i, r) in right.iter().enumerate() {
+                    if r[inner - offset] != Column::Null {
+                        index
+                            .entry(DistinctKey::new(&r[inner - offset], collation))
+                            .or_default()
+                            .push(i);
+                    }
+                }
+                // With many rows to look up, most of those without a match
+                // are ruled out by a Bloom filter before the hash lookup
+                let filter = (left.len() >= BLOOM_FILTER_PROBES).then(|| {
+                    let mut filter = BloomFilter::new(index.len());
+                    for key in index.keys() {
+                        filter.insert(key);
+                    }
+                    filter
+                });
+                Some((outer, collation, index, filter))
+            }
+            _ => None,
+        };
+
+        let mut joined = vec![];
+        for l in &left {
+            let mut matched = false;
+            let candidates: Box<dyn Iterator<Item = &Row>> = match &automatic {
+                Some((outer, collation, index, filter)) => {
+                    let key = DistinctKey::new(&l[*outer], *collation);
+                    let found = match filter {
+                        Some(filter) => {
+                            stats.probes.set(stats.probes.get() + 1);
+                            if filter.may_contain(&key) {
+                                let found = index.get(&key);
+                                if found.is_none() {
+                                    stats.false_positives.set(stats.false_positives.get() + 1);
+                                }
+                                found
+                            } else {
+                                stats.filtered.set(stats.filtered.get() + 1);
+                                None
+                            }
+                        }
+                        None => index.get(&key),
+                    };
+                    Box::new(found.into_iter().flatten().map(|&i| &right[i]))
+                }
+                None => Box::new(right.iter()),
+            };
+            for r in candidates {
+                let mut row = l.clone();
+                row.extend(r.iter().cloned());
+                if self.constraint.as_ref().map_or(true, |c| c.is_true(&row)) {
+                    memory.charge(&row)?;
+                    joined.push(row);
+                    matched = true;
+                }
+            }
+            if self.left && !matched {
+                let mut row = l.clone();
+                row.resize(l.len() + self.width, Column::Null);
+                memory.charge(&row)?;
+                joined.push(row);
+            }
+        }
+        Ok(joined)
+    }
+}
+
+/// Rows to look up in a join's automatic index from which on a Bloom filter
+/// is checked first.
+const BLOOM_FILTER_PROBES: usize = 10_000;
+
+/// How the Bloom filters of a statement's joins did, for `--stats`.
+#[derive(Debug, Default)]
+pub struct JoinStats {
+    /// Rows checked against a Bloom filter
+    pub probes: Cell<u64>,
+    /// Rows the filter ruled out, without a hash lookup
+    pub filtered: Cell<u64>,
+    /// Rows the filter let through that had no match after all
+    pub false_positives: Cell<u64>,
+}
+
+/// Where a page of results ends, for the next page to start from. It only
+/// means something to the statement that returned it, and is written as a
+/// short token, such as `r42`, that `parse` reads back.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cursor(Position);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Position {
+    /// After the row with this rowid, in a scan of the table
+    RowId(u64),
+    /// After the row with this rowid among those found for the `key`th key
+    /// of the index lookup
+    Index { key: usize, row_id: u64 },
+    /// After this many rows, for statements whose rows can only be counted
+    /// off from the first one
+    Offset(usize),
+}
+
+impl fmt::Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Position::RowId(row_id) => write!(f, "r{}", row_id),
+            Position::Index { key, row_id } => write!(f, "i{}.{}", key, row_id),
+            Position::Offset(offset) => write!(f, "o{}", offset),
+        }
+    }
+}
+
+impl FromStr for Cursor {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Cursor> {
+        let position = match s.split_at(s.len().min(1)) {
+            ("r", row_id) => row_id.parse().ok().map(Position::RowId),
+            ("i", rest) => rest.split_once('.').and_then(|(key, row_id)| {
+                Some(Position::Index {
+                    key: key.parse().ok()?,
+                    row_id: row_id.parse().ok()?,
+                })
+            }),
+            ("o", offset) => offset.parse().ok().map(Position::Offset),
+            _ => None,
+        };
+        position
+            .map(Cursor)
+            .with_context(|| format!("invalid cursor: {}", s))
+    }
+}
+
+/// Some of the result rows of a statement.
+#[derive(Debug)]
+pub struct Page {
+    pub rows: Vec<Row>,
+    /// Where the next page starts, unless this one holds the last rows
+    pub next: Option<Cursor>,
+}
+
+/// Finds the table `table_ref` names, in the database it is qualified with or
+/// else the first one that has it, returning that database's index.
+fn find_table<'a>(schemas: &'a [Schema], table_ref: &TableRef) -> Result<(usize, &'a Table)> {
+    let schema = table_ref.schema.as_deref();
+    if let Some(schema) = schema {
+        if !schemas.iter().any(|s| s.name.eq_ignore_ascii_case(schema)) {
+            bail!("unknown database {}", schema);
+        }
+    }
+    schemas
+        .iter()
+        .enumerate()
+        .filter(|(_, s)| schema.map_or(true, |schema| s.name.eq_ignore_ascii_case(schema)))
+        .find_map(|(i, s)| {
+            s.tables
+                .iter()
+                .find(|t| t.ty == "table" && t.name.eq_ignore_ascii_case(&table_ref.name))
+                .map(|t| (i, t))
+        })
+        .ok_or_else(|| {
+            Error::UnknownTable(match schema {
+                Some(schema) => format!("{}.{}", schema, table_ref.name),
+                None => table_ref.name.clone(),
+            })
+            .into()
+        })
+}
+
+/// The table and definition of the column at `index` of the joined row.
+fn column_at(scope: &[ScopeTable], mut index: usize) -> (&ScopeTable, &ColumnDef) {
+    for t in scope {
+        if index < t.columns.len() {
+            return (t, &t.columns[index]);
+        }
+        index -= t.columns.len();
+    }
+    unreachable!("column index out of range of the scope")
+}
+
+#[derive(Debug, Clone)]
+struct IndexLookup {
+    rootpage: u32,
+    /// At most one row can match each key, since it covers the whole unique index
+    unique: bool,
+    /// The values looked up, each of the first columns of the index, in
+    /// index order
+    keys: Vec<Vec<Column>>,
+    /// How the index orders those columns
+    orders: Vec<KeyOrder>,
+    /// The column of the table each column of the index holds
+    columns: Vec<usize>,
+}
+
+/// A range of the values of an indexed column, whose rows are found by
+/// reading that part of the index, in the direction of the statement. An
+/// unbounded range reads the whole index, for the order it keeps the rows in.
+#[derive(Debug, Clone)]
+struct IndexRange {
+    rootpage: u32,
+    lower: Bound<Column>,
+    upper: Bound<Column>,
+    collation: Collation,
+    /// The column of the table the index holds
+    column: usize,
+}
+
+impl Statement {
+    pub fn prepare(sql: &str, schemas: &[Schema]) -> Result<Statement> {
+        Statement::from_select(&sql::parse_select(sql)?, schemas)
+    }
+
+    /// Resolves a parsed statement against the tables of `schemas`.
+    pub fn from_select(select: &SelectStatement, schemas: &[Schema]) -> Result<Statement> {
+        // Without FROM, the result is a single row with no columns to refer to
+        let mut from = vec![];
+        let mut scope_tables = vec![];
+        for table_ref in select
+            .from
+            .iter()
+            .chain(select.joins.iter().map(|j| &j.table))
+        {
+            let (database, table) = find_table(schemas, table_ref)?;
+            from.push((database, table));
+            scope_tables.push(ScopeTable {
+                schema: schemas[database].name.clone(),
+                name: table_ref
+                    .alias
+                    .clone()
+                    .unwrap_or_else(|| table.name.clone()),
+                table: table.name.clone(),
+                columns: table_columns(&table.sql)?,
+            });
+        }
+        let (database, table) = match from.first() {
+            Some((database, table)) => (*database, Some(*table)),
+            None => (0, None),
+        };
+        let aliases = select
+            .columns
+            .iter()
+            .filter_map(|c| match c {
+                ResultColumn::Expr {
+                    expr,
+                    alias: Some(alias),
+                    ..
+                } => Some((alias.clone(), expr.clone())),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        // Result columns can't refer to each other's aliases, but the other
+        // clauses can
+        let scope = Scope {
+            tables: &scope_tables,
+            aliases: &aliases,
+            schemas,
+        };
+        let unaliased = Scope {
+            aliases: &[],
+            ..scope
+        };
+
+        // Each ON constraint is checked as soon as its table is joined, so it
+        // can only refer to that table and those before it
+        let joins = select
+            .joins
+            .iter()
+            .enumerate()
+            .map(|(i, join)| {
+                let constraint = match &join.constraint {
+                    Some(constraint) => {
+                        let scope = Scope {
+                            tables: &scope_tables[..i + 2],
+                            aliases: &[],
+                            schemas,
+                        };
+                        Some(BoundExpr::bind(constraint, scope)?.fold())
+                    }
+                    None => None,
+                };
+                let offset = scope_tables[..i + 1].iter().map(|t| t.columns.len()).sum();
+                let key = constraint.as_ref().and_then(|c| {
+                    c.conjuncts()
+                        .into_iter()
+                        .find_map(|term| term.join_term(offset))
+                });
+                Ok(JoinedTable {
+                    database: from[i + 1].0,
+                    rootpage: from[i + 1].1.rootpage,
+                    vtab: from[i + 1].1.vtab.clone(),
+                    layout: TableLayout::new(&scope_tables[i + 1].columns),
+                    width: scope_tables[i + 1].columns.len(),
+                    left: join.left,
+                    constraint,
+                    key,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut aggregates = Vec::new();
+        let mut projection = Vec::new();
+        let mut columns = Vec::new();
+        for column in &select.columns {
+            let (expr, text) = match column {
+                ResultColumn::Star(qualifier) => {
+                    if scope_tables.is_empty() {
+                        bail!("no tables specified");
+                    }
+                    let mut offset = 0;
+                    let mut found = false;
+                    for t in &scope_tables {
+                        if qualifier
+                            .as_ref()
+                            .map_or(true, |q| q.eq_ignore_ascii_case(&t.name))
+                        {
+                            found = true;
+                            for (i, c) in t.columns.iter().enumerate() {
+                                projection.push(BoundExpr::Column {
+                                    index: offset + i,
+                                    affinity: c.affinity(),
+                                    collation: c.collation,
+                                });
+                                columns.push(ColumnInfo {
+                                    name: c.name.clone(),
+                                    decl_type: c.decl_type.clone(),
+                                    table: Some(t.table.clone()),
+                                });
+                            }
+                        }
+                        offset += t.columns.len();
+                    }
+                    if let (false, Some(qualifier)) = (found, qualifier) {
+                        return Err(Error::UnknownTable(qualifier.to_string()).into());
+                    }
+                    continue;
+                }
+                ResultColumn::Expr { expr, text, alias } => (expr, alias.as_ref().unwrap_or(text)),
+            };
+            let bound = BoundExpr::bind_aggregate(expr, unaliased, &mut aggregates)?.fold();
+            // A column reference keeps its declared type and table, and
+            // unless aliased is named after the column without any qualifier
+            let info = match (expr, &bound) {
+                (Expr::Column { .. }, BoundExpr::Column { index, .. }) => {
+                    let (t, c) = column_at(&scope_tables, *index);
+                    ColumnInfo {
+                        name: match column {
+                            ResultColumn::Expr { alias: Some(_), .. } => text.clone(),
+                            _ => c.name.clone(),
+                        },
+                        decl_type: c.decl_type.clone(),
+                        table: Some(t.table.clone()),
+                    }
+                }
+                _ => ColumnInfo {
+                    name: text.clone(),
+                    decl_type: None,
+                    table: None,
+                },
+            };
+            projection.push(bound);
+            columns.push(info);
+        }
+
+        let filters = match &select.where_clause {
+            Some(where_clause) => where_clause
+                .conjuncts()
+                .into_iter()
+                .map(|e| BoundExpr::bind(e, scope).map(BoundExpr::fold))
+                .collect::<Result<Vec<_>>>()?,
+            None => vec![],
+        };
+        // Terms that don't depend on the row are decided once, here
+        let (constant, mut filters): (Vec<_>, Vec<_>) =
+            filters.into_iter().partition(BoundExpr::is_constant);
+        let no_rows = constant.iter().any(|f| !f.is_true(&vec![]));
+
+        // An index on the first table's `column_index`th column alone, that
+        // is ordered consistently with a term comparing under `term_collation`
+        let usable_index = |column_index: usize, term_collation: Collation| {
+            let table = table?;
+            let sql_columns = &scope_tables[0].columns;
+            if column_index >= sql_columns.len() {
+                return None;
+            }
+            schemas[database]
+                .tables
+                .iter()
+                .filter(|t| t.ty == "index" && t.tbl_name == table.name)
+                .find_map(|t| {
+                    // Indexes backing UNIQUE/PRIMARY KEY constraints have no SQL and are skipped
+                    let index = sql::parse_create_index(&t.sql).ok()?;
+                    let [column] = index.columns.as_slice() else {
+                        return None;
+                    };
+                    if index.partial || column.descending {
+                        return None;
+                    }
+                    let collation = match &column.collation {
+                        Some(name) => Collation::from_name(name)?,
+                        None => sql_columns[column_index].collation,
+                    };
+
+                    // The index is only ordered consistently with the term
+                    // if both compare under the same collation
+                    (sql_columns[column_index]
+                        .name
+                        .eq_ignore_ascii_case(&column.name)
+                        && collation == term_collation)
+                        .then_some((t, collation))
+                })
+        };
+
+        // Use the index on the first table with the longest run of leading
+        // columns that `column = literal` or `column IN (literals)` terms
+        // cover, looking up every combination of their values; the remaining
+        // terms are checked against the rows fetched through it.
+        let lookup_terms = filters
+            .iter()
+            .enumerate()
+            .filter_map(|(i, filter)| Some((i, filter.lookup_term()?)))
+            .collect::<Vec<_>>();
+        let index_lookup = table.and_then(|table| {
+            let sql_columns = &scope_tables[0].columns;
+            schemas[database]
+                .tables
+                .iter()
+                .filter(|t| t.ty == "index" && t.tbl_name == table.name)
+                .filter_map(|t| {
+                    // Indexes backing UNIQUE/PRIMARY KEY constraints have no SQL and are skipped
+                    let index = sql::parse_create_index(&t.sql).ok()?;
+                    if index.partial {
+                        return None;
+                    }
+                    let columns = index
+                        .columns
+                        .iter()
+                        .map(|column| {
+                            sql_columns
+                                .iter()
+                                .position(|c| c.name.eq_ignore_ascii_case(&column.name))
+                        })
+                        .collect::<Option<Vec<_>>>()?;
+                    let mut used = vec![];
+                    let mut orders = vec![];
+                    let mut values = vec![];
+                    for (column, &column_index) in index.columns.iter().zip(&columns) {
+                        let collation = match &column.collation {
+                            Some(name) => Collation::from_name(name)?,
+                            None => sql_columns[column_index].collation,
+                        };
+                        // The index is only ordered consistently with the
+                        // term if both compare under the same collation
+                        let Some((i, (_, keys, _))) = lookup_terms
+                            .iter()
+                            .find(|(_, term)| term.0 == column_index && term.2 == collation)
+                        else {
+                            break;
+                        };
+                        let mut keys = keys.clone();
+                        if column.descending {
+                            keys.reverse();
+                        }
+                        used.push(*i);
+                        orders.push(KeyOrder {
+                            collation,
+                            descending: column.descending,
+                        });
+                        values.push(keys);
+                    }
+                    let unique = index.unique && used.len() == index.columns.len();
+                    (!used.is_empty())
+                        .then_some((t.rootpage, unique, used, orders, values, columns))
+                })
+                // The first of those covering the most columns
+                .min_by_key(|(_, _, used, ..)| Reverse(used.len()))
+        });
+        let index_lookup =
+            index_lookup.map(|(rootpage, unique, mut used, orders, values, columns)| {
+                used.sort_unstable();
+                for i in used.into_iter().rev() {
+                    filters.remove(i);
+                }
+                IndexLookup {
+                    rootpage,
+                    unique,
+                    keys: values.into_iter().multi_cartesian_product().collect(),
+                    orders,
+                    columns,
+                }
+            });
+
+        // Otherwise a range of an indexed column is read through the index
+        // when the index's sqlite_stat4 samples show the range to be small
+        // enough: each row found costs a descent of the table b-tree, where a
+        // full scan costs a row read per row of the table. The terms stay
+        // filters, so a range doesn't need to hold all the terms on its column.
+        let mut index_range = if index_lookup.is_some() {
+            None
+        } else {
+            let mut ranges: Vec<(usize, Bound<Column>, Bound<Column>, Collation)> = vec![];
+            for (column_index, lower, upper, collation) in
+                filters.iter().filter_map(BoundExpr::range_term)
+            {
+                match ranges
+                    .iter_mut()
+                    .find(|r| r.0 == column_index && r.3 == collation)
+                {
+                    Some(range) if lower != Bound::Unbounded => range.1 = lower,
+                    Some(range) => range.2 = upper,
+                    None => ranges.push((column_index, lower, upper, collation)),
+                }
+            }
+            ranges
+                .into_iter()
+                .filter_map(|(column_index, lower, upper, term_collation)| {
+                    let (t, collation) = usable_index(column_index, term_collation)?;
+                    let stats = schemas[database].stats.get(&t.name.to_lowercase())?;
+                    let estimate = stats.estimate(lower.as_ref(), upper.as_ref(), collation);
+                    let depth = 64 - stats.rows().leading_zeros() as u64;
+                    (estimate * depth < stats.rows()).then_some((
+                        estimate,
+                        IndexRange {
+                            rootpage: t.rootpage,
+                            lower,
+                            upper,
+                            collation,
+                            column: column_index,
+                        },
+                    ))
+                })
+                .min_by_key(|(estimate, _)| *estimate)
+                .map(|(_, range)| range)
+        };
+
+        let group_by = select
+            .group_by
+            .iter()
+            .enumerate()
+            .map(|(n, expr)| {
+                let key = match strip_hints(expr)? {
+                    // A bare integer refers to a result column, as in ORDER BY
+                    Expr::Literal(Column::Integer(k)) => {
+                        let Some(e) = (*k as usize).checked_sub(1).and_then(|i| projection.get(i))
+                        else {
+                            bail!(
+                                "{} GROUP BY term out of range - should be between 1 and {}",
+                                ordinal(n + 1),
+                                projection.len()
+                            );
+                        };
+                        if e.contains(&|e| matches!(e, BoundExpr::Aggregate { .. })) {
+                            bail!("aggregate functions are not allowed in the GROUP BY clause");
+                        }
+                        e.clone()
+                    }
+                    expr => BoundExpr::bind(expr, scope)?.fold(),
+                };
+                Ok(SortKey {
+                    collation: key.collation().unwrap_or_default(),
+                    key,
+                    descending: false,
+                    nulls_first: true,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let having = match &select.having {
+            Some(having) => Some(BoundExpr::bind_aggregate(having, scope, &mut aggregates)?.fold()),
+            None => None,
+        };
+
+        let mut order_by = Vec::new();
+        for (n, term) in select.order_by.iter().enumerate() {
+            let key = match strip_hints(&term.expr)? {
+                // A bare integer refers to a result column
+                Expr::Literal(Column::Integer(k)) => {
+                    match (*k as usize).checked_sub(1).and_then(|i| projection.get(i)) {
+                        Some(e) => e.clone(),
+                        None => bail!(
+                            "{} ORDER BY term out of range - should be between 1 and {}",
+                            ordinal(n + 1),
+                            projection.len()
+                        ),
+                    }
+                }
+                // A name is looked up among the aliases first, unlike in WHERE
+                Expr::Column {
+                    table: None, name, ..
+                } if aliases.iter().any(|(a, _)| a.eq_ignore_ascii_case(name)) => {
+                    let (_, aliased) = aliases
+                        .iter()
+                        .find(|(a, _)| a.eq_ignore_ascii_case(name))
+                        .unwrap();
+                    BoundExpr::bind_aggregate(aliased, unaliased, &mut aggregates)?.fold()
+                }
+                expr => BoundExpr::bind_aggregate(expr, scope, &mut aggregates)?.fold(),
+            };
+            if key.is_constant() {
+                continue;
+            }
+            let collation = match &term.collation {
+                Some(name) => Collation::from_name(name)
+                    .with_context(|| format!("no such collation sequence: {}", name))?,
+                None => key.collation().unwrap_or_default(),
+            };
+            order_by.push(SortKey {
+                key,
+                collation,
+                descending: term.descending,
+                // NULL is the smallest value unless the term says otherwise
+                nulls_first: term.nulls_first.unwrap_or(!term.descending),
+            });
+        }
+
+        let aggregate = !aggregates.is_empty() || !group_by.is_empty();
+        if having.is_some() && !aggregate {
+            bail!("HAVING clause on a non-aggregate query");
+        }
+
+        // Ordering by the rowid alone is the order of the table b-tree itself.
+        // The first table drives the outermost loop of any join, so this
+        // holds for its rowid even when other tables are joined to it.
+        let mut direction = Direction::Forward;
+        let mut scan_ordered = false;
+        if let [SortKey {
+            key: BoundExpr::Column { index, .. },
+            descending,
+            ..
+        }] = order_by.as_slice()
+        {
+            let rowid = scope_tables
+                .first()
+                .and_then(|t| t.columns.get(*index))
+                .map_or(false, ColumnDef::is_rowid_alias);
+            if index_lookup.is_none() && index_range.is_none() && !aggregate && rowid {
+                if *descending {
+                    direction = Direction::Backward;
+                }
+                order_by.clear();
+                scan_ordered = true;
+            }
+        }
+
+        // Ordering by an indexed column alone is the order of its index, read
+        // backward for DESC, which puts NULLs last as DESC does: through the
+        // range already chosen if it is of that column, or else the whole index
+        if let [SortKey {
+            key: BoundExpr::Column { index: column, .. },
+            collation,
+            descending,
+            nulls_first,
+        }] = order_by.as_slice()
+        {
+            if index_lookup.is_none() && !aggregate && nulls_first != descending {
+                if index_range.is_none() {
+                    index_range =
+                        usable_index(*column, *collation).map(|(t, collation)| IndexRange {
+                            rootpage: t.rootpage,
+                            lower: Bound::Unbounded,
+                            upper: Bound::Unbounded,
+                            collation,
+                            column: *column,
+                        });
+                }
+                if index_range
+                    .as_ref()
+                    .map_or(false, |r| r.column == *column && r.collation == *collation)
+                {
+                    if *descending {
+                        direction = Direction::Backward;
+                    }
+                    order_by.clear();
+                    scan_ordered = true;
+                }
+            }
+        }
+
+        // A statement reading only the first table, and only columns of it
+        // that the index it reads through holds, doesn't need the table
+        let index_columns = match (&index_lookup, &index_range) {
+            (Some(lookup), _) => Some(lookup.columns.clone()),
+            (_, Some(range)) => Some(vec![range.column]),
+            _ => None,
+        };
+        let covering = index_columns.map_or(false, |index_columns| {
+            let rowid_alias = scope_tables.first().and_then(|t| rowid_alias(&t.columns));
+            let exprs = projection
+                .iter()
+                .chain(&filters)
+                .chain(aggregates.iter().filter_map(|a| a.arg.as_ref()))
+                .chain(group_by.iter().map(|k| &k.key))
+                .chain(&having)
+                .chain(order_by.iter().map(|k| &k.key))
+                .collect::<Vec<_>>();
+            joins.is_empty()
+                && (0..scope.width())
+                    .filter(|&c| !index_columns.contains(&c) && Some(c) != rowid_alias)
+                    .all(|c| {
+                        !exprs.iter().any(|e| {
+                            e.contains(
+                                &|e| matches!(e, BoundExpr::Column { index, .. } if *index == c),
+                            )
+                        })
+                    })
+        });
+
+        let (limit, offset) = match &select.limit {
+            Some(limit) => {
+                let count = integer_value(&limit.count)?;
+                let offset = match &limit.offset {
+                    Some(offset) => integer_value(offset)?,
+                    None => 0,
+                };
+                // A negative limit means no limit, and a negative offset none
+                (usize::try_from(count).ok(), offset.max(0) as usize)
+            }
+            None => (None, 0),
+        };
+
+        Ok(Statement {
+            rootpage: table.map(|t| t.rootpage),
+            vtab: table.and_then(|t| t.vtab.clone()),
+            database,
+            layout: scope_tables
+                .first()
+                .map_or_else(TableLayout::default, |t| TableLayout::new(&t.columns)),
+            reorder_joins: !scan_ordered && !joins.iter().any(|j| j.left),
+            joins,
+            width: scope.width(),
+            columns,
+            projection,
+            distinct: select.distinct,
+            index_lookup,
+            index_range,
+            covering,
+            filters,
+            no_rows,
+            aggregate,
+            aggregates,
+            group_by,
+            having,
+            direction,
+            order_by,
+            limit,
+            offset,
+            memory_limit: None,
+            spill_dir: None,
+            join_stats: Rc::default(),
+            select: format!("{:?}", select),
+        })
+    }
+
+    pub fn set_memory_limit(&mut self, limit: Option<usize>) {
+        self.memory_limit = limit;
+    }
+
+    pub fn set_spill_dir(&mut self, dir: Option<PathBuf>) {
+        self.spill_dir = dir;
+    }
+
+    pub fn columns(&self) -> &[ColumnInfo] {
+        &self.columns
+    }
+
+    /// How the Bloom filters of the joins did in the executions so far.
+    pub fn join_stats(&self) -> &JoinStats {
+        &self.join_stats
+    }
+
+    /// Runs the statement over `connections`, the open databases in the
+    /// order of the schemas it was prepared with.
+    pub fn execute(
+        &self,
+        connections: &mut [Connection],
+        out: &mut dyn OutputWriter,
+    ) -> Result<()> {
+        let rows = self.query(connections)?;
+        out.begin(self.columns())?;
+        for row in rows {
+            out.write_row(&row?)?;
+        }
+        out.finish()?;
+        Ok(())
+    }
+
+    /// The result rows of the statement over `connections`, read as they are
+    /// asked for as far as the plan allows: a LIMIT stops the scan, and the
+    /// rows of a table read by itself are never all held at once.
+    pub fn query<'a>(
+        &self,
+        connections: &'a mut [Connection],
+    ) -> Result<Box<dyn Iterator<Item = Result<Row>> + 'a>> {
+        self.run_subqueries(connections)?.stream(connections)
+    }
+
+    /// Up to `limit` result rows, starting after `cursor`, or with the first
+    /// row without one. A statement that only scans one table in rowid order,
+    /// by itself or through an index, picks up the scan where the cursor left
+    /// it; for any other, the rows are computed in full and the page is cut
+    /// out of them.
+    pub fn query_page(
+        &self,
+        connections: &mut [Connection],
+        limit: usize,
+        cursor: Option<&Cursor>,
+    ) -> Result<Page> {
+        let statement = self.run_subqueries(connections)?;
+        let position = cursor.map(|c| &c.0);
+        let resumable = statement.rootpage.is_some()
+            && statement.vtab.is_none()
+            && statement.joins.is_empty()
+            && !statement.aggregate
+            && !statement.distinct
+            && statement.order_by.is_empty()
+            && statement.direction == Direction::Forward
+            && statement.limit.is_none()
+            && statement.offset == 0
+            && statement.index_range.is_none()
+            && !statement.no_rows;
+        let (rows, last) = match (resumable, position, &statement.index_lookup) {
+            (true, None | Some(Position::RowId(_)), None) => {
+                let after = match position {
+                    Some(Position::RowId(row_id)) => Some(*row_id),
+                    _ => None,
+                };
+                statement.page_from_scan(connections, limit, after)?
+            }
+            (true, None | Some(Position::Index { .. }), Some(lookup)) => {
+                let after = match position {
+                    Some(Position::Index { key, row_id }) => Some((*key, *row_id)),
+                    _ => None,
+                };
+                statement.page_from_index(connections, lookup, limit, after)?
+            }
+            (false, None | Some(Position::Offset(_)), _) => {
+                let offset = match position {
+                    Some(Position::Offset(offset)) => *offset,
+                    _ => 0,
+                };
+                let rows = statement
+                    .stream(connections)?
+                    .skip(offset)
+                    .take(limit)
+                    .collect::<Result<Vec<_>>>()?;
+                let last = Position::Offset(offset + rows.len());
+                (rows, Some(last))
+            }
+            _ => bail!("the cursor is not from this statement"),
+        };
+        Ok(Page {
+            next: last.filter(|_| rows.len() == limit).map(Cursor),
+            rows,
+        })
+    }
+
+    /// Joins the rows of the first table, `first`, to those of the tables
+    /// joined to it, `joined`, smallest table first rather than in the order
+    /// they are written, so that the nested loops carry the fewest rows.
+    /// Every table is read in full to be joined anyway, so the terms on it
+    /// alone are applied first, and its size is how many rows they leave:
+    /// exact, where statistics would only estimate it, and at most one for a
+    /// lookup of a unique key. Each table after the first is, if any is, one
+    /// that a `column = column` term links to those already joined, so that
+    /// its rows are found through an automatic index rather than by a cross
+    /// join. The other ON constraints and WHERE terms are checked as soon as
+    /// the tables they refer to are joined, and the columns of the rows are
+    /// put back in the order of the tables as written at the end.
+    fn join_by_size(
+        &self,
+        first: Vec<Row>,
+        joined: Vec<Vec<Row>>,
+        memory: &MemoryUsage,
+    ) -> Result<Vec<Row>> {
+        let first_width = self.width - self.joins.iter().map(|j| j.width).sum::<usize>();
+        // Where each table's columns are in the row as written
+        let mut offsets = vec![0];
+        let mut widths = vec![first_width];
+        for join in &self.joins {
+            offsets.push(offsets[offsets.len() - 1] + widths[widths.len() - 1]);
+            widths.push(join.width);
+        }
+        let mut tables = iter::once(first).chain(joined).collect::<Vec<_>>();
+        let table_of = |column: usize| offsets.iter().rposition(|&o| o <= column).unwrap();
+
+        // Each term that must hold, with the tables it refers to
+        let terms = self
+            .joins
+            .iter()
+            .filter_map(|j| j.constraint.as_ref())
+            .chain(&self.filters)
+            .flat_map(BoundExpr::conjuncts)
+            .map(|term| {
+                let refers = term
+                    .columns()
+                    .into_iter()
+                    .map(table_of)
+                    .collect::<HashSet<_>>();
+                (term, refers)
+            })
+            .collect::<Vec<_>>();
+        // `term` with each column moved to where `to` says
+        let shift = |term: &BoundExpr, to: &dyn Fn(usize) -> usize| {
+            term.clone().transform(&mut |e| {
+                Ok(match e {
+                    BoundExpr::Column {
+                        index,
+                        affinity,
+                        collation,
+                    } => BoundExpr::Column {
+                        index: to(index),
+                        affinity,
+                        collation,
+                    },
+                    e => e,
+                })
+            })
+        };
+        for (t, rows) in tables.iter_mut().enumerate() {
+            let own = terms
+                .iter()
+                .filter(|(_, refers)| refers.len() == 1 && refers.contains(&t))
+                .map(|(term, _)| shift(term, &|column| column - offsets[t]))
+                .collect::<Result<Vec<_>>>()?;
+            if !own.is_empty() {
+                rows.retain(|row| own.iter().all(|term| term.is_true(row)));
+            }
+        }
+
+        let links = |joined: &[usize], table: usize| {
+            terms.iter().any(|(term, refers)| {
+                refers.len() == 2
+                    && refers.contains(&table)
+                    && refers.iter().all(|t| *t == table || joined.contains(t))
+                    && term
+                        .join_term(offsets[*refers.iter().max().unwrap()])
+                        .is_some()
+            })
+        };
+
+        let sizes = tables.iter().map(Vec::len).collect::<Vec<_>>();
+        let order = join_order(&sizes, &links);
+        if order.iter().copied().eq(0..tables.len()) {
+            let mut rows = mem::take(&mut tables[0]);
+            for (join, right) in self.joins.iter().zip(tables.into_iter().skip(1)) {
+                rows = join.join(rows, right, memory, &self.join_stats)?;
+            }
+            return Ok(rows);
+        }
+
+        // Where each table's columns are in the row as joined
+        let mut joined_offsets = vec![0; tables.len()];
+        let mut offset = 0;
+        for &t in &order {
+            joined_offsets[t] = offset;
+            offset += widths[t];
+        }
+        let joined_column = |column: usize| {
+            let t = table_of(column);
+            joined_offsets[t] + column - offsets[t]
+        };
+        // The terms on more than one table that can first be checked once the
+        // tables up to the `step`th in the join order are joined, or on none
+        // at the first, combined with AND
+        let checked_at = |step: usize| {
+            terms
+                .iter()
+                .filter(|(_, refers)| {
+                    refers.len() != 1
+                        && refers.iter().all(|t| order[..=step].contains(t))
+                        && (step == 0 || refers.contains(&order[step]))
+                })
+                .map(|(term, _)| shift(term, &joined_column))
+                .collect::<Result<Vec<_>>>()
+                .map(|terms| {
+                    terms
+                        .into_iter()
+                        .reduce(|a, b| BoundExpr::And(Box::new(a), Box::new(b)))
+                })
+        };
+
+        let mut rows = mem::take(&mut tables[order[0]]);
+        if let Some(constraint) = checked_at(0)? {
+            rows.retain(|row| constraint.is_true(row));
+        }
+        for (step, &t) in order.iter().enumerate().skip(1) {
+            let constraint = checked_at(step)?;
+            let key = constraint.as_ref().and_then(|c| {
+                c.conjuncts()
+                    .into_iter()
+                    .find_map(|c| c.join_term(joined_offsets[t]))
+            });
+            // The rows are already read, so only how they are joined matters
+            let join = JoinedTable {
+                database: 0,
+                rootpage: 0,
+                vtab: None,
+                layout: TableLayout::default(),
+                width: widths[t],
+                left: false,
+                constraint,
+                key,
+            };
+            rows = join.join(rows, mem::take(&mut tables[t]), memory, &self.join_stats)?;
+        }
+
+        Ok(rows
+            .into_iter()
+            .map(|row| {
+                (0..tables.len())
+                    .flat_map(|t| {
+                        row[joined_offsets[t]..joined_offsets[t] + widths[t]]
+                            .iter()
+                            .cloned()
+                    })
+                    .collect()
+            })
+            .collect())
+    }
+
+    fn matches(&self, row: &Row) -> bool {
+        self.filters.iter().all(|f| f.is_true(row))
+    }
+
+    fn project(&self, row: &Row) -> Row {
+        self.projection.iter().map(|e| e.eval(row)).collect()
+    }
+
+    /// The row of the first table that the index entry `entry` was made
+    /// from, as far as a covering index holds it: the index's `columns` and
+    /// the rowid alias, with NULL for the others, which the statement doesn't
+    /// read.
+    fn covered_row(&self, entry: &[Column], columns: &[usize]) -> Row {
+        let mut row = vec![Column::Null; self.width];
+        for (value, &column) in entry.iter().zip(columns) {
+            row[column] = value.clone();
+        }
+        if let Some(alias) = self.layout.rowid_alias {
+            row[alias] = entry.last().unwrap().clone();
+        }
+        row
+    }
+
+    /// A page of a table scan, continuing after the rowid `after`. The scan
+    /// reads as many rows as the page still needs at a time, each time
+    /// descending straight to where the previous read stopped.
+    fn page_from_scan(
+        &self,
+        connections: &mut [Connection],
+        limit: usize,
+        mut after: Option<u64>,
+    ) -> Result<(Vec<Row>, Option<Position>)> {
+        let Connection { pager, format, .. } = &mut connections[self.database];
+        let page = pager.get_page(self.rootpage.unwrap())?;
+        let mut rows = vec![];
+        let mut last = None;
+        while rows.len() < limit {
+            let wanted = limit - rows.len();
+            let mut budget = wanted;
+            let batch = rows_after(&page, pager, *format, &self.layout, after, &mut budget)?;
+            let exhausted = batch.len() < wanted;
+            for (row_id, row) in batch {
+                after = Some(row_id);
+                if self.matches(&row) {
+                    rows.push(self.project(&row));
+                    last = Some(Position::RowId(row_id));
+                }
+            }
+            if exhausted {
+                break;
+            }
+        }
+        Ok((rows, last))
+    }
+
+    /// A page of the rows found through the index, continuing after the
+    /// given rowid among those of the given key.
+    fn page_from_index(
+        &self,
+        connections: &mut [Connection],
+        lookup: &IndexLookup,
+        limit: usize,
+        after: Option<(usize, u64)>,
+    ) -> Result<(Vec<Row>, Option<Position>)> {
+        let Connection { pager, format, .. } = &mut connections[self.database];
+        let format = *format;
+        let page = pager.get_page(self.rootpage.unwrap())?;
+        let index_page = pager.get_page(lookup.rootpage)?;
+        let (first_key, after) = after.map_or((0, None), |(key, row_id)| (key, Some(row_id)));
+        let mut rows = vec![];
+        let mut last = None;
+        'keys: for (k, key) in lookup.keys.iter().enumerate().skip(first_key) {
+            let entries = index(
+                pager,
+                &index_page,
+                format,
+                key,
+                &lookup.orders,
+                lookup.unique,
+            )?;
+            // The entries of a key come in the same order each time, so the
+            // page continues with those after the cursor's
+            let mut skipping = k == first_key && after.is_some();
+            for entry in entries {
+                let row_id = entry_row_id(&entry, lookup.rootpage)?;
+                if skipping {
+                    skipping = after != Some(row_id);
+                    continue;
+                }
+                let row = if self.covering {
+                    self.covered_row(&entry, &lookup.columns)
+                } else {
+                    select(row_id, &page, pager, format, &self.layout)?
+                };
+                if self.matches(&row) {
+                    rows.push(self.project(&row));
+                    last = Some(Position::Index { key: k, row_id });
+                    if rows.len() == limit {
+                        break 'keys;
+                    }
+                }
+            }
+        }
+        Ok((rows, last))
+    }
+
+    /// The result rows, for a subquery.
+    fn rows(&self, connections: &mut [Connection]) -> Result<Vec<Row>> {
+        self.query(connections)?.collect()
+    }
+
+    /// This statement with each of its subqueries run, and replaced by their
+    /// results. They don't depend on the row, so each only runs once, and a
+    /// subquery appearing more than once only runs the first time.
+    fn run_subqueries(&self, connections: &mut [Connection]) -> Result<Statement> {
+        let mut results: HashMap<String, Vec<Row>> = HashMap::new();
+        let mut rows = |statement: &Statement| -> Result<Vec<Row>> {
+            if let Some(rows) = results.get(&statement.select) {
+                return Ok(rows.clone());
+            }
+            let rows = statement.rows(connections)?;
+            results.insert(statement.select.clone(), rows.clone());
+            Ok(rows)
+        };
+        let mut run = |e: BoundExpr| {
+            e.transform(&mut |e| {
+                Ok(match e {
+                    BoundExpr::Subquery(statement) => BoundExpr::Literal(
+                        rows(&statement)?
+                            .into_iter()
+                            .next()
+                            .map_or(Column::Null, |mut row| row.swap_remove(0)),
+                    ),
+                    BoundExpr::InSubquery {
+                        expr,
+                        statement,
+                        negated,
+                        collation,
+                    } => BoundExpr::In {
+                        expr,
+                        list: rows(&statement)?
+                            .into_iter()
+                            .map(|mut row| BoundExpr::Literal(row.swap_remove(0)))
+                            .collect(),
+                        negated,
+                        collation,
+                    },
+                    e => e,
+                })
+            })
+            .map(BoundExpr::fold)
+        };
+
+        let mut statement = self.clone();
+        statement.projection = statement
+            .projection
+            .into_iter()
+            .map(&mut run)
+            .collect::<Result<_>>()?;
+        let filters = statement
+            .filters
+            .into_iter()
+            .map(&mut run)
+            .collect::<Result<Vec<_>>>()?;
+        statement.having = statement.having.map(&mut run).transpose()?;
+        for key in statement.group_by.iter_mut().chain(&mut statement.order_by) {
+            key.key = run(key.key.clone())?;
+        }
+        for join in &mut statement.joins {
+            join.constraint = join.constraint.take().map(&mut run).transpose()?;
+        }
+        for aggregate in &mut statement.aggregates {
+            aggregate.arg = aggregate.arg.take().map(&mut run).transpose()?;
+        }
+
+        // Filters that only depended on subqueries are now decided
+        let (constant, filters): (Vec<_>, Vec<_>) =
+            filters.into_iter().partition(BoundExpr::is_constant);
+        statement.no_rows |= constant.iter().any(|f| !f.is_true(&vec![]));
+        statement.filters = filters;
+        Ok(statement)
+    }
+
+    /// The rows fetched, sorted, projected, rid of duplicates and cut down
+    /// to the OFFSET and LIMIT, in that order.
+    fn stream<'a>(
+        &self,
+        connections: &'a mut [Connection],
+    ) -> Result<Box<dyn Iterator<Item = Result<Row>> + 'a>> {
+        let memory = MemoryUsage::new(self.memory_limit, self.spill_dir.clone());
+        let mut rows = self.fetch(connections, &memory)?;
+
+        if !self.order_by.is_empty() {
+            let mut sorter = Sorter::new(&self.order_by, &memory);
+            for row in rows {
+                sorter.add(row?)?;
+            }
+            rows = Box::new(sorter.finish()?.map(|row| row.map(|(_, row)| row)));
+        }
+
+        let collations = self
+            .projection
+            .iter()
+            .map(|e| e.collation().unwrap_or_default())
+            .collect::<Vec<_>>();
+        // The first of each set of duplicates is kept, so the order is that
+        // of the rows as scanned or sorted
+        let mut seen = HashSet::new();
+        let projection = self.projection.clone();
+        let distinct = self.distinct;
+        let mut skip = self.offset;
+        let rows = rows
+            .filter_map(move |row| {
+                let row = match row {
+                    Ok(row) => projection.iter().map(|e| e.eval(&row)).collect::<Row>(),
+                    Err(e) => return Some(Err(e)),
+                };
+                if distinct {
+                    let key = row
+                        .iter()
+                        .zip(&collations)
+                        .map(|(value, collation)| DistinctKey::new(value, *collation))
+                        .collect::<Vec<_>>();
+                    if !seen.insert(key) {
+                        return None;
+                    }
+                    if let Err(e) = memory.charge(&row) {
+                        return Some(Err(e));
+                    }
+                }
+                if skip > 0 {
+                    skip -= 1;
+                    return None;
+                }
+                Some(Ok(row))
+            })
+            .take(self.limit.unwrap_or(usize::MAX));
+        Ok(Box::new(rows))
+    }
+
+    /// The rows matching the WHERE clause, or for an aggregate query one row
+    /// per group. Those of a table read by itself are read one at a time as
+    /// they are asked for, so that a LIMIT stops the scan and rows are
+    /// written out without all of them being held.
+    fn fetch<'a>(
+        &self,
+        connections: &'a mut [Connection],
+        memory: &MemoryUsage,
+    ) -> Result<Box<dyn Iterator<Item = Result<Row>> + 'a>> {
+        let rows: Box<dyn Iterator<Item = Result<Row>>> = match self.rootpage {
+            _ if self.no_rows => Box::new(iter::empty()),
+            None => Box::new(iter::once(Ok(vec![]))),
+            Some(rootpage) if self.vtab.is_none() && self.counts_every_row() => {
+                // Counting every row only needs the cell counts of the leaf pages
+                let pager = &mut connections[self.database].pager;
+                let page = pager.get_page(rootpage)?;
+                let mut row = vec![Column::Null; self.width];
+                row.push(Column::Integer(count_rows(&page, pager)? as i64));
+                return Ok(Box::new(iter::once(Ok(row))));
+            }
+            Some(rootpage) if self.joins.is_empty() => {
+                let rows = self.table_rows(rootpage, &mut connections[self.database])?;
+                let filters = self.filters.clone();
+                Box::new(rows.filter(move |row| {
+                    row.as_ref()
+                        .map_or(true, |row| filters.iter().all(|f| f.is_true(row)))
+                }))
+            }
+            Some(rootpage) => {
+                // The tables joined are looped over for each row, so the rows
+                // of every table are all read first
+                let width = self.width - self.joins.iter().map(|j| j.width).sum::<usize>();
+                let mut rows = self
+                    .table_rows(rootpage, &mut connections[self.database])?
+                    .map(|row| {
+                        let mut row = row?;
+                        row.resize(width, Column::Null);
+                        Ok(row)
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                let mut tables = vec![];
+                for join in &self.joins {
+                    let connection = &mut connections[join.database];
+                    tables.push(join.rows(&mut connection.pager, connection.format)?);
+                }
+                if self.reorder_joins {
+                    rows = self.join_by_size(rows, tables, memory)?;
+                } else {
+                    for (join, right) in self.joins.iter().zip(tables) {
+                        rows = join.join(rows, right, memory, &self.join_stats)?;
+                    }
+                }
+                rows.retain(|row| self.matches(row));
+                Box::new(rows.into_iter().map(Ok))
+            }
+        };
+
+        if self.aggregate {
+            self.group(rows, memory)
+        } else {
+            Ok(rows)
+        }
+    }
+
+    /// Whether the query is a plain `count(*)` of the whole table.
+    fn counts_every_row(&self) -> bool {
+        let bare_column = |e: &BoundExpr| e.contains(&|e| matches!(e, BoundExpr::Column { .. }));
+        matches!(
+            self.aggregates.as_slice(),
+            [Aggregate {
+                function: AggregateFunction::Count,
+                arg: None
+            }]
+        ) && self.group_by.is_empty()
+            && self.having.is_none()
+            && self.joins.is_empty()
+            && self.filters.is_empty()
+            && self.index_lookup.is_none()
+            && !self.projection.iter().any(bare_column)
+            && !self.order_by.iter().any(|k| bare_column(&k.key))
+    }
+
+    /// The rows of the first table, whose root page is `rootpage` unless it
+    /// is virtual, that may match.
+    fn table_rows<'a>(
+        &self,
+        rootpage: u32,
+        connection: &'a mut Connection,
+    ) -> Result<Box<dyn Iterator<Item = Result<Row>> + 'a>> {
+        Ok(match &self.vtab {
+            Some(vtab) => {
+                let mut rows = vtab.rows()?;
+                if self.direction == Direction::Backward {
+                    rows.reverse();
+                }
+                Box::new(rows.into_iter().map(Ok))
+            }
+            None => {
+                let page = connection.pager.get_page(rootpage)?;
+                self.scan(page, &mut connection.pager, connection.format)?
+            }
+        })
+    }
+
+    /// The rows of the first table that may match: those found through the
+    /// index if one is used, or else every row, read as they are asked for.
+    fn scan<'a>(
+        &self,
+        page: Rc<pager::Page>,
+        pager: &'a mut Pager,
+        format: Format,
+    ) -> Result<Box<dyn Iterator<Item = Result<Row>> + 'a>> {
+        Ok(if let Some(lookup) = &self.index_lookup {
+            let index_page = pager.get_page(lookup.rootpage)?;
+            let mut result = vec![];
+            // One descent of the index per key; the keys are distinct, so no
+            // row is found twice
+            for key in &lookup.keys {
+                let indices = index(
+                    pager,
+                    &index_page,
+                    format,
+                    key,
+                    &lookup.orders,
+                    lookup.unique,
+                )?;
+                for i in indices {
+                    if self.covering {
+                        result.push(self.covered_row(&i, &lookup.columns));
+                        continue;
+                    }
+                    let row_id = entry_row_id(&i, lookup.rootpage)?;
+                    result.push(select(row_id, &page, pager, format, &self.layout)?);
+                }
+            }
+            Box::new(result.into_iter().map(Ok))
+        } else if let Some(range) = &self.index_range {
+            let index_page = pager.get_page(range.rootpage)?;
+            let entries = match (&range.lower, &range.upper) {
+                // The whole index, read for the order of its column
+                (Bound::Unbounded, Bound::Unbounded) => {
+                    index_rows(&index_page, pager, format, self.direction)?
+                }
+                _ => {
+                    let mut entries = index_range(
+                        pager,
+                        &index_page,
+                        format,
+                        range.lower.as_ref(),
+                        range.upper.as_ref(),
+                        range.collation,
+                    )?;
+                    if self.direction == Direction::Backward {
+                        entries.reverse();
+                    }
+                    entries
+                }
+            };
+            let rows = entries
+                .into_iter()
+                .map(|entry| {
+                    if self.covering {
+                        return Ok(self.covered_row(&entry, &[range.column]));
+                    }
+                    let row_id = entry_row_id(&entry, range.rootpage)?;
+                    select(row_id, &page, pager, format, &self.layout)
+                })
+                .collect::<Vec<_>>();
+            Box::new(rows.into_iter())
+        } else {
+            let rows = TableCursor::new(page, pager, format, self.direction, &self.layout)?;
+            Box::new(rows.map(|row| Ok(row?.1)))
+        })
+    }
+
+    /// Combines `rows` into one row per group: the table columns of a row
+    /// from the group, followed by the results of the aggregates. Each row is
+    /// added to its group's accumulators as it is read, so only one row per
+    /// group is held rather than every row. The groups come out in the order
+    /// of their keys, as in SQLite.
+    ///
+    /// Groups are found by their keys in a hash map while it fits in half of
+    /// memory; after that, the rows of groups not yet found are sorted by
+    /// their keys, spilling to files, and grouped in that order at the end.
+    fn group(
+        &self,
+        rows: impl Iterator<Item = Result<Row>>,
+        memory: &MemoryUsage,
+    ) -> Result<Box<dyn Iterator<Item = Result<Row>>>> {
+        struct Group {
+            keys: Vec<Column>,
+            first: Row,
+            accumulators: Vec<Accumulator>,
+        }
+        let mut groups: Vec<Group> = vec![];
+        let mut found: HashMap<Vec<DistinctKey>, usize> = HashMap::new();
+        let room = memory.room() / 2;
+        let mut held = 0;
+        let mut spilled: Option<Sorter> = None;
+        for row in rows {
+            let row = row?;
+            let keys = self
+                .group_by
+                .iter()
+                .map(|k| k.key.eval(&row))
+                .collect::<Vec<_>>();
+            let distinct = keys
+                .iter()
+                .zip(&self.group_by)
+                .map(|(value, k)| DistinctKey::new(value, k.collation))
+                .collect::<Vec<_>>();
+            let group = match found.get(&distinct) {
+                Some(&group) => group,
+                None => {
+                    let size = MemoryUsage::size(&keys) + MemoryUsage::size(&row);
+                    if held + size > room && memory.spill_dir.is_some() {
+                        spilled
+                            .get_or_insert_with(|| Sorter::new(&self.group_by, memory))
+                            .push(keys, row)?;
+                        continue;
+                    }
+                    memory.reserve(size)?;
+                    held += size;
+                    found.insert(distinct, groups.len());
+                    groups.push(Group {
+                        keys,
+                        first: row.clone(),
+                        accumulators: self.aggregates.iter().map(Aggregate::accumulator).collect(),
+                    });
+                    groups.len() - 1
+                }
+            };
+            for (aggregate, accumulator) in
+                self.aggregates.iter().zip(&mut groups[group].accumulators)
+            {
+                aggregate.step(accumulator, &row);
+            }
+        }
+        // Without GROUP BY, all rows form one group even when there are none
+        if self.group_by.is_empty() && groups.is_empty() {
+            groups.push(Group {
+                keys: vec![],
+                first: vec![],
+                accumulators: self.aggregates.iter().map(Aggregate::accumulator).collect(),
+            });
+        }
+        groups.sort_by(|a, b| SortKey::compare(&self.group_by, &a.keys, &b.keys));
+        let mut groups: Box<dyn Iterator<Item = Result<Group>>> =
+            Box::new(groups.into_iter().map(Ok));
+
+        if let Some(sorter) = spilled {
+            // The spilled rows of each group are next to each other, and no
+            // group of theirs is among those in memory
+            let mut sorted = sorter.finish()?.peekable();
+            let aggregates = self.aggregates.clone();
+            let group_by = self.group_by.clone();
+            let more = iter::from_fn(move || {
+                let (keys, first) = match sorted.next()? {
+                    Ok(row) => row,
+                    Err(e) => return Some(Err(e)),
+                };
+                let mut accumulators = aggregates
+                    .iter()
+                    .map(Aggregate::accumulator)
+                    .collect::<Vec<_>>();
+                let mut row = Some(first.clone());
+                while let Some(next) = row {
+                    for (aggregate, accumulator) in aggregates.iter().zip(&mut accumulators) {
+                        aggregate.step(accumulator, &next);
+                    }
+                    row = match sorted.peek() {
+                        Some(Ok((next, _))) if SortKey::compare(&group_by, &keys, next).is_eq() => {
+                            sorted.next().and_then(|row| row.ok()).map(|(_, row)| row)
+                        }
+                        _ => None,
+                    };
+                }
+                Some(Ok(Group {
+                    keys,
+                    first,
+                    accumulators,
+                }))
+            });
+            let group_by = self.group_by.clone();
+            groups = Box::new(groups.merge_by(more, move |a, b| match (a, b) {
+                (Ok(a), Ok(b)) => SortKey::compare(&group_by, &a.keys, &b.keys).is_le(),
+                (_, Err(_)) => false,
+                (Err(_), _) => true,
+            }));
+        }
+
+        let width = self.width;
+        let having = self.having.clone();
+        Ok(Box::new(groups.filter_map(move |group| {
+            let group = match group {
+                Ok(group) => group,
+                Err(e) => return Some(Err(e)),
+            };
+            // Bare columns take their values from the first row of the group,
+            // or from the row that a lone min() or max() picked, as in SQLite
+            let mut row = match group.accumulators.as_slice() {
+                [accumulator] if accumulator.extreme_row().is_some() => {
+                    accumulator.extreme_row().unwrap().clone()
+                }
+                _ => group.first,
+            };
+            row.resize(width, Column::Null);
+            for accumulator in group.accumulators {
+                match accumulator.finish() {
+                    Ok(value) => row.push(value),
+                    Err(e) => return Some(Err(e)),
+                }
+            }
+            match &having {
+                Some(having) if !having.is_true(&row) => None,
+                _ => Some(Ok(row)),
+            }
+        })))
+    }
+}
+
+/// The order to join tables of `sizes` rows in: the smallest first, then
+/// each time the smallest of those that `links` says a join term links to
+/// the ones before, or of all that are left if none is. Of tables of the
+/// same size, the one written first goes first.
+fn join_order(sizes: &[usize], links: &dyn Fn(&[usize], usize) -> bool) -> Vec<usize> {
+    let mut order: Vec<usize> = vec![];
+    while order.len() < sizes.len() {
+        let next = (0..sizes.len())
+            .filter(|t| !order.contains(t))
+            .min_by_key(|&t| (!order.is_empty() && !links(&order, t), sizes[t]))
+            .unwrap();
+        order.push(next);
+    }
+    order
+}
+
+/// The rowid an index entry ends with, after the indexed columns.
+fn entry_row_id(entry: &[Column], rootpage: u32) -> Result<u64> {
+    match entry.last() {
+        Some(&Column::Integer(row_id)) => Ok(row_id as u64),
+        _ => Err(Error::corrupt(rootpage, 0, "index entry without a rowid").into()),
+    }
+}
+
+/// The value of a LIMIT or OFFSET expression, which must be a constant
+/// integer.
+fn integer_value(expr: &Expr) -> Result<i64> {
+    match BoundExpr::bind(expr, Scope::default())?.fold() {
+        BoundExpr::Literal(value) => match Affinity::Integer.apply(value) {
+            Column::Integer(i) => Ok(i),
+            _ => bail!("datatype mismatch"),
+        },
+        // Subqueries are run with the statement, after LIMIT is needed
+        _ => Err(Error::UnsupportedFeature("a subquery in LIMIT or OFFSET".to_string()).into()),
+    }
+}
+
+/// `1st`, `2nd`, `3rd`, `4th`, ... as in SQLite's error messages.
+fn ordinal(n: usize) -> String {
+    let suffix = match (n % 10, n % 100) {
+        (_, 11..=13) => "th",
+        (1, _) => "st",
+        (2, _) => "nd",
+        (3, _) => "rd",
+        _ => "th",
+    };
+    format!("{}{}", n, suffix)
+}
+
+/// Result metadata, for callers that need to describe a result set before
+/// (or without) stepping through it.
+impl Statement {
+    pub fn column_count(&self) -> usize {
+        self.columns.len()
+    }
+
+    pub fn column_name(&self, i: usize) -> &str {
+        &self.columns[i].name
+    }
+
+    pub fn column_decl_type(&self, i: usize) -> Option<&str> {
+        self.columns[i].decl_type.as_deref()
+    }
+
+    pub fn column_table_name(&self, i: usize) -> Option<&str> {
+        self.columns[i].table.as_deref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{Database, Options};
+    use crate::load::load;
+    use crate::testing::TempDatabase;
+    use std::env;
+
+    #[test]
+    fn join_order_follows_links_from_the_smallest() {
+        // big - mid - small, linked in a chain through mid
+        let links = |joined: &[usize], t: usize| match t {
+            1 => true,
+            _ => joined.contains(&1),
+        };
+        assert_eq!(join_order(&[1000, 300, 10], &links), [2, 1, 0]);
+        // Without links, by size alone; ties in the order written
+        assert_eq!(join_order(&[5, 2, 5], &|_, _| false), [1, 0, 2]);
+    }
+
+    #[test]
+    fn join_with_a_filtered_table() {
+        let database = TempDatabase::new("join-filtered");
+        let rows = (1..=500).map(|i| Ok(vec![Column::Integer(i), Column::Integer(i % 7)]));
+        load(
+            &database.0,
+            "CREATE TABLE big (id INTEGER PRIMARY KEY, k)",
+            &[],
+            rows,
+        )
+        .unwrap();
+        // The terms on b leave fewer of its rows than those on s, so b drives
+        // the join though written second; the columns still come back in
+        // the order written
+        let sql = "SELECT b.id, s.id, s.k FROM big s JOIN big b ON b.k = s.k \
+                   WHERE b.id IN (7, 8) AND s.id <= 20 ORDER BY 1, 2";
+        let rows = database.query(sql);
+        let expected = [7, 8]
+            .iter()
+            .flat_map(|&b| {
+                (1..=20).filter(move |s| s % 7 == b % 7).map(move |s| {
+                    vec![
+                        Column::Integer(b),
+                        Column::Integer(s),
+                        Column::Integer(s % 7),
+                    ]
+                })
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(rows, expected);
+    }
+
+    #[test]
+    fn sorts_and_groups_spill_past_the_memory_limit() {
+        let database = TempDatabase::new("spill");
+        let rows = (0..2000).map(|i| {
+            Ok(vec![
+                Column::Integer(i * 7919 % 500),
+                Column::Text(format!("row {}", i)),
+            ])
+        });
+        load(&database.0, "CREATE TABLE t (k, v)", &[], rows).unwrap();
+        let mut db = Database::open(database.filename()).unwrap();
+        let mut run = |sql: &str, limit, spill_dir| -> Result<Vec<Row>> {
+            let mut statement = Statement::prepare(sql, &db.schemas)?;
+            statement.set_memory_limit(limit);
+            statement.set_spill_dir(spill_dir);
+            let _transactions = db
+                .connections
+                .iter_mut()
+                .map(Connection::begin_read)
+                .collect::<Result<Vec<_>>>()?;
+            let rows = statement.query(&mut db.connections)?.collect();
+            rows
+        };
+        for sql in [
+            "SELECT k, v FROM t ORDER BY k DESC, v",
+            "SELECT k, count(*), min(v) FROM t GROUP BY k",
+            "SELECT k, count(*) FROM t GROUP BY k HAVING k % 3 = 0 ORDER BY 2, 1 DESC",
+        ] {
+            let expected = run(sql, None, None).unwrap();
+            let spilled = run(sql, Some(20_000), Some(env::temp_dir())).unwrap();
+            assert_eq!(spilled, expected, "{}", sql);
+            // Kept in memory, they don't fit
+            assert!(run(sql, Some(20_000), None).is_err(), "{}", sql);
+        }
+    }
+
+    #[test]
+    fn pages_add_up_to_the_whole_result() {
+        let database = TempDatabase::new("pages");
+        let rows = (1..=100).map(|i| {
+            Ok(vec![
+                Column::Integer(i),
+                Column::Integer(i % 4),
+                Column::Integer(100 - i),
+            ])
+        });
+        load(
+            &database.0,
+            "CREATE TABLE t (id INTEGER PRIMARY KEY, k, v)",
+            &["CREATE INDEX k ON t (k)".to_string()],
+            rows,
+        )
+        .unwrap();
+        let mut db = Database::open(database.filename()).unwrap();
+        // Through a scan, an index lookup, and a sort
+        for (sql, kind) in [
+            ("SELECT id, v FROM t WHERE v % 3 = 0", 'r'),
+            ("SELECT id, k FROM t WHERE k IN (3, 1)", 'i'),
+            ("SELECT v FROM t ORDER BY v", 'o'),
+        ] {
+            let statement = Statement::prepare(sql, &db.schemas).unwrap();
+            let expected = statement
+                .query(&mut db.connections)
+                .unwrap()
+                .collect::<Result<Vec<_>>>()
+                .unwrap();
+            let mut rows = vec![];
+            let mut cursor = None;
+            loop {
+                let page = statement
+                    .query_page(&mut db.connections, 7, cursor.as_ref())
+                    .unwrap();
+                assert!(page.rows.len() <= 7);
+                rows.extend(page.rows);
+                let Some(next) = page.next else {
+                    break;
+                };
+                // As the shell writes it out and reads it back
+                let written = next.to_string();
+                assert!(written.starts_with(kind), "{}: {}", sql, written);
+                cursor = Some(written.parse::<Cursor>().unwrap());
+            }
+            assert_eq!(rows, expected, "{}", sql);
+        }
+        assert!("x1".parse::<Cursor>().is_err());
+        assert!("i1".parse::<Cursor>().is_err());
+    }
+
+    #[test]
+    fn ordered_by_an_indexed_column_through_its_index() {
+        let database = TempDatabase::new("index-order");
+        let rows = (1..=300).map(|i| {
+            Ok(vec![
+                Column::Integer(i),
+                match i % 50 {
+                    0 => Column::Null,
+                    k => Column::Integer(k),
+                },
+            ])
+        });
+        load(
+            &database.0,
+            "CREATE TABLE t (id INTEGER PRIMARY KEY, k)",
+            &["CREATE INDEX k ON t (k)".to_string()],
+            rows,
+        )
+        .unwrap();
+        let db = Database::open(database.filename()).unwrap();
+        let plan = |sql| Statement::prepare(sql, &db.schemas).unwrap();
+        for (sql, direction) in [
+            ("SELECT k FROM t ORDER BY k", Direction::Forward),
+            ("SELECT k FROM t ORDER BY k DESC", Direction::Backward),
+        ] {
+            let statement = plan(sql);
+            assert!(statement.order_by.is_empty(), "{}", sql);
+            assert!(statement.index_range.is_some(), "{}", sql);
+            assert_eq!(statement.direction, direction, "{}", sql);
+        }
+        // NULLs where the index doesn't keep them need a sort
+        assert!(!plan("SELECT k FROM t ORDER BY k DESC NULLS FIRST")
+            .order_by
+            .is_empty());
+
+        let keys = |sql| {
+            database
+                .query(sql)
+                .into_iter()
+                .map(|row| row[0].clone())
+                .collect::<Vec<_>>()
+        };
+        let mut expected = keys("SELECT k FROM t");
+        expected.sort_by(|a, b| a.compare(b, Collation::Binary));
+        assert_eq!(keys("SELECT k FROM t ORDER BY k"), expected);
+        expected.reverse();
+        assert_eq!(keys("SELECT k FROM t ORDER BY k DESC"), expected);
+        assert_eq!(
+            keys("SELECT id FROM t ORDER BY k DESC LIMIT 3"),
+            [
+                Column::Integer(299),
+                Column::Integer(249),
+                Column::Integer(199)
+            ]
+        );
+    }
+
+    #[test]
+    fn columns_qualified_with_their_database() {
+        let main = TempDatabase::new("qualified-main");
+        let aux = TempDatabase::new("qualified-aux");
+        let ids = |ids: &'static [i64]| ids.iter().map(|&i| Ok(vec![Column::Integer(i)]));
+        load(
+            &main.0,
+            "CREATE TABLE t (id INTEGER PRIMARY KEY)",
+            &[],
+            ids(&[1, 2, 3]),
+        )
+        .unwrap();
+        load(
+            &aux.0,
+            "CREATE TABLE b (id INTEGER PRIMARY KEY)",
+            &[],
+            ids(&[1, 3]),
+        )
+        .unwrap();
+        let mut db =
+            Database::open_all(&[main.filename(), aux.filename()], Options::default()).unwrap();
+        let mut query = |sql: &str| -> Result<Vec<Row>> {
+            let statement = Statement::prepare(sql, &db.schemas)?;
+            let rows = statement.query(&mut db.connections)?.collect();
+            rows
+        };
+        assert_eq!(
+            query("SELECT count(*) FROM t JOIN aux1.b ON aux1.b.id = main.t.id").unwrap(),
+            [vec![Column::Integer(2)]]
+        );
+        // A table is only in its own database, and by its alias if it has one
+        assert!(query("SELECT main.b.id FROM t JOIN aux1.b ON b.id = t.id").is_err());
+        assert!(query("SELECT aux1.b.id FROM t JOIN aux1.b AS z ON z.id = t.id").is_err());
+    }
+}
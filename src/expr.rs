@@ -0,0 +1,1041 @@
+//! Expressions with their column references resolved against a table, ready
+//! to be evaluated on each row.
+
+use crate::aggregate::{Aggregate, AggregateFunction};
+use crate::exec::Statement;
+use crate::function::ScalarFunction;
+use crate::header;
+use crate::sql::{BinaryOp, Expr, SelectStatement};
+use crate::{Affinity, Collation, Column, ColumnDef, Error, Row, Schema};
+use anyhow::{bail, Context, Result};
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::ops::Bound;
+
+/// A table in FROM, as seen by the expressions of a statement. The rows they
+/// are evaluated on hold the columns of each table of the scope in turn.
+#[derive(Debug)]
+pub struct ScopeTable {
+    /// The database the table is in, such as `main` or `aux1`
+    pub schema: String,
+    /// The name columns are qualified with: the alias, if the table has one
+    pub name: String,
+    /// The name of the table in the schema
+    pub table: String,
+    pub columns: Vec<ColumnDef>,
+}
+
+/// What the names in an expression can refer to.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Scope<'a> {
+    pub tables: &'a [ScopeTable],
+    /// Result column aliases, which a name that isn't a column refers to
+    pub aliases: &'a [(String, Expr)],
+    /// The databases subqueries find their tables in
+    pub schemas: &'a [Schema],
+}
+
+impl Scope<'_> {
+    pub fn width(&self) -> usize {
+        self.tables.iter().map(|t| t.columns.len()).sum()
+    }
+}
+
+/// Finds the column `name`, of the table `table` in the database `schema` if
+/// given, returning its position in the row along with its definition.
+pub fn resolve<'a>(
+    scope: &'a [ScopeTable],
+    schema: Option<&str>,
+    table: Option<&str>,
+    name: &str,
+) -> Result<(usize, &'a ColumnDef)> {
+    let display = match (schema, table) {
+        (Some(schema), Some(table)) => format!("{}.{}.{}", schema, table, name),
+        (_, Some(table)) => format!("{}.{}", table, name),
+        _ => name.to_string(),
+    };
+    let mut found = None;
+    let mut offset = 0;
+    for t in scope {
+        if table.map_or(true, |table| table.eq_ignore_ascii_case(&t.name))
+            && schema.map_or(true, |schema| schema.eq_ignore_ascii_case(&t.schema))
+        {
+            if let Some(i) = t
+                .columns
+                .iter()
+                .position(|c| c.name.eq_ignore_ascii_case(name))
+            {
+                if found.is_some() {
+                    bail!("ambiguous column name: {}", display);
+                }
+                found = Some((offset + i, &t.columns[i]));
+            }
+        }
+        offset += t.columns.len();
+    }
+    found.ok_or_else(|| Error::UnknownColumn(display).into())
+}
+
+#[derive(Debug, Clone)]
+pub enum BoundExpr {
+    /// The value at this position in the record
+    Column {
+        index: usize,
+        affinity: Affinity,
+        collation: Collation,
+    },
+    Literal(Column),
+    /// The result of an aggregate function, found at this position of the
+    /// group's row, after the table columns
+    Aggregate {
+        index: usize,
+    },
+    Function {
+        function: ScalarFunction,
+        args: Vec<BoundExpr>,
+    },
+    Not(Box<BoundExpr>),
+    Negate(Box<BoundExpr>),
+    And(Box<BoundExpr>, Box<BoundExpr>),
+    Or(Box<BoundExpr>, Box<BoundExpr>),
+    Compare {
+        op: BinaryOp,
+        left: Box<BoundExpr>,
+        right: Box<BoundExpr>,
+        collation: Collation,
+    },
+    /// An arithmetic operator or `||`
+    Operator {
+        op: BinaryOp,
+        left: Box<BoundExpr>,
+        right: Box<BoundExpr>,
+    },
+    /// `expr [NOT] IN (list)`, comparing against each value as `=` would
+    In {
+        expr: Box<BoundExpr>,
+        list: Vec<BoundExpr>,
+        negated: bool,
+        collation: Collation,
+    },
+    /// `expr [NOT] IN (SELECT ...)`. The subquery is run when the statement
+    /// is executed, turning this into `In` with the values it returned.
+    InSubquery {
+        expr: Box<BoundExpr>,
+        statement: Box<Statement>,
+        negated: bool,
+        collation: Collation,
+    },
+    /// A scalar subquery, replaced by its value when the statement is executed
+    Subquery(Box<Statement>),
+    Case {
+        operand: Option<Box<BoundExpr>>,
+        branches: Vec<(BoundExpr, BoundExpr)>,
+        otherwise: Option<Box<BoundExpr>>,
+    },
+}
+
+impl BoundExpr {
+    /// Resolves the column names in `expr` against `scope`.
+    pub fn bind(expr: &Expr, scope: Scope) -> Result<BoundExpr> {
+        BoundExpr::bind_in(expr, scope, None)
+    }
+
+    /// Like `bind`, but also allows aggregate functions, which are added to
+    /// `aggregates` and evaluated per group.
+    pub fn bind_aggregate(
+        expr: &Expr,
+        scope: Scope,
+        aggregates: &mut Vec<Aggregate>,
+    ) -> Result<BoundExpr> {
+        BoundExpr::bind_in(expr, scope, Some(aggregates))
+    }
+
+    fn bind_in(
+        expr: &Expr,
+        scope: Scope,
+        mut aggregates: Option<&mut Vec<Aggregate>>,
+    ) -> Result<BoundExpr> {
+        let mut bind =
+            |e: &Expr| BoundExpr::bind_in(e, scope, aggregates.as_deref_mut()).map(Box::new);
+        Ok(match strip_hints(expr)? {
+            Expr::Column {
+                schema,
+                table,
+                name,
+            } => {
+                let resolved = resolve(scope.tables, schema.as_deref(), table.as_deref(), name);
+                let alias = scope
+                    .aliases
+                    .iter()
+                    .find(|(alias, _)| table.is_none() && alias.eq_ignore_ascii_case(name));
+                if let (Err(_), Some((_, aliased))) = (&resolved, alias) {
+                    // The aliased expression can't refer to aliases in turn
+                    let scope = Scope {
+                        aliases: &[],
+                        ..scope
+                    };
+                    return BoundExpr::bind_in(aliased, scope, aggregates);
+                }
+                let (index, column) = resolved?;
+                BoundExpr::Column {
+                    index,
+                    affinity: column.affinity(),
+                    collation: column.collation,
+                }
+            }
+            Expr::Literal(value) => BoundExpr::Literal(value.clone()),
+            Expr::Function { name, args }
+                if AggregateFunction::from_name(name).is_some()
+                    && (args.len() == 1 || name.eq_ignore_ascii_case("count")) =>
+            {
+                let function = AggregateFunction::from_name(name).unwrap();
+                let Some(aggregates) = aggregates else {
+                    bail!("misuse of aggregate function {}()", name);
+                };
+                let arg = match args.as_slice() {
+                    [] => None,
+                    // Aggregates can't be nested
+                    [arg] => Some(BoundExpr::bind(arg, scope)?),
+                    _ => bail!("wrong number of arguments to function {}()", name),
+                };
+                aggregates.push(Aggregate { function, arg });
+                BoundExpr::Aggregate {
+                    index: scope.width() + aggregates.len() - 1,
+                }
+            }
+            Expr::Function { name, args } if ScalarFunction::from_name(name).is_some() => {
+                let function = ScalarFunction::from_name(name).unwrap();
+                function.check_arity(name, args.len())?;
+                BoundExpr::Function {
+                    function,
+                    args: args
+                        .iter()
+                        .map(|arg| bind(arg).map(|arg| *arg))
+                        .collect::<Result<_>>()?,
+                }
+            }
+            Expr::Function { name, args } if args.is_empty() => BoundExpr::Literal(
+                constant_function(name).with_context(|| format!("no such function: {}", name))?,
+            ),
+            Expr::Function { name, .. } => bail!("unsupported function: {}", name),
+            Expr::Not(e) => BoundExpr::Not(bind(e)?),
+            Expr::Negate(e) => BoundExpr::Negate(bind(e)?),
+            Expr::Binary { op, left, right } => {
+                let (left, right) = (bind(left)?, bind(right)?);
+                match op {
+                    BinaryOp::And => BoundExpr::And(left, right),
+                    BinaryOp::Or => BoundExpr::Or(left, right),
+                    BinaryOp::Eq
+                    | BinaryOp::Ne
+                    | BinaryOp::Lt
+                    | BinaryOp::Le
+                    | BinaryOp::Gt
+                    | BinaryOp::Ge
+                    | BinaryOp::Is
+                    | BinaryOp::IsNot => BoundExpr::Compare {
+                        op: *op,
+                        // The left operand's collation wins, as in SQLite
+                        collation: left.collation().or(right.collation()).unwrap_or_default(),
+                        left,
+                        right,
+                    },
+                    BinaryOp::Add
+                    | BinaryOp::Subtract
+                    | BinaryOp::Multiply
+                    | BinaryOp::Divide
+                    | BinaryOp::Remainder
+                    | BinaryOp::Concat => BoundExpr::Operator {
+                        op: *op,
+                        left,
+                        right,
+                    },
+                }
+            }
+            Expr::In {
+                expr,
+                list,
+                negated,
+            } => {
+                let expr = bind(expr)?;
+                BoundExpr::In {
+                    collation: expr.collation().unwrap_or_default(),
+                    expr,
+                    list: list
+                        .iter()
+                        .map(|e| bind(e).map(|e| *e))
+                        .collect::<Result<_>>()?,
+                    negated: *negated,
+                }
+            }
+            // Subqueries can't refer to the tables of the statement around them
+            Expr::InSubquery {
+                expr,
+                select,
+                negated,
+            } => {
+                let expr = bind(expr)?;
+                BoundExpr::InSubquery {
+                    collation: expr.collation().unwrap_or_default(),
+                    expr,
+                    statement: Box::new(subquery(select, scope.schemas)?),
+                    negated: *negated,
+                }
+            }
+            Expr::Subquery(select) => {
+                BoundExpr::Subquery(Box::new(subquery(select, scope.schemas)?))
+            }
+            Expr::Case {
+                operand,
+                branches,
+                otherwise,
+            } => BoundExpr::Case {
+                operand: operand.as_deref().map(&mut bind).transpose()?,
+                branches: branches
+                    .iter()
+                    .map(|(when, then)| Ok((*bind(when)?, *bind(then)?)))
+                    .collect::<Result<_>>()?,
+                otherwise: otherwise.as_deref().map(&mut bind).transpose()?,
+            },
+        })
+    }
+
+    pub fn eval(&self, row: &Row) -> Column {
+        match self {
+            // REAL columns store values without a fractional part as
+            // integers, which read back as reals
+            BoundExpr::Column {
+                index,
+                affinity: Affinity::Real,
+                ..
+            } => match row.get(*index) {
+                Some(Column::Integer(i)) => Column::Real(*i as f64),
+                value => value.cloned().unwrap_or(Column::Null),
+            },
+            // A record can have fewer values than its table has columns, as
+            // those of rows written before an ALTER TABLE ... ADD COLUMN do,
+            // and the columns it lacks read as NULL
+            BoundExpr::Column { index, .. } | BoundExpr::Aggregate { index } => {
+                row.get(*index).cloned().unwrap_or(Column::Null)
+            }
+            BoundExpr::Literal(value) => value.clone(),
+            BoundExpr::Function { function, args } => {
+                let args = args.iter().map(|arg| arg.eval(row)).collect::<Vec<_>>();
+                function.call(&args)
+            }
+            BoundExpr::Not(e) => match truth(&e.eval(row)) {
+                Some(value) => boolean(!value),
+                None => Column::Null,
+            },
+            BoundExpr::Negate(e) => negate(e.eval(row)),
+            // NULL is an unknown value: the result is NULL unless the other
+            // side decides it whatever that value is
+            BoundExpr::And(a, b) => match truth(&a.eval(row)) {
+                Some(false) => boolean(false),
+                a => match (a, truth(&b.eval(row))) {
+                    (_, Some(false)) => boolean(false),
+                    (Some(true), Some(true)) => boolean(true),
+                    _ => Column::Null,
+                },
+            },
+            BoundExpr::Or(a, b) => match truth(&a.eval(row)) {
+                Some(true) => boolean(true),
+                a => match (a, truth(&b.eval(row))) {
+                    (_, Some(true)) => boolean(true),
+                    (Some(false), Some(false)) => boolean(false),
+                    _ => Column::Null,
+                },
+            },
+            BoundExpr::Compare {
+                op,
+                left,
+                right,
+                collation,
+            } => {
+                let (a, b) = (left.eval(row), right.eval(row));
+                let null_safe = matches!(op, BinaryOp::Is | BinaryOp::IsNot);
+                if !null_safe && (a == Column::Null || b == Column::Null) {
+                    return Column::Null;
+                }
+                let (a, b) = coerce(left, right, a, b);
+                let ordering = a.compare(&b, *collation);
+                boolean(match op {
+                    BinaryOp::Eq | BinaryOp::Is => ordering == Ordering::Equal,
+                    BinaryOp::Ne | BinaryOp::IsNot => ordering != Ordering::Equal,
+                    BinaryOp::Lt => ordering == Ordering::Less,
+                    BinaryOp::Le => ordering != Ordering::Greater,
+                    BinaryOp::Gt => ordering == Ordering::Greater,
+                    BinaryOp::Ge => ordering != Ordering::Less,
+                    _ => unreachable!(),
+                })
+            }
+            BoundExpr::Operator { op, left, right } => {
+                operator(*op, left.eval(row), right.eval(row))
+            }
+            BoundExpr::In {
+                expr,
+                list,
+                negated,
+                collation,
+            } => {
+                let value = expr.eval(row);
+                // Without a match, a NULL on either side makes the result
+                // unknown; only an empty list is false even for NULL
+                let mut unknown = false;
+                for e in list {
+                    match equals(expr, e, value.clone(), e.eval(row), *collation) {
+                        Some(true) => return boolean(!negated),
+                        Some(false) => {}
+                        None => unknown = true,
+                    }
+                }
+                if unknown {
+                    Column::Null
+                } else {
+                    boolean(*negated)
+                }
+            }
+            BoundExpr::Case {
+                operand,
+                branches,
+                otherwise,
+            } => {
+                let operand = operand.as_ref().map(|e| (e, e.eval(row)));
+                for (when, then) in branches {
+                    let taken = match &operand {
+                        // Compared as `operand = when` would be, so NULL matches nothing
+                        Some((operand, value)) => {
+                            let collation =
+                                operand.collation().or(when.collation()).unwrap_or_default();
+                            equals(operand, when, value.clone(), when.eval(row), collation)
+                                == Some(true)
+                        }
+                        None => when.is_true(row),
+                    };
+                    if taken {
+                        return then.eval(row);
+                    }
+                }
+                otherwise.as_ref().map_or(Column::Null, |e| e.eval(row))
+            }
+            BoundExpr::InSubquery { .. } | BoundExpr::Subquery(_) => {
+                unreachable!("subqueries are replaced by their results before rows are evaluated")
+            }
+        }
+    }
+
+    /// Evaluates every part of the expression that doesn't depend on the row,
+    /// so that it is computed once when preparing instead of for each row.
+    pub fn fold(self) -> BoundExpr {
+        let constant = |e: &BoundExpr, value: bool| matches!(e, BoundExpr::Literal(v) if truth(v) == Some(value));
+        let folded = match self {
+            BoundExpr::Function { function, args } => BoundExpr::Function {
+                function,
+                args: args.into_iter().map(BoundExpr::fold).collect(),
+            },
+            BoundExpr::Not(e) => BoundExpr::Not(Box::new(e.fold())),
+            BoundExpr::Negate(e) => BoundExpr::Negate(Box::new(e.fold())),
+            BoundExpr::And(a, b) => {
+                let (a, b) = (a.fold(), b.fold());
+                // `x AND false` is false whatever x is
+                if constant(&a, false) || constant(&b, false) {
+                    return BoundExpr::Literal(boolean(false));
+                }
+                BoundExpr::And(Box::new(a), Box::new(b))
+            }
+            BoundExpr::Or(a, b) => {
+                let (a, b) = (a.fold(), b.fold());
+                if constant(&a, true) || constant(&b, true) {
+                    return BoundExpr::Literal(boolean(true));
+                }
+                BoundExpr::Or(Box::new(a), Box::new(b))
+            }
+            BoundExpr::Compare {
+                op,
+                left,
+                right,
+                collation,
+            } => BoundExpr::Compare {
+                op,
+                left: Box::new(left.fold()),
+                right: Box::new(right.fold()),
+                collation,
+            },
+            BoundExpr::Operator { op, left, right } => BoundExpr::Operator {
+                op,
+                left: Box::new(left.fold()),
+                right: Box::new(right.fold()),
+            },
+            BoundExpr::In {
+                expr,
+                list,
+                negated,
+                collation,
+            } => BoundExpr::In {
+                expr: Box::new(expr.fold()),
+                list: list.into_iter().map(BoundExpr::fold).collect(),
+                negated,
+                collation,
+            },
+            BoundExpr::Case {
+                operand,
+                branches,
+                otherwise,
+            } => BoundExpr::Case {
+                operand: operand.map(|e| Box::new(e.fold())),
+                branches: branches
+                    .into_iter()
+                    .map(|(when, then)| (when.fold(), then.fold()))
+                    .collect(),
+                otherwise: otherwise.map(|e| Box::new(e.fold())),
+            },
+            e => e,
+        };
+        if folded.is_constant() {
+            BoundExpr::Literal(folded.eval(&vec![]))
+        } else {
+            folded
+        }
+    }
+
+    /// Whether the value is known while preparing: it depends neither on the
+    /// row nor on a subquery, which only runs when the statement is executed.
+    pub fn is_constant(&self) -> bool {
+        !self.contains(&|e| {
+            matches!(
+                e,
+                BoundExpr::Column { .. }
+                    | BoundExpr::Aggregate { .. }
+                    | BoundExpr::InSubquery { .. }
+                    | BoundExpr::Subquery(_)
+            )
+        })
+    }
+
+    /// Rebuilds the expression bottom-up, replacing each part with what `f`
+    /// makes of it once its operands have been replaced.
+    pub fn transform(self, f: &mut dyn FnMut(BoundExpr) -> Result<BoundExpr>) -> Result<BoundExpr> {
+        let boxed = |e: Box<BoundExpr>, f: &mut dyn FnMut(BoundExpr) -> Result<BoundExpr>| {
+            e.transform(f).map(Box::new)
+        };
+        let rebuilt = match self {
+            BoundExpr::Function { function, args } => BoundExpr::Function {
+                function,
+                args: args
+                    .into_iter()
+                    .map(|arg| arg.transform(f))
+                    .collect::<Result<_>>()?,
+            },
+            BoundExpr::Not(e) => BoundExpr::Not(boxed(e, f)?),
+            BoundExpr::Negate(e) => BoundExpr::Negate(boxed(e, f)?),
+            BoundExpr::And(a, b) => BoundExpr::And(boxed(a, f)?, boxed(b, f)?),
+            BoundExpr::Or(a, b) => BoundExpr::Or(boxed(a, f)?, boxed(b, f)?),
+            BoundExpr::Compare {
+                op,
+                left,
+                right,
+                collation,
+            } => BoundExpr::Compare {
+                op,
+                left: boxed(left, f)?,
+                right: boxed(right, f)?,
+                collation,
+            },
+            BoundExpr::Operator { op, left, right } => BoundExpr::Operator {
+                op,
+                left: boxed(left, f)?,
+                right: boxed(right, f)?,
+            },
+            BoundExpr::In {
+                expr,
+                list,
+                negated,
+                collation,
+            } => BoundExpr::In {
+                expr: boxed(expr, f)?,
+                list: list
+                    .into_iter()
+                    .map(|e| e.transform(f))
+                    .collect::<Result<_>>()?,
+                negated,
+                collation,
+            },
+            BoundExpr::InSubquery {
+                expr,
+                statement,
+                negated,
+                collation,
+            } => BoundExpr::InSubquery {
+                expr: boxed(expr, f)?,
+                statement,
+                negated,
+                collation,
+            },
+            BoundExpr::Case {
+                operand,
+                branches,
+                otherwise,
+            } => BoundExpr::Case {
+                operand: operand.map(|e| boxed(e, f)).transpose()?,
+                branches: branches
+                    .into_iter()
+                    .map(|(when, then)| Ok((when.transform(f)?, then.transform(f)?)))
+                    .collect::<Result<_>>()?,
+                otherwise: otherwise.map(|e| boxed(e, f)).transpose()?,
+            },
+            e => e,
+        };
+        f(rebuilt)
+    }
+
+    /// Whether this expression or any of its operands satisfies `predicate`.
+    pub fn contains(&self, predicate: &dyn Fn(&BoundExpr) -> bool) -> bool {
+        predicate(self)
+            || match self {
+                BoundExpr::Column { .. } | BoundExpr::Literal(_) | BoundExpr::Aggregate { .. } => {
+                    false
+                }
+                BoundExpr::Function { args, .. } => args.iter().any(|arg| arg.contains(predicate)),
+                BoundExpr::Not(e) | BoundExpr::Negate(e) => e.contains(predicate),
+                BoundExpr::And(a, b) | BoundExpr::Or(a, b) => {
+                    a.contains(predicate) || b.contains(predicate)
+                }
+                BoundExpr::Compare { left, right, .. }
+                | BoundExpr::Operator { left, right, .. } => {
+                    left.contains(predicate) || right.contains(predicate)
+                }
+                BoundExpr::In { expr, list, .. } => {
+                    expr.contains(predicate) || list.iter().any(|e| e.contains(predicate))
+                }
+                BoundExpr::InSubquery { expr, .. } => expr.contains(predicate),
+                BoundExpr::Subquery(_) => false,
+                BoundExpr::Case {
+                    operand,
+                    branches,
+                    otherwise,
+                } => {
+                    operand
+                        .iter()
+                        .chain(otherwise)
+                        .any(|e| e.contains(predicate))
+                        || branches.iter().any(|(when, then)| {
+                            when.contains(predicate) || then.contains(predicate)
+                        })
+                }
+            }
+    }
+
+    /// The positions in the row of the columns it refers to.
+    pub fn columns(&self) -> Vec<usize> {
+        let columns = RefCell::new(vec![]);
+        self.contains(&|e| {
+            if let BoundExpr::Column { index, .. } = e {
+                columns.borrow_mut().push(*index);
+            }
+            false
+        });
+        columns.into_inner()
+    }
+
+    pub fn is_true(&self, row: &Row) -> bool {
+        truthy(&self.eval(row))
+    }
+
+    /// Whether the value on `row` is false, and not merely unknown.
+    pub fn is_false(&self, row: &Row) -> bool {
+        truth(&self.eval(row)) == Some(false)
+    }
+
+    fn affinity(&self) -> Option<Affinity> {
+        match self {
+            BoundExpr::Column { affinity, .. } => Some(*affinity),
+            _ => None,
+        }
+    }
+
+    pub fn collation(&self) -> Option<Collation> {
+        match self {
+            BoundExpr::Column { collation, .. } => Some(*collation),
+            _ => None,
+        }
+    }
+
+    /// The terms of a chain of `AND`s, which must all hold.
+    pub fn conjuncts(&self) -> Vec<&BoundExpr> {
+        match self {
+            BoundExpr::And(left, right) => {
+                let mut terms = left.conjuncts();
+                terms.extend(right.conjuncts());
+                terms
+            }
+            e => vec![e],
+        }
+    }
+
+    /// For a `column = column` term comparing a column before `offset` in the
+    /// row with one at or after it, the positions of the two, and the
+    /// collation of the comparison. Only terms comparing the values as they
+    /// are, without converting either to the other's affinity, qualify.
+    pub fn join_term(&self, offset: usize) -> Option<(usize, usize, Collation)> {
+        let BoundExpr::Compare {
+            op: BinaryOp::Eq,
+            left,
+            right,
+            collation,
+        } = self
+        else {
+            return None;
+        };
+        let (
+            BoundExpr::Column {
+                index: a,
+                affinity: a_affinity,
+                ..
+            },
+            BoundExpr::Column {
+                index: b,
+                affinity: b_affinity,
+                ..
+            },
+        ) = (left.as_ref(), right.as_ref())
+        else {
+            return None;
+        };
+        if a_affinity.is_numeric() != b_affinity.is_numeric() {
+            return None;
+        }
+        match (*a < offset, *b < offset) {
+            (true, false) => Some((*a, *b, *collation)),
+            (false, true) => Some((*b, *a, *collation)),
+            _ => None,
+        }
+    }
+
+    /// For a `column = literal` or `column IN (literals)` term, the column
+    /// position, the values as they compare against the column, and the
+    /// collation of the comparison: everything needed to look the values up
+    /// in an index on that column. The values are in index order, without
+    /// duplicates or NULLs, which match nothing.
+    pub fn lookup_term(&self) -> Option<(usize, Vec<Column>, Collation)> {
+        let (index, affinity, values, collation) = match self {
+            BoundExpr::Compare {
+                op: BinaryOp::Eq,
+                left,
+                right,
+                collation,
+            } => match (left.as_ref(), right.as_ref()) {
+                (
+                    BoundExpr::Column {
+                        index, affinity, ..
+                    },
+                    BoundExpr::Literal(value),
+                )
+                | (
+                    BoundExpr::Literal(value),
+                    BoundExpr::Column {
+                        index, affinity, ..
+                    },
+                ) => (*index, *affinity, vec![value.clone()], *collation),
+                _ => return None,
+            },
+            BoundExpr::In {
+                expr,
+                list,
+                negated: false,
+                collation,
+            } => {
+                let BoundExpr::Column {
+                    index, affinity, ..
+                } = expr.as_ref()
+                else {
+                    return None;
+                };
+                let values = list
+                    .iter()
+                    .map(|e| match e {
+                        BoundExpr::Literal(value) => Some(value.clone()),
+                        _ => None,
+                    })
+                    .collect::<Option<Vec<_>>>()?;
+                (*index, *affinity, values, *collation)
+            }
+            _ => return None,
+        };
+        let mut keys = values
+            .into_iter()
+            .filter(|v| *v != Column::Null)
+            .map(|v| affinity.apply(v))
+            .collect::<Vec<_>>();
+        keys.sort_by(|a, b| a.compare(b, collation));
+        keys.dedup_by(|a, b| a.compare(b, collation) == Ordering::Equal);
+        Some((index, keys, collation))
+    }
+
+    /// For a `column < literal` term, or one with `<=`, `>` or `>=` or the
+    /// operands the other way around, the column position, the lower and
+    /// upper bounds the term puts on the column's values, and the collation
+    /// of the comparison.
+    pub fn range_term(&self) -> Option<(usize, Bound<Column>, Bound<Column>, Collation)> {
+        let BoundExpr::Compare {
+            op,
+            left,
+            right,
+            collation,
+        } = self
+        else {
+            return None;
+        };
+        let (index, affinity, value, op) = match (left.as_ref(), right.as_ref()) {
+            (
+                BoundExpr::Column {
+                    index, affinity, ..
+                },
+                BoundExpr::Literal(value),
+            ) => (*index, *affinity, value, *op),
+            // `literal < column` is `column > literal`
+            (
+                BoundExpr::Literal(value),
+                BoundExpr::Column {
+                    index, affinity, ..
+                },
+            ) => (
+                *index,
+                *affinity,
+                value,
+                match op {
+                    BinaryOp::Lt => BinaryOp::Gt,
+                    BinaryOp::Le => BinaryOp::Ge,
+                    BinaryOp::Gt => BinaryOp::Lt,
+                    BinaryOp::Ge => BinaryOp::Le,
+                    op => *op,
+                },
+            ),
+            _ => return None,
+        };
+        if *value == Column::Null {
+            return None;
+        }
+        let value = affinity.apply(value.clone());
+        let (lower, upper) = match op {
+            BinaryOp::Lt => (Bound::Unbounded, Bound::Excluded(value)),
+            BinaryOp::Le => (Bound::Unbounded, Bound::Included(value)),
+            BinaryOp::Gt => (Bound::Excluded(value), Bound::Unbounded),
+            BinaryOp::Ge => (Bound::Included(value), Bound::Unbounded),
+            _ => return None,
+        };
+        Some((index, lower, upper, *collation))
+    }
+}
+
+/// Applies affinity to the operands of a comparison the way SQLite does: a
+/// numeric column converts the other side to a number, and a text column
+/// converts a plain value to text.
+fn coerce(left: &BoundExpr, right: &BoundExpr, a: Column, b: Column) -> (Column, Column) {
+    let numeric = |e: &BoundExpr| e.affinity().map_or(false, Affinity::is_numeric);
+    match (left.affinity(), right.affinity()) {
+        _ if numeric(left) && !numeric(right) => (a, Affinity::Numeric.apply(b)),
+        _ if numeric(right) && !numeric(left) => (Affinity::Numeric.apply(a), b),
+        (Some(Affinity::Text), None) => (a, Affinity::Text.apply(b)),
+        (None, Some(Affinity::Text)) => (Affinity::Text.apply(a), b),
+        _ => (a, b),
+    }
+}
+
+/// Whether `a`, the value of `left`, equals `b`, the value of `right`, as
+/// `=` decides it: None if either is NULL.
+fn equals(
+    left: &BoundExpr,
+    right: &BoundExpr,
+    a: Column,
+    b: Column,
+    collation: Collation,
+) -> Option<bool> {
+    if a == Column::Null || b == Column::Null {
+        return None;
+    }
+    let (a, b) = coerce(left, right, a, b);
+    Some(a.compare(&b, collation) == Ordering::Equal)
+}
+
+/// Converts a value to a number for arithmetic: text by the longest prefix
+/// that reads as a number, so `'12abc'` is 12 and `'abc'` is 0. A blob is
+/// read as the text of its bytes.
+fn numeric(value: Column) -> Column {
+    let s = match value {
+        Column::Text(s) => s,
+        Column::Blob(b) => String::from_utf8_lossy(&b).into_owned(),
+        value => return value,
+    };
+    let s = s.trim_start();
+    let bytes = s.as_bytes();
+    let digits = |mut i: usize| {
+        while i < bytes.len() && bytes[i].is_ascii_digit() {
+            i += 1;
+        }
+        i
+    };
+    let start = usize::from(matches!(bytes.first(), Some(b'+' | b'-')));
+    let integer_end = digits(start);
+    let mut end = integer_end;
+    let mut real = false;
+    if bytes.get(end) == Some(&b'.') {
+        end = digits(end + 1);
+        real = true;
+    }
+    // Without any digits, not even after the point, the value is 0
+    if integer_end == start && end <= integer_end + 1 {
+        return Column::Integer(0);
+    }
+    // An exponent only counts if it has digits
+    if matches!(bytes.get(end), Some(b'e' | b'E')) {
+        let sign = usize::from(matches!(bytes.get(end + 1), Some(b'+' | b'-')));
+        let exponent_end = digits(end + 1 + sign);
+        if exponent_end > end + 1 + sign {
+            end = exponent_end;
+            real = true;
+        }
+    }
+    let prefix = &s[..end];
+    if !real {
+        if let Ok(i) = prefix.parse() {
+            return Column::Integer(i);
+        }
+    }
+    Column::Real(prefix.parse().unwrap_or(0.0))
+}
+
+fn negate(value: Column) -> Column {
+    match numeric(value) {
+        Column::Integer(i) => i
+            .checked_neg()
+            .map_or(Column::Real(-(i as f64)), Column::Integer),
+        Column::Real(r) => Column::Real(-r),
+        value => value,
+    }
+}
+
+/// Applies an arithmetic operator or `||` the way SQLite does: NULL if either
+/// side is, integer arithmetic while it doesn't overflow, and NULL for a
+/// division by zero.
+fn operator(op: BinaryOp, a: Column, b: Column) -> Column {
+    if a == Column::Null || b == Column::Null {
+        return Column::Null;
+    }
+    if op == BinaryOp::Concat {
+        return Column::Text(format!("{}{}", a, b));
+    }
+    let result = match (numeric(a), numeric(b)) {
+        (Column::Integer(a), Column::Integer(b)) => {
+            let exact = match op {
+                BinaryOp::Add => a.checked_add(b),
+                BinaryOp::Subtract => a.checked_sub(b),
+                BinaryOp::Multiply => a.checked_mul(b),
+                BinaryOp::Divide | BinaryOp::Remainder if b == 0 => return Column::Null,
+                BinaryOp::Divide => a.checked_div(b),
+                // The remainder of anything by -1 is 0, even for i64::MIN
+                BinaryOp::Remainder => Some(a % b.abs().max(1)),
+                _ => unreachable!(),
+            };
+            match exact {
+                Some(i) => return Column::Integer(i),
+                None => real_operator(op, a as f64, b as f64),
+            }
+        }
+        (a, b) => real_operator(op, real(&a), real(&b)),
+    };
+    match result {
+        Some(r) if !r.is_nan() => Column::Real(r),
+        _ => Column::Null,
+    }
+}
+
+fn real_operator(op: BinaryOp, a: f64, b: f64) -> Option<f64> {
+    match op {
+        BinaryOp::Add => Some(a + b),
+        BinaryOp::Subtract => Some(a - b),
+        BinaryOp::Multiply => Some(a * b),
+        BinaryOp::Divide if b == 0.0 => None,
+        BinaryOp::Divide => Some(a / b),
+        // Like SQLite, the remainder of reals is taken on their integer parts
+        BinaryOp::Remainder => {
+            let (a, b) = (a as i64, (b as i64).abs());
+            (b != 0).then(|| (a % b) as f64)
+        }
+        _ => unreachable!(),
+    }
+}
+
+fn real(value: &Column) -> f64 {
+    match value {
+        Column::Integer(i) => *i as f64,
+        Column::Real(r) => *r,
+        _ => 0.0,
+    }
+}
+
+fn boolean(b: bool) -> Column {
+    Column::Integer(b as i64)
+}
+
+/// Whether a value counts as true in a condition: numbers are true unless
+/// zero, and text by the number it starts with, so `'1abc'` is true and
+/// `'abc'` is false. NULL is neither, and a condition that is NULL doesn't
+/// hold.
+fn truthy(value: &Column) -> bool {
+    truth(value) == Some(true)
+}
+
+/// The truth value of `value`, or None for NULL, which is unknown.
+fn truth(value: &Column) -> Option<bool> {
+    match value {
+        Column::Null => None,
+        Column::Integer(i) => Some(*i != 0),
+        Column::Real(r) => Some(*r != 0.0),
+        Column::Text(_) | Column::Blob(_) => truth(&numeric(value.clone())),
+    }
+}
+
+/// Prepares the statement of a subquery, which must return a single column.
+fn subquery(select: &SelectStatement, schemas: &[Schema]) -> Result<Statement> {
+    let statement = Statement::from_select(select, schemas)?;
+    if statement.columns().len() != 1 {
+        bail!(
+            "sub-select returns {} columns - expected 1",
+            statement.columns().len()
+        );
+    }
+    Ok(statement)
+}
+
+/// Unwraps the planner hints `likely(X)`, `unlikely(X)` and
+/// `likelihood(X, P)`, which evaluate to `X`. There is no cost-based planner
+/// here, so the hinted probability is only validated.
+pub fn strip_hints(expr: &Expr) -> Result<&Expr> {
+    let Expr::Function { name, args } = expr else {
+        return Ok(expr);
+    };
+    match (name.to_lowercase().as_str(), args.as_slice()) {
+        ("likely" | "unlikely", [x]) => strip_hints(x),
+        ("likelihood", [x, p]) => match p {
+            Expr::Literal(Column::Real(p)) if (0.0..=1.0).contains(p) => strip_hints(x),
+            _ => bail!("second argument to likelihood() must be a constant between 0.0 and 1.0"),
+        },
+        ("likely" | "unlikely" | "likelihood", _) => {
+            bail!("wrong number of arguments to function {}()", name)
+        }
+        _ => Ok(expr),
+    }
+}
+
+/// Functions of no arguments whose value is fixed for the whole process, and
+/// so can be computed while preparing the statement.
+fn constant_function(name: &str) -> Option<Column> {
+    match name.to_lowercase().as_str() {
+        "sqlite_version" => Some(Column::Text(header::sqlite_version())),
+        "sqlite_source_id" => Some(Column::Text(format!(
+            "{} {}",
+            env!("CARGO_PKG_NAME"),
+            env!("CARGO_PKG_VERSION")
+        ))),
+        _ => None,
+    }
+}
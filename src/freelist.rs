@@ -0,0 +1,52 @@
+//! The freelist: pages that are no longer in use, kept for the database to
+//! reuse before it grows the file.
+//! https://www.sqlite.org/fileformat.html#the_freelist
+
+use crate::pager::Pager;
+use anyhow::{bail, Result};
+
+/// The pages of the freelist, which is a chain of trunk pages each listing
+/// some of the free leaf pages.
+#[derive(Debug, Default)]
+pub struct Freelist {
+    pub trunks: Vec<u32>,
+    pub leaves: Vec<u32>,
+}
+
+impl Freelist {
+    /// Follows the chain of trunk pages that starts at `first_trunk`, of a
+    /// database of `page_count` pages.
+    pub fn read(pager: &mut Pager, first_trunk: u32, page_count: u32) -> Result<Freelist> {
+        let mut freelist = Freelist::default();
+        let mut next = first_trunk;
+        while next != 0 {
+            // A chain longer than the database has pages must loop
+            if next > page_count || freelist.trunks.len() >= page_count as usize {
+                bail!("freelist trunk page {} is out of place", next);
+            }
+            let page = pager.get_page(next)?;
+            // The next trunk, the number of leaves, and then the leaves
+            let count = u32::from_be_bytes(page[4..8].try_into().unwrap()) as usize;
+            if count > pager.page_size() / 4 - 2 {
+                bail!("freelist trunk page {} lists {} leaves", next, count);
+            }
+            freelist.trunks.push(next);
+            next = u32::from_be_bytes(page[..4].try_into().unwrap());
+            freelist.leaves.extend(
+                page[8..8 + 4 * count]
+                    .chunks_exact(4)
+                    .map(|leaf| u32::from_be_bytes(leaf.try_into().unwrap())),
+            );
+        }
+        Ok(freelist)
+    }
+
+    /// The number of pages on the freelist, trunks included.
+    pub fn len(&self) -> usize {
+        self.trunks.len() + self.leaves.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.trunks.is_empty() && self.leaves.is_empty()
+    }
+}
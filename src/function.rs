@@ -0,0 +1,118 @@
+//! Scalar functions, evaluated on the values of a single row.
+
+use crate::{Affinity, Column};
+use anyhow::{bail, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScalarFunction {
+    Upper,
+    Lower,
+    Length,
+    Substr,
+    Typeof,
+}
+
+impl ScalarFunction {
+    pub fn from_name(name: &str) -> Option<ScalarFunction> {
+        match name.to_lowercase().as_str() {
+            "upper" => Some(ScalarFunction::Upper),
+            "lower" => Some(ScalarFunction::Lower),
+            "length" => Some(ScalarFunction::Length),
+            "substr" | "substring" => Some(ScalarFunction::Substr),
+            "typeof" => Some(ScalarFunction::Typeof),
+            _ => None,
+        }
+    }
+
+    /// Checks that the function can be called with `count` arguments.
+    pub fn check_arity(self, name: &str, count: usize) -> Result<()> {
+        let valid = match self {
+            ScalarFunction::Substr => count == 2 || count == 3,
+            _ => count == 1,
+        };
+        if !valid {
+            bail!("wrong number of arguments to function {}()", name);
+        }
+        Ok(())
+    }
+
+    pub fn call(self, args: &[Column]) -> Column {
+        // Every function but typeof() is NULL when an argument is
+        if self != ScalarFunction::Typeof && args.contains(&Column::Null) {
+            return Column::Null;
+        }
+        match self {
+            // Like SQLite's built-in functions, only ASCII letters change case
+            ScalarFunction::Upper => Column::Text(args[0].to_string().to_ascii_uppercase()),
+            ScalarFunction::Lower => Column::Text(args[0].to_string().to_ascii_lowercase()),
+            // The length of a blob is in bytes
+            ScalarFunction::Length => match &args[0] {
+                Column::Blob(b) => Column::Integer(b.len() as i64),
+                value => Column::Integer(value.to_string().chars().count() as i64),
+            },
+            ScalarFunction::Substr => substr(
+                &args[0].to_string(),
+                integer(&args[1]),
+                args.get(2).map(integer),
+            ),
+            ScalarFunction::Typeof => Column::Text(
+                match args[0] {
+                    Column::Null => "null",
+                    Column::Integer(_) => "integer",
+                    Column::Real(_) => "real",
+                    Column::Text(_) => "text",
+                    Column::Blob(_) => "blob",
+                }
+                .to_string(),
+            ),
+        }
+    }
+}
+
+/// An argument used as an integer, the way `sqlite3_value_int64` reads it.
+fn integer(value: &Column) -> i64 {
+    match Affinity::Numeric.apply(value.clone()) {
+        Column::Integer(i) => i,
+        Column::Real(r) => r as i64,
+        _ => 0,
+    }
+}
+
+/// `substr(s, start, length)`, counting characters from 1. A negative start
+/// counts from the end of the string, and a negative length takes the
+/// characters before start instead of after it.
+fn substr(s: &str, start: i64, length: Option<i64>) -> Column {
+    let chars = s.chars().count() as i64;
+    let (mut start, mut length, backwards) = match length {
+        Some(length) if length < 0 => (start, -length, true),
+        Some(length) => (start, length, false),
+        // SQLite's default SQLITE_MAX_LENGTH, so that adjusting it below
+        // still leaves the rest of the string
+        None => (start, 1_000_000_000, false),
+    };
+    // The same adjustments SQLite makes, turning start into a 0-based offset
+    if start < 0 {
+        start += chars;
+        if start < 0 {
+            length = (length + start).max(0);
+            start = 0;
+        }
+    } else if start > 0 {
+        start -= 1;
+    } else if length > 0 {
+        length -= 1;
+    }
+    if backwards {
+        start -= length;
+        if start < 0 {
+            length += start;
+            start = 0;
+        }
+    }
+    Column::Text(
+        s.chars()
+            .skip(start as usize)
+            .take(length.max(0) as usize)
+            .collect(),
+    )
+}
@@ -0,0 +1,274 @@
+//! The 100-byte header at the start of every database file.
+//! https://www.sqlite.org/fileformat.html#the_database_header
+
+use crate::record::Format;
+use anyhow::{bail, Result};
+use std::cmp::Ordering;
+
+const MAGIC: &[u8; 16] = b"SQLite format 3\0";
+
+/// The SQLite release whose file format and shell output this crate
+/// follows, as `X*1000000 + Y*1000 + Z` for version X.Y.Z
+pub const SQLITE_VERSION: u32 = 3045000;
+
+/// [`SQLITE_VERSION`] as `sqlite_version()` returns it, "X.Y.Z".
+pub fn sqlite_version() -> String {
+    format!(
+        "{}.{}.{}",
+        SQLITE_VERSION / 1_000_000,
+        SQLITE_VERSION / 1000 % 1000,
+        SQLITE_VERSION % 1000
+    )
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DatabaseHeader {
+    /// Bytes per page, a power of two from 512 to 65536
+    pub page_size: usize,
+    /// 1 for a rollback journal, 2 for WAL
+    pub write_version: u8,
+    pub read_version: u8,
+    /// Bytes at the end of each page set aside for extensions
+    pub reserved_space: u8,
+    pub max_payload_fraction: u8,
+    pub min_payload_fraction: u8,
+    pub leaf_payload_fraction: u8,
+    /// Incremented by each transaction that changes the file
+    pub change_counter: u32,
+    /// Size of the database in pages, if `version_valid_for` says it is current
+    pub page_count: u32,
+    pub first_freelist_trunk: u32,
+    pub freelist_pages: u32,
+    pub schema_cookie: u32,
+    pub schema_format: u32,
+    pub default_cache_size: u32,
+    /// The largest root page, when auto-vacuum keeps pointer map pages; 0 otherwise
+    pub largest_root_page: u32,
+    pub text_encoding: TextEncoding,
+    pub user_version: u32,
+    pub incremental_vacuum: u32,
+    pub application_id: u32,
+    /// The change counter the page count was last valid for
+    pub version_valid_for: u32,
+    /// The SQLite version that last wrote the file, such as 3045001
+    pub sqlite_version: u32,
+}
+
+impl DatabaseHeader {
+    /// The page size in `header`, all that is needed of it to read the
+    /// database's pages, the WAL's among them.
+    pub fn parse_page_size(header: &[u8; 100]) -> Result<usize> {
+        if &header[..16] != MAGIC {
+            bail!("missing the \"SQLite format 3\" magic string");
+        }
+        // 65536 doesn't fit in the two bytes of the page size, and is stored as 1
+        let page_size = match u16::from_be_bytes([header[16], header[17]]) {
+            1 => 65536,
+            size => size as usize,
+        };
+        if !(512..=65536).contains(&page_size) || !page_size.is_power_of_two() {
+            bail!("invalid page size: {}", page_size);
+        }
+        Ok(page_size)
+    }
+
+    pub fn parse(header: &[u8; 100]) -> Result<DatabaseHeader> {
+        let page_size = DatabaseHeader::parse_page_size(header)?;
+        let u32_at = |offset: usize| {
+            u32::from_be_bytes([
+                header[offset],
+                header[offset + 1],
+                header[offset + 2],
+                header[offset + 3],
+            ])
+        };
+
+        if page_size - (header[20] as usize) < 480 {
+            bail!(
+                "{} reserved bytes leave too little of a page to use",
+                header[20]
+            );
+        }
+
+        Ok(DatabaseHeader {
+            page_size,
+            write_version: header[18],
+            read_version: header[19],
+            reserved_space: header[20],
+            max_payload_fraction: header[21],
+            min_payload_fraction: header[22],
+            leaf_payload_fraction: header[23],
+            change_counter: u32_at(24),
+            page_count: u32_at(28),
+            first_freelist_trunk: u32_at(32),
+            freelist_pages: u32_at(36),
+            schema_cookie: u32_at(40),
+            schema_format: u32_at(44),
+            default_cache_size: u32_at(48),
+            largest_root_page: u32_at(52),
+            text_encoding: match u32_at(56) {
+                // Not set until the first table or index is created
+                0 | 1 => TextEncoding::Utf8,
+                2 => TextEncoding::Utf16le,
+                3 => TextEncoding::Utf16be,
+                n => bail!("unknown text encoding: {}", n),
+            },
+            user_version: u32_at(60),
+            incremental_vacuum: u32_at(64),
+            application_id: u32_at(68),
+            version_valid_for: u32_at(92),
+            sqlite_version: u32_at(96),
+        })
+    }
+
+    /// The header of a new UTF-8 database of `page_count` pages of
+    /// `page_size` bytes, as SQLite would write it.
+    pub fn new(page_size: usize, page_count: u32) -> DatabaseHeader {
+        DatabaseHeader {
+            page_size,
+            write_version: 1,
+            read_version: 1,
+            reserved_space: 0,
+            max_payload_fraction: 64,
+            min_payload_fraction: 32,
+            leaf_payload_fraction: 32,
+            change_counter: 1,
+            page_count,
+            first_freelist_trunk: 0,
+            freelist_pages: 0,
+            schema_cookie: 0,
+            schema_format: 4,
+            default_cache_size: 0,
+            largest_root_page: 0,
+            text_encoding: TextEncoding::Utf8,
+            user_version: 0,
+            incremental_vacuum: 0,
+            application_id: 0,
+            version_valid_for: 1,
+            sqlite_version: SQLITE_VERSION,
+        }
+    }
+
+    /// The header as it is stored at the start of the file.
+    pub fn to_bytes(&self) -> [u8; 100] {
+        let mut header = [0; 100];
+        header[..16].copy_from_slice(MAGIC);
+        let page_size = match self.page_size {
+            65536 => 1,
+            size => size as u16,
+        };
+        header[16..18].copy_from_slice(&page_size.to_be_bytes());
+        header[18] = self.write_version;
+        header[19] = self.read_version;
+        header[20] = self.reserved_space;
+        header[21] = self.max_payload_fraction;
+        header[22] = self.min_payload_fraction;
+        header[23] = self.leaf_payload_fraction;
+        for (offset, value) in [
+            (24, self.change_counter),
+            (28, self.page_count),
+            (32, self.first_freelist_trunk),
+            (36, self.freelist_pages),
+            (40, self.schema_cookie),
+            (44, self.schema_format),
+            (48, self.default_cache_size),
+            (52, self.largest_root_page),
+            (56, self.text_encoding as u32),
+            (60, self.user_version),
+            (64, self.incremental_vacuum),
+            (68, self.application_id),
+            (92, self.version_valid_for),
+            (96, self.sqlite_version),
+        ] {
+            header[offset..offset + 4].copy_from_slice(&value.to_be_bytes());
+        }
+        header
+    }
+
+    /// The size of the database in pages. The count in the header is only
+    /// trusted if the last version to change the file kept it up to date;
+    /// otherwise the size of the file, `file_size`, tells.
+    pub fn page_count(&self, file_size: u64) -> u32 {
+        if self.page_count != 0 && self.version_valid_for == self.change_counter {
+            self.page_count
+        } else {
+            (file_size / self.page_size as u64) as u32
+        }
+    }
+
+    /// What decoding the content of the pages depends on.
+    pub fn format(&self) -> Format {
+        Format {
+            usable_size: self.page_size - self.reserved_space as usize,
+            encoding: self.text_encoding,
+        }
+    }
+}
+
+/// How every text value of a database is stored, chosen when it was created.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextEncoding {
+    Utf8 = 1,
+    Utf16le = 2,
+    Utf16be = 3,
+}
+
+impl TextEncoding {
+    pub fn name(self) -> &'static str {
+        match self {
+            TextEncoding::Utf8 => "utf8",
+            TextEncoding::Utf16le => "utf16le",
+            TextEncoding::Utf16be => "utf16be",
+        }
+    }
+
+    pub fn decode(self, bytes: &[u8]) -> String {
+        let units = bytes.chunks_exact(2).map(|unit| match self {
+            TextEncoding::Utf16le => u16::from_le_bytes([unit[0], unit[1]]),
+            _ => u16::from_be_bytes([unit[0], unit[1]]),
+        });
+        match self {
+            TextEncoding::Utf8 => String::from_utf8_lossy(bytes).into_owned(),
+            _ => char::decode_utf16(units)
+                .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                .collect(),
+        }
+    }
+
+    /// Compares texts as the BINARY collation does in a database of this
+    /// encoding, which is by the bytes they are stored as. Only for UTF-8
+    /// is that the order of their characters.
+    pub fn compare(self, a: &str, b: &str) -> Ordering {
+        match self {
+            TextEncoding::Utf8 => a.cmp(b),
+            TextEncoding::Utf16le => a
+                .encode_utf16()
+                .flat_map(u16::to_le_bytes)
+                .cmp(b.encode_utf16().flat_map(u16::to_le_bytes)),
+            TextEncoding::Utf16be => a.encode_utf16().cmp(b.encode_utf16()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let header = DatabaseHeader::new(4096, 3);
+        assert_eq!(DatabaseHeader::parse(&header.to_bytes()).unwrap(), header);
+    }
+
+    #[test]
+    fn empty_database_encoding() {
+        // SQLite leaves the encoding at 0 until the first table is created
+        let mut bytes = DatabaseHeader::new(4096, 1).to_bytes();
+        bytes[56..60].copy_from_slice(&0u32.to_be_bytes());
+        let header = DatabaseHeader::parse(&bytes).unwrap();
+        assert_eq!(header.text_encoding, TextEncoding::Utf8);
+
+        bytes[56..60].copy_from_slice(&4u32.to_be_bytes());
+        assert!(DatabaseHeader::parse(&bytes).is_err());
+    }
+}
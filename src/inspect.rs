@@ -0,0 +1,464 @@
+//! Commands that show the file format itself rather than the data in it,
+//! for debugging the format or a writer of it.
+
+use crate::connection::Connection;
+use crate::db::Options;
+use crate::output::sql_literal;
+use crate::record::{self, Format};
+use crate::subcommands::{dot_escape, GraphFormat};
+use crate::{tables, try_variant, Error};
+use anyhow::{bail, Result};
+use std::collections::HashSet;
+use std::io::Write;
+
+/// The kinds of b-tree page, by the byte their header starts with.
+fn page_kind(flag: u8) -> Option<&'static str> {
+    match flag {
+        0x02 => Some("interior index b-tree page"),
+        0x05 => Some("interior table b-tree page"),
+        0x0a => Some("leaf index b-tree page"),
+        0x0d => Some("leaf table b-tree page"),
+        _ => None,
+    }
+}
+
+/// The size in bytes of the cell of a page of kind `flag` at the start of
+/// `cell`, overflow page number included, or None if its varints run past
+/// the end of `cell`.
+fn cell_size(flag: u8, cell: &[u8], format: Format) -> Option<usize> {
+    let start = cell.len();
+    // Interior cells start with the number of their child page
+    let rest = match flag {
+        0x02 | 0x05 => cell.get(4..)?,
+        _ => cell,
+    };
+    if flag == 0x05 {
+        let (_, rest) = try_variant(rest)?;
+        return Some(start - rest.len());
+    }
+    let (payload_length, mut rest) = try_variant(rest)?;
+    if flag == 0x0d {
+        rest = try_variant(rest)?.1;
+    }
+    let local = record::local_size(format, payload_length, flag != 0x0d);
+    let overflow = if local < payload_length as usize {
+        4
+    } else {
+        0
+    };
+    Some(start - rest.len() + local + overflow)
+}
+
+/// `len` bytes of page `page_no` from `offset`, failing if they run past
+/// the end of the page.
+fn bytes_at(page: &[u8], page_no: u32, offset: usize, len: usize) -> Result<&[u8]> {
+    page.get(offset..offset + len)
+        .ok_or_else(|| Error::corrupt(page_no, offset, "runs past the end of the page").into())
+}
+
+/// The big-endian number of `N` bytes at `offset` of page `page_no`.
+fn be_at<const N: usize>(page: &[u8], page_no: u32, offset: usize) -> Result<u64> {
+    let bytes = bytes_at(page, page_no, offset, N)?;
+    Ok(bytes.iter().fold(0, |n, &b| n << 8 | b as u64))
+}
+
+/// `page <db> <pageno>`: the kind of the page, the fields of its b-tree
+/// header and its cell pointer array, followed by a hex dump of it split
+/// into the regions they describe.
+pub fn page(path: &str, options: &Options, page_no: u32, out: &mut dyn Write) -> Result<()> {
+    let Connection {
+        mut pager,
+        format,
+        header,
+        ..
+    } = Connection::open(path, options)?;
+    let page_count = header.page_count(pager.file().metadata()?.len());
+    if page_no == 0 || page_no > page_count {
+        bail!(
+            "no page {}: the database has pages 1 to {}",
+            page_no,
+            page_count
+        );
+    }
+    let page = pager.get_any_page(page_no)?;
+    if let Some(pointer_map) = pager.pointer_map().filter(|map| map.contains(page_no)) {
+        writeln!(out, "page {} of {}: pointer map page", page_no, page_count)?;
+        for (covered, kind, parent) in pointer_map.entries(page_no, &page) {
+            match parent {
+                0 => writeln!(out, "page {}: {}", covered, kind)?,
+                parent => writeln!(out, "page {}: {}, from page {}", covered, kind, parent)?,
+            }
+        }
+        writeln!(out)?;
+        return hex_dump(&page, 0, out);
+    }
+
+    let start = page.btree_start();
+    let flag = page[start];
+    let Some(kind) = page_kind(flag) else {
+        writeln!(
+            out,
+            "page {} of {}: not a b-tree page (an overflow or freelist page)",
+            page_no, page_count
+        )?;
+        writeln!(out)?;
+        return hex_dump(&page, 0, out);
+    };
+    let u16_at = |offset: usize| be_at::<2>(&page, page_no, offset).map(|n| n as usize);
+    let interior = flag == 0x02 || flag == 0x05;
+    let header_size = if interior { 12 } else { 8 };
+    let number_of_cells = u16_at(start + 3)?;
+    // A content area starting at 0 starts at 65536, past a page of that size
+    let content_start = match u16_at(start + 5)? {
+        0 => 65536,
+        offset => offset,
+    };
+
+    writeln!(out, "page {} of {}: {}", page_no, page_count, kind)?;
+    writeln!(out, "first freeblock: {}", u16_at(start + 1)?)?;
+    writeln!(out, "number of cells: {}", number_of_cells)?;
+    writeln!(out, "cell content area: {}", content_start)?;
+    writeln!(out, "fragmented free bytes: {}", page[start + 7])?;
+    if interior {
+        writeln!(
+            out,
+            "right-most pointer: {}",
+            be_at::<4>(&page, page_no, start + 8)?
+        )?;
+    }
+    let pointers = (0..number_of_cells)
+        .map(|i| u16_at(start + header_size + 2 * i))
+        .collect::<Result<Vec<_>>>()?;
+    writeln!(
+        out,
+        "cell pointers: {}",
+        pointers
+            .iter()
+            .map(|p| p.to_string())
+            .collect::<Vec<_>>()
+            .join(" ")
+    )?;
+
+    // The regions of the page in order, each a hex dump of its own
+    let mut regions = vec![];
+    if start > 0 {
+        regions.push((0, start, "database header".to_string()));
+    }
+    let pointers_start = start + header_size;
+    regions.push((start, pointers_start, "b-tree page header".to_string()));
+    let pointers_end = pointers_start + 2 * number_of_cells;
+    regions.push((
+        pointers_start,
+        pointers_end,
+        "cell pointer array".to_string(),
+    ));
+    regions.push((
+        pointers_end,
+        content_start.min(page.len()),
+        "unallocated".to_string(),
+    ));
+    let mut cells = pointers
+        .iter()
+        .enumerate()
+        .filter(|(_, &offset)| offset < format.usable_size)
+        .map(|(i, &offset)| {
+            // A cell that can't be made out takes the rest of the page
+            let end = cell_size(flag, &page[offset..format.usable_size], format)
+                .map_or(format.usable_size, |size| offset + size);
+            (offset, end.min(format.usable_size), format!("cell {}", i))
+        })
+        .collect::<Vec<_>>();
+    cells.sort();
+    // Whatever lies between cells is free: freeblocks and fragments
+    let mut offset = content_start;
+    for cell in cells {
+        if cell.0 > offset {
+            regions.push((offset, cell.0, "free".to_string()));
+        }
+        offset = offset.max(cell.1);
+        regions.push(cell);
+    }
+    if offset < format.usable_size {
+        regions.push((offset, format.usable_size, "free".to_string()));
+    }
+    if format.usable_size < page.len() {
+        regions.push((format.usable_size, page.len(), "reserved".to_string()));
+    }
+
+    for (from, to, name) in regions.into_iter().filter(|(from, to, _)| from < to) {
+        writeln!(out)?;
+        writeln!(
+            out,
+            "{} ({} bytes at offset {}, {:#x}):",
+            name,
+            to - from,
+            from,
+            from
+        )?;
+        hex_dump(&page[from..to], from, out)?;
+    }
+    Ok(())
+}
+
+/// Writes `bytes`, which start at `offset` in the page, 16 to a line as hex
+/// and as ASCII. Repeats of a line are written as a single `*`.
+fn hex_dump(bytes: &[u8], offset: usize, out: &mut dyn Write) -> Result<()> {
+    let mut previous: Option<&[u8]> = None;
+    let mut repeating = false;
+    for (i, line) in bytes.chunks(16).enumerate() {
+        if previous == Some(line) && line.len() == 16 {
+            if !repeating {
+                writeln!(out, "*")?;
+                repeating = true;
+            }
+            continue;
+        }
+        previous = Some(line);
+        repeating = false;
+        let ascii = line
+            .iter()
+            .map(|&b| {
+                if (0x20..0x7f).contains(&b) {
+                    b as char
+                } else {
+                    '.'
+                }
+            })
+            .collect::<String>();
+        writeln!(
+            out,
+            "{:06x}  {:<47}  |{}|",
+            offset + 16 * i,
+            hex(line),
+            ascii
+        )?;
+    }
+    Ok(())
+}
+
+/// `cell <db> <pageno> <cellidx>`: cell `index` of a b-tree page, decoded
+/// field by field: its varints with their bytes, the serial types of its
+/// record's header, and each value with the bytes it is stored as.
+pub fn cell(
+    path: &str,
+    options: &Options,
+    page_no: u32,
+    index: usize,
+    out: &mut dyn Write,
+) -> Result<()> {
+    let Connection {
+        mut pager, format, ..
+    } = Connection::open(path, options)?;
+    let page = pager.get_page(page_no)?;
+    let start = page.btree_start();
+    let flag = page[start];
+    let Some(kind) = page_kind(flag) else {
+        bail!("page {} is not a b-tree page", page_no);
+    };
+    let header_size = if flag == 0x02 || flag == 0x05 { 12 } else { 8 };
+    let number_of_cells = be_at::<2>(&page, page_no, start + 3)? as usize;
+    if index >= number_of_cells {
+        bail!("no cell {}: the page has {} cells", index, number_of_cells);
+    }
+    let pointer = start + header_size + 2 * index;
+    let offset = be_at::<2>(&page, page_no, pointer)? as usize;
+    writeln!(
+        out,
+        "page {} ({}), cell {} at offset {}",
+        page_no, kind, index, offset
+    )?;
+
+    let malformed = || Error::corrupt(page_no, offset, "malformed cell");
+    let mut cell = page.get(offset..).ok_or_else(malformed)?;
+    if flag == 0x02 || flag == 0x05 {
+        let child = bytes_at(cell, page_no, offset, 4)?;
+        writeln!(
+            out,
+            "left child page: {} ({})",
+            u32::from_be_bytes(child.try_into().unwrap()),
+            hex(child)
+        )?;
+        cell = &cell[4..];
+    }
+    // The varint at the start of `cell`, with the bytes it takes
+    let varint = |cell: &[u8]| {
+        let (value, rest) = try_variant(cell).ok_or_else(malformed)?;
+        Ok::<_, Error>((
+            value,
+            hex(&cell[..cell.len() - rest.len()]),
+            cell.len() - rest.len(),
+        ))
+    };
+    if flag == 0x05 {
+        let (key, bytes, _) = varint(cell)?;
+        writeln!(out, "key: rowid {} (varint {})", key, bytes)?;
+        return Ok(());
+    }
+    let (payload_length, bytes, size) = varint(cell)?;
+    writeln!(out, "payload length: {} (varint {})", payload_length, bytes)?;
+    cell = &cell[size..];
+    if flag == 0x0d {
+        let (row_id, bytes, size) = varint(cell)?;
+        writeln!(out, "rowid: {} (varint {})", row_id, bytes)?;
+        cell = &cell[size..];
+    }
+    let index_cell = flag != 0x0d;
+    let local = record::local_size(format, payload_length, index_cell);
+    if local < payload_length as usize {
+        writeln!(
+            out,
+            "payload: {} bytes on the page, the rest from overflow page {}",
+            local,
+            be_at::<4>(cell, page_no, local).map_err(|_| malformed())?
+        )?;
+    } else {
+        writeln!(out, "payload: all {} bytes on the page", local)?;
+    }
+
+    let payload = record::payload(&mut pager, format, payload_length, cell, index_cell)
+        .map_err(|e| e.context(malformed()))?;
+    let (header_length, bytes, size) = varint(&payload)?;
+    writeln!(
+        out,
+        "record header: {} bytes (varint {})",
+        header_length, bytes
+    )?;
+    let mut header = usize::try_from(header_length)
+        .ok()
+        .and_then(|length| payload.get(size..length))
+        .ok_or_else(malformed)?;
+    let mut serial_types = vec![];
+    while !header.is_empty() {
+        let (serial_type, bytes, size) = varint(header)?;
+        serial_types.push((serial_type, bytes));
+        header = &header[size..];
+    }
+    for (i, ((serial_type, type_bytes), (_, bytes))) in serial_types
+        .into_iter()
+        .zip(record::fields(&payload).ok_or_else(malformed)?)
+        .enumerate()
+    {
+        let value = record::value(serial_type, bytes, format.encoding);
+        writeln!(
+            out,
+            "column {}: serial type {} (varint {}), {}",
+            i,
+            serial_type,
+            type_bytes,
+            record::describe(serial_type)
+        )?;
+        if !bytes.is_empty() {
+            writeln!(out, "  bytes: {}", hex(bytes))?;
+        }
+        writeln!(out, "  value: {}", sql_literal(&value))?;
+    }
+    Ok(())
+}
+
+/// `bytes` as hex, two digits to a byte, separated by spaces.
+fn hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// `btree <db> <table|index> --format dot`: the b-tree of a table or index
+/// as a Graphviz graph, with a node per page. Interior pages show their keys
+/// between the ports their children hang off, and leaves how many cells
+/// they hold, so how full pages are and where they were split can be seen.
+pub fn btree(
+    path: &str,
+    options: &Options,
+    name: &str,
+    format: GraphFormat,
+    out: &mut dyn Write,
+) -> Result<()> {
+    if let GraphFormat::Mermaid = format {
+        bail!("btree only writes dot");
+    }
+    let Connection {
+        mut pager, format, ..
+    } = Connection::open(path, options)?;
+    let schema = tables(&mut pager, format)?;
+    let Some(tree) = schema
+        .iter()
+        .find(|t| (t.ty == "table" || t.ty == "index") && t.name.eq_ignore_ascii_case(name))
+    else {
+        bail!("no such table or index: {}", name);
+    };
+
+    writeln!(out, "digraph btree {{")?;
+    writeln!(out, "    node [shape=record];")?;
+    let mut pages = vec![tree.rootpage];
+    // A corrupt b-tree may lead back to a page already drawn, and would
+    // otherwise be walked around forever
+    let mut visited = HashSet::new();
+    while let Some(page_no) = pages.pop() {
+        if !visited.insert(page_no) {
+            return Err(Error::corrupt(page_no, 0, "the b-tree leads to the page twice").into());
+        }
+        let page = pager.get_page(page_no)?;
+        let start = page.btree_start();
+        let flag = page[start];
+        let number_of_cells = be_at::<2>(&page, page_no, start + 3)? as usize;
+        match flag {
+            0x0a | 0x0d => {
+                let what = if flag == 0x0d { "rows" } else { "entries" };
+                writeln!(
+                    out,
+                    "    p{} [label=\"page {}|{} {}\"];",
+                    page_no, page_no, number_of_cells, what
+                )?;
+            }
+            0x02 | 0x05 => {
+                let mut label = format!("page {}", page_no);
+                let mut children = vec![];
+                for i in 0..number_of_cells {
+                    let pointer = start + 12 + 2 * i;
+                    let offset = be_at::<2>(&page, page_no, pointer)? as usize;
+                    let malformed = || Error::corrupt(page_no, offset, "malformed cell");
+                    children.push(be_at::<4>(&page, page_no, offset)? as u32);
+                    let (value, rest) = page
+                        .get(offset + 4..)
+                        .and_then(try_variant)
+                        .ok_or_else(malformed)?;
+                    let key = if flag == 0x05 {
+                        value.to_string()
+                    } else {
+                        let payload = record::payload(&mut pager, format, value, rest, true)?;
+                        let key = record::decode(&payload, format.encoding)
+                            .ok_or_else(malformed)?
+                            .iter()
+                            .map(sql_literal)
+                            .collect::<Vec<_>>()
+                            .join(", ");
+                        truncate(&key, 24)
+                    };
+                    label += &format!("|<c{}>|{}", i, dot_escape(&key));
+                }
+                label += &format!("|<c{}>", number_of_cells);
+                children.push(be_at::<4>(&page, page_no, start + 8)? as u32);
+                writeln!(out, "    p{} [label=\"{}\"];", page_no, label)?;
+                for (i, child) in children.iter().enumerate() {
+                    writeln!(out, "    p{}:c{} -> p{};", page_no, i, child)?;
+                }
+                // Visited right to left off the stack, so nodes are written
+                // in key order
+                pages.extend(children.into_iter().rev());
+            }
+            _ => bail!("page {} is not a b-tree page", page_no),
+        }
+    }
+    writeln!(out, "}}")?;
+    Ok(())
+}
+
+/// `text` cut to at most `max` characters, ending with `...` if it was cut.
+fn truncate(text: &str, max: usize) -> String {
+    if text.chars().count() <= max {
+        return text.to_string();
+    }
+    text.chars().take(max - 3).collect::<String>() + "..."
+}
@@ -0,0 +1,450 @@
+//! Reading SQLite database files without SQLite: the file format, a query
+//! engine for the `SELECT` statements SQLite would run on them, and the
+//! tooling around it that the command-line interface is made of.
+//!
+//! [`db::Database`] is the way in for a program: `Database::open(path)?`
+//! and then `.query("SELECT ...")?` for an iterator over the rows. What
+//! fails because of the query or of the file, rather than of I/O, fails with
+//! an [`Error`], which the `anyhow::Error` returned downcasts to.
+
+mod aggregate;
+mod bloom;
+pub mod btree;
+pub mod connection;
+pub mod db;
+pub mod dump;
+mod error;
+pub mod exec;
+mod expr;
+pub mod freelist;
+mod function;
+pub mod header;
+pub mod inspect;
+pub mod load;
+pub mod mmap;
+pub mod output;
+pub mod pager;
+pub mod pragma;
+mod ptrmap;
+pub mod record;
+pub mod serve;
+pub mod sql;
+mod stat4;
+pub mod subcommands;
+pub mod teach;
+#[cfg(test)]
+mod testing;
+pub mod vtab;
+mod wal;
+
+use anyhow::Result;
+use connection::Connection;
+use db::Options;
+pub use error::Error;
+use expr::{BoundExpr, Scope};
+use pager::Pager;
+use record::Format;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::rc::Rc;
+use vtab::VirtualTable;
+
+#[derive(Debug)]
+#[allow(dead_code)]
+pub struct Table {
+    pub ty: String,
+    pub name: String,
+    pub tbl_name: String,
+    pub rootpage: u32,
+    pub sql: String,
+    /// Where the rows of a virtual table come from, which has no b-tree
+    pub vtab: Option<Rc<dyn VirtualTable>>,
+}
+
+/// The tables of one of the open databases, under the name statements
+/// qualify them with.
+#[derive(Debug)]
+pub struct Schema {
+    pub name: String,
+    pub tables: Vec<Table>,
+    /// Statistics of the indexes that have been analyzed, by lowercased name
+    pub stats: HashMap<String, stat4::IndexStats>,
+}
+
+impl Schema {
+    /// The foreign keys `table` declares, or None if there is no such table.
+    fn foreign_keys(&self, table: &str) -> Result<Option<Vec<sql::ForeignKey>>> {
+        self.tables
+            .iter()
+            .find(|t| t.ty == "table" && t.name.eq_ignore_ascii_case(table))
+            .map(|t| Ok(sql::parse_create_table(&t.sql)?.foreign_keys))
+            .transpose()
+    }
+}
+
+#[derive(Debug, PartialEq, PartialOrd, Clone)]
+pub enum Column {
+    Null,
+    Integer(i64),
+    Real(f64),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+impl Display for Column {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Column::Null => Ok(()),
+            Column::Integer(i) => write!(f, "{}", i),
+            Column::Real(r) => write!(f, "{}", format_real(*r)),
+            Column::Text(s) => write!(f, "{}", s),
+            // The bytes as they are, as far as they can be written as text;
+            // the output writers write them out in full
+            Column::Blob(b) => write!(f, "{}", String::from_utf8_lossy(b)),
+        }
+    }
+}
+
+impl Column {
+    /// Compares two values the way SQLite sorts them: NULL first, then
+    /// numbers, then text compared under `collation`, then blobs.
+    fn compare(&self, other: &Column, collation: Collation) -> Ordering {
+        match (self, other) {
+            (Column::Null, Column::Null) => Ordering::Equal,
+            (Column::Null, _) => Ordering::Less,
+            (_, Column::Null) => Ordering::Greater,
+            (Column::Integer(a), Column::Integer(b)) => a.cmp(b),
+            (Column::Integer(a), Column::Real(b)) => (*a as f64).total_cmp(b),
+            (Column::Real(a), Column::Integer(b)) => a.total_cmp(&(*b as f64)),
+            (Column::Real(a), Column::Real(b)) => a.total_cmp(b),
+            (Column::Integer(_) | Column::Real(_), Column::Text(_)) => Ordering::Less,
+            (Column::Text(_), Column::Integer(_) | Column::Real(_)) => Ordering::Greater,
+            (Column::Text(a), Column::Text(b)) => collation.compare(a, b),
+            (Column::Blob(a), Column::Blob(b)) => a.cmp(b),
+            (Column::Blob(_), _) => Ordering::Greater,
+            (_, Column::Blob(_)) => Ordering::Less,
+        }
+    }
+}
+
+/// Formats a real the way sqlite3 prints it (`%!.15g`): 15 significant
+/// digits, always with a decimal point, switching to exponent notation for
+/// very large or small magnitudes.
+fn format_real(r: f64) -> String {
+    if r.is_infinite() {
+        return if r > 0.0 { "Inf" } else { "-Inf" }.to_string();
+    }
+    if r == 0.0 {
+        return "0.0".to_string();
+    }
+
+    let scientific = format!("{:.14e}", r);
+    let (mantissa, exponent) = scientific.split_once('e').unwrap();
+    let exponent: i32 = exponent.parse().unwrap();
+    let trim = |s: &str| {
+        let s = s.trim_end_matches('0');
+        if s.ends_with('.') {
+            format!("{}0", s)
+        } else {
+            s.to_string()
+        }
+    };
+
+    if !(-4..15).contains(&exponent) {
+        let sign = if exponent < 0 { '-' } else { '+' };
+        format!("{}e{}{:02}", trim(mantissa), sign, exponent.abs())
+    } else {
+        trim(&format!("{:.*}", (14 - exponent) as usize, r))
+    }
+}
+
+pub type Row = Vec<Column>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Collation {
+    #[default]
+    Binary,
+    NoCase,
+    RTrim,
+}
+
+impl Collation {
+    fn from_name(name: &str) -> Option<Collation> {
+        match name.to_uppercase().as_str() {
+            "BINARY" => Some(Collation::Binary),
+            "NOCASE" => Some(Collation::NoCase),
+            "RTRIM" => Some(Collation::RTrim),
+            _ => None,
+        }
+    }
+
+    fn compare(self, a: &str, b: &str) -> Ordering {
+        match self {
+            Collation::Binary => a.cmp(b),
+            // NOCASE only folds ASCII letters, like SQLite's built-in collation
+            Collation::NoCase => a
+                .bytes()
+                .map(|c| c.to_ascii_lowercase())
+                .cmp(b.bytes().map(|c| c.to_ascii_lowercase())),
+            Collation::RTrim => a.trim_end_matches(' ').cmp(b.trim_end_matches(' ')),
+        }
+    }
+
+    /// A form of `s` that is the same for two strings exactly when they
+    /// compare equal under this collation.
+    fn canonical(self, s: &str) -> String {
+        match self {
+            Collation::Binary => s.to_string(),
+            Collation::NoCase => s.to_ascii_lowercase(),
+            Collation::RTrim => s.trim_end_matches(' ').to_string(),
+        }
+    }
+}
+
+/// Column type affinity, derived from the declared type as described in
+/// https://www.sqlite.org/datatype3.html#determination_of_column_affinity
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Affinity {
+    Integer,
+    Text,
+    Blob,
+    Real,
+    Numeric,
+}
+
+impl Affinity {
+    fn from_decl_type(decl_type: Option<&str>) -> Affinity {
+        let Some(decl_type) = decl_type else {
+            return Affinity::Blob;
+        };
+        let decl_type = decl_type.to_uppercase();
+        if decl_type.contains("INT") {
+            Affinity::Integer
+        } else if ["CHAR", "CLOB", "TEXT"]
+            .iter()
+            .any(|t| decl_type.contains(t))
+        {
+            Affinity::Text
+        } else if decl_type.contains("BLOB") {
+            Affinity::Blob
+        } else if ["REAL", "FLOA", "DOUB"]
+            .iter()
+            .any(|t| decl_type.contains(t))
+        {
+            Affinity::Real
+        } else {
+            Affinity::Numeric
+        }
+    }
+
+    /// Converts a value compared against a column of this affinity, so that
+    /// e.g. `WHERE id = '5'` matches the integer 5.
+    fn apply(self, value: Column) -> Column {
+        match (self, value) {
+            (Affinity::Integer | Affinity::Real | Affinity::Numeric, Column::Text(s)) => {
+                match (s.trim().parse::<i64>(), parse_real(s.trim())) {
+                    (Ok(i), _) => Column::Integer(i),
+                    (_, Some(r)) => Column::Real(r),
+                    _ => Column::Text(s),
+                }
+            }
+            (Affinity::Text, Column::Integer(i)) => Column::Text(i.to_string()),
+            (Affinity::Text, Column::Real(r)) => Column::Text(format_real(r)),
+            (_, value) => value,
+        }
+    }
+
+    fn is_numeric(self) -> bool {
+        matches!(self, Affinity::Integer | Affinity::Real | Affinity::Numeric)
+    }
+}
+
+/// Parses `s` as a real if it is entirely a decimal number, which unlike
+/// `f64::from_str` excludes words such as `inf` and `NaN`.
+fn parse_real(s: &str) -> Option<f64> {
+    if !s.contains(|c: char| c.is_ascii_digit())
+        || !s
+            .chars()
+            .all(|c| c.is_ascii_digit() || matches!(c, '.' | 'e' | 'E' | '+' | '-'))
+    {
+        return None;
+    }
+    s.parse().ok()
+}
+
+/// The varint at the start of `buf` and the bytes after it, or None if `buf`
+/// ends before the varint does.
+fn try_variant(buf: &[u8]) -> Option<(u64, &[u8])> {
+    let mut i = 0;
+    let mut v = 0;
+    loop {
+        let byte = *buf.get(i)?;
+        v = (v << 7) | (byte & 0x7f) as u64;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        i += 1;
+    }
+    Some((v, &buf[i + 1..]))
+}
+
+/// Appends `value` to `buf` as a varint: seven bits to a byte, most
+/// significant first, except for the ninth byte of the largest values,
+/// which holds eight.
+fn put_variant(value: u64, buf: &mut Vec<u8>) {
+    if value >> 56 != 0 {
+        let high = value >> 8;
+        buf.extend((0..8).rev().map(|i| (high >> (7 * i)) as u8 | 0x80));
+        buf.push(value as u8);
+        return;
+    }
+    let groups = (1..9).find(|n| value >> (7 * n) == 0).unwrap();
+    buf.extend((0..groups).rev().map(|i| {
+        let byte = (value >> (7 * i)) as u8 & 0x7f;
+        if i == 0 {
+            byte
+        } else {
+            byte | 0x80
+        }
+    }));
+}
+
+/// The entries of `sqlite_schema`, which is rooted at page 1.
+fn tables(pager: &mut Pager, format: Format) -> Result<Vec<Table>> {
+    let first_page = pager.get_page(1)?;
+    btree::TableCursor::new(
+        first_page,
+        pager,
+        format,
+        btree::Direction::Forward,
+        &record::TableLayout::default(),
+    )?
+    .map(|row| {
+        // sqlite_schema(type, name, tbl_name, rootpage, sql)
+        let mut values = row?.1.into_iter();
+        let text = |value: Option<Column>| match value {
+            Some(Column::Text(s)) => s,
+            // sql is NULL for indexes created implicitly by UNIQUE/PRIMARY KEY constraints
+            _ => String::new(),
+        };
+        let (ty, name, tbl_name) = (
+            text(values.next()),
+            text(values.next()),
+            text(values.next()),
+        );
+        let rootpage = match values.next() {
+            Some(Column::Integer(rootpage)) => rootpage as u32,
+            _ => 0,
+        };
+        Ok(Table {
+            ty,
+            name,
+            tbl_name,
+            rootpage,
+            sql: text(values.next()),
+            vtab: None,
+        })
+    })
+    .collect()
+}
+
+#[derive(Debug, Clone)]
+pub struct ColumnDef {
+    pub name: String,
+    pub decl_type: Option<String>,
+    pub collation: Collation,
+    pub primary_key: bool,
+    /// The value of the column in rows written before it was added by
+    /// `ALTER TABLE ... ADD COLUMN`: its `DEFAULT`, or NULL
+    pub default: Column,
+}
+
+impl ColumnDef {
+    fn affinity(&self) -> Affinity {
+        Affinity::from_decl_type(self.decl_type.as_deref())
+    }
+
+    /// An `INTEGER PRIMARY KEY` column is another name for the rowid, so the
+    /// table b-tree is already sorted by it.
+    fn is_rowid_alias(&self) -> bool {
+        self.primary_key
+            && self
+                .decl_type
+                .as_deref()
+                .map_or(false, |t| t.eq_ignore_ascii_case("INTEGER"))
+    }
+}
+
+/// The position of the column that is another name for the rowid, if any.
+fn rowid_alias(columns: &[ColumnDef]) -> Option<usize> {
+    columns.iter().position(ColumnDef::is_rowid_alias)
+}
+
+fn sql_columns(sql: &str) -> Result<Vec<ColumnDef>> {
+    Ok(column_defs(sql::parse_create_table(sql)?))
+}
+
+/// The columns of the table `sql` creates, which must have a rowid for its
+/// rows to be read: a WITHOUT ROWID table is a b-tree of index pages.
+fn table_columns(sql: &str) -> Result<Vec<ColumnDef>> {
+    let table = sql::parse_create_table(sql)?;
+    if table.without_rowid {
+        return Err(Error::UnsupportedFeature("reading a WITHOUT ROWID table".to_string()).into());
+    }
+    Ok(column_defs(table))
+}
+
+fn column_defs(table: sql::CreateTable) -> Vec<ColumnDef> {
+    table
+        .columns
+        .into_iter()
+        .map(|c| {
+            let default = match c.default.map(|e| BoundExpr::bind(&e, Scope::default())) {
+                Some(Ok(e)) => match e.fold() {
+                    BoundExpr::Literal(value) => {
+                        Affinity::from_decl_type(c.decl_type.as_deref()).apply(value)
+                    }
+                    _ => Column::Null,
+                },
+                _ => Column::Null,
+            };
+            ColumnDef {
+                name: c.name,
+                decl_type: c.decl_type,
+                collation: c
+                    .collation
+                    .as_deref()
+                    .and_then(Collation::from_name)
+                    .unwrap_or_default(),
+                primary_key: c.primary_key,
+                default,
+            }
+        })
+        .collect()
+}
+
+/// The schemas of the databases of `connections`: the first is `main`, and
+/// the others are `aux1`, `aux2`... The virtual tables of `options` are in
+/// `main`.
+pub fn load_schemas(connections: &mut [Connection], options: &Options) -> Result<Vec<Schema>> {
+    connections
+        .iter_mut()
+        .enumerate()
+        .map(|(i, connection)| {
+            let Connection { pager, format, .. } = connection;
+            let mut tables = tables(pager, *format)?;
+            if i == 0 {
+                tables.extend(vtab::tables(&options.vtabs)?);
+            }
+            Ok(Schema {
+                name: match i {
+                    0 => "main".to_string(),
+                    i => format!("aux{}", i),
+                },
+                stats: stat4::load(pager, *format, &tables)?,
+                tables,
+            })
+        })
+        .collect()
+}
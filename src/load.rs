@@ -0,0 +1,551 @@
+//! Writing a new database in one go from rows that are already in order, as
+//! `load` does with a CSV file, or with no rows for a `CREATE TABLE` run on
+//! a file that doesn't exist yet. Each b-tree is built bottom-up: its leaves
+//! are filled one after the other, and the interior pages above them are
+//! written as each level fills, rather than every row being inserted from
+//! the root down.
+//! https://www.sqlite.org/fileformat.html#b_tree_pages
+
+use crate::btree::{compare_key, KeyOrder};
+use crate::header::{DatabaseHeader, TextEncoding};
+use crate::record::{self, Format};
+use crate::{put_variant, rowid_alias, sql, sql_columns, Collation, Column, Error, Row};
+use anyhow::{bail, Context, Result};
+use std::cmp::Ordering;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::mem;
+use std::path::Path;
+
+/// SQLite's default page size, with no bytes reserved
+const PAGE_SIZE: usize = 4096;
+const FORMAT: Format = Format {
+    usable_size: PAGE_SIZE,
+    encoding: TextEncoding::Utf8,
+};
+
+/// Writes a new database at `path` with one table in it, declared by the
+/// `CREATE TABLE` statement `table`, holding `rows`, and the indexes the
+/// `CREATE INDEX` statements `indexes` declare on it.
+///
+/// The rows are stored in the order they come in. A table with an `INTEGER
+/// PRIMARY KEY` takes their rowids from it, so they must be sorted by it;
+/// otherwise they are numbered from 1. The entries of each index are sorted
+/// before it is written.
+pub fn load(
+    path: impl AsRef<Path>,
+    table: &str,
+    indexes: &[String],
+    rows: impl IntoIterator<Item = Result<Row>>,
+) -> Result<()> {
+    let path = path.as_ref();
+    let file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .with_context(|| format!("cannot create {}", path.display()))?;
+    let result = write(file, table, indexes, rows);
+    // Not to leave half a database behind
+    if result.is_err() {
+        let _ = fs::remove_file(path);
+    }
+    result
+}
+
+/// An index to build, and the entries gathered for it from the rows.
+struct Index {
+    name: String,
+    /// As it is stored in the schema
+    sql: String,
+    unique: bool,
+    /// The position in the table of each of its columns
+    columns: Vec<usize>,
+    orders: Vec<KeyOrder>,
+    /// The values of its columns in each row, followed by the rowid
+    entries: Vec<Row>,
+}
+
+fn write(
+    file: File,
+    table_sql: &str,
+    indexes: &[String],
+    rows: impl IntoIterator<Item = Result<Row>>,
+) -> Result<()> {
+    let table = sql::parse_create_table(table_sql)?;
+    let columns = sql_columns(table_sql)?;
+    let alias = rowid_alias(&columns);
+    if let Some(schema) = table.schema.as_ref() {
+        if !schema.eq_ignore_ascii_case("main") {
+            bail!("unknown database {}", schema);
+        }
+    }
+    if table.without_rowid {
+        return Err(Error::UnsupportedFeature("writing a WITHOUT ROWID table".into()).into());
+    }
+    // Each key but the rowid has an index of its own, which SQLite expects
+    // to find as sqlite_autoindex_<table>_<N>
+    let keyed = !table.primary_key.is_empty()
+        || !table.unique.is_empty()
+        || table
+            .columns
+            .iter()
+            .enumerate()
+            .any(|(i, c)| c.unique || (c.primary_key && Some(i) != alias));
+    if keyed {
+        return Err(Error::UnsupportedFeature(
+            "writing a PRIMARY KEY or UNIQUE constraint, other than INTEGER PRIMARY KEY,".into(),
+        )
+        .into());
+    }
+    let mut indexes = indexes
+        .iter()
+        .map(|sql| {
+            let index = sql::parse_create_index(sql)?;
+            if let Some(schema) = index.schema.as_ref() {
+                if !schema.eq_ignore_ascii_case("main") {
+                    bail!("unknown database {}", schema);
+                }
+            }
+            if !index.table.eq_ignore_ascii_case(&table.name) {
+                return Err(Error::UnknownTable(index.table).into());
+            }
+            if index.partial {
+                return Err(Error::UnsupportedFeature("loading a partial index".into()).into());
+            }
+            let (positions, orders) = index
+                .columns
+                .iter()
+                .map(|column| {
+                    let Some(i) = columns
+                        .iter()
+                        .position(|c| c.name.eq_ignore_ascii_case(&column.name))
+                    else {
+                        return Err(Error::UnknownColumn(column.name.clone()).into());
+                    };
+                    let collation = match &column.collation {
+                        Some(name) => Collation::from_name(name)
+                            .with_context(|| format!("no such collation sequence: {}", name))?,
+                        None => columns[i].collation,
+                    };
+                    Ok((
+                        i,
+                        KeyOrder {
+                            collation,
+                            descending: column.descending,
+                        },
+                    ))
+                })
+                .collect::<Result<Vec<_>>>()?
+                .into_iter()
+                .unzip();
+            Ok(Index {
+                name: index.name,
+                sql: sql::schema_sql(sql)?,
+                unique: index.unique,
+                columns: positions,
+                orders,
+                entries: vec![],
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut out = Output::new(file)?;
+    let mut tree = BTree::new(false);
+    let mut last_rowid = None;
+    for row in rows {
+        let row = row?;
+        if row.len() > columns.len() {
+            bail!(
+                "table {} has {} columns but {} values were supplied",
+                table.name,
+                columns.len(),
+                row.len()
+            );
+        }
+        let mut row = row
+            .into_iter()
+            .chain(std::iter::repeat(Column::Null))
+            .zip(&columns)
+            .map(|(value, column)| column.affinity().apply(value))
+            .collect::<Row>();
+        let rowid = match alias.map(|i| mem::replace(&mut row[i], Column::Null)) {
+            None | Some(Column::Null) => last_rowid.map_or(1, |last| last + 1),
+            Some(Column::Integer(rowid)) if last_rowid.map_or(true, |last| rowid > last) => rowid,
+            Some(Column::Integer(rowid)) => bail!(
+                "rows are not sorted by {}: {} comes after {}",
+                columns[alias.unwrap()].name,
+                rowid,
+                last_rowid.unwrap()
+            ),
+            Some(_) => bail!("datatype mismatch"),
+        };
+        last_rowid = Some(rowid);
+        for index in &mut indexes {
+            let mut entry = index
+                .columns
+                .iter()
+                .map(|&i| {
+                    if Some(i) == alias {
+                        Column::Integer(rowid)
+                    } else {
+                        row[i].clone()
+                    }
+                })
+                .collect::<Row>();
+            entry.push(Column::Integer(rowid));
+            index.entries.push(entry);
+        }
+        let cell = out.cell(Some(rowid as u64), &record::encode(&row))?;
+        tree.add(&mut out, 0, cell)?;
+    }
+    let mut schema = vec![(
+        "table",
+        table.name.clone(),
+        table.name.clone(),
+        tree.finish(&mut out)?,
+        sql::schema_sql(table_sql)?,
+    )];
+    // Where SQLite keeps the largest rowid an AUTOINCREMENT table has had
+    if table.columns.iter().any(|c| c.autoincrement) {
+        let mut tree = BTree::new(false);
+        if let Some(rowid) = last_rowid {
+            let row = [Column::Text(table.name.clone()), Column::Integer(rowid)];
+            let cell = out.cell(Some(1), &record::encode(&row))?;
+            tree.add(&mut out, 0, cell)?;
+        }
+        let name = "sqlite_sequence".to_string();
+        let sql = "CREATE TABLE sqlite_sequence(name,seq)".to_string();
+        schema.push(("table", name.clone(), name, tree.finish(&mut out)?, sql));
+    }
+
+    for mut index in indexes {
+        let width = index.columns.len();
+        index.entries.sort_by(|a, b| {
+            compare_key(a, b, &index.orders, FORMAT.encoding)
+                .then_with(|| a[width].compare(&b[width], Collation::Binary))
+        });
+        if index.unique {
+            // NULLs are distinct from one another, so only keys without one can clash
+            let duplicate = index.entries.windows(2).find(|pair| {
+                !pair[0][..width].contains(&Column::Null)
+                    && compare_key(&pair[0], &pair[1], &index.orders, FORMAT.encoding)
+                        == Ordering::Equal
+            });
+            if duplicate.is_some() {
+                let names = index
+                    .columns
+                    .iter()
+                    .map(|&i| format!("{}.{}", table.name, columns[i].name))
+                    .collect::<Vec<_>>();
+                bail!("UNIQUE constraint failed: {}", names.join(", "));
+            }
+        }
+        let mut tree = BTree::new(true);
+        for entry in &index.entries {
+            let cell = out.cell(None, &record::encode(entry))?;
+            tree.add(&mut out, 0, cell)?;
+        }
+        let root = tree.finish(&mut out)?;
+        schema.push(("index", index.name, table.name.clone(), root, index.sql));
+    }
+
+    // sqlite_schema(type, name, tbl_name, rootpage, sql), rooted at page 1
+    let mut cells = vec![];
+    for (rowid, (ty, name, tbl_name, rootpage, sql)) in schema.into_iter().enumerate() {
+        let row = [
+            Column::Text(ty.to_string()),
+            Column::Text(name),
+            Column::Text(tbl_name),
+            Column::Integer(rootpage as i64),
+            Column::Text(sql),
+        ];
+        cells.push(out.cell(Some(rowid as u64 + 1), &record::encode(&row))?);
+    }
+    if 108 + cells.iter().map(|c| c.bytes.len() + 2).sum::<usize>() > PAGE_SIZE {
+        bail!("the schema doesn't fit on the first page");
+    }
+    let mut first_page = page(0x0d, &cells, None, 100);
+    let header = DatabaseHeader {
+        schema_cookie: 1,
+        ..DatabaseHeader::new(PAGE_SIZE, out.pages)
+    };
+    first_page[..100].copy_from_slice(&header.to_bytes());
+    out.finish(&first_page)
+}
+
+/// The file being written, from page 2 on, while page 1 waits for the
+/// schema and the header.
+struct Output {
+    file: BufWriter<File>,
+    /// The number of pages written so far, page 1 included
+    pages: u32,
+}
+
+impl Output {
+    fn new(file: File) -> Result<Output> {
+        let mut file = BufWriter::new(file);
+        file.write_all(&[0; PAGE_SIZE])?;
+        Ok(Output { file, pages: 1 })
+    }
+
+    /// Writes `page` as the next page of the file, and returns its number.
+    fn append(&mut self, page: &[u8]) -> Result<u32> {
+        self.file.write_all(page)?;
+        self.pages += 1;
+        Ok(self.pages)
+    }
+
+    /// The cell holding `payload`, of a table if it has a rowid and of an
+    /// index otherwise. What doesn't fit on the page goes to a chain of
+    /// overflow pages, written first.
+    fn cell(&mut self, rowid: Option<u64>, payload: &[u8]) -> Result<Cell> {
+        let mut bytes = vec![];
+        put_variant(payload.len() as u64, &mut bytes);
+        if let Some(rowid) = rowid {
+            put_variant(rowid, &mut bytes);
+        }
+        let local = record::local_size(FORMAT, payload.len() as u64, rowid.is_none());
+        bytes.extend_from_slice(&payload[..local]);
+        if local < payload.len() {
+            // Each overflow page starts with the number of the next one,
+            // which is the one written after it
+            let first = self.pages + 1;
+            let mut chunks = payload[local..].chunks(PAGE_SIZE - 4).peekable();
+            while let Some(chunk) = chunks.next() {
+                let next = match chunks.peek() {
+                    Some(_) => self.pages + 2,
+                    None => 0,
+                };
+                let mut page = next.to_be_bytes().to_vec();
+                page.extend_from_slice(chunk);
+                page.resize(PAGE_SIZE, 0);
+                self.append(&page)?;
+            }
+            bytes.extend_from_slice(&first.to_be_bytes());
+        }
+        let key = match rowid {
+            Some(rowid) => {
+                let mut key = vec![];
+                put_variant(rowid, &mut key);
+                key
+            }
+            None => bytes.clone(),
+        };
+        Ok(Cell { bytes, key })
+    }
+
+    /// Writes page 1, `first_page`, over the space kept for it.
+    fn finish(mut self, first_page: &[u8]) -> Result<()> {
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(first_page)?;
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+/// A cell of a page being filled, and the key the page above finds it by:
+/// the rowid of a table cell, as a varint, or the whole of an index cell.
+struct Cell {
+    bytes: Vec<u8>,
+    key: Vec<u8>,
+}
+
+/// A b-tree being built from its cells in order, leaves first.
+struct BTree {
+    index: bool,
+    /// The cells of the page being filled at each level, from the leaves up
+    levels: Vec<Vec<Cell>>,
+}
+
+impl BTree {
+    fn new(index: bool) -> BTree {
+        BTree {
+            index,
+            levels: vec![vec![]],
+        }
+    }
+
+    fn page_type(&self, level: usize) -> u8 {
+        match (self.index, level) {
+            (false, 0) => 0x0d,
+            (false, _) => 0x05,
+            (true, 0) => 0x0a,
+            (true, _) => 0x02,
+        }
+    }
+
+    /// Adds `cell` to the page being filled at `level`. When it doesn't fit,
+    /// that page is written and `cell` starts the next one, and a cell
+    /// pointing to the page written goes up a level, under the key that
+    /// divides the two.
+    fn add(&mut self, out: &mut Output, level: usize, cell: Cell) -> Result<()> {
+        if level == self.levels.len() {
+            self.levels.push(vec![]);
+        }
+        let header = if level == 0 { 8 } else { 12 };
+        let used = header
+            + self.levels[level]
+                .iter()
+                .map(|c| c.bytes.len() + 2)
+                .sum::<usize>();
+        if used + cell.bytes.len() + 2 <= PAGE_SIZE {
+            self.levels[level].push(cell);
+            return Ok(());
+        }
+
+        let mut cells = mem::replace(&mut self.levels[level], vec![cell]);
+        let (right, divider) = if level == 0 && !self.index {
+            // A rowid is only copied up, and its row stays in the leaf
+            (None, cells.last().unwrap().key.clone())
+        } else {
+            // An index entry is moved up, as each is in the b-tree once. The
+            // child of an interior cell moved up becomes the right-most
+            // child of its page.
+            let last = cells.pop().unwrap();
+            let right =
+                (level > 0).then(|| u32::from_be_bytes(last.bytes[..4].try_into().unwrap()));
+            (right, last.key)
+        };
+        let number = out.append(&page(self.page_type(level), &cells, right, 0))?;
+        let mut bytes = number.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&divider);
+        self.add(
+            out,
+            level + 1,
+            Cell {
+                bytes,
+                key: divider,
+            },
+        )
+    }
+
+    /// Writes the pages still being filled, each the right-most child of the
+    /// one above, and returns the number of the root page.
+    fn finish(self, out: &mut Output) -> Result<u32> {
+        let mut child = None;
+        for (level, cells) in self.levels.iter().enumerate() {
+            child = Some(out.append(&page(self.page_type(level), cells, child, 0))?);
+        }
+        Ok(child.unwrap())
+    }
+}
+
+/// A b-tree page of `page_type` holding `cells`, with its header at `start`
+/// and, on an interior page, `right` as its right-most child. The cells are
+/// packed at the end of the page, in reverse.
+fn page(page_type: u8, cells: &[Cell], right: Option<u32>, start: usize) -> Vec<u8> {
+    let mut page = vec![0; PAGE_SIZE];
+    let pointers = start + if right.is_some() { 12 } else { 8 };
+    let mut content = PAGE_SIZE;
+    for (i, cell) in cells.iter().enumerate() {
+        content -= cell.bytes.len();
+        page[content..content + cell.bytes.len()].copy_from_slice(&cell.bytes);
+        page[pointers + 2 * i..pointers + 2 * i + 2]
+            .copy_from_slice(&(content as u16).to_be_bytes());
+    }
+    page[start] = page_type;
+    page[start + 3..start + 5].copy_from_slice(&(cells.len() as u16).to_be_bytes());
+    page[start + 5..start + 7].copy_from_slice(&(content as u16).to_be_bytes());
+    if let Some(right) = right {
+        page[start + 8..start + 12].copy_from_slice(&right.to_be_bytes());
+    }
+    page
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Database;
+    use crate::testing::TempDatabase;
+    use std::iter;
+
+    fn text(s: &str) -> Column {
+        Column::Text(s.to_string())
+    }
+
+    #[test]
+    fn rows_over_many_pages() {
+        let database = TempDatabase::new("many-pages");
+        // Enough rows, with values long enough, for an interior level of
+        // interior pages
+        let rows = (1..=20_000).map(|i| {
+            Ok(vec![
+                Column::Integer(i),
+                text(&"x".repeat(i as usize % 300)),
+            ])
+        });
+        load(
+            &database.0,
+            "CREATE TABLE t (id INTEGER PRIMARY KEY, name TEXT)",
+            &[],
+            rows,
+        )
+        .unwrap();
+        assert_eq!(
+            database.query("SELECT count(*), sum(id), max(length(name)) FROM t"),
+            [vec![
+                Column::Integer(20_000),
+                Column::Integer(20_000 * 20_001 / 2),
+                Column::Integer(299),
+            ]]
+        );
+        assert_eq!(
+            database.query("SELECT id, length(name) FROM t WHERE id = 12345"),
+            [vec![Column::Integer(12345), Column::Integer(12345 % 300)]]
+        );
+    }
+
+    #[test]
+    fn indexes() {
+        let database = TempDatabase::new("indexes");
+        let rows = ["pear", "apple", "fig", "apple"]
+            .into_iter()
+            .zip(1..)
+            .map(|(name, id)| Ok(vec![Column::Integer(id), text(name)]));
+        load(
+            &database.0,
+            "CREATE TABLE fruits (id INTEGER PRIMARY KEY, name TEXT)",
+            &["CREATE INDEX by_name ON fruits (name)".to_string()],
+            rows,
+        )
+        .unwrap();
+        assert_eq!(
+            database.query("SELECT id FROM fruits WHERE name = 'apple'"),
+            [vec![Column::Integer(2)], vec![Column::Integer(4)]]
+        );
+        let schema = &Database::open(database.filename()).unwrap().schemas[0];
+        let names = schema
+            .tables
+            .iter()
+            .map(|t| (t.ty.as_str(), t.name.as_str()));
+        assert_eq!(
+            names.collect::<Vec<_>>(),
+            [("table", "fruits"), ("index", "by_name")]
+        );
+    }
+
+    #[test]
+    fn unsorted_rowids() {
+        let database = TempDatabase::new("unsorted");
+        let rows = [2, 1].map(|i| Ok(vec![Column::Integer(i)]));
+        assert!(load(
+            &database.0,
+            "CREATE TABLE t (id INTEGER PRIMARY KEY)",
+            &[],
+            rows
+        )
+        .is_err());
+        // Not to leave half a database behind
+        assert!(!database.0.exists());
+    }
+
+    #[test]
+    fn empty_table() {
+        let database = TempDatabase::new("empty");
+        load(&database.0, "CREATE TABLE t (a, b)", &[], iter::empty()).unwrap();
+        assert_eq!(
+            database.query("SELECT count(*) FROM t"),
+            [vec![Column::Integer(0)]]
+        );
+    }
+}
@@ -1,676 +1,384 @@
-use anyhow::{bail, Result};
-use core::num;
-use regex::RegexBuilder;
-use std::fmt::{self, Display};
-use std::fs::File;
-use std::io::{prelude::*, SeekFrom};
-use std::{cell, vec};
-
-#[derive(Debug)]
-#[allow(dead_code)]
-struct Table {
-    ty: String,
-    name: String,
-    tbl_name: String,
-    rootpage: u32,
-    sql: String,
-}
-
-#[derive(Debug, PartialEq, PartialOrd, Clone)]
-enum Column {
-    Integer(i64),
-    Text(String),
-    // NULL,
+//! The command-line interface: `[options] <database path>... <command>`,
+//! where the command is SQL or a dot-command, or `<subcommand> <database
+//! path> ...` for the tools that look into a database file. Given only a
+//! database, commands are read from stdin instead.
+
+mod config;
+mod shell;
+
+use anyhow::{bail, Context, Result};
+use shell::Shell;
+use sqlite_starter_rust::connection::Filename;
+use sqlite_starter_rust::db::{Database, Options, TempStore};
+use sqlite_starter_rust::exec::Cursor;
+use sqlite_starter_rust::output::{self, Mode, Quote, TextFormat};
+use sqlite_starter_rust::subcommands::{self, GraphFormat};
+use sqlite_starter_rust::{inspect, load, serve, vtab};
+use std::io;
+use std::iter;
+use std::process;
+
+/// What `main` needs to report an error the way the arguments ask.
+#[derive(Debug, Default)]
+struct ErrorReport {
+    /// As JSON on stderr, with `--json-errors` or `-json`
+    json: bool,
+    /// The statement or dot-command being run, once the arguments are read
+    sql: Option<String>,
 }
 
-impl Display for Column {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            Column::Integer(i) => write!(f, "{}", i),
-            Column::Text(s) => write!(f, "{}", s),
-            // Column::NULL => write!(f, "NULL"),
+fn main() -> Result<()> {
+    let mut report = ErrorReport::default();
+    match run(&mut report) {
+        Err(error) if output::is_broken_pipe(&error) => Ok(()),
+        Err(error) if report.json => {
+            eprintln!("{}", output::json_error(&error, report.sql.as_deref()));
+            process::exit(1)
         }
+        result => result,
     }
 }
 
-type Row = Vec<Column>;
-
-fn variant(buf: &[u8]) -> (u64, &[u8]) {
-    let mut i = 0;
-    let mut v = 0;
-    loop {
-        let byte = buf[i];
-        v = (v << 7) | (byte & 0x7f) as u64;
-        if byte & 0x80 == 0 {
-            break;
-        }
-        i += 1;
+fn run(report: &mut ErrorReport) -> Result<()> {
+    let defaults = config::Defaults::load()?;
+    let mut options = Options::default();
+    if let Some(size) = defaults.cache_size {
+        options.cache_size = size;
+    }
+    if let Some(temp_store) = defaults.temp_store {
+        options.temp_store = temp_store;
     }
-    (v, &buf[i + 1..])
-}
-
-fn tables(first_page: &[u8]) -> Vec<Table> {
-    assert_eq!(first_page[100], 0x0d);
-    let number_of_cells = u16::from_be_bytes([first_page[103], first_page[104]]);
-
-    let cell_indices = (0..number_of_cells as usize)
-        .map(|i| u16::from_be_bytes([first_page[100 + 8 + 2 * i], first_page[100 + 8 + 2 * i + 1]]))
-        .collect::<Vec<_>>();
-
-    cell_indices
-        .into_iter()
-        .map(|i| {
-            let cell = &first_page[i as usize..];
-            let (_payload_length, cell) = variant(cell);
-            let (_row_id, cell) = variant(cell);
-            // assume header length is 1 byte
-            let header_length = cell[0];
-            let header = &cell[1..header_length as usize];
-            let mut cell = &cell[header_length as usize..];
-
-            // type text
-            let (t, header) = variant(header);
-            assert!(t >= 13 && t % 2 == 1);
-            let length = ((t - 13) / 2) as usize;
-            let ty = std::str::from_utf8(&cell[..length]).unwrap();
-            cell = &cell[length..];
-
-            // name text
-            let (t, header) = variant(header);
-            assert!(t >= 13 && t % 2 == 1);
-            let length = ((t - 13) / 2) as usize;
-            let name = std::str::from_utf8(&cell[..length]).unwrap();
-            cell = &cell[length..];
-
-            // tbl_name text
-            let (t, header) = variant(header);
-            assert!(t >= 13 && t % 2 == 1);
-            let length = ((t - 13) / 2) as usize;
-            let tbl_name = std::str::from_utf8(&cell[..length]).unwrap();
-            cell = &cell[length..];
-
-            // rootpage integer
-            let (t, header) = variant(header);
-            let rootpage = match t {
-                // TODO
-                // 0 => row.push(Column::Integer(row_id as i64)),
-                1 => {
-                    let p = cell[0];
-                    cell = &cell[1..];
-                    p as u32
-                }
-                2 => {
-                    let p = i16::from_be_bytes([cell[0], cell[1]]) as i64;
-                    cell = &cell[2..];
-                    p as u32
-                }
-                3 => {
-                    let p = i32::from_be_bytes([
-                        if cell[0] & 0x80 != 0 { 0xff } else { 0 },
-                        cell[0],
-                        cell[1],
-                        cell[2],
-                    ]) as i64;
-                    cell = &cell[3..];
-                    p as u32
-                }
-                _ => panic!("TODO"),
-            };
-
-            // sql text
-            let (t, _header) = variant(header);
-            assert!(t >= 13 && t % 2 == 1);
-            let length = ((t - 13) / 2) as usize;
-            let sql = std::str::from_utf8(&cell[..length]).unwrap();
 
-            Table {
-                ty: ty.to_string(),
-                name: name.to_string(),
-                tbl_name: tbl_name.to_string(),
-                rootpage,
-                sql: sql.to_string(),
+    // Parse arguments
+    let mut mode = defaults.mode.unwrap_or(Mode::List);
+    let mut headers = defaults.headers.unwrap_or(false);
+    let mut count = 10;
+    let mut batch_size = output::DEFAULT_BATCH_SIZE;
+    let mut memory_limit = None;
+    let mut text = TextFormat {
+        null: defaults.null.unwrap_or_default(),
+        ..TextFormat::default()
+    };
+    let mut timer = defaults.timer.unwrap_or(false);
+    let mut page_rows = None;
+    let mut after = None;
+    let mut immutable = false;
+    let mut stats = false;
+    let mut topk = None;
+    let mut graph_format = GraphFormat::default();
+    let mut stdio = false;
+    let mut http = None;
+    let mut postgres = None;
+    let mut indexes = vec![];
+    let mut args = vec![];
+    let mut argv = std::env::args().skip(1);
+    while let Some(arg) = argv.next() {
+        match arg.strip_prefix('-').and_then(Mode::from_name) {
+            Some(m) => mode = m,
+            None if arg == "--mode" => {
+                let name = argv.next().context("Missing <MODE> after --mode")?;
+                mode = Mode::from_name(&name).with_context(|| {
+                    format!(
+                        "--mode expects csv, insert, json, list or table, not {}",
+                        name
+                    )
+                })?;
             }
-        })
-        .collect()
-}
-
-fn select(row_id: u64, page: &[u8], file: &mut File, page_size: usize) -> Row {
-    match page[0] {
-        0x05 => {
-            // internal page
-            let number_of_cells = u16::from_be_bytes([page[3], page[4]]);
-            let right_most_pointer = u32::from_be_bytes([page[8], page[9], page[10], page[11]]);
-
-            let cell_indices = (0..number_of_cells as usize)
-                .map(|i| u16::from_be_bytes([page[12 + 2 * i], page[12 + 2 * i + 1]]))
-                .collect::<Vec<_>>();
-
-            for i in cell_indices {
-                let cell = &page[i as usize..];
-                let left_page = u32::from_be_bytes([cell[0], cell[1], cell[2], cell[3]]);
-                let cell = &cell[4..];
-                let (key, _) = variant(cell);
-
-                if row_id <= key {
-                    let mut page = vec![0; page_size];
-                    file.seek(SeekFrom::Start((left_page as u64 - 1) * page_size as u64))
-                        .unwrap();
-                    file.read_exact(&mut page).unwrap();
-                    return select(row_id, &page, file, page_size);
-                }
+            None if arg == "-header" => headers = true,
+            None if arg == "-noheader" => headers = false,
+            None if arg == "-nullvalue" => {
+                text.null = argv.next().context("Missing <TEXT> after -nullvalue")?
             }
-            let mut page = vec![0; page_size];
-            file.seek(SeekFrom::Start(
-                (right_most_pointer as u64 - 1) * page_size as u64,
-            ))
-            .unwrap();
-            file.read_exact(&mut page).unwrap();
-            return select(row_id, &page, file, page_size);
-        }
-        0x0d => {
-            // leaf page
-            let number_of_cells = u16::from_be_bytes([page[3], page[4]]);
-
-            let cell_indices = (0..number_of_cells as usize)
-                .map(|i| u16::from_be_bytes([page[8 + 2 * i], page[8 + 2 * i + 1]]))
-                .collect::<Vec<_>>();
-
-            for i in cell_indices {
-                let cell = &page[i as usize..];
-
-                let (_payload_length, cell) = variant(cell);
-                let (k, cell) = variant(cell);
-                if row_id != k {
-                    continue;
-                }
-                // assume header length is 1 byte
-                let header_length = cell[0];
-                let mut header = &cell[1..header_length as usize];
-                let mut cell = &cell[header_length as usize..];
-
-                let mut row = vec![];
-
-                while !header.is_empty() {
-                    let (t, header_) = variant(header);
-                    header = header_;
-
-                    match t {
-                        // TODO
-                        0 => row.push(Column::Integer(row_id as i64)),
-                        1 => {
-                            row.push(Column::Integer(cell[0] as i64));
-                            cell = &cell[1..];
-                        }
-                        2 => {
-                            row.push(Column::Integer(
-                                i16::from_be_bytes([cell[0], cell[1]]) as i64
-                            ));
-                            cell = &cell[2..];
-                        }
-                        9 => {
-                            row.push(Column::Integer(1));
-                        }
-                        t if t >= 13 && t % 2 == 1 => {
-                            let length = ((t - 13) / 2) as usize;
-                            let text = std::str::from_utf8(&cell[..length]).unwrap();
-                            row.push(Column::Text(text.to_string()));
-                            cell = &cell[length..];
-                        }
-                        _ => unimplemented!("type {}", t),
-                    }
-                }
-
-                return row;
+            // Like `.timer on` in sqlite3: how long the statement took, after its results
+            None if arg == "--timer" => timer = true,
+            None if arg == "--cache-size" => {
+                options.cache_size = argv
+                    .next()
+                    .context("Missing <N> after --cache-size")?
+                    .parse()
+                    .context("--cache-size expects a number of pages, or of KiB if negative")?
             }
-            unreachable!();
-        }
-        _ => unreachable!(),
-    }
-}
-
-fn rows(page: &[u8], file: &mut File, page_size: usize) -> Vec<Row> {
-    match page[0] {
-        0x05 => {
-            // internal page
-            let number_of_cells = u16::from_be_bytes([page[3], page[4]]);
-
-            let cell_indices = (0..number_of_cells as usize)
-                .map(|i| u16::from_be_bytes([page[12 + 2 * i], page[12 + 2 * i + 1]]))
-                .collect::<Vec<_>>();
-
-            cell_indices
-                .into_iter()
-                .flat_map(|i| {
-                    let cell = &page[i as usize..];
-                    let next_page = u32::from_be_bytes([cell[0], cell[1], cell[2], cell[3]]);
-                    let mut page = vec![0; page_size];
-                    file.seek(SeekFrom::Start((next_page as u64 - 1) * page_size as u64))
-                        .unwrap();
-                    file.read_exact(&mut page).unwrap();
-
-                    rows(&page, file, page_size).into_iter()
-                })
-                .collect()
-        }
-        0x0d => {
-            // leaf page
-            let number_of_cells = u16::from_be_bytes([page[3], page[4]]);
-
-            let cell_indices = (0..number_of_cells as usize)
-                .map(|i| u16::from_be_bytes([page[8 + 2 * i], page[8 + 2 * i + 1]]))
-                .collect::<Vec<_>>();
-
-            cell_indices
-                .into_iter()
-                .map(|i| {
-                    let cell = &page[i as usize..];
-
-                    let (_payload_length, cell) = variant(cell);
-                    let (row_id, cell) = variant(cell);
-                    // assume header length is 1 byte
-                    let header_length = cell[0];
-                    let mut header = &cell[1..header_length as usize];
-                    let mut cell = &cell[header_length as usize..];
-
-                    let mut row = vec![];
-
-                    while !header.is_empty() {
-                        let (t, header_) = variant(header);
-                        header = header_;
-
-                        match t {
-                            // TODO
-                            0 => row.push(Column::Integer(row_id as i64)),
-                            1 => {
-                                row.push(Column::Integer(cell[0] as i64));
-                                cell = &cell[1..];
-                            }
-                            2 => {
-                                row.push(Column::Integer(
-                                    i16::from_be_bytes([cell[0], cell[1]]) as i64
-                                ));
-                                cell = &cell[2..];
-                            }
-                            9 => {
-                                row.push(Column::Integer(1));
-                            }
-                            t if t >= 13 && t % 2 == 1 => {
-                                let length = ((t - 13) / 2) as usize;
-                                let text = std::str::from_utf8(&cell[..length]).unwrap();
-                                row.push(Column::Text(text.to_string()));
-                                cell = &cell[length..];
-                            }
-                            _ => unimplemented!("type {}", t),
-                        }
-                    }
-
-                    row
-                })
-                .collect()
-        }
-        _ => unimplemented!(),
-    }
-}
-
-fn row(cell: &[u8]) -> Row {
-    // assume header length is 1 byte
-    let header_length = cell[0];
-    let mut header = &cell[1..header_length as usize];
-    let mut cell = &cell[header_length as usize..];
-
-    let mut row = vec![];
-
-    while !header.is_empty() {
-        let (t, header_) = variant(header);
-        header = header_;
-
-        match t {
-            0 => row.push(Column::Integer(0)),
-            1 => {
-                row.push(Column::Integer(cell[0] as i64));
-                cell = &cell[1..];
+            None if arg == "-n" => {
+                count = argv
+                    .next()
+                    .context("Missing <N> after -n")?
+                    .parse()
+                    .context("-n expects a number of rows")?
             }
-            2 => {
-                row.push(Column::Integer(
-                    i16::from_be_bytes([cell[0], cell[1]]) as i64
-                ));
-                cell = &cell[2..];
+            None if arg == "--batch-size" => {
+                batch_size = argv
+                    .next()
+                    .context("Missing <BYTES> after --batch-size")?
+                    .parse()
+                    .context("--batch-size expects a number of bytes")?
             }
-            3 => {
-                row.push(Column::Integer(i32::from_be_bytes([
-                    if cell[0] & 0x80 != 0 { 0xff } else { 0 },
-                    cell[0],
-                    cell[1],
-                    cell[2],
-                ]) as i64));
-                cell = &cell[3..];
+            None if arg == "--memory-limit" => {
+                memory_limit = Some(
+                    argv.next()
+                        .context("Missing <BYTES> after --memory-limit")?
+                        .parse()
+                        .context("--memory-limit expects a number of bytes")?,
+                )
             }
-            9 => {
-                row.push(Column::Integer(1));
+            None if arg == "--quote" => {
+                let name = argv.next().context("Missing <WHEN> after --quote")?;
+                text.quote = Some(Quote::from_name(&name).with_context(|| {
+                    format!("--quote expects always, needed or never, not {}", name)
+                })?);
             }
-            t if t >= 13 && t % 2 == 1 => {
-                let length = ((t - 13) / 2) as usize;
-                let text = std::str::from_utf8(&cell[..length]).unwrap();
-                row.push(Column::Text(text.to_string()));
-                cell = &cell[length..];
+            None if arg == "--escape" => text.escape = true,
+            None if arg == "--max-column-width" => {
+                text.max_width = Some(
+                    argv.next()
+                        .context("Missing <N> after --max-column-width")?
+                        .parse()
+                        .context("--max-column-width expects a number of characters")?,
+                )
             }
-            _ => unimplemented!("type {}", t),
-        }
-    }
-
-    row
-}
-
-fn index(file: &mut File, page: &[u8], page_size: usize, key: &str) -> Vec<Row> {
-    match page[0] {
-        0x02 => {
-            // internal page
-            let _right_most_pointer = u32::from_be_bytes([page[8], page[9], page[10], page[11]]);
-            let number_of_cells = u16::from_be_bytes([page[3], page[4]]);
-
-            let cell_indices = (0..number_of_cells as usize)
-                .map(|i| u16::from_be_bytes([page[12 + 2 * i], page[12 + 2 * i + 1]]))
-                .collect::<Vec<_>>();
-
-            let mut left_key = None;
-            let mut result = vec![];
-
-            for i in cell_indices {
-                let cell = &page[i as usize..];
-                let next_page = u32::from_be_bytes([cell[0], cell[1], cell[2], cell[3]]);
-                let cell = &cell[4..];
-                let (_payload_length, cell) = variant(cell);
-
-                // assume header length is 1 byte
-                /*
-                let header_length = cell[0];
-                let header = &cell[1..header_length as usize];
-                let cell = &cell[header_length as usize..];
-                let (t, _) = variant(header);
-                assert!(t >= 13 && t % 2 == 1);
-                let length = ((t - 13) / 2) as usize;
-                let text = std::str::from_utf8(&cell[..length]).unwrap();
-                */
-                let row = row(cell);
-                let text = row[0].to_string();
-
-                if row[0].to_string() == key {
-                    result.push(row);
-                }
-
-                match left_key {
-                    None => {
-                        if key <= text.as_str() {
-                            let mut page = vec![0; page_size];
-                            file.seek(SeekFrom::Start((next_page as u64 - 1) * page_size as u64))
-                                .unwrap();
-                            file.read_exact(&mut page).unwrap();
-
-                            result.extend(index(file, &page, page_size, key));
-                        }
-                        left_key = Some(text);
-                    }
-                    Some(lk) => {
-                        if lk.as_str() <= key && key <= text.as_str() {
-                            let mut page = vec![0; page_size];
-                            file.seek(SeekFrom::Start((next_page as u64 - 1) * page_size as u64))
-                                .unwrap();
-                            file.read_exact(&mut page).unwrap();
-
-                            result.extend(index(file, &page, page_size, key));
-                        } else if text.as_str() > key {
-                            break;
-                        }
-
-                        left_key = Some(text);
-                    }
-                }
+            // Results a page at a time, each continuing from the cursor the
+            // previous one ended with
+            None if arg == "--page" => {
+                page_rows = Some(
+                    argv.next()
+                        .context("Missing <ROWS> after --page")?
+                        .parse()
+                        .context("--page expects a number of rows")?,
+                )
             }
-            /*
-            let mut page = vec![0; page_size];
-            file.seek(SeekFrom::Start(
-                (right_most_pointer as u64 - 1) * page_size as u64,
-            ))
-            .unwrap();
-            file.read_exact(&mut page).unwrap();
-            result.extend(index(file, &page, page_size, key));
-            */
-
-            result
-        }
-        0x0a => {
-            let number_of_cells = u16::from_be_bytes([page[3], page[4]]);
-
-            let cell_indices = (0..number_of_cells as usize)
-                .map(|i| u16::from_be_bytes([page[8 + 2 * i], page[8 + 2 * i + 1]]))
-                .collect::<Vec<_>>();
-
-            let mut result = vec![];
-
-            for i in cell_indices {
-                let cell = &page[i as usize..];
-                let (_payload_length, cell) = variant(cell);
-
-                let row = row(cell);
-                if row[0].to_string() == key {
-                    result.push(row);
-                }
+            None if arg == "--after" => {
+                after = Some(
+                    argv.next()
+                        .context("Missing <CURSOR> after --after")?
+                        .parse::<Cursor>()?,
+                )
             }
-
-            result
+            None if arg == "--topk" => {
+                topk = Some(
+                    argv.next()
+                        .context("Missing <N> after --topk")?
+                        .parse()
+                        .context("--topk expects a number of values")?,
+                )
+            }
+            None if arg == "--format" => {
+                let name = argv.next().context("Missing <FORMAT> after --format")?;
+                graph_format = GraphFormat::from_name(&name)
+                    .with_context(|| format!("--format expects dot or mermaid, not {}", name))?;
+            }
+            // With `load`, the columns of an index to build, comma-separated
+            None if arg == "--index" => {
+                indexes.push(argv.next().context("Missing <COLUMNS> after --index")?)
+            }
+            None if arg == "--verify-pages" => options.verify_pages = true,
+            // A promise that the file can't change while it is read, as for
+            // databases on read-only media, which makes checking for changes
+            // pointless
+            None if arg == "--immutable" => immutable = true,
+            // Like `.stats on` in sqlite3: figures about the statement after its results
+            None if arg == "--stats" => stats = true,
+            // A walkthrough of the file format on stderr as the command reads it
+            None if arg == "--teach" => options.teach = true,
+            // Pages read from a mapping of the file rather than copied out of it
+            None if arg == "--mmap" => options.mmap = true,
+            None if arg == "--stdio" => stdio = true,
+            None if arg == "--json-errors" => report.json = true,
+            None if arg == "--vtab" => options.vtabs.push(vtab::parse_argument(
+                &argv.next().context("Missing <NAME>=<PATH> after --vtab")?,
+            )?),
+            // Result sets `serve` keeps to answer the same queries again
+            None if arg == "--result-cache-size" => {
+                options.result_cache_size = argv
+                    .next()
+                    .context("Missing <N> after --result-cache-size")?
+                    .parse()
+                    .context("--result-cache-size expects a number of result sets")?
+            }
+            // Where sorts, groups and the counts of `--topk` spill to once they
+            // outgrow memory
+            None if arg == "--temp-dir" => {
+                options.temp_dir = Some(
+                    argv.next()
+                        .context("Missing <DIR> after --temp-dir")?
+                        .into(),
+                )
+            }
+            None if arg == "--temp-store" => {
+                let name = argv.next().context("Missing <STORE> after --temp-store")?;
+                options.temp_store = TempStore::from_name(&name).with_context(|| {
+                    format!("--temp-store expects default, file or memory, not {}", name)
+                })?;
+            }
+            None if arg == "--http" => {
+                http = Some(argv.next().context("Missing <ADDRESS> after --http")?)
+            }
+            None if arg == "--postgres" => {
+                postgres = Some(argv.next().context("Missing <ADDRESS> after --postgres")?)
+            }
+            None => args.push(arg),
         }
-        _ => unreachable!(),
     }
-}
-
-fn sql_column_names(sql: &str) -> Vec<String> {
-    let inner_bracket: String = sql
-        .chars()
-        .skip_while(|c| *c != '(')
-        .skip(1)
-        .take_while(|c| *c != ')')
-        .collect();
-    inner_bracket
-        .split(',')
-        .map(|s| s.trim().split_whitespace().next().unwrap().to_string())
-        .collect()
-}
-
-fn main() -> Result<()> {
-    // Parse arguments
-    let args = std::env::args().collect::<Vec<_>>();
-    match args.len() {
-        0 | 1 => bail!("Missing <database path> and <command>"),
-        2 => bail!("Missing <command>"),
-        _ => {}
+    report.json |= mode == Mode::Json;
+
+    // `file:` URIs can declare the databases immutable as well, and then
+    // none of them needs checking
+    let databases = match args.first().map(String::as_str) {
+        Some(
+            "counts" | "head" | "sample" | "analyze-table" | "schema-graph" | "verify-constraints"
+            | "find-duplicates" | "page" | "cell" | "btree" | "serve" | "load",
+        ) => args.get(1..2).unwrap_or_default(),
+        _ => args
+            .split_last()
+            .map_or(&[][..], |(_, databases)| databases),
+    };
+    let mut all_immutable = !databases.is_empty();
+    for database in databases {
+        all_immutable &= Filename::parse(database)?.immutable;
     }
+    immutable |= all_immutable;
+    options.verify_pages &= !immutable;
 
-    let mut file = File::open(&args[1])?;
-    let mut header = [0; 100];
-    file.read_exact(&mut header)?;
-
-    // The page size is stored at the 16th byte offset, using 2 bytes in big-endian order
-    let page_size = u16::from_be_bytes([header[16], header[17]]);
-
-    let mut first_page = vec![0; page_size as usize - 100];
-    file.read_exact(&mut first_page)?;
-    let first_page = first_page;
-
-    let number_of_cells = u16::from_be_bytes([first_page[3], first_page[4]]);
-
-    // Parse command and act accordingly
-    let command = &args[2];
-    if command == ".dbinfo" {
-        println!("database page size: {}", page_size);
-        println!("number of tables: {}", number_of_cells);
-    } else if command == ".tables" {
-        let mut first_page = vec![0; page_size as usize];
-        file.seek(SeekFrom::Start(0))?;
-        file.read_exact(&mut first_page)?;
-        let first_page = first_page;
-
-        let tables = tables(&first_page);
-        println!(
-            "{}",
-            tables
-                .into_iter()
-                .filter(|t| t.name != "sqlite_sequence")
-                .map(|t| t.name)
-                .collect::<Vec<_>>()
-                .join(" ")
-        );
-    } else if command.to_uppercase().starts_with("SELECT COUNT(*) FROM") {
-        let table_name = command.split_whitespace().nth(3).unwrap();
-
-        let mut first_page = vec![0; page_size as usize];
-        file.seek(SeekFrom::Start(0))?;
-        file.read_exact(&mut first_page)?;
-        let first_page = first_page;
-
-        let tables = tables(&first_page);
-
-        let root_page = tables
-            .into_iter()
-            .find(|t| t.name == table_name)
-            .unwrap()
-            .rootpage;
-
-        let mut page = vec![0; page_size as usize];
-        file.seek(SeekFrom::Start((root_page - 1) as u64 * page_size as u64))?;
-        file.read_exact(&mut page)?;
-        let page = page;
-        let number_of_cells = u16::from_be_bytes([page[3], page[4]]);
-
-        println!("{}", number_of_cells);
-    } else if let Some(captures) = RegexBuilder::new(r"SELECT (.+) FROM (\w+)( WHERE (.+))?")
-        .case_insensitive(true)
-        .build()
-        .unwrap()
-        .captures(command)
+    if let Some("counts") = args.first().map(String::as_str) {
+        let [_, path] = args.as_slice() else {
+            bail!("Usage: counts <database path>");
+        };
+        let mut out = output::writer(mode, output::stdout(batch_size), headers, text);
+        return subcommands::counts(path, &options, out.as_mut());
+    }
+    if let Some("schema-graph") = args.first().map(String::as_str) {
+        let [_, path] = args.as_slice() else {
+            bail!("Usage: schema-graph <database path> [--format dot|mermaid]");
+        };
+        return subcommands::schema_graph(path, &options, graph_format, &mut io::stdout().lock());
+    }
+    if let Some(subcommand @ ("verify-constraints" | "find-duplicates")) =
+        args.first().map(String::as_str)
     {
-        let table_name = captures.get(2).unwrap().as_str();
-        let column_names = captures.get(1).unwrap().as_str();
-        let column_names = column_names
-            .split(',')
-            .map(|s| s.trim())
-            .collect::<Vec<_>>();
-        let where_clause = captures.get(4).map(|m| m.as_str());
-
-        let mut first_page = vec![0; page_size as usize];
-        file.seek(SeekFrom::Start(0))?;
-        file.read_exact(&mut first_page)?;
-        let first_page = first_page;
-
-        let tables = tables(&first_page);
-
-        let table = tables.iter().find(|t| t.name == table_name).unwrap();
-
-        let sql_coumn_names = sql_column_names(table.sql.as_str());
-
-        let indices: Vec<usize> = column_names
-            .into_iter()
-            .map(|c| sql_coumn_names.iter().position(|s| s == &c).unwrap())
-            .collect();
-
-        let mut page = vec![0; page_size as usize];
-        file.seek(SeekFrom::Start(
-            (table.rootpage - 1) as u64 * page_size as u64,
-        ))?;
-        file.read_exact(&mut page)?;
-
-        let equals = if let Some(where_clause) = where_clause {
-            let mut equals = Vec::new();
-            let mut iter = where_clause.split('=');
-            let column_name = iter.next().unwrap().trim();
-            let value = iter
-                .next()
-                .unwrap()
-                .trim()
-                .trim_start_matches('\'')
-                .trim_end_matches('\'');
-            let column_index = sql_coumn_names
-                .iter()
-                .position(|s| s == &column_name)
-                .unwrap();
-            equals.push((column_index, value));
-            equals
-        } else {
-            Vec::new()
+        let (path, table) = match args.as_slice() {
+            [_, path] => (path, None),
+            [_, path, table] => (path, Some(table.as_str())),
+            _ => bail!("Usage: {} <database path> [<table>]", subcommand),
         };
-
-        let applicable_index = if let Some((column_index, value)) = equals.first() {
-            tables
-                .iter()
-                .filter(|t| t.ty == "index" && t.tbl_name == table_name)
-                .find(|t| {
-                    let column = RegexBuilder::new(r"CREATE INDEX \w+\s+ON (\w+) \((.+)\)")
-                        .case_insensitive(true)
-                        .build()
-                        .unwrap()
-                        .captures(t.sql.as_str())
-                        .unwrap()
-                        .get(2)
-                        .unwrap()
-                        .as_str();
-
-                    sql_coumn_names[*column_index] == column
-                })
-                .map(|t| t.rootpage)
-        } else {
-            None
+        let mut out = output::writer(mode, output::stdout(batch_size), headers, text);
+        return match subcommand {
+            "verify-constraints" => {
+                subcommands::verify_constraints(path, &options, table, out.as_mut())
+            }
+            _ => subcommands::find_duplicates(path, &options, table, out.as_mut()),
         };
-
-        let rows: Vec<Row> = if let Some(index_page) = applicable_index {
-            let mut page = vec![0; page_size as usize];
-            file.seek(SeekFrom::Start((index_page as u64 - 1) * page_size as u64))
-                .unwrap();
-            file.read_exact(&mut page).unwrap();
-
-            let indices = index(
-                &mut file,
-                &page,
-                page_size as usize,
-                equals.first().unwrap().1,
-            );
-
-            let mut page = vec![0; page_size as usize];
-            file.seek(SeekFrom::Start(
-                (table.rootpage - 1) as u64 * page_size as u64,
-            ))
-            .unwrap();
-            file.read_exact(&mut page).unwrap();
-
-            indices
-                .into_iter()
-                .map(|i| {
-                    let Column::Integer(row_id) = &i[1] else {unreachable!()};
-                    select(*row_id as u64, &page, &mut file, page_size as usize)
-                })
-                .collect()
-        } else {
-            rows(&page, &mut file, page_size as usize)
-                .into_iter()
-                .filter(|row| {
-                    equals.iter().all(|(column_index, value)| {
-                        row[*column_index] == Column::Text(value.to_string())
-                    })
-                })
-                .collect()
+    }
+    if let Some("page") = args.first().map(String::as_str) {
+        let [_, path, page_no] = args.as_slice() else {
+            bail!("Usage: page <database path> <page number>");
+        };
+        let page_no = page_no.parse().context("<page number> expects a number")?;
+        return inspect::page(path, &options, page_no, &mut io::stdout().lock());
+    }
+    if let Some("cell") = args.first().map(String::as_str) {
+        let [_, path, page_no, index] = args.as_slice() else {
+            bail!("Usage: cell <database path> <page number> <cell index>");
+        };
+        let page_no = page_no.parse().context("<page number> expects a number")?;
+        let index = index.parse().context("<cell index> expects a number")?;
+        return inspect::cell(path, &options, page_no, index, &mut io::stdout().lock());
+    }
+    if let Some("btree") = args.first().map(String::as_str) {
+        let [_, path, name] = args.as_slice() else {
+            bail!("Usage: btree <database path> <table or index> [--format dot]");
         };
+        return inspect::btree(path, &options, name, graph_format, &mut io::stdout().lock());
+    }
+    if let Some("serve") = args.first().map(String::as_str) {
+        return match (args.as_slice(), stdio, http, postgres) {
+            ([_, path], true, None, None) => {
+                serve::stdio(path, &options, io::stdin().lock(), &mut io::stdout().lock())
+            }
+            ([_, path], false, Some(address), None) => serve::http(path, &options, &address),
+            ([_, path], false, None, Some(address)) => serve::postgres(path, &options, &address),
+            _ => {
+                bail!("Usage: serve --stdio|--http <address>|--postgres <address> <database path>")
+            }
+        };
+    }
+    if let Some("load") = args.first().map(String::as_str) {
+        let [_, path, table, csv] = args.as_slice() else {
+            bail!("Usage: load <new database path> <table> <CSV path> [--index <columns>]...");
+        };
+        return subcommands::load_csv(path, table, csv, &indexes);
+    }
+    if let Some("analyze-table") = args.first().map(String::as_str) {
+        let mut out = output::writer(mode, output::stdout(batch_size), true, text);
+        return match (args.as_slice(), topk) {
+            ([_, path, table], None) => {
+                subcommands::analyze_table(path, &options, table, out.as_mut())
+            }
+            ([_, path, table, column], Some(k)) => subcommands::top_values(
+                path,
+                &options,
+                table,
+                column,
+                k,
+                memory_limit,
+                out.as_mut(),
+            ),
+            _ => bail!("Usage: analyze-table <database path> <table> [<column> --topk N]"),
+        };
+    }
+    if let Some(subcommand @ ("head" | "sample")) = args.first().map(String::as_str) {
+        let [_, path, table] = args.as_slice() else {
+            bail!("Usage: {} <database path> <table> [-n N]", subcommand);
+        };
+        // A preview is only useful with column names
+        let mut out = output::writer(mode, output::stdout(batch_size), true, text);
+        return match subcommand {
+            "head" => subcommands::head(path, &options, table, count, out.as_mut()),
+            _ => subcommands::sample(path, &options, table, count, out.as_mut()),
+        };
+    }
 
-        for row in rows {
-            println!(
-                "{}",
-                indices
-                    .iter()
-                    .map(|&i| row[i].to_string())
-                    .collect::<Vec<_>>()
-                    .join("|")
-            );
+    let mut shell = Shell {
+        mode,
+        headers,
+        text,
+        batch_size,
+        memory_limit,
+        page_rows,
+        after,
+        stats,
+        timer,
+        json_errors: report.json,
+    };
+    match args.len() {
+        0 => bail!("Missing <database path> and <command>"),
+        // Only a database: commands are read from stdin, at a prompt when
+        // it is a terminal
+        1 => {
+            return shell.repl(
+                &args,
+                options,
+                io::stdin().lock(),
+                shell::stdin_is_terminal(),
+            )
         }
-    } else {
-        bail!("Missing or invalid command passed: {}", command)
+        _ => {}
     }
 
-    Ok(())
+    // Every argument before the command is a database: the first is `main`,
+    // and the others are attached as `aux1`, `aux2`... so that one statement
+    // can join tables across files
+    let (command, databases) = args.split_last().unwrap();
+    report.sql = Some(command.clone());
+    // A `CREATE TABLE` makes the database if there isn't one yet, with the
+    // new table as all there is in it
+    let words = command.split_whitespace().take(2).collect::<Vec<_>>();
+    if let [create, table] = words[..] {
+        let path = Filename::parse(&databases[0])?.path;
+        if create.eq_ignore_ascii_case("CREATE")
+            && table.eq_ignore_ascii_case("TABLE")
+            && !path.exists()
+        {
+            return load::load(path, command, &[], iter::empty());
+        }
+    }
+    let mut database = Database::open_all(databases, options)?;
+    shell.run(&mut database, command)
 }
@@ -0,0 +1,109 @@
+//! `--mmap`: the database file mapped into memory, so that pages are read
+//! straight from the OS page cache rather than copied out of it with a
+//! `read` each.
+
+use anyhow::{bail, Result};
+use std::fs::File;
+
+/// A read-only mapping of a whole file, as long as it was when mapped.
+///
+/// The mapping is shared with the file, so it sees changes made to it by
+/// others, and a file truncated while mapped faults with SIGBUS on the pages
+/// past its new end. See [`Mmap::new`] for what that asks of its users.
+#[derive(Debug)]
+pub struct Mmap {
+    ptr: *const u8,
+    len: usize,
+}
+
+impl Mmap {
+    /// Maps the whole of `file`.
+    ///
+    /// # Safety
+    ///
+    /// The slices handed out by [`Mmap::as_slice`] change under their
+    /// borrowers if the file is written, and fault if it is truncated. The
+    /// caller must make sure that it isn't while they are in use: the pager
+    /// maps the file again at the start of each read transaction if its size
+    /// changed, and a transaction that a writer overlapped fails when it ends.
+    #[cfg(unix)]
+    pub unsafe fn new(file: &File) -> Result<Mmap> {
+        use std::ffi::c_void;
+        use std::os::unix::io::AsRawFd;
+
+        extern "C" {
+            fn mmap(
+                addr: *mut c_void,
+                len: usize,
+                prot: i32,
+                flags: i32,
+                fd: i32,
+                offset: i64,
+            ) -> *mut c_void;
+        }
+        const PROT_READ: i32 = 1;
+        const MAP_SHARED: i32 = 1;
+
+        let len = file.metadata()?.len() as usize;
+        if len == 0 {
+            bail!("cannot map an empty file");
+        }
+        // SAFETY: a new mapping of a file we hold open, which aliases no
+        // memory of ours
+        let ptr = unsafe {
+            mmap(
+                std::ptr::null_mut(),
+                len,
+                PROT_READ,
+                MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr as isize == -1 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(Mmap {
+            ptr: ptr as *const u8,
+            len,
+        })
+    }
+
+    /// Maps the whole of `file`, which needs Unix.
+    ///
+    /// # Safety
+    ///
+    /// As on Unix.
+    #[cfg(not(unix))]
+    pub unsafe fn new(_file: &File) -> Result<Mmap> {
+        bail!("memory-mapped files are only supported on Unix")
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        // SAFETY: the mapping is `len` bytes long and lives as long as `self`
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        {
+            extern "C" {
+                fn munmap(addr: *mut std::ffi::c_void, len: usize) -> i32;
+            }
+            // SAFETY: the mapping made in `new`, which nothing borrows any more
+            unsafe {
+                munmap(self.ptr as *mut _, self.len);
+            }
+        }
+    }
+}
@@ -0,0 +1,802 @@
+use crate::Column;
+use crate::Error;
+use std::io::{self, BufWriter, Write};
+
+/// Metadata describing one column of a result set.
+#[derive(Debug, Clone)]
+pub struct ColumnInfo {
+    pub name: String,
+    /// The declared type of the table column this result column comes from.
+    pub decl_type: Option<String>,
+    /// The table this result column comes from, if it is a plain column reference.
+    pub table: Option<String>,
+}
+
+/// A sink for query results.
+///
+/// Writers are told about the result columns once, then receive rows one at a
+/// time as they are produced. Where the bytes end up (stdout, a file, an
+/// in-memory buffer) is decided by the `Write` the writer was built over.
+pub trait OutputWriter {
+    fn begin(&mut self, columns: &[ColumnInfo]) -> io::Result<()>;
+    fn write_row(&mut self, row: &[Column]) -> io::Result<()>;
+    fn finish(&mut self) -> io::Result<()>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    List,
+    Csv,
+    Json,
+    Table,
+    Insert,
+}
+
+impl Mode {
+    pub fn from_name(name: &str) -> Option<Mode> {
+        match name {
+            "list" => Some(Mode::List),
+            "csv" => Some(Mode::Csv),
+            "json" => Some(Mode::Json),
+            "table" => Some(Mode::Table),
+            "insert" => Some(Mode::Insert),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Mode::List => "list",
+            Mode::Csv => "csv",
+            Mode::Json => "json",
+            Mode::Table => "table",
+            Mode::Insert => "insert",
+        }
+    }
+}
+
+/// When text values are quoted in list and csv output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quote {
+    Always,
+    /// Only values that would be ambiguous otherwise: empty, or containing
+    /// the separator, a quote, whitespace or other special characters
+    Needed,
+    Never,
+}
+
+impl Quote {
+    pub fn from_name(name: &str) -> Option<Quote> {
+        match name {
+            "always" => Some(Quote::Always),
+            "needed" => Some(Quote::Needed),
+            "never" => Some(Quote::Never),
+            _ => None,
+        }
+    }
+}
+
+/// How text values are written in list and csv output.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TextFormat {
+    /// None for the mode's own default: never for list, when needed for csv
+    pub quote: Option<Quote>,
+    /// Backslash-escape backslashes, the separator and control characters
+    /// such as newlines, so that every row stays on one line
+    pub escape: bool,
+    /// In table mode, text longer than this many characters is cut short
+    /// and ends in `...`, so one huge value doesn't stretch the whole table
+    pub max_width: Option<usize>,
+    /// What NULL is written as in list, csv and table output, like the
+    /// sqlite3 shell's `.nullvalue`: nothing by default
+    pub null: String,
+}
+
+impl TextFormat {
+    /// `field` as written between separators. It is bytes rather than text
+    /// so that blobs are written as they are, as sqlite3 does, even when
+    /// they aren't valid UTF-8.
+    fn field(&self, field: &[u8], separator: &str, default: Quote) -> Vec<u8> {
+        let field = if self.escape {
+            escape(field, separator.as_bytes())
+        } else {
+            field.to_vec()
+        };
+        let quote = match self.quote.unwrap_or(default) {
+            Quote::Always => true,
+            // Same rule as the sqlite3 shell: anything that is not plain
+            // printable ASCII (or contains a delimiter or quote) gets quoted.
+            // An escaped separator is no longer ambiguous.
+            Quote::Needed => {
+                field.is_empty()
+                    || (!self.escape && contains(&field, separator.as_bytes()))
+                    || field
+                        .iter()
+                        .any(|&b| b <= b' ' || b >= 0x7f || matches!(b, b'"' | b'\''))
+            }
+            Quote::Never => false,
+        };
+        if quote {
+            let mut quoted = vec![b'"'];
+            for &b in &field {
+                quoted.push(b);
+                if b == b'"' {
+                    quoted.push(b'"');
+                }
+            }
+            quoted.push(b'"');
+            quoted
+        } else {
+            field
+        }
+    }
+
+    /// `value` cut down to `max_width` characters, if it is longer.
+    fn truncate(&self, value: String) -> String {
+        const ELLIPSIS: &str = "...";
+        match self.max_width {
+            Some(width) if value.chars().count() > width => {
+                let kept = width.saturating_sub(ELLIPSIS.len());
+                let mut truncated = value.chars().take(kept).collect::<String>();
+                truncated.push_str(&ELLIPSIS[..width.min(ELLIPSIS.len())]);
+                truncated
+            }
+            _ => value,
+        }
+    }
+}
+
+/// Backslash-escapes `field` for `--escape`, so that a value can never be
+/// mistaken for the end of its field or row. Only ASCII is escaped, which
+/// never occurs inside the encoding of another character in UTF-8.
+fn escape(field: &[u8], separator: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(field.len());
+    let mut rest = field;
+    while let Some(&b) = rest.first() {
+        if !separator.is_empty() && rest.starts_with(separator) {
+            escaped.push(b'\\');
+            escaped.extend_from_slice(separator);
+            rest = &rest[separator.len()..];
+            continue;
+        }
+        match b {
+            b'\\' => escaped.extend_from_slice(b"\\\\"),
+            b'\n' => escaped.extend_from_slice(b"\\n"),
+            b'\r' => escaped.extend_from_slice(b"\\r"),
+            b'\t' => escaped.extend_from_slice(b"\\t"),
+            b if b < b' ' => escaped.extend_from_slice(format!("\\x{:02x}", b).as_bytes()),
+            b => escaped.push(b),
+        }
+        rest = &rest[1..];
+    }
+    escaped
+}
+
+/// Whether `needle` occurs anywhere in `haystack`, as `str::contains`.
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    needle.is_empty() || haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+/// Default capacity of the buffer in front of stdout, in bytes.
+pub const DEFAULT_BATCH_SIZE: usize = 64 * 1024;
+
+/// Stdout behind a buffer of `batch_size` bytes. Stdout on its own is line
+/// buffered and takes its lock on every write, which dominates the time spent
+/// printing large results; this holds the lock and writes in large batches
+/// until the writer flushes it in `finish`.
+pub fn stdout(batch_size: usize) -> Box<dyn Write> {
+    Box::new(BufWriter::with_capacity(batch_size, io::stdout().lock()))
+}
+
+/// Whether `error` is from writing to a pipe whose reader has gone, as
+/// `head` goes once it has read enough. Nothing more can be written then,
+/// and there is nothing wrong to report.
+pub fn is_broken_pipe(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        cause
+            .downcast_ref::<io::Error>()
+            .map_or(false, |e| e.kind() == io::ErrorKind::BrokenPipe)
+    })
+}
+
+pub fn writer<'a>(
+    mode: Mode,
+    out: Box<dyn Write + 'a>,
+    headers: bool,
+    text: TextFormat,
+) -> Box<dyn OutputWriter + 'a> {
+    match mode {
+        Mode::List => Box::new(ListWriter::new(out, "|", headers).text_format(text)),
+        Mode::Csv => Box::new(CsvWriter::new(out, headers).text_format(text)),
+        Mode::Json => Box::new(JsonWriter::new(out)),
+        Mode::Table => Box::new(TableWriter::new(out).text_format(text)),
+        Mode::Insert => Box::new(InsertWriter::new(out, "table", headers)),
+    }
+}
+
+pub struct ListWriter<W: Write> {
+    out: W,
+    separator: String,
+    headers: bool,
+    text: TextFormat,
+}
+
+impl<W: Write> ListWriter<W> {
+    pub fn new(out: W, separator: &str, headers: bool) -> Self {
+        Self {
+            out,
+            separator: separator.to_string(),
+            headers,
+            text: TextFormat::default(),
+        }
+    }
+
+    pub fn text_format(mut self, text: TextFormat) -> Self {
+        self.text = text;
+        self
+    }
+
+    fn field(&self, field: &[u8]) -> Vec<u8> {
+        self.text.field(field, &self.separator, Quote::Never)
+    }
+}
+
+impl<W: Write> OutputWriter for ListWriter<W> {
+    fn begin(&mut self, columns: &[ColumnInfo]) -> io::Result<()> {
+        if self.headers {
+            let names = columns
+                .iter()
+                .map(|c| self.field(c.name.as_bytes()))
+                .collect::<Vec<_>>();
+            self.out.write_all(&names.join(self.separator.as_bytes()))?;
+            writeln!(self.out)?;
+        }
+        Ok(())
+    }
+
+    fn write_row(&mut self, row: &[Column]) -> io::Result<()> {
+        // Only text is quoted or escaped; NULL is empty unless given a
+        // value, which quoting tells apart from the empty string. Blobs are
+        // their bytes as they are.
+        let values = row
+            .iter()
+            .map(|c| match c {
+                Column::Null => self.text.null.clone().into_bytes(),
+                Column::Text(s) => self.field(s.as_bytes()),
+                Column::Blob(b) => b.clone(),
+                c => c.to_string().into_bytes(),
+            })
+            .collect::<Vec<_>>();
+        self.out
+            .write_all(&values.join(self.separator.as_bytes()))?;
+        writeln!(self.out)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+pub struct CsvWriter<W: Write> {
+    out: W,
+    headers: bool,
+    text: TextFormat,
+}
+
+impl<W: Write> CsvWriter<W> {
+    pub fn new(out: W, headers: bool) -> Self {
+        Self {
+            out,
+            headers,
+            text: TextFormat::default(),
+        }
+    }
+
+    pub fn text_format(mut self, text: TextFormat) -> Self {
+        self.text = text;
+        self
+    }
+
+    fn field(&self, field: &[u8]) -> Vec<u8> {
+        self.text.field(field, ",", Quote::Needed)
+    }
+}
+
+impl<W: Write> OutputWriter for CsvWriter<W> {
+    fn begin(&mut self, columns: &[ColumnInfo]) -> io::Result<()> {
+        if self.headers {
+            let names = columns
+                .iter()
+                .map(|c| self.field(c.name.as_bytes()))
+                .collect::<Vec<_>>();
+            self.out.write_all(&names.join(&b","[..]))?;
+            writeln!(self.out)?;
+        }
+        Ok(())
+    }
+
+    fn write_row(&mut self, row: &[Column]) -> io::Result<()> {
+        // NULL is an empty field by default, unlike the empty string which
+        // is quoted.
+        // Numbers never need quoting, even with `always`.
+        let fields = row
+            .iter()
+            .map(|c| match c {
+                Column::Null => self.text.null.clone().into_bytes(),
+                Column::Text(s) => self.field(s.as_bytes()),
+                Column::Blob(b) => self.field(b),
+                c => c.to_string().into_bytes(),
+            })
+            .collect::<Vec<_>>();
+        self.out.write_all(&fields.join(&b","[..]))?;
+        writeln!(self.out)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+pub struct JsonWriter<W: Write> {
+    out: W,
+    names: Vec<String>,
+    rows: usize,
+}
+
+impl<W: Write> JsonWriter<W> {
+    pub fn new(out: W) -> Self {
+        Self {
+            out,
+            names: vec![],
+            rows: 0,
+        }
+    }
+}
+
+pub fn json_string(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len() + 2);
+    escaped.push('"');
+    for c in s.chars() {
+        push_json_char(&mut escaped, c);
+    }
+    escaped.push('"');
+    escaped
+}
+
+/// A blob as a JSON string, as sqlite3 writes it: the text its bytes are in
+/// UTF-8, with each byte that isn't part of a valid character escaped, as
+/// `\u0080` to `\u00ff`, so that any bytes make valid JSON.
+fn json_blob(bytes: &[u8]) -> String {
+    let mut escaped = String::with_capacity(bytes.len() + 2);
+    escaped.push('"');
+    let mut rest = bytes;
+    while !rest.is_empty() {
+        let (valid, invalid) = match std::str::from_utf8(rest) {
+            Ok(_) => (rest.len(), 0),
+            Err(e) => (
+                e.valid_up_to(),
+                e.error_len().unwrap_or(rest.len() - e.valid_up_to()),
+            ),
+        };
+        for c in String::from_utf8_lossy(&rest[..valid]).chars() {
+            push_json_char(&mut escaped, c);
+        }
+        for b in &rest[valid..valid + invalid] {
+            escaped.push_str(&format!("\\u{:04x}", b));
+        }
+        rest = &rest[valid + invalid..];
+    }
+    escaped.push('"');
+    escaped
+}
+
+fn push_json_char(escaped: &mut String, c: char) {
+    match c {
+        '"' => escaped.push_str("\\\""),
+        '\\' => escaped.push_str("\\\\"),
+        '\n' => escaped.push_str("\\n"),
+        '\r' => escaped.push_str("\\r"),
+        '\t' => escaped.push_str("\\t"),
+        c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+        c => escaped.push(c),
+    }
+}
+
+/// A value as JSON. Blobs have no JSON counterpart and become strings.
+pub fn json_value(value: &Column) -> String {
+    match value {
+        Column::Null => "null".to_string(),
+        Column::Integer(i) => i.to_string(),
+        // JSON has no infinities or NaN: they are written as JSON.stringify does
+        Column::Real(r) if !r.is_finite() => "null".to_string(),
+        // As many digits as read back the same value, as in `.dump`
+        Column::Real(r) => format!("{:?}", r),
+        Column::Text(s) => json_string(s),
+        Column::Blob(b) => json_blob(b),
+    }
+}
+
+/// `error` as a JSON object, for tools running the command to parse: the
+/// name of the SQLite result code closest to it, its message, and the SQL
+/// it is about along with the offset in it where parsing failed, or null.
+pub fn json_error(error: &anyhow::Error, sql: Option<&str>) -> String {
+    let not_a_database = error
+        .chain()
+        .any(|e| e.to_string().ends_with("file is not a database"));
+    let corrupt = error
+        .chain()
+        .any(|e| matches!(e.downcast_ref(), Some(Error::CorruptPage { .. })));
+    let io = error.chain().find_map(|e| e.downcast_ref::<io::Error>());
+    let code = match io {
+        _ if not_a_database => "SQLITE_NOTADB",
+        _ if corrupt => "SQLITE_CORRUPT",
+        Some(e) if e.kind() == io::ErrorKind::NotFound => "SQLITE_CANTOPEN",
+        Some(_) => "SQLITE_IOERR",
+        None => "SQLITE_ERROR",
+    };
+    let offset = match error.downcast_ref::<Error>() {
+        Some(Error::ParseError { offset, .. }) => offset.to_string(),
+        _ => "null".to_string(),
+    };
+    format!(
+        "{{\"code\":{},\"message\":{},\"sql\":{},\"offset\":{}}}",
+        json_string(code),
+        json_string(&format!("{:#}", error)),
+        sql.map_or("null".to_string(), json_string),
+        offset
+    )
+}
+
+impl<W: Write> OutputWriter for JsonWriter<W> {
+    fn begin(&mut self, columns: &[ColumnInfo]) -> io::Result<()> {
+        self.names = columns.iter().map(|c| json_string(&c.name)).collect();
+        Ok(())
+    }
+
+    fn write_row(&mut self, row: &[Column]) -> io::Result<()> {
+        let fields = self
+            .names
+            .iter()
+            .zip(row)
+            .map(|(name, value)| format!("{}:{}", name, json_value(value)))
+            .collect::<Vec<_>>();
+        let prefix = if self.rows == 0 { "[" } else { ",\n" };
+        write!(self.out, "{}{{{}}}", prefix, fields.join(","))?;
+        self.rows += 1;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        if self.rows > 0 {
+            writeln!(self.out, "]")?;
+        }
+        self.out.flush()
+    }
+}
+
+/// Renders an ASCII box like sqlite3's `.mode table`. Column widths depend on
+/// every value, so rows are buffered until `finish`.
+pub struct TableWriter<W: Write> {
+    out: W,
+    names: Vec<String>,
+    rows: Vec<Vec<String>>,
+    text: TextFormat,
+}
+
+impl<W: Write> TableWriter<W> {
+    pub fn new(out: W) -> Self {
+        Self {
+            out,
+            names: vec![],
+            rows: vec![],
+            text: TextFormat::default(),
+        }
+    }
+
+    pub fn text_format(mut self, text: TextFormat) -> Self {
+        self.text = text;
+        self
+    }
+}
+
+impl<W: Write> OutputWriter for TableWriter<W> {
+    fn begin(&mut self, columns: &[ColumnInfo]) -> io::Result<()> {
+        self.names = columns.iter().map(|c| c.name.clone()).collect();
+        Ok(())
+    }
+
+    fn write_row(&mut self, row: &[Column]) -> io::Result<()> {
+        let text = &self.text;
+        self.rows.push(
+            row.iter()
+                .map(|c| match c {
+                    Column::Null => text.null.clone(),
+                    Column::Text(s) => text.truncate(s.clone()),
+                    c => c.to_string(),
+                })
+                .collect(),
+        );
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        if self.rows.is_empty() {
+            return self.out.flush();
+        }
+
+        let widths = self
+            .names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| {
+                self.rows
+                    .iter()
+                    .map(|row| row[i].chars().count())
+                    .chain(std::iter::once(name.chars().count()))
+                    .max()
+                    .unwrap_or(0)
+            })
+            .collect::<Vec<_>>();
+
+        let border = widths
+            .iter()
+            .map(|w| "-".repeat(w + 2))
+            .collect::<Vec<_>>()
+            .join("+");
+        let border = format!("+{}+", border);
+
+        writeln!(self.out, "{}", border)?;
+        let header = self
+            .names
+            .iter()
+            .zip(&widths)
+            .map(|(name, w)| {
+                let padding = w - name.chars().count();
+                let left = padding / 2;
+                format!(
+                    " {}{}{} ",
+                    " ".repeat(left),
+                    name,
+                    " ".repeat(padding - left)
+                )
+            })
+            .collect::<Vec<_>>();
+        writeln!(self.out, "|{}|", header.join("|"))?;
+        writeln!(self.out, "{}", border)?;
+        for row in &self.rows {
+            let cells = row
+                .iter()
+                .zip(&widths)
+                .map(|(value, w)| format!(" {}{} ", value, " ".repeat(w - value.chars().count())))
+                .collect::<Vec<_>>();
+            writeln!(self.out, "|{}|", cells.join("|"))?;
+        }
+        writeln!(self.out, "{}", border)?;
+        self.out.flush()
+    }
+}
+
+pub struct InsertWriter<W: Write> {
+    out: W,
+    table: String,
+    headers: bool,
+    target: String,
+}
+
+impl<W: Write> InsertWriter<W> {
+    pub fn new(out: W, table: &str, headers: bool) -> Self {
+        Self {
+            out,
+            table: table.to_string(),
+            headers,
+            target: String::new(),
+        }
+    }
+}
+
+pub fn sql_literal(value: &Column) -> String {
+    match value {
+        Column::Null => "NULL".to_string(),
+        Column::Integer(i) => i.to_string(),
+        // Infinities have no literal, but overflow to one, and other reals
+        // are written with as many digits as read back the same value
+        Column::Real(r) if r.is_infinite() => if *r > 0.0 { "1e999" } else { "-1e999" }.to_string(),
+        Column::Real(r) => format!("{:?}", r),
+        Column::Text(s) => format!("'{}'", s.replace('\'', "''")),
+        Column::Blob(b) => format!(
+            "X'{}'",
+            b.iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect::<String>()
+        ),
+    }
+}
+
+/// Quotes an identifier for use in generated SQL, unless it is a plain name
+/// that can be written as-is.
+pub fn quote_identifier(name: &str) -> String {
+    const KEYWORDS: &[&str] = &[
+        "ALL",
+        "AND",
+        "AS",
+        "BETWEEN",
+        "BY",
+        "CASE",
+        "CHECK",
+        "COLLATE",
+        "CREATE",
+        "DEFAULT",
+        "DELETE",
+        "DISTINCT",
+        "DROP",
+        "ELSE",
+        "END",
+        "FROM",
+        "GROUP",
+        "HAVING",
+        "IN",
+        "INDEX",
+        "INSERT",
+        "INTO",
+        "IS",
+        "JOIN",
+        "KEY",
+        "LIMIT",
+        "NOT",
+        "NULL",
+        "ON",
+        "OR",
+        "ORDER",
+        "PRIMARY",
+        "REFERENCES",
+        "SELECT",
+        "SET",
+        "TABLE",
+        "THEN",
+        "UNION",
+        "UNIQUE",
+        "UPDATE",
+        "VALUES",
+        "WHEN",
+        "WHERE",
+    ];
+    let plain = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !KEYWORDS.contains(&name.to_uppercase().as_str());
+    if plain {
+        name.to_string()
+    } else {
+        format!("\"{}\"", name.replace('"', "\"\""))
+    }
+}
+
+impl<W: Write> OutputWriter for InsertWriter<W> {
+    fn begin(&mut self, columns: &[ColumnInfo]) -> io::Result<()> {
+        self.target = quote_identifier(&self.table);
+        if self.headers {
+            let names = columns
+                .iter()
+                .map(|c| quote_identifier(&c.name))
+                .collect::<Vec<_>>();
+            self.target = format!("{}({})", self.target, names.join(","));
+        }
+        Ok(())
+    }
+
+    fn write_row(&mut self, row: &[Column]) -> io::Result<()> {
+        let values = row.iter().map(sql_literal).collect::<Vec<_>>();
+        writeln!(
+            self.out,
+            "INSERT INTO {} VALUES({});",
+            self.target,
+            values.join(",")
+        )
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Row;
+
+    fn columns(names: &[&str]) -> Vec<ColumnInfo> {
+        names
+            .iter()
+            .map(|name| ColumnInfo {
+                name: name.to_string(),
+                decl_type: None,
+                table: None,
+            })
+            .collect()
+    }
+
+    fn written(writer: &mut dyn OutputWriter, names: &[&str], rows: &[Row]) {
+        writer.begin(&columns(names)).unwrap();
+        for row in rows {
+            writer.write_row(row).unwrap();
+        }
+        writer.finish().unwrap();
+    }
+
+    #[test]
+    fn every_mode_as_sqlite3_writes_it() {
+        let rows = [
+            vec![Column::Integer(1), Column::Text(r#"a|b,c "q""#.into())],
+            vec![Column::Null, Column::Real(2.5)],
+            vec![Column::Integer(-3), Column::Text("it's".into())],
+        ];
+        for (mode, expected) in [
+            (Mode::List, "n|s\n1|a|b,c \"q\"\n|2.5\n-3|it's\n"),
+            (Mode::Csv, "n,s\n1,\"a|b,c \"\"q\"\"\"\n,2.5\n-3,\"it's\"\n"),
+            (
+                Mode::Json,
+                "[{\"n\":1,\"s\":\"a|b,c \\\"q\\\"\"},\n\
+                 {\"n\":null,\"s\":2.5},\n\
+                 {\"n\":-3,\"s\":\"it's\"}]\n",
+            ),
+            (
+                Mode::Table,
+                "+----+-----------+\n\
+                 | n  |     s     |\n\
+                 +----+-----------+\n\
+                 | 1  | a|b,c \"q\" |\n\
+                 |    | 2.5       |\n\
+                 | -3 | it's      |\n\
+                 +----+-----------+\n",
+            ),
+            (
+                Mode::Insert,
+                "INSERT INTO \"table\"(n,s) VALUES(1,'a|b,c \"q\"');\n\
+                 INSERT INTO \"table\"(n,s) VALUES(NULL,2.5);\n\
+                 INSERT INTO \"table\"(n,s) VALUES(-3,'it''s');\n",
+            ),
+        ] {
+            let mut out = vec![];
+            let mut writer = writer(mode, Box::new(&mut out), true, TextFormat::default());
+            written(writer.as_mut(), &["n", "s"], &rows);
+            drop(writer);
+            assert_eq!(String::from_utf8(out).unwrap(), expected, "{:?}", mode);
+        }
+    }
+
+    #[test]
+    fn blobs_as_their_bytes() {
+        let rows = [vec![Column::Blob(vec![0, 0xff]), Column::Text("a".into())]];
+        let mut out = vec![];
+        written(
+            &mut ListWriter::new(&mut out, "|", false),
+            &["b", "s"],
+            &rows,
+        );
+        assert_eq!(out, b"\x00\xff|a\n");
+
+        let mut out = vec![];
+        written(&mut CsvWriter::new(&mut out, false), &["b", "s"], &rows);
+        assert_eq!(out, b"\"\x00\xff\",a\n");
+    }
+
+    #[test]
+    fn blobs_in_json() {
+        assert_eq!(
+            json_value(&Column::Blob(vec![0, 0xff])),
+            r#""\u0000\u00ff""#
+        );
+        // Valid UTF-8 is kept, as sqlite3 does
+        assert_eq!(
+            json_value(&Column::Blob("h\u{e9}\"".as_bytes().to_vec())),
+            "\"h\u{e9}\\\"\""
+        );
+    }
+
+    #[test]
+    fn reals_in_json_read_back_the_same() {
+        for r in [0.1 + 0.2, 1.0 / 3.0, 1.0, -2.5e-300, 1e300] {
+            assert_eq!(json_value(&Column::Real(r)).parse::<f64>().unwrap(), r);
+        }
+        assert_eq!(json_value(&Column::Real(f64::INFINITY)), "null");
+    }
+}
@@ -0,0 +1,365 @@
+//! Reading the pages of a database file, through a cache of the ones used
+//! most recently: every descent of a b-tree reads its root and interior
+//! pages again, and an index lookup reads those of two b-trees per row.
+//! With `--mmap`, pages of the file are slices of a mapping of it instead,
+//! which need neither reading nor caching.
+
+use crate::db::Options;
+use crate::mmap::Mmap;
+use crate::ptrmap::PointerMap;
+use crate::teach;
+use crate::wal::Wal;
+use crate::Error;
+use anyhow::{bail, Result};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::{prelude::*, ErrorKind, SeekFrom};
+use std::ops::Deref;
+use std::rc::Rc;
+
+/// A page in full, along with its number so that what is wrong with it can
+/// be told.
+#[derive(Debug)]
+pub struct Page {
+    number: u32,
+    content: Content,
+}
+
+/// Where the bytes of a page are: read from the file or the WAL, or in a
+/// mapping of the file.
+#[derive(Debug)]
+enum Content {
+    Read(Vec<u8>),
+    Mapped {
+        map: Rc<Mmap>,
+        offset: usize,
+        len: usize,
+    },
+}
+
+impl Page {
+    /// A page made of `bytes` read from elsewhere than a pager.
+    pub fn new(number: u32, bytes: Vec<u8>) -> Page {
+        Page {
+            number,
+            content: Content::Read(bytes),
+        }
+    }
+
+    /// Its 1-based page number.
+    pub fn number(&self) -> u32 {
+        self.number
+    }
+
+    /// Where its b-tree page header starts. The offsets of cells, and those
+    /// given in the header, are from the start of the page all the same.
+    pub fn btree_start(&self) -> usize {
+        btree_start(self.number)
+    }
+
+    /// The type of b-tree page it is, the first byte of its b-tree page header.
+    pub fn page_type(&self) -> u8 {
+        self[self.btree_start()]
+    }
+
+    pub fn number_of_cells(&self) -> usize {
+        let start = self.btree_start();
+        u16::from_be_bytes([self[start + 3], self[start + 4]]) as usize
+    }
+
+    /// The child of an interior page after every key of its cells.
+    pub fn right_most_pointer(&self) -> u32 {
+        let start = self.btree_start();
+        u32::from_be_bytes(self[start + 8..start + 12].try_into().unwrap())
+    }
+
+    /// Where the cell pointer array starts, following the b-tree page
+    /// header, which is 12 bytes long on interior pages and 8 on leaves.
+    pub fn cell_pointers(&self) -> usize {
+        match self.page_type() {
+            0x02 | 0x05 => self.btree_start() + 12,
+            _ => self.btree_start() + 8,
+        }
+    }
+}
+
+/// Where the b-tree page header of page `page_no` starts: page 1 holds the
+/// 100-byte database header first, and every other page starts with it.
+pub fn btree_start(page_no: u32) -> usize {
+    if page_no == 1 {
+        100
+    } else {
+        0
+    }
+}
+
+impl Deref for Page {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match &self.content {
+            Content::Read(page) => page,
+            Content::Mapped { map, offset, len } => &map.as_slice()[*offset..*offset + *len],
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Pager {
+    file: File,
+    page_size: usize,
+    /// The file mapped into memory, with `--mmap`
+    map: Option<Rc<Mmap>>,
+    /// Most pages the cache holds
+    capacity: usize,
+    /// The cached pages, along with when each was last used
+    cache: HashMap<u32, (Rc<Page>, u64)>,
+    /// The page numbers of the cached pages by when they were last used,
+    /// the least recently used first
+    recency: BTreeMap<u64, u32>,
+    /// Counts the pages asked for, to tell when each was last used
+    clock: u64,
+    /// The WAL, which has the pages of commits not yet checkpointed
+    wal: Option<Wal>,
+    /// The pointer map, in auto-vacuum databases
+    pointer_map: Option<PointerMap>,
+    /// Hashes of the pages read so far, with `--verify-pages`
+    hashes: Option<HashMap<u32, u64>>,
+    /// Whether each page read is described on stderr, with `--teach`
+    teach: bool,
+}
+
+impl Pager {
+    /// A pager for `file`, caching, mapping and checking pages as `options`
+    /// say.
+    pub fn new(file: File, page_size: usize, wal: Option<Wal>, options: &Options) -> Pager {
+        Pager {
+            map: options.mmap.then(|| map(&file)).flatten(),
+            file,
+            page_size,
+            capacity: match options.cache_size {
+                size if size < 0 => size.unsigned_abs() as usize * 1024 / page_size,
+                size => size as usize,
+            }
+            .max(1),
+            cache: HashMap::new(),
+            recency: BTreeMap::new(),
+            clock: 0,
+            wal,
+            pointer_map: None,
+            hashes: options.verify_pages.then(HashMap::new),
+            teach: options.teach,
+        }
+    }
+
+    /// Whether reading the file is described on stderr, with `--teach`.
+    pub fn teaches(&self) -> bool {
+        self.teach
+    }
+
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    pub fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    pub fn wal(&self) -> Option<&Wal> {
+        self.wal.as_ref()
+    }
+
+    /// Reads pages from `wal` from now on, forgetting every cached page.
+    pub fn set_wal(&mut self, wal: Option<Wal>) {
+        self.wal = wal;
+        self.cache.clear();
+        self.recency.clear();
+    }
+
+    pub fn pointer_map(&self) -> Option<PointerMap> {
+        self.pointer_map
+    }
+
+    pub fn set_pointer_map(&mut self, pointer_map: Option<PointerMap>) {
+        self.pointer_map = pointer_map;
+    }
+
+    /// Page `page_no` (1-based) of a b-tree or an overflow chain.
+    pub fn get_page(&mut self, page_no: u32) -> Result<Rc<Page>> {
+        // No b-tree or overflow chain leads to a pointer map page but a corrupt one
+        if self.pointer_map.map_or(false, |map| map.contains(page_no)) {
+            bail!(
+                "page {} is a pointer map page, not part of any b-tree",
+                page_no
+            );
+        }
+        self.get_any_page(page_no)
+    }
+
+    /// Page `page_no` (1-based), whatever it holds.
+    pub fn get_any_page(&mut self, page_no: u32) -> Result<Rc<Page>> {
+        // Only a corrupt b-tree or freelist leads to page 0
+        if page_no == 0 {
+            return Err(Error::corrupt(0, 0, "page numbers start at 1").into());
+        }
+        if let Some(page) = self.mapped(page_no) {
+            self.check(page_no, &page)?;
+            return Ok(Rc::new(page));
+        }
+
+        self.clock += 1;
+        if let Some((page, used)) = self.cache.get_mut(&page_no) {
+            self.recency.remove(used);
+            *used = self.clock;
+            self.recency.insert(self.clock, page_no);
+            return Ok(page.clone());
+        }
+
+        let page = Rc::new(Page::new(page_no, self.read(page_no)?));
+        if self.cache.len() == self.capacity {
+            if let Some((_, evicted)) = self.recency.pop_first() {
+                self.cache.remove(&evicted);
+            }
+        }
+        self.cache.insert(page_no, (page.clone(), self.clock));
+        self.recency.insert(self.clock, page_no);
+        Ok(page)
+    }
+
+    /// Forgets every cached page, which may have changed since it was read
+    /// unless a read transaction has been held all along. This is where a
+    /// read transaction begins, so the file is mapped again if its size
+    /// changed: pages it has grown by are then in the mapping, and none it
+    /// was truncated by are handed out to fault when read.
+    pub fn clear(&mut self) -> Result<()> {
+        self.cache.clear();
+        self.recency.clear();
+        if let Some(mapped) = &self.map {
+            if mapped.len() as u64 != self.file.metadata()?.len() {
+                self.map = map(&self.file);
+            }
+        }
+        Ok(())
+    }
+
+    /// Where page `page_no` starts in the file.
+    fn offset(&self, page_no: u32) -> u64 {
+        (page_no as u64 - 1) * self.page_size as u64
+    }
+
+    /// Page `page_no` in the mapping of the file, unless the file isn't
+    /// mapped, the page is past the end of the mapping, or the WAL has a
+    /// newer version of it.
+    fn mapped(&self, page_no: u32) -> Option<Page> {
+        let map = self.map.as_ref()?;
+        let offset = self.offset(page_no) as usize;
+        if offset + self.page_size > map.len()
+            || self.wal.as_ref().map_or(false, |wal| wal.has_page(page_no))
+        {
+            return None;
+        }
+        Some(Page {
+            number: page_no,
+            content: Content::Mapped {
+                map: map.clone(),
+                offset,
+                len: self.page_size,
+            },
+        })
+    }
+
+    /// Reads page `page_no` from the WAL if it has a version of it, or else
+    /// from the file.
+    fn read(&mut self, page_no: u32) -> Result<Vec<u8>> {
+        let mut page = vec![0; self.page_size];
+        let in_wal = match &mut self.wal {
+            Some(wal) => wal.read_page(page_no, &mut page)?,
+            None => false,
+        };
+        if !in_wal {
+            self.file.seek(SeekFrom::Start(self.offset(page_no)))?;
+            if let Err(e) = self.file.read_exact(&mut page) {
+                return Err(match e.kind() {
+                    ErrorKind::UnexpectedEof => {
+                        Error::corrupt(page_no, 0, "past the end of the file").into()
+                    }
+                    _ => e.into(),
+                });
+            }
+        }
+        self.check(page_no, &page)?;
+        Ok(page)
+    }
+
+    /// What is done with every page as it is read: telling about it with
+    /// `--teach`, and checking that it hasn't changed with `--verify-pages`.
+    fn check(&mut self, page_no: u32, page: &[u8]) -> Result<()> {
+        if self.teach {
+            teach::page(page_no, page);
+        }
+
+        // A page read again, once it has left the cache, must not have changed
+        if let Some(hashes) = &mut self.hashes {
+            let mut hasher = DefaultHasher::new();
+            page.hash(&mut hasher);
+            let hash = hasher.finish();
+            if *hashes.entry(page_no).or_insert(hash) != hash {
+                bail!(
+                    "page {} changed since it was first read: the database is being modified",
+                    page_no
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The mapping of `file` with `--mmap`. Where the file can't be mapped, its
+/// pages are read as without it.
+fn map(file: &File) -> Option<Rc<Mmap>> {
+    // SAFETY: `clear` maps the file again whenever its size changed at the
+    // start of a read transaction, and the transaction fails at its end if
+    // the file was written during it
+    unsafe { Mmap::new(file) }.ok().map(Rc::new)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::connection::Connection;
+    use crate::db::Options;
+    use crate::load::load;
+    use crate::testing::TempDatabase;
+    use crate::Column;
+    use std::fs::OpenOptions;
+
+    #[test]
+    fn mapped_file_truncated_between_reads() {
+        let database = TempDatabase::new("mmap-truncated");
+        let rows = (1..=1000).map(|i| Ok(vec![Column::Integer(i), Column::Text("x".repeat(100))]));
+        load(
+            &database.0,
+            "CREATE TABLE t (id INTEGER PRIMARY KEY, s)",
+            &[],
+            rows,
+        )
+        .unwrap();
+        let options = Options {
+            mmap: true,
+            ..Options::default()
+        };
+        let mut connection = Connection::open(database.filename(), &options).unwrap();
+        let read = connection.begin_read().unwrap();
+        let page = connection.pager.get_page(2).unwrap();
+        assert_eq!(page.len(), connection.pager.page_size());
+        drop(page);
+        read.end().unwrap();
+
+        let file = OpenOptions::new().write(true).open(&database.0).unwrap();
+        file.set_len(connection.pager.page_size() as u64).unwrap();
+        // Page 2 is gone from the new mapping, rather than faulting in the old
+        let _read = connection.begin_read().unwrap();
+        assert!(connection.pager.get_page(2).is_err());
+    }
+}
@@ -0,0 +1,320 @@
+//! The `PRAGMA` statements that report on the schema, and `temp_store`.
+
+use crate::btree::{rows, rows_after, Direction};
+use crate::connection::Connection;
+use crate::db::{Options, TempStore};
+use crate::exec::DistinctKey;
+use crate::output::{ColumnInfo, OutputWriter};
+use crate::record::TableLayout;
+use crate::sql::{self, Pragma};
+use crate::{table_columns, Column, Error, Schema, Table};
+use anyhow::{bail, Result};
+use std::collections::HashSet;
+
+pub fn execute(
+    pragma: &Pragma,
+    schemas: &[Schema],
+    connections: &mut [Connection],
+    options: &mut Options,
+    out: &mut dyn OutputWriter,
+) -> Result<()> {
+    // The databases to look in: the one named, or else all of them with
+    // `main` first
+    let databases = match &pragma.schema {
+        Some(name) => match schemas
+            .iter()
+            .position(|s| s.name.eq_ignore_ascii_case(name))
+        {
+            Some(i) => vec![i],
+            None => bail!("unknown database {}", name),
+        },
+        None => (0..schemas.len()).collect(),
+    };
+
+    // Like SQLite, pragmas it doesn't know do nothing
+    match pragma.name.to_ascii_lowercase().as_str() {
+        "foreign_key_list" => {
+            if let Some(table) = &pragma.argument {
+                let schemas = databases.iter().map(|&i| &schemas[i]).collect::<Vec<_>>();
+                foreign_key_list(table, &schemas, out)?;
+            }
+        }
+        "foreign_key_check" => foreign_key_check(
+            pragma.argument.as_deref(),
+            &databases,
+            schemas,
+            connections,
+            out,
+        )?,
+        "temp_store" => match &pragma.argument {
+            // Like SQLite, a value it doesn't know leaves the setting as it is
+            Some(value) => {
+                if let Some(temp_store) = TempStore::from_name(value) {
+                    options.temp_store = temp_store;
+                }
+            }
+            None => {
+                out.begin(&[ColumnInfo {
+                    name: "temp_store".to_string(),
+                    decl_type: None,
+                    table: None,
+                }])?;
+                out.write_row(&[Column::Integer(options.temp_store.number())])?;
+                out.finish()?;
+            }
+        },
+        _ => {}
+    }
+    Ok(())
+}
+
+/// `PRAGMA foreign_key_list(table)`: a row for each column of each foreign
+/// key of `table`.
+fn foreign_key_list(table: &str, schemas: &[&Schema], out: &mut dyn OutputWriter) -> Result<()> {
+    // A table that isn't qualified is looked for in `main` first
+    let Some(foreign_keys) = schemas
+        .iter()
+        .find_map(|s| s.foreign_keys(table).transpose())
+        .transpose()?
+    else {
+        return Ok(());
+    };
+    if foreign_keys.is_empty() {
+        return Ok(());
+    }
+
+    let names = [
+        "id",
+        "seq",
+        "table",
+        "from",
+        "to",
+        "on_update",
+        "on_delete",
+        "match",
+    ];
+    out.begin(&names.map(|name| ColumnInfo {
+        name: name.to_string(),
+        decl_type: None,
+        table: None,
+    }))?;
+    // Numbered from the last declared, as by SQLite
+    for (id, key) in foreign_keys.iter().rev().enumerate() {
+        for (seq, from) in key.columns.iter().enumerate() {
+            out.write_row(&[
+                Column::Integer(id as i64),
+                Column::Integer(seq as i64),
+                Column::Text(key.table.clone()),
+                Column::Text(from.clone()),
+                key.parent_columns
+                    .get(seq)
+                    .map_or(Column::Null, |to| Column::Text(to.clone())),
+                Column::Text(key.on_update.name().to_string()),
+                Column::Text(key.on_delete.name().to_string()),
+                Column::Text("NONE".to_string()),
+            ])?;
+        }
+    }
+    out.finish()?;
+    Ok(())
+}
+
+/// `PRAGMA foreign_key_check[(table)]`: the rows of `table`, or of every
+/// table of the first database, whose foreign key refers to no row of the
+/// parent table. A key with a NULL in it refers to nothing and is never
+/// reported.
+///
+/// Like a join on the key, the keys of the parent table are put in a hash
+/// set that each row of the child table is looked up in.
+fn foreign_key_check(
+    table: Option<&str>,
+    databases: &[usize],
+    schemas: &[Schema],
+    connections: &mut [Connection],
+    out: &mut dyn OutputWriter,
+) -> Result<()> {
+    let is_table = |t: &&Table| t.ty == "table";
+    let children = match table {
+        Some(name) => {
+            let found = databases.iter().find_map(|&i| {
+                schemas[i]
+                    .tables
+                    .iter()
+                    .filter(is_table)
+                    .find(|t| t.name.eq_ignore_ascii_case(name))
+                    .map(|t| (i, t))
+            });
+            match found {
+                Some(child) => vec![child],
+                None => return Err(Error::UnknownTable(name.to_string()).into()),
+            }
+        }
+        None => schemas[databases[0]]
+            .tables
+            .iter()
+            .filter(is_table)
+            .map(|t| (databases[0], t))
+            .collect(),
+    };
+
+    // Every key is checked to be usable before any row is reported
+    let mut violations = vec![];
+    for (database, child) in children {
+        let foreign_keys = sql::parse_create_table(&child.sql)?.foreign_keys;
+        if foreign_keys.is_empty() {
+            continue;
+        }
+        let child_columns = table_columns(&child.sql)?;
+        let Connection { pager, format, .. } = &mut connections[database];
+        let format = *format;
+
+        // Per foreign key, numbered as by `foreign_key_list`: the positions of
+        // its columns in the child, and the parent's keys, or None when
+        // there is no parent table and so no key can be found
+        let mut checks = vec![];
+        for key in foreign_keys.iter().rev() {
+            let positions = key
+                .columns
+                .iter()
+                .map(|name| {
+                    child_columns
+                        .iter()
+                        .position(|c| c.name.eq_ignore_ascii_case(name))
+                        .ok_or_else(|| Error::UnknownColumn(name.to_string()).into())
+                })
+                .collect::<Result<Vec<_>>>()?;
+            let parent = schemas[database]
+                .tables
+                .iter()
+                .filter(is_table)
+                .find(|t| t.name.eq_ignore_ascii_case(&key.table));
+            let Some(parent) = parent else {
+                checks.push((positions, None, vec![]));
+                continue;
+            };
+
+            // The key must refer to the parent's primary key, which it does
+            // when it names no columns, or to columns that are UNIQUE
+            let parent_table = sql::parse_create_table(&parent.sql)?;
+            let parent_columns = table_columns(&parent.sql)?;
+            let keys = parent_keys(&parent_table, &schemas[database].tables);
+            let referenced = match &key.parent_columns[..] {
+                [] => keys.first().cloned().unwrap_or_default(),
+                columns => columns.to_vec(),
+            };
+            let is_key = keys.iter().any(|k| same_columns(k, &referenced));
+            let referenced = referenced
+                .iter()
+                .map(|name| {
+                    parent_columns
+                        .iter()
+                        .position(|c| c.name.eq_ignore_ascii_case(name))
+                })
+                .collect::<Option<Vec<_>>>();
+            let referenced = match referenced {
+                Some(referenced) if is_key && referenced.len() == positions.len() => referenced,
+                _ => bail!(
+                    "foreign key mismatch - \"{}\" referencing \"{}\"",
+                    child.name,
+                    parent.name
+                ),
+            };
+
+            let page = pager.get_page(parent.rootpage)?;
+            let layout = TableLayout::new(&parent_columns);
+            let keys = rows(page, pager, format, Direction::Forward, &layout)?
+                .into_iter()
+                .map(|mut row| {
+                    // Rows written before columns were added are short
+                    row.resize(parent_columns.len(), Column::Null);
+                    referenced
+                        .iter()
+                        .map(|&i| DistinctKey::new(&row[i], parent_columns[i].collation))
+                        .collect::<Vec<_>>()
+                })
+                .collect::<HashSet<_>>();
+            // Child values are compared as the parent columns' values are
+            let columns = referenced.iter().map(|&i| parent_columns[i].clone());
+            checks.push((positions, Some(keys), columns.collect()));
+        }
+
+        let page = pager.get_page(child.rootpage)?;
+        let mut budget = usize::MAX;
+        let child_rows = rows_after(
+            &page,
+            pager,
+            format,
+            &TableLayout::new(&child_columns),
+            None,
+            &mut budget,
+        )?;
+        for (row_id, mut row) in child_rows {
+            row.resize(child_columns.len(), Column::Null);
+            for (fkid, (positions, keys, parent_columns)) in checks.iter().enumerate() {
+                if positions.iter().any(|&i| row[i] == Column::Null) {
+                    continue;
+                }
+                let found = keys.as_ref().map_or(false, |keys| {
+                    let key = positions
+                        .iter()
+                        .zip(parent_columns)
+                        .map(|(&i, column)| {
+                            let value = column.affinity().apply(row[i].clone());
+                            DistinctKey::new(&value, column.collation)
+                        })
+                        .collect::<Vec<_>>();
+                    keys.contains(&key)
+                });
+                if !found {
+                    violations.push([
+                        Column::Text(child.name.clone()),
+                        Column::Integer(row_id as i64),
+                        Column::Text(foreign_keys[foreign_keys.len() - 1 - fkid].table.clone()),
+                        Column::Integer(fkid as i64),
+                    ]);
+                }
+            }
+        }
+    }
+    if violations.is_empty() {
+        return Ok(());
+    }
+
+    let names = ["table", "rowid", "parent", "fkid"];
+    out.begin(&names.map(|name| ColumnInfo {
+        name: name.to_string(),
+        decl_type: None,
+        table: None,
+    }))?;
+    for row in &violations {
+        out.write_row(row)?;
+    }
+    out.finish()?;
+    Ok(())
+}
+
+/// The column sets a foreign key can refer to in `table`: its primary key
+/// first, if it has one, and then those of its UNIQUE constraints and
+/// indexes.
+fn parent_keys(table: &sql::CreateTable, tables: &[Table]) -> Vec<Vec<String>> {
+    let mut keys = table.unique_keys();
+    // Indexes of constraints have no SQL, and are covered above
+    for index in tables
+        .iter()
+        .filter(|t| t.ty == "index" && t.tbl_name.eq_ignore_ascii_case(&table.name))
+    {
+        if let Ok(index) = sql::parse_create_index(&index.sql) {
+            if index.unique && !index.partial {
+                keys.push(index.columns.into_iter().map(|c| c.name).collect());
+            }
+        }
+    }
+    keys
+}
+
+/// Whether `a` and `b` name the same columns, in any order.
+fn same_columns(a: &[String], b: &[String]) -> bool {
+    a.len() == b.len()
+        && a.iter()
+            .all(|name| b.iter().any(|other| other.eq_ignore_ascii_case(name)))
+}
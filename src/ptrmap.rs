@@ -0,0 +1,57 @@
+//! Pointer map pages, which auto-vacuum databases keep between the others so
+//! that pages can be moved: each tells, for the pages that follow it, what
+//! they are and which page points to them. They aren't part of any b-tree.
+//! https://www.sqlite.org/fileformat.html#pointer_map_or_ptrmap_pages
+
+use crate::header::DatabaseHeader;
+
+/// Where the pointer map pages of an auto-vacuum database are.
+#[derive(Debug, Clone, Copy)]
+pub struct PointerMap {
+    usable_size: usize,
+}
+
+impl PointerMap {
+    /// The pointer map of the database with `header`, if it has one, which
+    /// it does when the header names its largest root page.
+    pub fn new(header: &DatabaseHeader) -> Option<PointerMap> {
+        (header.largest_root_page != 0).then(|| PointerMap {
+            usable_size: header.format().usable_size,
+        })
+    }
+
+    /// Every pointer map page has an entry of 5 bytes for each page up to
+    /// the next pointer map page. The first is page 2.
+    fn entries_per_page(self) -> u32 {
+        (self.usable_size / 5) as u32
+    }
+
+    pub fn contains(self, page_no: u32) -> bool {
+        page_no >= 2 && (page_no - 2) % (self.entries_per_page() + 1) == 0
+    }
+
+    /// The entries of pointer map page `page_no`: for each page it covers,
+    /// that page, what it is and its parent page.
+    pub fn entries(self, page_no: u32, page: &[u8]) -> Vec<(u32, &'static str, u32)> {
+        page[..self.entries_per_page() as usize * 5]
+            .chunks_exact(5)
+            .zip(page_no + 1..)
+            .filter(|(entry, _)| entry[0] != 0)
+            .map(|(entry, covered)| {
+                let kind = match entry[0] {
+                    1 => "b-tree root page",
+                    2 => "free page",
+                    3 => "first overflow page",
+                    4 => "overflow page",
+                    5 => "b-tree page",
+                    _ => "unknown",
+                };
+                (
+                    covered,
+                    kind,
+                    u32::from_be_bytes(entry[1..].try_into().unwrap()),
+                )
+            })
+            .collect()
+    }
+}
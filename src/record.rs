@@ -0,0 +1,356 @@
+//! The record format table rows and index entries are stored in: a header
+//! of serial types, one per value, followed by the values themselves.
+//! https://www.sqlite.org/fileformat.html#record_format
+
+use crate::header::TextEncoding;
+use crate::pager::Pager;
+use crate::{put_variant, rowid_alias, teach, try_variant, Column, ColumnDef, Error, Row};
+use anyhow::{bail, Result};
+use std::borrow::Cow;
+
+/// What decoding the cells of a database's pages depends on.
+#[derive(Debug, Clone, Copy)]
+pub struct Format {
+    /// The page size less the bytes reserved at the end of every page
+    pub usable_size: usize,
+    pub encoding: TextEncoding,
+}
+
+/// What decoding the records of a table into rows depends on.
+#[derive(Debug, Clone, Default)]
+pub struct TableLayout {
+    /// The column that is another name for the rowid, if any
+    pub rowid_alias: Option<usize>,
+    /// The value of each column in rows too short to have it, which were
+    /// written before it was added to the table
+    pub defaults: Vec<Column>,
+}
+
+impl TableLayout {
+    pub fn new(columns: &[ColumnDef]) -> TableLayout {
+        TableLayout {
+            rowid_alias: rowid_alias(columns),
+            defaults: columns.iter().map(|c| c.default.clone()).collect(),
+        }
+    }
+}
+
+/// How many bytes of a payload of `payload_length` bytes stay on the page.
+/// A payload too large for the page keeps only its start there, followed by
+/// the number of the first of a chain of overflow pages holding the rest.
+/// How much stays depends on the usable size of a page, which is the page
+/// size less the bytes reserved at the end of each page, and on whether the
+/// cell is an index entry.
+/// https://www.sqlite.org/fileformat.html#cell_payload_size_limits
+pub fn local_size(format: Format, payload_length: u64, index: bool) -> usize {
+    let (length, usable_size) = (payload_length as usize, format.usable_size);
+    let max_local = if index {
+        (usable_size - 12) * 64 / 255 - 23
+    } else {
+        usable_size - 35
+    };
+    if length <= max_local {
+        return length;
+    }
+    let min_local = (usable_size - 12) * 32 / 255 - 23;
+    match min_local + (length - min_local) % (usable_size - 4) {
+        size if size <= max_local => size,
+        _ => min_local,
+    }
+}
+
+/// The payload of `payload_length` bytes of a cell, whose part on the page
+/// starts at `local`, read on from its overflow pages if it has any.
+pub fn payload<'a>(
+    pager: &mut Pager,
+    format: Format,
+    payload_length: u64,
+    local: &'a [u8],
+    index: bool,
+) -> Result<Cow<'a, [u8]>> {
+    let (length, usable_size) = (payload_length as usize, format.usable_size);
+    let local_size = local_size(format, payload_length, index);
+    if local_size == length {
+        let Some(local) = local.get(..length) else {
+            bail!("cell runs off the page");
+        };
+        return Ok(Cow::Borrowed(local));
+    }
+
+    let Some(pointer) = local.get(local_size..local_size + 4) else {
+        bail!("cell runs off the page");
+    };
+    let mut payload = local[..local_size].to_vec();
+    let mut next = u32::from_be_bytes(pointer.try_into().unwrap());
+    if pager.teaches() {
+        teach::overflow(length, local_size, next);
+    }
+    if next == 0 {
+        bail!("cell overflows, but not to any page");
+    }
+    let mut previous = next;
+    while payload.len() < length {
+        if next == 0 {
+            return Err(Error::corrupt(
+                previous,
+                0,
+                format!("overflow chain ends {} bytes short", length - payload.len()),
+            )
+            .into());
+        }
+        // Each overflow page starts with the number of the next one
+        let page = pager.get_page(next)?;
+        previous = next;
+        next = u32::from_be_bytes(page[..4].try_into().unwrap());
+        let content = &page[4..usable_size];
+        payload.extend_from_slice(&content[..content.len().min(length - payload.len())]);
+    }
+    Ok(Cow::Owned(payload))
+}
+
+/// Decodes the values of `record`, whose text is in `encoding`, or None if it
+/// is malformed.
+pub fn decode(record: &[u8], encoding: TextEncoding) -> Option<Row> {
+    let fields = fields(record)?;
+    Some(
+        fields
+            .into_iter()
+            .map(|(serial_type, bytes)| value(serial_type, bytes, encoding))
+            .collect(),
+    )
+}
+
+/// Decodes the values of the row with `row_id`. The column that is another
+/// name for the rowid, if any, is stored as NULL and read as the rowid, and
+/// columns past the end of a short row read as their defaults.
+pub fn decode_row(
+    record: &[u8],
+    row_id: u64,
+    layout: &TableLayout,
+    encoding: TextEncoding,
+) -> Option<Row> {
+    let mut row = decode(record, encoding)?;
+    if let Some(defaults) = layout.defaults.get(row.len()..) {
+        row.extend_from_slice(defaults);
+    }
+    if let Some(value) = layout.rowid_alias.and_then(|i| row.get_mut(i)) {
+        if *value == Column::Null {
+            *value = Column::Integer(row_id as i64);
+        }
+    }
+    Some(row)
+}
+
+/// Encodes `row` as a record of a UTF-8 database, each integer in as few
+/// bytes as hold it.
+pub fn encode(row: &[Column]) -> Vec<u8> {
+    let mut types = vec![];
+    let mut body = vec![];
+    for value in row {
+        let serial_type = match value {
+            Column::Null => 0,
+            Column::Integer(0) => 8,
+            Column::Integer(1) => 9,
+            Column::Integer(i) => {
+                let (serial_type, size) = match *i {
+                    -0x80..=0x7f => (1, 1),
+                    -0x8000..=0x7fff => (2, 2),
+                    -0x80_0000..=0x7f_ffff => (3, 3),
+                    -0x8000_0000..=0x7fff_ffff => (4, 4),
+                    -0x8000_0000_0000..=0x7fff_ffff_ffff => (5, 6),
+                    _ => (6, 8),
+                };
+                body.extend_from_slice(&i.to_be_bytes()[8 - size..]);
+                serial_type
+            }
+            Column::Real(r) => {
+                body.extend_from_slice(&r.to_be_bytes());
+                7
+            }
+            Column::Text(s) => {
+                body.extend_from_slice(s.as_bytes());
+                13 + 2 * s.len() as u64
+            }
+            Column::Blob(b) => {
+                body.extend_from_slice(b);
+                12 + 2 * b.len() as u64
+            }
+        };
+        put_variant(serial_type, &mut types);
+    }
+    // The header starts with its own length, which counts itself
+    let mut header_length = types.len() + 1;
+    let mut record = vec![];
+    loop {
+        put_variant(header_length as u64, &mut record);
+        if record.len() + types.len() == header_length {
+            break;
+        }
+        header_length = record.len() + types.len();
+        record.clear();
+    }
+    record.extend(types);
+    record.extend(body);
+    record
+}
+
+/// The serial type and bytes of each value of `record`, or None if its header
+/// or its values run past its end.
+pub fn fields(record: &[u8]) -> Option<Vec<(u64, &[u8])>> {
+    // The header starts with its own length, which counts itself
+    let (header_length, header) = try_variant(record)?;
+    let header_length = usize::try_from(header_length).ok()?;
+    let (mut header, mut body) = (
+        header.get(..header_length.checked_sub(record.len() - header.len())?)?,
+        record.get(header_length..)?,
+    );
+
+    let mut fields = vec![];
+    while !header.is_empty() {
+        let (serial_type, rest) = try_variant(header)?;
+        header = rest;
+        let size = size(serial_type)?;
+        if size > body.len() {
+            return None;
+        }
+        let (bytes, rest) = body.split_at(size);
+        fields.push((serial_type, bytes));
+        body = rest;
+    }
+    Some(fields)
+}
+
+/// The number of bytes a value of `serial_type` takes up, or None for the
+/// reserved types 10 and 11.
+pub fn size(serial_type: u64) -> Option<usize> {
+    match serial_type {
+        0 | 8 | 9 => Some(0),
+        1..=4 => Some(serial_type as usize),
+        5 => Some(6),
+        6 | 7 => Some(8),
+        t if t >= 12 => Some(((t - 12) / 2) as usize),
+        _ => None,
+    }
+}
+
+/// What a value of `serial_type` is, in words.
+pub fn describe(serial_type: u64) -> String {
+    match serial_type {
+        0 => "NULL".to_string(),
+        1 => "8-bit integer".to_string(),
+        2 => "16-bit integer".to_string(),
+        3 => "24-bit integer".to_string(),
+        4 => "32-bit integer".to_string(),
+        5 => "48-bit integer".to_string(),
+        6 => "64-bit integer".to_string(),
+        7 => "64-bit float".to_string(),
+        8 => "the integer 0".to_string(),
+        9 => "the integer 1".to_string(),
+        t if t >= 12 && t % 2 == 0 => format!("blob of {} bytes", (t - 12) / 2),
+        t if t >= 13 => format!("text of {} bytes", (t - 13) / 2),
+        _ => "reserved".to_string(),
+    }
+}
+
+/// Decodes the value of `serial_type` stored in `bytes`.
+pub fn value(serial_type: u64, bytes: &[u8], encoding: TextEncoding) -> Column {
+    match serial_type {
+        0 => Column::Null,
+        // big-endian two's complement integers of 1, 2, 3, 4, 6 and 8 bytes
+        1..=6 => {
+            let mut be = if bytes[0] & 0x80 != 0 {
+                [0xff; 8]
+            } else {
+                [0; 8]
+            };
+            be[8 - bytes.len()..].copy_from_slice(bytes);
+            Column::Integer(i64::from_be_bytes(be))
+        }
+        7 => Column::Real(f64::from_be_bytes(bytes.try_into().unwrap())),
+        8 => Column::Integer(0),
+        9 => Column::Integer(1),
+        t if t % 2 == 1 => Column::Text(encoding.decode(bytes)),
+        _ => Column::Blob(bytes.to_vec()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(row: Row) {
+        let record = encode(&row);
+        let values = fields(&record)
+            .unwrap()
+            .into_iter()
+            .map(|(serial_type, bytes)| value(serial_type, bytes, TextEncoding::Utf8))
+            .collect::<Row>();
+        assert_eq!(values, row);
+        assert_eq!(decode(&record, TextEncoding::Utf8), Some(row));
+    }
+
+    #[test]
+    fn integers_of_every_size() {
+        for i in [
+            0,
+            1,
+            -1,
+            2,
+            0x7f,
+            -0x80,
+            0x80,
+            0x7fff,
+            -0x8000,
+            0x7f_ffff,
+            0x8000_0000,
+            0x7fff_ffff_ffff,
+            0x8000_0000_0000,
+            i64::MAX,
+            i64::MIN,
+        ] {
+            round_trip(vec![Column::Integer(i)]);
+        }
+    }
+
+    #[test]
+    fn integers_take_as_few_bytes_as_hold_them() {
+        let serial_types = |row: &[Column]| {
+            fields(&encode(row))
+                .unwrap()
+                .into_iter()
+                .map(|(serial_type, _)| serial_type)
+                .collect::<Vec<_>>()
+        };
+        let row = [0, 1, 2, 0x100, 0x10000, 0x1000000, 0x100000000, 1 << 48].map(Column::Integer);
+        assert_eq!(serial_types(&row), [8, 9, 1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn every_type() {
+        round_trip(vec![
+            Column::Null,
+            Column::Integer(42),
+            Column::Real(-0.5),
+            Column::Text("héllo".to_string()),
+            Column::Blob(vec![0, 1, 0xff]),
+            Column::Text(String::new()),
+            Column::Blob(vec![]),
+        ]);
+        round_trip(vec![]);
+    }
+
+    #[test]
+    fn header_longer_than_one_byte() {
+        // 130 serial types make a header whose length takes two bytes
+        round_trip(vec![Column::Integer(7); 130]);
+        round_trip(vec![Column::Text("x".repeat(200))]);
+    }
+
+    #[test]
+    fn truncated_records() {
+        let record = encode(&[Column::Text("hello".to_string()), Column::Integer(300)]);
+        for len in 0..record.len() {
+            assert_eq!(fields(&record[..len]), None, "{} bytes", len);
+        }
+    }
+}
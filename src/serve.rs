@@ -0,0 +1,709 @@
+//! `serve`: a long-running process answering queries against one database,
+//! so that editors and other programs can drive it without the database
+//! being opened and its schema read again for every query.
+
+use crate::connection::percent_decode;
+use crate::db::{Database, Options};
+use crate::output::{json_string, json_value};
+use crate::{Column, Row};
+use anyhow::{bail, Context, Result};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// The open database, which is opened anew once it is written to so that
+/// its schema is read again.
+struct Session {
+    filename: String,
+    database: Database,
+    /// The results of the latest queries by their SQL, the most recently
+    /// used last. They hold as long as the database doesn't change, and
+    /// the session is opened anew when it does.
+    cache: Vec<(String, Rc<Results>)>,
+}
+
+impl Session {
+    fn open(filename: &str, options: Options) -> Result<Session> {
+        Ok(Session {
+            filename: filename.to_string(),
+            database: Database::open_all(&[filename], options)?,
+            cache: vec![],
+        })
+    }
+
+    /// Runs `sql`, a statement or a PRAGMA, in a read transaction of its own,
+    /// or answers it from the cache if it ran since the database last changed.
+    fn query(&mut self, sql: &str) -> Result<Rc<Results>> {
+        if self.database.is_stale()? {
+            *self = Session::open(&self.filename, self.database.options.clone())?;
+        }
+        if let Some(i) = self.cache.iter().position(|(cached, _)| cached == sql) {
+            let entry = self.cache.remove(i);
+            let results = entry.1.clone();
+            self.cache.push(entry);
+            return Ok(results);
+        }
+
+        let mut rows = self.database.query(sql)?;
+        let results = Rc::new(Results {
+            columns: rows.columns().to_vec(),
+            rows: rows.by_ref().collect(),
+        });
+        rows.finish()?;
+        let capacity = self.database.options.result_cache_size;
+        if capacity > 0 {
+            if self.cache.len() == capacity {
+                self.cache.remove(0);
+            }
+            self.cache.push((sql.to_string(), results.clone()));
+        }
+        Ok(results)
+    }
+}
+
+/// The result of a query, kept to be written out whole once it succeeds.
+#[derive(Debug)]
+struct Results {
+    columns: Vec<String>,
+    rows: Vec<Row>,
+}
+
+impl Results {
+    fn to_json(&self) -> String {
+        let columns = self.columns.iter().map(|c| json_string(c));
+        let rows = self.rows.iter().map(|row| {
+            let values = row.iter().map(json_value).collect::<Vec<_>>();
+            format!("[{}]", values.join(","))
+        });
+        format!(
+            "\"columns\":[{}],\"rows\":[{}]",
+            columns.collect::<Vec<_>>().join(","),
+            rows.collect::<Vec<_>>().join(",")
+        )
+    }
+}
+
+/// Answers requests read from `input` one per line, each a JSON object such
+/// as `{"id": 1, "sql": "SELECT ..."}`, with one line of JSON each on `out`:
+/// `{"id": 1, "columns": [...], "rows": [[...], ...]}`, or
+/// `{"id": 1, "error": "..."}` if the query failed. The `id` is optional and
+/// only copied to the response, to match the two up.
+pub fn stdio(
+    filename: &str,
+    options: &Options,
+    input: impl BufRead,
+    out: &mut impl Write,
+) -> Result<()> {
+    let mut session = Session::open(filename, options.clone())?;
+    for line in input.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (id, response) = match Request::parse(&line) {
+            Ok(request) => (
+                request.id,
+                session.query(&request.sql).map(|results| results.to_json()),
+            ),
+            Err(e) => (None, Err(e)),
+        };
+        let response =
+            response.unwrap_or_else(|e| format!("\"error\":{}", json_string(&format!("{:#}", e))));
+        match id {
+            Some(id) => writeln!(out, "{{\"id\":{},{}}}", id, response)?,
+            None => writeln!(out, "{{{}}}", response)?,
+        }
+        out.flush()?;
+    }
+    Ok(())
+}
+
+/// Answers HTTP requests on `address`, one connection at a time, for as long
+/// as the process runs. `GET /query?sql=...` responds with the JSON of the
+/// results, as an object with `columns` and `rows`, or with `error` and
+/// status 400 if the query failed.
+pub fn http(filename: &str, options: &Options, address: &str) -> Result<()> {
+    let mut session = Session::open(filename, options.clone())?;
+    let listener =
+        TcpListener::bind(address).with_context(|| format!("cannot listen on {}", address))?;
+    eprintln!("listening on http://{}", listener.local_addr()?);
+    for stream in listener.incoming() {
+        // A client going away mid-request is no reason to stop serving others
+        if let Err(e) = stream
+            .map_err(anyhow::Error::from)
+            .and_then(|stream| respond(&mut session, stream))
+        {
+            eprintln!("{:#}", e);
+        }
+    }
+    Ok(())
+}
+
+/// How long the HTTP server waits for a client to send its request, and a
+/// PostgreSQL client for its startup message.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Reads one HTTP request from `stream` and writes the response.
+fn respond(session: &mut Session, mut stream: TcpStream) -> Result<()> {
+    // Clients are served one at a time, so one that connects and never
+    // sends its request mustn't hold up the rest
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // The headers are of no use, but have to be read before responding
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && header.trim_end() != "" {
+        header.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = (
+        parts.next().unwrap_or_default(),
+        parts.next().unwrap_or_default(),
+    );
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let (status, body) = if method != "GET" {
+        (
+            "405 Method Not Allowed",
+            error_json("only GET is supported"),
+        )
+    } else if path != "/query" {
+        (
+            "404 Not Found",
+            error_json("no such endpoint: use /query?sql=..."),
+        )
+    } else {
+        let sql = query.split('&').find_map(|parameter| {
+            let (key, value) = parameter.split_once('=').unwrap_or((parameter, ""));
+            // In a query string `+` is a space
+            (key == "sql").then(|| percent_decode(&value.replace('+', " ")))
+        });
+        match sql.map(|sql| session.query(&sql)) {
+            Some(Ok(results)) => ("200 OK", format!("{{{}}}", results.to_json())),
+            Some(Err(e)) => ("400 Bad Request", error_json(&format!("{:#}", e))),
+            None => ("400 Bad Request", error_json("missing the sql parameter")),
+        }
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()?;
+    Ok(())
+}
+
+fn error_json(message: &str) -> String {
+    format!("{{\"error\":{}}}", json_string(message))
+}
+
+/// Speaks enough of the PostgreSQL wire protocol on `address` for psql and
+/// other clients to connect and run queries: the startup handshake, with no
+/// authentication and no TLS, and simple queries. Each connection is served
+/// by a thread of its own, with its own session, up to
+/// [`MAX_POSTGRES_CONNECTIONS`] at once.
+/// https://www.postgresql.org/docs/current/protocol-flow.html
+pub fn postgres(filename: &str, options: &Options, address: &str) -> Result<()> {
+    // Fail early if the database can't be read
+    Session::open(filename, options.clone())?;
+    let listener =
+        TcpListener::bind(address).with_context(|| format!("cannot listen on {}", address))?;
+    eprintln!(
+        "listening for PostgreSQL clients on {}",
+        listener.local_addr()?
+    );
+    let connections = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("{}", e);
+                continue;
+            }
+        };
+        if connections.fetch_add(1, Ordering::SeqCst) >= MAX_POSTGRES_CONNECTIONS {
+            connections.fetch_sub(1, Ordering::SeqCst);
+            // As PostgreSQL answers past max_connections, without waiting
+            // for the startup message
+            let _ = stream.set_write_timeout(Some(WRITE_TIMEOUT));
+            let _ = error_fields(
+                &mut stream,
+                "FATAL",
+                "53300",
+                "sorry, too many clients already",
+            );
+            continue;
+        }
+        let filename = filename.to_string();
+        let options = options.clone();
+        let connections = connections.clone();
+        thread::spawn(move || {
+            if let Err(e) = postgres_session(&filename, options, stream) {
+                eprintln!("{:#}", e);
+            }
+            connections.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+    Ok(())
+}
+
+/// How many PostgreSQL clients are served at once. Each has a thread and a
+/// session of its own, so more are turned away rather than let in to use up
+/// the memory and threads of the process.
+const MAX_POSTGRES_CONNECTIONS: usize = 64;
+
+/// How long the server waits for a PostgreSQL client to take in a response.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a PostgreSQL client may sit between queries before it is
+/// disconnected.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// The longest startup message accepted, as in PostgreSQL, which caps it
+/// since it is read before the client has been let in.
+const MAX_STARTUP_LENGTH: usize = 10_000;
+
+/// The longest message accepted after startup, so that no client can make
+/// the server allocate more than this for one.
+const MAX_MESSAGE_LENGTH: usize = 1 << 20;
+
+/// The startup message codes of an SSL request and a cancel request, which
+/// come in place of a protocol version.
+const SSL_REQUEST: u32 = 80877103;
+const CANCEL_REQUEST: u32 = 80877102;
+const PROTOCOL_VERSION_3: u32 = 196608;
+
+/// Type OIDs of the PostgreSQL types values are described as.
+const INT8: u32 = 20;
+const FLOAT8: u32 = 701;
+const TEXT: u32 = 25;
+const BYTEA: u32 = 17;
+
+fn postgres_session(filename: &str, options: Options, stream: TcpStream) -> Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    let mut input = BufReader::new(&stream);
+    let mut out = BufWriter::new(&stream);
+
+    // The startup message has no type byte. Clients asking for TLS first
+    // are told it isn't available, and then send the startup message.
+    loop {
+        let startup = read_body(&mut input, MAX_STARTUP_LENGTH)?;
+        let Some(code) = startup.get(..4) else {
+            bail!("the startup message is too short");
+        };
+        let code = u32::from_be_bytes(code.try_into()?);
+        match code {
+            SSL_REQUEST => {
+                out.write_all(b"N")?;
+                out.flush()?;
+            }
+            CANCEL_REQUEST => return Ok(()),
+            PROTOCOL_VERSION_3 => break,
+            _ => bail!(
+                "unsupported protocol version {}.{}",
+                code >> 16,
+                code & 0xffff
+            ),
+        }
+    }
+
+    let mut session = Session::open(filename, options)?;
+    // AuthenticationOk: no password is asked for
+    message(&mut out, b'R', &0u32.to_be_bytes())?;
+    for (name, value) in [
+        ("server_version", "14.0"),
+        ("server_encoding", "UTF8"),
+        ("client_encoding", "UTF8"),
+        ("DateStyle", "ISO, MDY"),
+        ("integer_datetimes", "on"),
+        ("standard_conforming_strings", "on"),
+    ] {
+        let mut body = vec![];
+        cstring(&mut body, name);
+        cstring(&mut body, value);
+        message(&mut out, b'S', &body)?;
+    }
+    ready_for_query(&mut out)?;
+
+    loop {
+        // A client may wait long between queries, but not in the middle of one
+        stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+        let mut kind = [0];
+        if input.read(&mut kind)? == 0 {
+            return Ok(());
+        }
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        let body = read_body(&mut input, MAX_MESSAGE_LENGTH)?;
+        match kind[0] {
+            b'Q' => {
+                let sql = std::str::from_utf8(body.split(|&b| b == 0).next().unwrap_or_default())?;
+                if sql.trim().trim_end_matches(';').trim().is_empty() {
+                    message(&mut out, b'I', &[])?;
+                } else {
+                    match session.query(sql) {
+                        Ok(results) => write_results(&mut out, &results)?,
+                        Err(e) => error_response(&mut out, &format!("{:#}", e))?,
+                    }
+                }
+                ready_for_query(&mut out)?;
+            }
+            // Terminate
+            b'X' => return Ok(()),
+            _ => {
+                error_response(
+                    &mut out,
+                    "only simple queries are supported, not the extended query protocol",
+                )?;
+                ready_for_query(&mut out)?;
+            }
+        }
+    }
+}
+
+/// Reads the length of a message, which counts itself, and the rest of it,
+/// which must be no longer than `max_length` bytes in all.
+fn read_body(input: &mut impl Read, max_length: usize) -> Result<Vec<u8>> {
+    let mut length = [0; 4];
+    input.read_exact(&mut length)?;
+    let length = u32::from_be_bytes(length) as usize;
+    if !(4..=max_length).contains(&length) {
+        bail!("invalid message length {}", length);
+    }
+    let mut body = vec![0; length - 4];
+    input.read_exact(&mut body)?;
+    Ok(body)
+}
+
+fn message(out: &mut impl Write, kind: u8, body: &[u8]) -> Result<()> {
+    out.write_all(&[kind])?;
+    out.write_all(&(body.len() as u32 + 4).to_be_bytes())?;
+    out.write_all(body)?;
+    Ok(())
+}
+
+fn cstring(body: &mut Vec<u8>, s: &str) {
+    body.extend_from_slice(s.as_bytes());
+    body.push(0);
+}
+
+/// ReadyForQuery, outside of any transaction, which ends each response.
+fn ready_for_query(out: &mut impl Write) -> Result<()> {
+    message(out, b'Z', b"I")?;
+    out.flush()?;
+    Ok(())
+}
+
+fn error_response(out: &mut impl Write, text: &str) -> Result<()> {
+    error_fields(out, "ERROR", "XX000", text)
+}
+
+/// An ErrorResponse of `severity`, with SQLSTATE `code`.
+fn error_fields(out: &mut impl Write, severity: &str, code: &str, text: &str) -> Result<()> {
+    let mut body = vec![];
+    for (field, value) in [
+        (b'S', severity),
+        (b'V', severity),
+        (b'C', code),
+        (b'M', text),
+    ] {
+        body.push(field);
+        cstring(&mut body, value);
+    }
+    body.push(0);
+    message(out, b'E', &body)
+}
+
+/// RowDescription, a DataRow per row and CommandComplete. Values are all
+/// sent as text; a column is described as a bigint, a double, or a bytea
+/// when every value in it is one, and as text otherwise.
+fn write_results(out: &mut impl Write, results: &Results) -> Result<()> {
+    let mut body = (results.columns.len() as u16).to_be_bytes().to_vec();
+    for (i, name) in results.columns.iter().enumerate() {
+        let values = || {
+            results
+                .rows
+                .iter()
+                .map(move |row| &row[i])
+                .filter(|v| **v != Column::Null)
+        };
+        let type_oid = if values().all(|v| matches!(v, Column::Integer(_))) {
+            if values().next().is_some() {
+                INT8
+            } else {
+                TEXT
+            }
+        } else if values().all(|v| matches!(v, Column::Integer(_) | Column::Real(_))) {
+            FLOAT8
+        } else if values().all(|v| matches!(v, Column::Blob(_))) {
+            BYTEA
+        } else {
+            TEXT
+        };
+        cstring(&mut body, name);
+        body.extend_from_slice(&0u32.to_be_bytes()); // table OID
+        body.extend_from_slice(&0u16.to_be_bytes()); // column number
+        body.extend_from_slice(&type_oid.to_be_bytes());
+        body.extend_from_slice(&(-1i16).to_be_bytes()); // variable size
+        body.extend_from_slice(&(-1i32).to_be_bytes()); // no type modifier
+        body.extend_from_slice(&0u16.to_be_bytes()); // text format
+    }
+    message(out, b'T', &body)?;
+
+    for row in &results.rows {
+        let mut body = (row.len() as u16).to_be_bytes().to_vec();
+        for value in row {
+            let text = match value {
+                Column::Null => {
+                    body.extend_from_slice(&(-1i32).to_be_bytes());
+                    continue;
+                }
+                // bytea's hex format
+                Column::Blob(b) => format!(
+                    "\\x{}",
+                    b.iter().map(|b| format!("{:02x}", b)).collect::<String>()
+                ),
+                value => value.to_string(),
+            };
+            body.extend_from_slice(&(text.len() as u32).to_be_bytes());
+            body.extend_from_slice(text.as_bytes());
+        }
+        message(out, b'D', &body)?;
+    }
+
+    let mut body = vec![];
+    cstring(&mut body, &format!("SELECT {}", results.rows.len()));
+    message(out, b'C', &body)
+}
+
+/// A request of the stdio server.
+#[derive(Debug)]
+struct Request {
+    /// The `id` member as the JSON it was written as
+    id: Option<String>,
+    sql: String,
+}
+
+impl Request {
+    fn parse(line: &str) -> Result<Request> {
+        let mut parser = JsonParser {
+            input: line.as_bytes(),
+            at: 0,
+        };
+        let members = parser.object().context("requests are JSON objects")?;
+        parser.whitespace();
+        if parser.at != parser.input.len() {
+            bail!("trailing characters after the request");
+        }
+        let mut id = None;
+        let mut sql = None;
+        for (name, value) in members {
+            match (name.as_str(), value) {
+                ("id", Json::Raw(raw)) => id = Some(raw),
+                ("id", Json::String(s)) => id = Some(json_string(&s)),
+                ("sql", Json::String(s)) => sql = Some(s),
+                ("sql", _) => bail!("\"sql\" must be a string"),
+                _ => {}
+            }
+        }
+        Ok(Request {
+            id,
+            sql: sql.context("the request has no \"sql\"")?,
+        })
+    }
+}
+
+/// A JSON value, as far as requests need one: strings are decoded, and
+/// anything else is kept as written.
+#[derive(Debug)]
+enum Json {
+    String(String),
+    Raw(String),
+}
+
+struct JsonParser<'a> {
+    input: &'a [u8],
+    at: usize,
+}
+
+impl JsonParser<'_> {
+    fn whitespace(&mut self) {
+        while self
+            .input
+            .get(self.at)
+            .map_or(false, |b| b" \t\r\n".contains(b))
+        {
+            self.at += 1;
+        }
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<()> {
+        self.whitespace();
+        if self.input.get(self.at) != Some(&byte) {
+            bail!("expected '{}' at offset {}", byte as char, self.at);
+        }
+        self.at += 1;
+        Ok(())
+    }
+
+    /// Whether the next byte is `byte`, consuming it if so.
+    fn eat(&mut self, byte: u8) -> bool {
+        self.whitespace();
+        let found = self.input.get(self.at) == Some(&byte);
+        if found {
+            self.at += 1;
+        }
+        found
+    }
+
+    fn object(&mut self) -> Result<Vec<(String, Json)>> {
+        self.expect(b'{')?;
+        let mut members = vec![];
+        if self.eat(b'}') {
+            return Ok(members);
+        }
+        loop {
+            self.whitespace();
+            let name = self.string()?;
+            self.expect(b':')?;
+            members.push((name, self.value()?));
+            if self.eat(b'}') {
+                return Ok(members);
+            }
+            self.expect(b',')?;
+        }
+    }
+
+    fn value(&mut self) -> Result<Json> {
+        self.whitespace();
+        let start = self.at;
+        match self.input.get(self.at) {
+            Some(b'"') => return Ok(Json::String(self.string()?)),
+            Some(b'{') => {
+                self.object()?;
+            }
+            Some(b'[') => {
+                self.at += 1;
+                if !self.eat(b']') {
+                    loop {
+                        self.value()?;
+                        if self.eat(b']') {
+                            break;
+                        }
+                        self.expect(b',')?;
+                    }
+                }
+            }
+            Some(_) => {
+                // A number, true, false or null
+                while self
+                    .input
+                    .get(self.at)
+                    .map_or(false, |b| b.is_ascii_alphanumeric() || b"+-.".contains(b))
+                {
+                    self.at += 1;
+                }
+                let word = std::str::from_utf8(&self.input[start..self.at])?;
+                if !matches!(word, "true" | "false" | "null") && word.parse::<f64>().is_err() {
+                    bail!("invalid JSON value at offset {}", start);
+                }
+            }
+            None => bail!("unexpected end of the request"),
+        }
+        let raw = std::str::from_utf8(&self.input[start..self.at])?;
+        Ok(Json::Raw(raw.to_string()))
+    }
+
+    fn string(&mut self) -> Result<String> {
+        self.expect(b'"')?;
+        let mut bytes = vec![];
+        loop {
+            let Some(&byte) = self.input.get(self.at) else {
+                bail!("unterminated string");
+            };
+            self.at += 1;
+            match byte {
+                b'"' => break,
+                b'\\' => {
+                    let Some(&escape) = self.input.get(self.at) else {
+                        bail!("unterminated string");
+                    };
+                    self.at += 1;
+                    let c = match escape {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => self.unicode_escape()?,
+                        _ => bail!("invalid escape \\{}", escape as char),
+                    };
+                    bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                }
+                byte => bytes.push(byte),
+            }
+        }
+        Ok(String::from_utf8(bytes)?)
+    }
+
+    /// The character of a `\uXXXX` escape, or of two for a surrogate pair.
+    fn unicode_escape(&mut self) -> Result<char> {
+        let first = self.hex()?;
+        let code = if (0xd800..0xdc00).contains(&first) {
+            if self.input.get(self.at..self.at + 2) != Some(b"\\u") {
+                bail!("unpaired surrogate in \\u escape");
+            }
+            self.at += 2;
+            let second = self.hex()?;
+            if !(0xdc00..0xe000).contains(&second) {
+                bail!("unpaired surrogate in \\u escape");
+            }
+            0x10000 + ((first - 0xd800) << 10) + (second - 0xdc00)
+        } else {
+            first
+        };
+        char::from_u32(code).context("invalid \\u escape")
+    }
+
+    /// The 4 hex digits of a `\u` escape.
+    fn hex(&mut self) -> Result<u32> {
+        let digits = self
+            .input
+            .get(self.at..self.at + 4)
+            .and_then(|d| std::str::from_utf8(d).ok())
+            .and_then(|d| u32::from_str_radix(d, 16).ok())
+            .context("invalid \\u escape")?;
+        self.at += 4;
+        Ok(digits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn message_lengths_are_capped() {
+        let message = |length: u32| {
+            let mut bytes = length.to_be_bytes().to_vec();
+            bytes.resize(length as usize, 0);
+            bytes
+        };
+        let body = read_body(&mut &message(10_000)[..], MAX_STARTUP_LENGTH).unwrap();
+        assert_eq!(body.len(), 10_000 - 4);
+        // Refused before anything is allocated for the body
+        assert!(read_body(&mut &10_001u32.to_be_bytes()[..], MAX_STARTUP_LENGTH).is_err());
+        assert!(read_body(&mut &u32::MAX.to_be_bytes()[..], MAX_MESSAGE_LENGTH).is_err());
+        assert!(read_body(&mut &3u32.to_be_bytes()[..], MAX_MESSAGE_LENGTH).is_err());
+    }
+}
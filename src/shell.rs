@@ -0,0 +1,329 @@
+//! Running commands against an open database: the one given on the command
+//! line, or each one read at the prompt when only a database is given.
+
+use anyhow::{bail, Context, Result};
+use sqlite_starter_rust::btree::count_rows;
+use sqlite_starter_rust::connection::Connection;
+use sqlite_starter_rust::db::{Database, Options};
+use sqlite_starter_rust::exec::{Cursor, Statement};
+use sqlite_starter_rust::freelist::Freelist;
+use sqlite_starter_rust::output::{self, Mode, OutputWriter, TextFormat};
+use sqlite_starter_rust::{dump, pragma, sql};
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::time::Instant;
+
+/// How deeply `.read` may nest, as in sqlite3: a script reading itself stops
+/// here rather than running until the stack overflows
+const MAX_READ_DEPTH: usize = 25;
+
+/// How commands are run and their results written, as the options set it.
+#[derive(Debug)]
+pub struct Shell {
+    pub mode: Mode,
+    pub headers: bool,
+    pub text: TextFormat,
+    pub batch_size: usize,
+    pub memory_limit: Option<usize>,
+    /// With `--page`, the number of rows of a page of results
+    pub page_rows: Option<usize>,
+    /// With `--after`, where the page starts
+    pub after: Option<Cursor>,
+    pub stats: bool,
+    pub timer: bool,
+    /// Errors are written as JSON, rather than as text
+    pub json_errors: bool,
+}
+
+impl Shell {
+    /// Runs `command`, SQL or a dot-command, against `database`, in a read
+    /// transaction of every database it has open. `.read` runs each command
+    /// of a file in turn instead.
+    pub fn run(&mut self, database: &mut Database, command: &str) -> Result<()> {
+        self.run_nested(database, command, 0)
+    }
+
+    /// Runs `command` as [`Shell::run`] does, from within `depth` scripts
+    /// run by `.read`.
+    fn run_nested(&mut self, database: &mut Database, command: &str, depth: usize) -> Result<()> {
+        if command.split_whitespace().next() == Some(".read") {
+            let [_, path] = command.split_whitespace().collect::<Vec<_>>()[..] else {
+                bail!("Usage: .read FILE");
+            };
+            if depth == MAX_READ_DEPTH {
+                bail!("input nesting limit ({}) reached", MAX_READ_DEPTH);
+            }
+            let script =
+                fs::read_to_string(path).with_context(|| format!("cannot open \"{}\"", path))?;
+            return self.script(database, &script, depth + 1);
+        }
+        let Database {
+            connections,
+            schemas,
+            options,
+        } = database;
+        // Everything the command writes goes through the one buffer, rows and
+        // the text of dot-commands alike
+        let mut stdout = output::stdout(self.batch_size);
+        let transactions = connections
+            .iter_mut()
+            .map(Connection::begin_read)
+            .collect::<Result<Vec<_>>>()?;
+        let Connection {
+            pager,
+            format,
+            header,
+            ..
+        } = &mut connections[0];
+        let format = *format;
+
+        let start = Instant::now();
+        if command == ".dbinfo" {
+            writeln!(stdout, "database page size: {}", header.page_size)?;
+            writeln!(stdout, "write format: {}", header.write_version)?;
+            writeln!(stdout, "read format: {}", header.read_version)?;
+            writeln!(stdout, "reserved bytes: {}", header.reserved_space)?;
+            writeln!(stdout, "file change counter: {}", header.change_counter)?;
+            let page_count = header.page_count(pager.file().metadata()?.len());
+            writeln!(stdout, "database page count: {}", page_count)?;
+            let freelist = Freelist::read(pager, header.first_freelist_trunk, page_count)?;
+            writeln!(stdout, "freelist page count: {}", freelist.len())?;
+            writeln!(stdout, "schema cookie: {}", header.schema_cookie)?;
+            writeln!(
+                stdout,
+                "text encoding: {} ({})",
+                header.text_encoding as u32,
+                header.text_encoding.name()
+            )?;
+            // The entries of sqlite_schema, indexes and views included
+            let first_page = pager.get_page(1)?;
+            writeln!(
+                stdout,
+                "number of tables: {}",
+                count_rows(&first_page, pager)?
+            )?;
+        } else if command == ".tables" {
+            // Tables of attached databases are qualified with their schema
+            let names = schemas.iter().flat_map(|schema| {
+                schema
+                    .tables
+                    .iter()
+                    .filter(|t| t.name != "sqlite_sequence")
+                    .map(move |t| match schema.name.as_str() {
+                        "main" => t.name.clone(),
+                        name => format!("{}.{}", name, t.name),
+                    })
+            });
+            writeln!(stdout, "{}", names.collect::<Vec<_>>().join(" "))?;
+        } else if command.split_whitespace().next() == Some(".schema") {
+            let pattern = match command.split_whitespace().collect::<Vec<_>>()[..] {
+                [_] => None,
+                [_, pattern] => Some(pattern),
+                _ => bail!("Usage: .schema ?LIKE-PATTERN?"),
+            };
+            dump::schema(&schemas[0].tables, pattern, &mut stdout)?;
+        } else if command.split_whitespace().next() == Some(".indexes") {
+            let pattern = match command.split_whitespace().collect::<Vec<_>>()[..] {
+                [_] => None,
+                [_, pattern] => Some(pattern),
+                _ => bail!("Usage: .indexes ?LIKE-PATTERN?"),
+            };
+            dump::indexes(schemas, pattern, &mut stdout)?;
+        } else if command.split_whitespace().next() == Some(".dump") {
+            let args = command.split_whitespace().skip(1).collect::<Vec<_>>();
+            dump::dump(pager, format, &schemas[0].tables, &args, &mut stdout)?;
+        } else if command.split_whitespace().next() == Some(".mode") {
+            // For the commands after it, as `--mode` is for the first
+            match command.split_whitespace().collect::<Vec<_>>()[..] {
+                [_] => writeln!(stdout, "current output mode: {}", self.mode.name())?,
+                [_, name] => {
+                    self.mode = Mode::from_name(name)
+                        .context("mode should be one of: csv insert json list table")?
+                }
+                _ => bail!("Usage: .mode ?MODE?"),
+            }
+        } else if command.starts_with('.') {
+            bail!(
+                "unknown command: {}",
+                command.split_whitespace().next().unwrap_or(command)
+            );
+        } else if command
+            .split_whitespace()
+            .next()
+            .map_or(false, |word| word.eq_ignore_ascii_case("PRAGMA"))
+        {
+            let pragma = sql::parse_pragma(command)?;
+            let mut out = self.writer(&mut stdout);
+            pragma::execute(&pragma, schemas, connections, options, out.as_mut())?;
+        } else {
+            let mut statement = Statement::prepare(command, schemas)?;
+            statement.set_memory_limit(self.memory_limit);
+            statement.set_spill_dir(options.spill_dir());
+            let mut out = self.writer(&mut stdout);
+            match self.page_rows {
+                Some(limit) => {
+                    let page = statement.query_page(connections, limit, self.after.as_ref())?;
+                    out.begin(statement.columns())?;
+                    for row in &page.rows {
+                        out.write_row(row)?;
+                    }
+                    out.finish()?;
+                    // On stderr, to keep stdout to the rows themselves
+                    if let Some(next) = page.next {
+                        eprintln!("next page: --after {}", next);
+                    }
+                }
+                None => statement.execute(connections, out.as_mut())?,
+            }
+            drop(out);
+            if self.stats {
+                let join_stats = statement.join_stats();
+                for (label, value) in [
+                    ("Bloom Filter Probes:", &join_stats.probes),
+                    ("Bloom Filter Rejections:", &join_stats.filtered),
+                    ("Bloom Filter False Positives:", &join_stats.false_positives),
+                ] {
+                    writeln!(stdout, "{:<37}{}", label, value.get())?;
+                }
+            }
+        }
+        if self.timer && !command.starts_with('.') {
+            writeln!(
+                stdout,
+                "Run Time: real {:.3}",
+                start.elapsed().as_secs_f64()
+            )?;
+        }
+
+        stdout.flush()?;
+
+        for transaction in transactions {
+            transaction.end()?;
+        }
+        Ok(())
+    }
+
+    /// The writer of result rows in the current mode, to `out`.
+    fn writer<'a>(&self, out: &'a mut dyn Write) -> Box<dyn OutputWriter + 'a> {
+        output::writer(self.mode, Box::new(out), self.headers, self.text.clone())
+    }
+
+    /// Runs the commands in `script` in order, as `.read` does, until
+    /// `.quit` or `.exit` or the end of it. An error is reported, and the
+    /// next command run, unless stdout is a pipe no one reads any more.
+    fn script(&mut self, database: &mut Database, script: &str, depth: usize) -> Result<()> {
+        let mut pending = String::new();
+        for line in script.split_inclusive('\n') {
+            for command in commands(&mut pending, line) {
+                if command == ".quit" || command == ".exit" {
+                    return Ok(());
+                }
+                match self.run_nested(database, &command, depth) {
+                    // No later command can write anything either
+                    Err(error) if output::is_broken_pipe(&error) => return Err(error),
+                    Err(error) => self.report(&error, &command),
+                    Ok(()) => {}
+                }
+            }
+        }
+        if !pending.is_empty() {
+            bail!("incomplete input");
+        }
+        Ok(())
+    }
+
+    /// Reads commands from `input` and runs each against the databases
+    /// named by `filenames`, until `.quit` or `.exit` or the end of the
+    /// input. An error is reported, and the next command read, unless stdout
+    /// is a pipe no one reads any more.
+    ///
+    /// The databases are opened again when they change in between, so that
+    /// each command sees their schema as it is then.
+    pub fn repl(
+        &mut self,
+        filenames: &[String],
+        options: Options,
+        mut input: impl BufRead,
+        prompt: bool,
+    ) -> Result<()> {
+        let mut database = Database::open_all(filenames, options)?;
+        let mut pending = String::new();
+        loop {
+            if prompt {
+                print!(
+                    "{}",
+                    if pending.is_empty() {
+                        "sqlite> "
+                    } else {
+                        "   ...> "
+                    }
+                );
+                io::stdout().flush()?;
+            }
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                break;
+            }
+            for command in commands(&mut pending, &line) {
+                if command == ".quit" || command == ".exit" {
+                    return Ok(());
+                }
+                let result = match database.is_stale() {
+                    Ok(true) => Database::open_all(filenames, database.options.clone())
+                        .map(|reopened| database = reopened),
+                    Ok(false) => Ok(()),
+                    Err(e) => Err(e),
+                }
+                .and_then(|()| self.run(&mut database, &command));
+                match result {
+                    Err(error) if output::is_broken_pipe(&error) => return Err(error),
+                    Err(error) => self.report(&error, &command),
+                    Ok(()) => {}
+                }
+            }
+        }
+        if !pending.is_empty() {
+            bail!("incomplete input");
+        }
+        Ok(())
+    }
+
+    /// Writes an error on stderr and carries on, as the prompt does.
+    fn report(&self, error: &anyhow::Error, command: &str) {
+        if self.json_errors {
+            eprintln!("{}", output::json_error(error, Some(command)));
+        } else {
+            eprintln!("Error: {:#}", error);
+        }
+    }
+}
+
+/// Adds `line` to the SQL read so far in `pending`, and returns the commands
+/// it completes. A dot-command is a line of its own, and SQL statements end
+/// with `;`, with any number of them to a line.
+fn commands(pending: &mut String, line: &str) -> Vec<String> {
+    if pending.is_empty() && line.trim_start().starts_with('.') {
+        return vec![line.trim().to_string()];
+    }
+    pending.push_str(line);
+    let (statements, rest) = sql::split_statements(pending);
+    let statements = statements.into_iter().map(str::to_string).collect();
+    *pending = rest.to_string();
+    statements
+}
+
+/// Whether stdin is a terminal, where commands are typed at a prompt, rather
+/// than piped in.
+#[cfg(unix)]
+pub fn stdin_is_terminal() -> bool {
+    extern "C" {
+        fn isatty(fd: i32) -> i32;
+    }
+    // SAFETY: isatty only looks the descriptor up
+    unsafe { isatty(0) == 1 }
+}
+
+#[cfg(not(unix))]
+pub fn stdin_is_terminal() -> bool {
+    false
+}
@@ -0,0 +1,285 @@
+use crate::Column;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SelectStatement {
+    /// `SELECT DISTINCT`: duplicate result rows are dropped
+    pub distinct: bool,
+    pub columns: Vec<ResultColumn>,
+    pub from: Option<TableRef>,
+    /// Tables joined to the one in FROM, in order
+    pub joins: Vec<Join>,
+    pub where_clause: Option<Expr>,
+    pub group_by: Vec<Expr>,
+    pub having: Option<Expr>,
+    pub order_by: Vec<OrderingTerm>,
+    pub limit: Option<Limit>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Limit {
+    pub count: Expr,
+    pub offset: Option<Expr>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OrderingTerm {
+    pub expr: Expr,
+    pub collation: Option<String>,
+    pub descending: bool,
+    /// `NULLS FIRST` or `NULLS LAST`, which puts NULLs there whatever the
+    /// direction. Without either, NULL sorts as the smallest value.
+    pub nulls_first: Option<bool>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ResultColumn {
+    /// `*`, or `table.*`: every column of the table, in schema order
+    Star(Option<String>),
+    Expr {
+        expr: Expr,
+        /// The expression as written, which is what sqlite3 uses as the column name
+        text: String,
+        /// `expr AS alias`, which names the column instead
+        alias: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableRef {
+    /// `schema.table`: the database the table is in, when several are open
+    pub schema: Option<String>,
+    pub name: String,
+    /// `table AS alias`, the only name the table can then be referred to by
+    pub alias: Option<String>,
+}
+
+/// `JOIN table ON constraint`. A comma join has no constraint.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Join {
+    /// `LEFT [OUTER] JOIN`: rows without a match in this table are kept,
+    /// with NULL for its columns
+    pub left: bool,
+    pub table: TableRef,
+    pub constraint: Option<Expr>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Literal(Column),
+    /// A column, optionally qualified by the name of its table, and that by
+    /// the name of its database
+    Column {
+        schema: Option<String>,
+        table: Option<String>,
+        name: String,
+    },
+    /// A function call. `name(*)` is parsed with no arguments, which is how
+    /// SQLite treats `count(*)` as well.
+    Function {
+        name: String,
+        args: Vec<Expr>,
+    },
+    Not(Box<Expr>),
+    /// Unary minus
+    Negate(Box<Expr>),
+    Binary {
+        op: BinaryOp,
+        left: Box<Expr>,
+        right: Box<Expr>,
+    },
+    /// `expr [NOT] IN (list)`
+    In {
+        expr: Box<Expr>,
+        list: Vec<Expr>,
+        negated: bool,
+    },
+    /// `expr [NOT] IN (SELECT ...)`
+    InSubquery {
+        expr: Box<Expr>,
+        select: Box<SelectStatement>,
+        negated: bool,
+    },
+    /// `(SELECT ...)`: the first column of its first row, or NULL without rows
+    Subquery(Box<SelectStatement>),
+    /// `CASE [operand] WHEN when THEN then ... [ELSE otherwise] END`. With an
+    /// operand, a branch is taken when it equals `when`; without one, when
+    /// `when` is true.
+    Case {
+        operand: Option<Box<Expr>>,
+        branches: Vec<(Expr, Expr)>,
+        otherwise: Option<Box<Expr>>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    /// `IS`: like `=`, but NULL is equal to NULL instead of unknown
+    Is,
+    IsNot,
+    And,
+    Or,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Remainder,
+    /// `||`
+    Concat,
+}
+
+impl Expr {
+    /// The terms of a chain of `AND`s, which must all hold.
+    pub fn conjuncts(&self) -> Vec<&Expr> {
+        match self {
+            Expr::Binary {
+                op: BinaryOp::And,
+                left,
+                right,
+            } => {
+                let mut terms = left.conjuncts();
+                terms.extend(right.conjuncts());
+                terms
+            }
+            e => vec![e],
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CreateTable {
+    /// The database named before the table, as in `aux1.apples`
+    pub schema: Option<String>,
+    pub name: String,
+    pub columns: Vec<ColumnDef>,
+    /// The columns of a `PRIMARY KEY (...)` table constraint, if it has one
+    pub primary_key: Vec<String>,
+    /// The columns of each `UNIQUE (...)` table constraint
+    pub unique: Vec<Vec<String>>,
+    /// Declared on columns and as table constraints alike, in order
+    pub foreign_keys: Vec<ForeignKey>,
+    /// Declared on columns and as table constraints alike, in order
+    pub checks: Vec<Check>,
+    /// The table is a b-tree keyed by its primary key, with no rowid
+    pub without_rowid: bool,
+}
+
+impl CreateTable {
+    /// The column sets declared to be unique: the primary key first, if
+    /// there is one, and then those of the UNIQUE constraints.
+    pub fn unique_keys(&self) -> Vec<Vec<String>> {
+        let mut keys = vec![];
+        let primary_key = match &self.primary_key[..] {
+            [] => self
+                .columns
+                .iter()
+                .filter(|c| c.primary_key)
+                .map(|c| c.name.clone())
+                .collect(),
+            columns => columns.to_vec(),
+        };
+        if !primary_key.is_empty() {
+            keys.push(primary_key);
+        }
+        keys.extend(
+            self.columns
+                .iter()
+                .filter(|c| c.unique)
+                .map(|c| vec![c.name.clone()]),
+        );
+        keys.extend(self.unique.iter().cloned());
+        keys
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColumnDef {
+    pub name: String,
+    pub decl_type: Option<String>,
+    pub collation: Option<String>,
+    pub primary_key: bool,
+    pub unique: bool,
+    /// Rowids are never reused, as `sqlite_sequence` keeps the largest
+    pub autoincrement: bool,
+    pub not_null: bool,
+    /// The `DEFAULT` value, if any understood here was given
+    pub default: Option<Expr>,
+}
+
+/// A `CHECK` constraint: no row may make `expr` false.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Check {
+    /// The name given with `CONSTRAINT`, if any
+    pub name: Option<String>,
+    /// The expression as written
+    pub sql: String,
+    /// None for an expression the parser here doesn't understand
+    pub expr: Option<Expr>,
+}
+
+/// A `REFERENCES` clause: the values of `columns` are keys of `table`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ForeignKey {
+    pub columns: Vec<String>,
+    pub table: String,
+    /// The referenced columns, or none for the primary key of `table`
+    pub parent_columns: Vec<String>,
+    pub on_update: ForeignKeyAction,
+    pub on_delete: ForeignKeyAction,
+}
+
+/// What happens to the rows referring to a parent key when it changes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ForeignKeyAction {
+    #[default]
+    NoAction,
+    Restrict,
+    SetNull,
+    SetDefault,
+    Cascade,
+}
+
+impl ForeignKeyAction {
+    /// The action as SQL spells it.
+    pub fn name(self) -> &'static str {
+        match self {
+            ForeignKeyAction::NoAction => "NO ACTION",
+            ForeignKeyAction::Restrict => "RESTRICT",
+            ForeignKeyAction::SetNull => "SET NULL",
+            ForeignKeyAction::SetDefault => "SET DEFAULT",
+            ForeignKeyAction::Cascade => "CASCADE",
+        }
+    }
+}
+
+/// `PRAGMA [schema.]name [= argument | (argument)]`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pragma {
+    pub schema: Option<String>,
+    pub name: String,
+    pub argument: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CreateIndex {
+    /// The database named before the index, as in `aux1.by_color`
+    pub schema: Option<String>,
+    pub name: String,
+    pub table: String,
+    pub unique: bool,
+    pub columns: Vec<IndexedColumn>,
+    /// Partial indexes only cover the rows matching their WHERE clause
+    pub partial: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexedColumn {
+    pub name: String,
+    pub collation: Option<String>,
+    pub descending: bool,
+}
@@ -0,0 +1,395 @@
+//! Tokenizer, AST and parser for the subset of SQL this crate understands:
+//! queries, plus the CREATE statements stored in `sqlite_schema`.
+
+mod ast;
+mod parser;
+mod tokenizer;
+
+use crate::Error;
+use anyhow::Result;
+pub use ast::*;
+use parser::Parser;
+use tokenizer::TokenKind;
+
+fn syntax_error(message: impl Into<String>, offset: usize) -> anyhow::Error {
+    Error::ParseError {
+        message: message.into(),
+        offset,
+    }
+    .into()
+}
+
+pub fn parse_select(sql: &str) -> Result<SelectStatement> {
+    let mut parser = Parser::new(sql)?;
+    let select = parser.select()?;
+    parser.finish()?;
+    Ok(select)
+}
+
+pub fn parse_create_table(sql: &str) -> Result<CreateTable> {
+    let mut parser = Parser::new(sql)?;
+    let table = parser.create_table()?;
+    parser.finish()?;
+    Ok(table)
+}
+
+pub fn parse_pragma(sql: &str) -> Result<Pragma> {
+    let mut parser = Parser::new(sql)?;
+    let pragma = parser.pragma()?;
+    parser.finish()?;
+    Ok(pragma)
+}
+
+pub fn parse_create_index(sql: &str) -> Result<CreateIndex> {
+    let mut parser = Parser::new(sql)?;
+    let index = parser.create_index()?;
+    parser.finish()?;
+    Ok(index)
+}
+
+/// `sql`, a CREATE statement, as SQLite stores it in `sqlite_schema`: what
+/// it creates, such as `CREATE TABLE`, followed by the rest from the name
+/// on, without `TEMP`, `IF NOT EXISTS`, the schema the name is qualified
+/// with, or a `;` at the end.
+pub fn schema_sql(sql: &str) -> Result<String> {
+    let tokens = tokenizer::tokenize(sql)?;
+    let keyword = |i: usize| match tokens.get(i).map(|t| &t.kind) {
+        Some(TokenKind::Ident(word)) => Some(word.to_uppercase()),
+        _ => None,
+    };
+    let mut create = vec![];
+    let mut i = 0;
+    while let Some(word) = keyword(i) {
+        i += 1;
+        if !matches!(word.as_str(), "TEMP" | "TEMPORARY") {
+            create.push(word.clone());
+        }
+        if matches!(word.as_str(), "TABLE" | "INDEX" | "VIEW" | "TRIGGER") {
+            break;
+        }
+    }
+    if create.first().map(String::as_str) != Some("CREATE") {
+        return Err(syntax_error("not a CREATE statement", 0));
+    }
+    if keyword(i).as_deref() == Some("IF") {
+        i += 3;
+    }
+    if tokens.get(i + 1).map(|t| &t.kind) == Some(&TokenKind::Symbol(".")) {
+        i += 2;
+    }
+    let end = tokens
+        .iter()
+        .rev()
+        .find(|t| t.kind != TokenKind::Symbol(";"))
+        .map_or(0, |t| t.end);
+    let Some(name) = tokens.get(i).filter(|t| t.start < end) else {
+        return Err(syntax_error("incomplete input", sql.len()));
+    };
+    Ok(format!("{} {}", create.join(" "), &sql[name.start..end]))
+}
+
+/// Splits `sql` at each `;` that ends a statement, rather than one in a
+/// string, a quoted identifier or a comment. Returns the statements, without
+/// their `;`, and what follows the last of them, which is empty unless it
+/// begins another statement. A statement with nothing in it is left out.
+pub fn split_statements(sql: &str) -> (Vec<&str>, &str) {
+    let bytes = sql.as_bytes();
+    // Just past the next `pattern` from `from`, if there is one
+    let past =
+        |from: usize, pattern: &str| sql[from..].find(pattern).map(|n| from + n + pattern.len());
+    let mut statements = vec![];
+    let mut start = 0;
+    // Whether anything but whitespace and comments follows `start`
+    let mut blank = true;
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b';' => {
+                if !blank {
+                    statements.push(sql[start..i].trim());
+                }
+                i += 1;
+                start = i;
+                blank = true;
+            }
+            b'-' if bytes.get(i + 1) == Some(&b'-') => i = past(i, "\n").unwrap_or(sql.len()),
+            b'/' if bytes.get(i + 1) == Some(&b'*') => match past(i + 2, "*/") {
+                Some(end) => i = end,
+                // The rest of the comment is still to come
+                None => {
+                    blank = false;
+                    i = sql.len();
+                }
+            },
+            // A doubled quote in a string scans as two strings back to back
+            quote @ (b'\'' | b'"' | b'`' | b'[') => {
+                let close = match quote {
+                    b'[' => "]",
+                    b'\'' => "'",
+                    b'"' => "\"",
+                    _ => "`",
+                };
+                i = past(i + 1, close).unwrap_or(sql.len());
+                blank = false;
+            }
+            b if b.is_ascii_whitespace() => i += 1,
+            _ => {
+                blank = false;
+                i += 1;
+            }
+        }
+    }
+    (statements, if blank { "" } else { &sql[start..] })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Column;
+
+    fn column(name: &str) -> Expr {
+        Expr::Column {
+            schema: None,
+            table: None,
+            name: name.to_string(),
+        }
+    }
+
+    fn binary(op: BinaryOp, left: Expr, right: Expr) -> Expr {
+        Expr::Binary {
+            op,
+            left: Box::new(left),
+            right: Box::new(right),
+        }
+    }
+
+    #[test]
+    fn select() {
+        let select = parse_select(
+            "SELECT DISTINCT name AS n, count(*) FROM main.apples AS a \
+             LEFT JOIN colors c ON a.color = c.id \
+             WHERE a.size > 1 AND name IN ('x', 'y') \
+             GROUP BY name HAVING count(*) > 2 \
+             ORDER BY n COLLATE NOCASE DESC LIMIT 10 OFFSET 5;",
+        )
+        .unwrap();
+        assert!(select.distinct);
+        assert_eq!(
+            select.columns,
+            [
+                ResultColumn::Expr {
+                    expr: column("name"),
+                    text: "name".to_string(),
+                    alias: Some("n".to_string()),
+                },
+                ResultColumn::Expr {
+                    expr: Expr::Function {
+                        name: "count".to_string(),
+                        args: vec![],
+                    },
+                    text: "count(*)".to_string(),
+                    alias: None,
+                },
+            ]
+        );
+        assert_eq!(
+            select.from,
+            Some(TableRef {
+                schema: Some("main".to_string()),
+                name: "apples".to_string(),
+                alias: Some("a".to_string()),
+            })
+        );
+        assert_eq!(select.joins.len(), 1);
+        assert!(select.joins[0].left);
+        assert_eq!(select.joins[0].table.alias.as_deref(), Some("c"));
+        let where_clause = select.where_clause.unwrap();
+        assert_eq!(where_clause.conjuncts().len(), 2);
+        assert_eq!(
+            where_clause.conjuncts()[1],
+            &Expr::In {
+                expr: Box::new(column("name")),
+                list: vec![
+                    Expr::Literal(Column::Text("x".to_string())),
+                    Expr::Literal(Column::Text("y".to_string())),
+                ],
+                negated: false,
+            }
+        );
+        assert_eq!(select.group_by, [column("name")]);
+        assert!(select.having.is_some());
+        assert_eq!(
+            select.order_by,
+            [OrderingTerm {
+                expr: column("n"),
+                collation: Some("NOCASE".to_string()),
+                descending: true,
+                nulls_first: None,
+            }]
+        );
+        assert_eq!(
+            select.limit,
+            Some(Limit {
+                count: Expr::Literal(Column::Integer(10)),
+                offset: Some(Expr::Literal(Column::Integer(5))),
+            })
+        );
+    }
+
+    #[test]
+    fn nulls_first_and_last() {
+        let select =
+            parse_select("SELECT a FROM t ORDER BY a NULLS LAST, b DESC NULLS FIRST").unwrap();
+        let nulls = select
+            .order_by
+            .iter()
+            .map(|t| t.nulls_first)
+            .collect::<Vec<_>>();
+        assert_eq!(nulls, [Some(false), Some(true)]);
+        assert!(parse_select("SELECT a FROM t ORDER BY a NULLS").is_err());
+    }
+
+    #[test]
+    fn qualified_columns() {
+        let select = parse_select("SELECT a, t.a, aux1.t.a FROM t").unwrap();
+        let exprs = select
+            .columns
+            .iter()
+            .map(|c| match c {
+                ResultColumn::Expr { expr, .. } => expr.clone(),
+                c => panic!("{:?}", c),
+            })
+            .collect::<Vec<_>>();
+        let qualified = |schema: Option<&str>, table: &str| Expr::Column {
+            schema: schema.map(str::to_string),
+            table: Some(table.to_string()),
+            name: "a".to_string(),
+        };
+        assert_eq!(
+            exprs,
+            [
+                column("a"),
+                qualified(None, "t"),
+                qualified(Some("aux1"), "t")
+            ]
+        );
+    }
+
+    #[test]
+    fn precedence() {
+        let select = parse_select("SELECT 1 + 2 * 3 = 7 OR NOT a AND b").unwrap();
+        let [ResultColumn::Expr { expr, .. }] = &select.columns[..] else {
+            panic!("{:?}", select.columns);
+        };
+        let literal = |i| Expr::Literal(Column::Integer(i));
+        assert_eq!(
+            expr,
+            &binary(
+                BinaryOp::Or,
+                binary(
+                    BinaryOp::Eq,
+                    binary(
+                        BinaryOp::Add,
+                        literal(1),
+                        binary(BinaryOp::Multiply, literal(2), literal(3)),
+                    ),
+                    literal(7),
+                ),
+                binary(BinaryOp::And, Expr::Not(Box::new(column("a"))), column("b"),),
+            )
+        );
+    }
+
+    #[test]
+    fn create_table() {
+        let table = parse_create_table(
+            "CREATE TABLE \"order items\" (\
+             id INTEGER PRIMARY KEY AUTOINCREMENT, \
+             name TEXT NOT NULL COLLATE NOCASE UNIQUE, \
+             qty INT DEFAULT 1, \
+             order_id REFERENCES orders(id), \
+             CHECK (qty > 0))",
+        )
+        .unwrap();
+        assert_eq!(table.name, "order items");
+        let names = table
+            .columns
+            .iter()
+            .map(|c| c.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["id", "name", "qty", "order_id"]);
+        assert_eq!(table.columns[0].decl_type.as_deref(), Some("INTEGER"));
+        assert!(table.columns[0].primary_key && table.columns[0].autoincrement);
+        assert!(table.columns[1].not_null && table.columns[1].unique);
+        assert_eq!(table.columns[1].collation.as_deref(), Some("NOCASE"));
+        assert_eq!(
+            table.columns[2].default,
+            Some(Expr::Literal(Column::Integer(1)))
+        );
+        assert_eq!(table.columns[3].decl_type, None);
+        assert_eq!(table.foreign_keys.len(), 1);
+        assert_eq!(table.checks.len(), 1);
+        assert!(!table.without_rowid);
+        assert_eq!(table.unique_keys(), [vec!["id"], vec!["name"]]);
+
+        let table =
+            parse_create_table("CREATE TABLE t(a, b, PRIMARY KEY (b, a)) WITHOUT ROWID").unwrap();
+        assert!(table.without_rowid);
+        assert_eq!(table.primary_key, ["b", "a"]);
+    }
+
+    #[test]
+    fn create_index() {
+        let index = parse_create_index(
+            "CREATE UNIQUE INDEX aux1.by_name ON t (name DESC, id) WHERE id > 0",
+        )
+        .unwrap();
+        assert_eq!(index.schema.as_deref(), Some("aux1"));
+        assert_eq!(index.name, "by_name");
+        assert_eq!(index.table, "t");
+        assert!(index.unique && index.partial);
+        assert_eq!(index.columns.len(), 2);
+    }
+
+    #[test]
+    fn pragma() {
+        assert_eq!(
+            parse_pragma("PRAGMA aux1.table_info(apples)").unwrap(),
+            Pragma {
+                schema: Some("aux1".to_string()),
+                name: "table_info".to_string(),
+                argument: Some("apples".to_string()),
+            }
+        );
+    }
+
+    #[test]
+    fn syntax_errors() {
+        let offset = |sql| match parse_select(sql).unwrap_err().downcast::<Error>() {
+            Ok(Error::ParseError { offset, .. }) => offset,
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(offset("SELECT a FROM"), 13);
+        assert_eq!(offset("SELECT a b c"), 11);
+        assert_eq!(offset("SELECT (1"), 9);
+    }
+
+    #[test]
+    fn split() {
+        assert_eq!(
+            split_statements("SELECT 1; SELECT ';' -- ;\n; ;SELECT \"a;b\", [c;d]; SELECT"),
+            (
+                vec!["SELECT 1", "SELECT ';' -- ;", "SELECT \"a;b\", [c;d]"],
+                " SELECT"
+            )
+        );
+        assert_eq!(
+            split_statements("SELECT 1 /* ; */;"),
+            (vec!["SELECT 1 /* ; */"], "")
+        );
+        // Incomplete until the comment is closed
+        assert_eq!(split_statements("/* ;"), (vec![], "/* ;"));
+        assert_eq!(split_statements("  -- only a comment\n"), (vec![], ""));
+        assert_eq!(split_statements("SELECT 'a;"), (vec![], "SELECT 'a;"));
+    }
+}
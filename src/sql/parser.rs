@@ -0,0 +1,1114 @@
+use super::ast::*;
+use super::syntax_error;
+use super::tokenizer::{tokenize, Token, TokenKind};
+use crate::{Column, Error};
+use anyhow::{bail, Result};
+
+/// Keywords that can never be read as a bare column name in an expression.
+const RESERVED: &[&str] = &[
+    "ALL", "AND", "AS", "BY", "CASE", "CREATE", "CROSS", "DISTINCT", "ELSE", "END", "FROM",
+    "GROUP", "HAVING", "IN", "INDEX", "INNER", "IS", "ISNULL", "JOIN", "LEFT", "LIMIT", "NOT",
+    "NOTNULL", "NULL", "ON", "OR", "ORDER", "OUTER", "SELECT", "TABLE", "THEN", "UNIQUE", "WHEN",
+    "WHERE",
+];
+
+/// A recursive-descent parser over the tokens of one SQL statement.
+pub struct Parser<'a> {
+    sql: &'a str,
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    pub fn new(sql: &'a str) -> Result<Self> {
+        Ok(Parser {
+            sql,
+            tokens: tokenize(sql)?,
+            pos: 0,
+        })
+    }
+
+    fn peek(&self) -> Option<&TokenKind> {
+        self.tokens.get(self.pos).map(|t| &t.kind)
+    }
+
+    fn advance(&mut self) -> Option<TokenKind> {
+        let token = self.tokens.get(self.pos)?.kind.clone();
+        self.pos += 1;
+        Some(token)
+    }
+
+    /// Byte offset where the next token starts.
+    fn offset(&self) -> usize {
+        self.tokens
+            .get(self.pos)
+            .map_or(self.sql.len(), |t| t.start)
+    }
+
+    /// Source text from `start` up to the end of the last consumed token.
+    fn text_from(&self, start: usize) -> &'a str {
+        let end = self.tokens[..self.pos].last().map_or(start, |t| t.end);
+        &self.sql[start..end.max(start)]
+    }
+
+    fn error<T>(&self) -> Result<T> {
+        Err(match self.tokens.get(self.pos) {
+            Some(token) => syntax_error(
+                format!(
+                    "near \"{}\": syntax error",
+                    &self.sql[token.start..token.end]
+                ),
+                token.start,
+            ),
+            None => syntax_error("incomplete input", self.sql.len()),
+        })
+    }
+
+    fn peek_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(TokenKind::Ident(word)) if word.eq_ignore_ascii_case(keyword))
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        let found = self.peek_keyword(keyword);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    /// Consumes `keywords` if they are the next tokens, in order.
+    fn eat_keywords(&mut self, keywords: &[&str]) -> bool {
+        let found = keywords.iter().enumerate().all(|(i, keyword)| {
+            matches!(
+                self.tokens.get(self.pos + i).map(|t| &t.kind),
+                Some(TokenKind::Ident(word)) if word.eq_ignore_ascii_case(keyword)
+            )
+        });
+        if found {
+            self.pos += keywords.len();
+        }
+        found
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<()> {
+        if !self.eat_keyword(keyword) {
+            return self.error();
+        }
+        Ok(())
+    }
+
+    fn peek_symbol(&self, symbol: &str) -> bool {
+        matches!(self.peek(), Some(TokenKind::Symbol(s)) if *s == symbol)
+    }
+
+    fn eat_symbol(&mut self, symbol: &str) -> bool {
+        let found = self.peek_symbol(symbol);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn expect_symbol(&mut self, symbol: &str) -> Result<()> {
+        if !self.eat_symbol(symbol) {
+            return self.error();
+        }
+        Ok(())
+    }
+
+    fn identifier(&mut self) -> Result<String> {
+        match self.peek() {
+            Some(TokenKind::Ident(name)) | Some(TokenKind::QuotedIdent(name)) => {
+                let name = name.clone();
+                self.pos += 1;
+                Ok(name)
+            }
+            _ => self.error(),
+        }
+    }
+
+    /// An optionally schema-qualified name such as `main.apples`, as the
+    /// schema, if any, and the name. SQLite also takes a string for the
+    /// name, as in the `CREATE TABLE 'f_data'` of an FTS5 table's shadow tables.
+    fn qualified_name(&mut self) -> Result<(Option<String>, String)> {
+        let name = match self.peek() {
+            Some(TokenKind::String(name)) => {
+                let name = name.clone();
+                self.pos += 1;
+                name
+            }
+            _ => self.identifier()?,
+        };
+        if self.eat_symbol(".") {
+            return Ok((Some(name), self.identifier()?));
+        }
+        Ok((None, name))
+    }
+
+    /// Skips a balanced parenthesized group, starting at its `(`.
+    fn skip_parenthesized(&mut self) -> Result<()> {
+        self.expect_symbol("(")?;
+        let mut depth = 1;
+        while depth > 0 {
+            match self.advance() {
+                Some(TokenKind::Symbol("(")) => depth += 1,
+                Some(TokenKind::Symbol(")")) => depth -= 1,
+                Some(_) => {}
+                None => return self.error(),
+            }
+        }
+        Ok(())
+    }
+
+    /// Consumes an optional trailing `;` and checks nothing else follows.
+    pub fn finish(&mut self) -> Result<()> {
+        self.eat_symbol(";");
+        if self.peek().is_some() {
+            return self.error();
+        }
+        Ok(())
+    }
+
+    pub fn select(&mut self) -> Result<SelectStatement> {
+        self.expect_keyword("SELECT")?;
+        let distinct = self.eat_keyword("DISTINCT");
+        if !distinct {
+            self.eat_keyword("ALL");
+        }
+
+        let mut columns = vec![self.result_column()?];
+        while self.eat_symbol(",") {
+            columns.push(self.result_column()?);
+        }
+
+        let mut joins = vec![];
+        let from = if self.eat_keyword("FROM") {
+            let from = self.table_ref()?;
+            while let Some(join) = self.join()? {
+                joins.push(join);
+            }
+            Some(from)
+        } else {
+            None
+        };
+
+        let where_clause = if self.eat_keyword("WHERE") {
+            Some(self.expr()?)
+        } else {
+            None
+        };
+
+        let mut group_by = vec![];
+        if self.eat_keyword("GROUP") {
+            self.expect_keyword("BY")?;
+            loop {
+                group_by.push(self.expr()?);
+                if !self.eat_symbol(",") {
+                    break;
+                }
+            }
+        }
+        let having = if self.eat_keyword("HAVING") {
+            Some(self.expr()?)
+        } else {
+            None
+        };
+
+        let mut order_by = vec![];
+        if self.eat_keyword("ORDER") {
+            self.expect_keyword("BY")?;
+            loop {
+                order_by.push(self.ordering_term()?);
+                if !self.eat_symbol(",") {
+                    break;
+                }
+            }
+        }
+
+        let limit = if self.eat_keyword("LIMIT") {
+            let count = self.expr()?;
+            if self.eat_keyword("OFFSET") {
+                Some(Limit {
+                    count,
+                    offset: Some(self.expr()?),
+                })
+            } else if self.eat_symbol(",") {
+                // `LIMIT offset, count`
+                Some(Limit {
+                    count: self.expr()?,
+                    offset: Some(count),
+                })
+            } else {
+                Some(Limit {
+                    count,
+                    offset: None,
+                })
+            }
+        } else {
+            None
+        };
+
+        Ok(SelectStatement {
+            distinct,
+            columns,
+            from,
+            joins,
+            where_clause,
+            group_by,
+            having,
+            order_by,
+            limit,
+        })
+    }
+
+    fn table_ref(&mut self) -> Result<TableRef> {
+        let mut schema = None;
+        let mut name = self.identifier()?;
+        if self.eat_symbol(".") {
+            schema = Some(name);
+            name = self.identifier()?;
+        }
+        let alias = self.alias()?;
+        Ok(TableRef {
+            schema,
+            name,
+            alias,
+        })
+    }
+
+    /// The next join in a FROM clause, if any.
+    fn join(&mut self) -> Result<Option<Join>> {
+        if self.eat_symbol(",") {
+            return Ok(Some(Join {
+                left: false,
+                table: self.table_ref()?,
+                constraint: None,
+            }));
+        }
+        // `CROSS JOIN` only differs from `JOIN` in fixing the order of the
+        // nested loops, which is always the order written here
+        let left = self.eat_keyword("LEFT");
+        if left {
+            self.eat_keyword("OUTER");
+        }
+        let qualified = left || self.eat_keyword("CROSS") || self.eat_keyword("INNER");
+        if !qualified && !self.peek_keyword("JOIN") {
+            return Ok(None);
+        }
+        self.expect_keyword("JOIN")?;
+        let table = self.table_ref()?;
+        let constraint = if self.eat_keyword("ON") {
+            Some(self.expr()?)
+        } else {
+            None
+        };
+        Ok(Some(Join {
+            left,
+            table,
+            constraint,
+        }))
+    }
+
+    fn ordering_term(&mut self) -> Result<OrderingTerm> {
+        let expr = self.expr()?;
+        let collation = if self.eat_keyword("COLLATE") {
+            Some(self.identifier()?)
+        } else {
+            None
+        };
+        let descending = if self.eat_keyword("DESC") {
+            true
+        } else {
+            self.eat_keyword("ASC");
+            false
+        };
+        let nulls_first = if self.eat_keyword("NULLS") {
+            if self.eat_keyword("FIRST") {
+                Some(true)
+            } else {
+                self.expect_keyword("LAST")?;
+                Some(false)
+            }
+        } else {
+            None
+        };
+        Ok(OrderingTerm {
+            expr,
+            collation,
+            descending,
+            nulls_first,
+        })
+    }
+
+    fn result_column(&mut self) -> Result<ResultColumn> {
+        if self.eat_symbol("*") {
+            return Ok(ResultColumn::Star(None));
+        }
+        if let (
+            Some(TokenKind::Ident(table) | TokenKind::QuotedIdent(table)),
+            Some(TokenKind::Symbol(".")),
+            Some(TokenKind::Symbol("*")),
+        ) = (
+            self.peek(),
+            self.tokens.get(self.pos + 1).map(|t| &t.kind),
+            self.tokens.get(self.pos + 2).map(|t| &t.kind),
+        ) {
+            let table = table.clone();
+            self.pos += 3;
+            return Ok(ResultColumn::Star(Some(table)));
+        }
+
+        let start = self.offset();
+        let expr = self.expr()?;
+        let text = self.text_from(start).to_string();
+        let alias = self.alias()?;
+        Ok(ResultColumn::Expr { expr, text, alias })
+    }
+
+    /// `AS name`, or just `name` as long as it isn't a keyword. As in
+    /// SQLite, the name may also be written as a string after `AS`.
+    fn alias(&mut self) -> Result<Option<String>> {
+        if self.eat_keyword("AS") {
+            if let Some(TokenKind::String(alias)) = self.peek() {
+                let alias = alias.clone();
+                self.pos += 1;
+                return Ok(Some(alias));
+            }
+            return self.identifier().map(Some);
+        }
+        match self.peek() {
+            Some(TokenKind::Ident(name))
+                if !RESERVED.iter().any(|k| k.eq_ignore_ascii_case(name)) =>
+            {
+                self.identifier().map(Some)
+            }
+            Some(TokenKind::QuotedIdent(_)) => self.identifier().map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    fn expr(&mut self) -> Result<Expr> {
+        self.or()
+    }
+
+    fn binary(op: BinaryOp, left: Expr, right: Expr) -> Expr {
+        Expr::Binary {
+            op,
+            left: Box::new(left),
+            right: Box::new(right),
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr> {
+        let mut expr = self.and()?;
+        while self.eat_keyword("OR") {
+            expr = Self::binary(BinaryOp::Or, expr, self.and()?);
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr> {
+        let mut expr = self.not()?;
+        while self.eat_keyword("AND") {
+            expr = Self::binary(BinaryOp::And, expr, self.not()?);
+        }
+        Ok(expr)
+    }
+
+    fn not(&mut self) -> Result<Expr> {
+        if self.eat_keyword("NOT") {
+            return Ok(Expr::Not(Box::new(self.not()?)));
+        }
+        self.equality()
+    }
+
+    /// Reads one of `operators` if it comes next.
+    fn binary_op(&mut self, operators: &[(&str, BinaryOp)]) -> Option<BinaryOp> {
+        let (_, op) = operators.iter().find(|(s, _)| self.peek_symbol(s))?;
+        self.pos += 1;
+        Some(*op)
+    }
+
+    /// `IN` and `IS` have the same precedence as `=`, as in SQLite.
+    fn equality(&mut self) -> Result<Expr> {
+        let mut expr = self.relational()?;
+        loop {
+            if let Some(op) = self.binary_op(&[
+                ("=", BinaryOp::Eq),
+                ("==", BinaryOp::Eq),
+                ("!=", BinaryOp::Ne),
+                ("<>", BinaryOp::Ne),
+            ]) {
+                expr = Self::binary(op, expr, self.relational()?);
+            } else if self.eat_keyword("IS") {
+                let op = if self.eat_keyword("NOT") {
+                    BinaryOp::IsNot
+                } else {
+                    BinaryOp::Is
+                };
+                expr = Self::binary(op, expr, self.relational()?);
+            } else if self.eat_keyword("ISNULL") {
+                expr = Self::binary(BinaryOp::Is, expr, Expr::Literal(Column::Null));
+            } else if self.eat_keyword("NOTNULL") || self.eat_keywords(&["NOT", "NULL"]) {
+                expr = Self::binary(BinaryOp::IsNot, expr, Expr::Literal(Column::Null));
+            } else if let Some(negated) = self.in_operator() {
+                self.expect_symbol("(")?;
+                let operand = Box::new(expr);
+                expr = if self.peek_keyword("SELECT") {
+                    let select = Box::new(self.select()?);
+                    self.expect_symbol(")")?;
+                    Expr::InSubquery {
+                        expr: operand,
+                        select,
+                        negated,
+                    }
+                } else {
+                    Expr::In {
+                        expr: operand,
+                        list: self.in_list()?,
+                        negated,
+                    }
+                };
+            } else {
+                return Ok(expr);
+            }
+        }
+    }
+
+    /// Reads `IN` or `NOT IN` if it comes next, returning whether it is negated.
+    fn in_operator(&mut self) -> Option<bool> {
+        if self.eat_keyword("IN") {
+            Some(false)
+        } else if self.eat_keywords(&["NOT", "IN"]) {
+            Some(true)
+        } else {
+            None
+        }
+    }
+
+    /// The values after `IN (`, up to the closing parenthesis. There may be
+    /// none.
+    fn in_list(&mut self) -> Result<Vec<Expr>> {
+        let mut list = vec![];
+        if !self.eat_symbol(")") {
+            list.push(self.expr()?);
+            while self.eat_symbol(",") {
+                list.push(self.expr()?);
+            }
+            self.expect_symbol(")")?;
+        }
+        Ok(list)
+    }
+
+    /// `<`, `<=`, `>` and `>=` bind tighter than `=` and `!=`, as in SQLite.
+    fn relational(&mut self) -> Result<Expr> {
+        let mut expr = self.additive()?;
+        while let Some(op) = self.binary_op(&[
+            ("<", BinaryOp::Lt),
+            ("<=", BinaryOp::Le),
+            (">", BinaryOp::Gt),
+            (">=", BinaryOp::Ge),
+        ]) {
+            expr = Self::binary(op, expr, self.additive()?);
+        }
+        Ok(expr)
+    }
+
+    fn additive(&mut self) -> Result<Expr> {
+        let mut expr = self.multiplicative()?;
+        while let Some(op) = self.binary_op(&[("+", BinaryOp::Add), ("-", BinaryOp::Subtract)]) {
+            expr = Self::binary(op, expr, self.multiplicative()?);
+        }
+        Ok(expr)
+    }
+
+    fn multiplicative(&mut self) -> Result<Expr> {
+        let mut expr = self.concat()?;
+        while let Some(op) = self.binary_op(&[
+            ("*", BinaryOp::Multiply),
+            ("/", BinaryOp::Divide),
+            ("%", BinaryOp::Remainder),
+        ]) {
+            expr = Self::binary(op, expr, self.concat()?);
+        }
+        Ok(expr)
+    }
+
+    /// `||` binds tighter than any other binary operator, as in SQLite.
+    fn concat(&mut self) -> Result<Expr> {
+        let mut expr = self.unary()?;
+        while let Some(op) = self.binary_op(&[("||", BinaryOp::Concat)]) {
+            expr = Self::binary(op, expr, self.unary()?);
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        if self.eat_symbol("-") {
+            // A negative number is a literal, so that the smallest integer,
+            // whose magnitude doesn't fit, can still be written
+            if let Some(TokenKind::Number(n)) = self.peek() {
+                let n = n.clone();
+                self.pos += 1;
+                return number(&n, true);
+            }
+            return Ok(Expr::Negate(Box::new(self.unary()?)));
+        }
+        if self.eat_symbol("+") {
+            return self.unary();
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Expr> {
+        match self.advance() {
+            Some(TokenKind::Symbol("(")) if self.peek_keyword("SELECT") => {
+                let select = self.select()?;
+                self.expect_symbol(")")?;
+                Ok(Expr::Subquery(Box::new(select)))
+            }
+            Some(TokenKind::Symbol("(")) => {
+                let expr = self.expr()?;
+                self.expect_symbol(")")?;
+                Ok(expr)
+            }
+            Some(TokenKind::String(s)) => Ok(Expr::Literal(Column::Text(s))),
+            Some(TokenKind::Blob(b)) => Ok(Expr::Literal(Column::Blob(b))),
+            Some(TokenKind::Number(n)) => number(&n, false),
+            Some(TokenKind::Ident(word)) if word.eq_ignore_ascii_case("NULL") => {
+                Ok(Expr::Literal(Column::Null))
+            }
+            Some(TokenKind::Ident(word)) if word.eq_ignore_ascii_case("CASE") => self.case(),
+            Some(TokenKind::Ident(name)) if self.peek_symbol("(") => {
+                self.pos += 1;
+                let mut args = vec![];
+                if !self.eat_symbol("*") && !self.peek_symbol(")") {
+                    args.push(self.expr()?);
+                    while self.eat_symbol(",") {
+                        args.push(self.expr()?);
+                    }
+                }
+                self.expect_symbol(")")?;
+                Ok(Expr::Function { name, args })
+            }
+            Some(TokenKind::Ident(table) | TokenKind::QuotedIdent(table))
+                if self.peek_symbol(".") =>
+            {
+                self.pos += 1;
+                let name = self.identifier()?;
+                // `schema.table.column`
+                if self.eat_symbol(".") {
+                    return Ok(Expr::Column {
+                        schema: Some(table),
+                        table: Some(name),
+                        name: self.identifier()?,
+                    });
+                }
+                Ok(Expr::Column {
+                    schema: None,
+                    table: Some(table),
+                    name,
+                })
+            }
+            Some(TokenKind::Ident(name))
+                if !RESERVED.iter().any(|k| k.eq_ignore_ascii_case(&name)) =>
+            {
+                Ok(Expr::Column {
+                    schema: None,
+                    table: None,
+                    name,
+                })
+            }
+            Some(TokenKind::QuotedIdent(name)) => Ok(Expr::Column {
+                schema: None,
+                table: None,
+                name,
+            }),
+            Some(_) => {
+                self.pos -= 1;
+                self.error()
+            }
+            None => self.error(),
+        }
+    }
+
+    /// The rest of a `CASE` expression, after the keyword.
+    fn case(&mut self) -> Result<Expr> {
+        let operand = if self.peek_keyword("WHEN") {
+            None
+        } else {
+            Some(Box::new(self.expr()?))
+        };
+        let mut branches = vec![];
+        while self.eat_keyword("WHEN") {
+            let when = self.expr()?;
+            self.expect_keyword("THEN")?;
+            branches.push((when, self.expr()?));
+        }
+        if branches.is_empty() {
+            return self.error();
+        }
+        let otherwise = if self.eat_keyword("ELSE") {
+            Some(Box::new(self.expr()?))
+        } else {
+            None
+        };
+        self.expect_keyword("END")?;
+        Ok(Expr::Case {
+            operand,
+            branches,
+            otherwise,
+        })
+    }
+
+    pub fn create_table(&mut self) -> Result<CreateTable> {
+        self.expect_keyword("CREATE")?;
+        if !self.eat_keyword("TEMP") {
+            self.eat_keyword("TEMPORARY");
+        }
+        self.expect_keyword("TABLE")?;
+        self.if_not_exists()?;
+        let (schema, name) = self.qualified_name()?;
+
+        if self.peek_keyword("AS") {
+            return Err(Error::UnsupportedFeature("CREATE TABLE ... AS SELECT".to_string()).into());
+        }
+        self.expect_symbol("(")?;
+        let mut columns = vec![];
+        let mut foreign_keys = vec![];
+        let mut primary_key = vec![];
+        let mut unique = vec![];
+        let mut checks = vec![];
+        loop {
+            if ["CONSTRAINT", "PRIMARY", "UNIQUE", "CHECK", "FOREIGN"]
+                .iter()
+                .any(|k| self.peek_keyword(k))
+            {
+                // Table constraints only ever follow the column definitions
+                let constraint = if self.eat_keyword("CONSTRAINT") {
+                    Some(self.identifier()?)
+                } else {
+                    None
+                };
+                if self.eat_keywords(&["FOREIGN", "KEY"]) {
+                    let columns = self.column_names()?;
+                    foreign_keys.push(ForeignKey {
+                        columns,
+                        ..self.references()?
+                    });
+                } else if self.eat_keywords(&["PRIMARY", "KEY"]) {
+                    primary_key = self.key_columns()?;
+                } else if self.eat_keyword("UNIQUE") {
+                    unique.push(self.key_columns()?);
+                } else if self.eat_keyword("CHECK") {
+                    checks.push(self.check(constraint)?);
+                }
+                self.skip_definition()?;
+            } else {
+                let (column, foreign_key, column_checks) = self.column_def()?;
+                foreign_keys.extend(foreign_key);
+                checks.extend(column_checks);
+                columns.push(column);
+            }
+            if !self.eat_symbol(",") {
+                break;
+            }
+        }
+        self.expect_symbol(")")?;
+        // Table options such as WITHOUT ROWID or STRICT
+        let mut without_rowid = false;
+        while self.peek().is_some() && !self.peek_symbol(";") {
+            if self.eat_keywords(&["WITHOUT", "ROWID"]) {
+                without_rowid = true;
+            } else {
+                self.pos += 1;
+            }
+        }
+
+        Ok(CreateTable {
+            schema,
+            name,
+            columns,
+            primary_key,
+            unique,
+            foreign_keys,
+            checks,
+            without_rowid,
+        })
+    }
+
+    /// The parenthesized expression of a `CHECK` constraint. One that isn't
+    /// understood here, though SQLite may well accept it, is kept as text.
+    fn check(&mut self, name: Option<String>) -> Result<Check> {
+        let open = self.pos;
+        self.expect_symbol("(")?;
+        let start = self.offset();
+        let expr = match self.expr() {
+            Ok(expr) if self.peek_symbol(")") => Some(expr),
+            _ => None,
+        };
+        self.pos = open;
+        self.skip_parenthesized()?;
+        let sql = self.text_from(start);
+        Ok(Check {
+            name,
+            sql: sql[..sql.len() - 1].trim().to_string(),
+            expr,
+        })
+    }
+
+    /// The names in the column list of a PRIMARY KEY or UNIQUE constraint,
+    /// whose columns may have a collation and a sort order.
+    fn key_columns(&mut self) -> Result<Vec<String>> {
+        self.expect_symbol("(")?;
+        let mut names = vec![];
+        loop {
+            names.push(self.identifier()?);
+            while !self.peek_symbol(",") && !self.peek_symbol(")") {
+                if self.advance().is_none() {
+                    return self.error();
+                }
+            }
+            if !self.eat_symbol(",") {
+                break;
+            }
+        }
+        self.expect_symbol(")")?;
+        Ok(names)
+    }
+
+    fn if_not_exists(&mut self) -> Result<()> {
+        if self.eat_keyword("IF") {
+            self.expect_keyword("NOT")?;
+            self.expect_keyword("EXISTS")?;
+        }
+        Ok(())
+    }
+
+    /// Skips tokens up to the `,` or `)` that ends a column definition or
+    /// table constraint.
+    fn skip_definition(&mut self) -> Result<()> {
+        loop {
+            match self.peek() {
+                Some(TokenKind::Symbol(",")) | Some(TokenKind::Symbol(")")) => return Ok(()),
+                Some(TokenKind::Symbol("(")) => self.skip_parenthesized()?,
+                Some(_) => self.pos += 1,
+                None => return self.error(),
+            }
+        }
+    }
+
+    /// A parenthesized, comma-separated list of column names.
+    fn column_names(&mut self) -> Result<Vec<String>> {
+        self.expect_symbol("(")?;
+        let mut names = vec![self.identifier()?];
+        while self.eat_symbol(",") {
+            names.push(self.identifier()?);
+        }
+        self.expect_symbol(")")?;
+        Ok(names)
+    }
+
+    /// A `REFERENCES` clause, as a foreign key whose `columns` are for the
+    /// caller to fill in. Whether it is `DEFERRABLE` is left for the caller
+    /// to skip.
+    fn references(&mut self) -> Result<ForeignKey> {
+        self.expect_keyword("REFERENCES")?;
+        let table = self.identifier()?;
+        let parent_columns = if self.peek_symbol("(") {
+            self.column_names()?
+        } else {
+            vec![]
+        };
+        let (mut on_update, mut on_delete) = Default::default();
+        loop {
+            if self.eat_keywords(&["ON", "UPDATE"]) {
+                on_update = self.foreign_key_action()?;
+            } else if self.eat_keywords(&["ON", "DELETE"]) {
+                on_delete = self.foreign_key_action()?;
+            } else if self.eat_keyword("MATCH") {
+                // Parsed but ignored, as by SQLite
+                self.identifier()?;
+            } else {
+                break;
+            }
+        }
+        Ok(ForeignKey {
+            columns: vec![],
+            table,
+            parent_columns,
+            on_update,
+            on_delete,
+        })
+    }
+
+    fn foreign_key_action(&mut self) -> Result<ForeignKeyAction> {
+        if self.eat_keywords(&["SET", "NULL"]) {
+            Ok(ForeignKeyAction::SetNull)
+        } else if self.eat_keywords(&["SET", "DEFAULT"]) {
+            Ok(ForeignKeyAction::SetDefault)
+        } else if self.eat_keyword("CASCADE") {
+            Ok(ForeignKeyAction::Cascade)
+        } else if self.eat_keyword("RESTRICT") {
+            Ok(ForeignKeyAction::Restrict)
+        } else if self.eat_keywords(&["NO", "ACTION"]) {
+            Ok(ForeignKeyAction::NoAction)
+        } else {
+            self.error()
+        }
+    }
+
+    /// A column definition, its foreign key if it has a `REFERENCES`
+    /// clause, and its `CHECK` constraints.
+    fn column_def(&mut self) -> Result<(ColumnDef, Option<ForeignKey>, Vec<Check>)> {
+        let name = self.identifier()?;
+
+        const CONSTRAINTS: &[&str] = &[
+            "CONSTRAINT",
+            "PRIMARY",
+            "NOT",
+            "NULL",
+            "UNIQUE",
+            "CHECK",
+            "DEFAULT",
+            "COLLATE",
+            "REFERENCES",
+            "GENERATED",
+            "AS",
+        ];
+        let start = self.offset();
+        let first = self.pos;
+        while matches!(self.peek(), Some(TokenKind::Ident(word)) if !CONSTRAINTS.iter().any(|k| k.eq_ignore_ascii_case(word)))
+        {
+            self.pos += 1;
+        }
+        if self.pos > first && self.peek_symbol("(") {
+            // Size arguments, as in VARCHAR(10) or DECIMAL(10, 2)
+            self.skip_parenthesized()?;
+        }
+        let decl_type = (self.pos > first).then(|| self.text_from(start).to_string());
+
+        let mut collation = None;
+        let mut primary_key = false;
+        let mut unique = false;
+        let mut autoincrement = false;
+        let mut not_null = false;
+        let mut default = None;
+        let mut foreign_key = None;
+        let mut checks = vec![];
+        loop {
+            match self.peek() {
+                Some(TokenKind::Symbol(",")) | Some(TokenKind::Symbol(")")) => break,
+                Some(TokenKind::Symbol("(")) => self.skip_parenthesized()?,
+                Some(TokenKind::Ident(word)) if word.eq_ignore_ascii_case("COLLATE") => {
+                    self.pos += 1;
+                    collation = Some(self.identifier()?);
+                }
+                Some(TokenKind::Ident(word)) if word.eq_ignore_ascii_case("PRIMARY") => {
+                    self.pos += 1;
+                    self.expect_keyword("KEY")?;
+                    primary_key = true;
+                }
+                Some(TokenKind::Ident(word)) if word.eq_ignore_ascii_case("UNIQUE") => {
+                    self.pos += 1;
+                    unique = true;
+                }
+                Some(TokenKind::Ident(word)) if word.eq_ignore_ascii_case("AUTOINCREMENT") => {
+                    self.pos += 1;
+                    autoincrement = true;
+                }
+                Some(TokenKind::Ident(word)) if word.eq_ignore_ascii_case("NOT") => {
+                    self.pos += 1;
+                    not_null |= self.eat_keyword("NULL");
+                }
+                Some(TokenKind::Ident(word)) if word.eq_ignore_ascii_case("DEFAULT") => {
+                    self.pos += 1;
+                    default = self.default_value()?;
+                }
+                // Only a CHECK constraint's name is of any use
+                Some(TokenKind::Ident(word)) if word.eq_ignore_ascii_case("CONSTRAINT") => {
+                    self.pos += 1;
+                    let name = self.identifier()?;
+                    if self.eat_keyword("CHECK") {
+                        checks.push(self.check(Some(name))?);
+                    }
+                }
+                Some(TokenKind::Ident(word)) if word.eq_ignore_ascii_case("CHECK") => {
+                    self.pos += 1;
+                    checks.push(self.check(None)?);
+                }
+                Some(TokenKind::Ident(word)) if word.eq_ignore_ascii_case("REFERENCES") => {
+                    foreign_key = Some(ForeignKey {
+                        columns: vec![name.clone()],
+                        ..self.references()?
+                    });
+                }
+                Some(_) => self.pos += 1,
+                None => return self.error(),
+            }
+        }
+
+        let column = ColumnDef {
+            name,
+            decl_type,
+            collation,
+            primary_key,
+            unique,
+            autoincrement,
+            not_null,
+            default,
+        };
+        Ok((column, foreign_key, checks))
+    }
+
+    /// The value after `DEFAULT`: a literal, a signed number, a bare word,
+    /// which is text, or a parenthesized expression. The time of the insert,
+    /// or an expression not understood here, is None.
+    fn default_value(&mut self) -> Result<Option<Expr>> {
+        if self.peek_symbol("(") {
+            let open = self.pos;
+            self.pos += 1;
+            let expr = match self.expr() {
+                Ok(expr) if self.peek_symbol(")") => Some(expr),
+                _ => None,
+            };
+            self.pos = open;
+            self.skip_parenthesized()?;
+            return Ok(expr);
+        }
+        match self.peek() {
+            Some(TokenKind::Ident(word) | TokenKind::QuotedIdent(word))
+                if !word.eq_ignore_ascii_case("NULL") =>
+            {
+                let value = match word.to_ascii_uppercase().as_str() {
+                    "TRUE" => Some(Column::Integer(1)),
+                    "FALSE" => Some(Column::Integer(0)),
+                    "CURRENT_TIME" | "CURRENT_DATE" | "CURRENT_TIMESTAMP" => None,
+                    _ => Some(Column::Text(word.clone())),
+                };
+                self.pos += 1;
+                Ok(value.map(Expr::Literal))
+            }
+            _ => self.unary().map(Some),
+        }
+    }
+
+    pub fn pragma(&mut self) -> Result<Pragma> {
+        self.expect_keyword("PRAGMA")?;
+        let mut schema = None;
+        let mut name = self.identifier()?;
+        if self.eat_symbol(".") {
+            schema = Some(name);
+            name = self.identifier()?;
+        }
+        let argument = if self.eat_symbol("=") {
+            Some(self.pragma_value()?)
+        } else if self.eat_symbol("(") {
+            let value = self.pragma_value()?;
+            self.expect_symbol(")")?;
+            Some(value)
+        } else {
+            None
+        };
+        Ok(Pragma {
+            schema,
+            name,
+            argument,
+        })
+    }
+
+    /// A name, string or number, as the argument of a pragma.
+    fn pragma_value(&mut self) -> Result<String> {
+        if let Some(TokenKind::String(value)) = self.peek() {
+            let value = value.clone();
+            self.pos += 1;
+            return Ok(value);
+        }
+        if !matches!(
+            self.peek(),
+            Some(TokenKind::Number(_)) | Some(TokenKind::Symbol("-"))
+        ) {
+            return self.identifier();
+        }
+        let start = self.offset();
+        self.eat_symbol("-");
+        match self.peek() {
+            Some(TokenKind::Number(_)) => {
+                self.pos += 1;
+                Ok(self.text_from(start).to_string())
+            }
+            _ => self.error(),
+        }
+    }
+
+    pub fn create_index(&mut self) -> Result<CreateIndex> {
+        self.expect_keyword("CREATE")?;
+        let unique = self.eat_keyword("UNIQUE");
+        self.expect_keyword("INDEX")?;
+        self.if_not_exists()?;
+        let (schema, name) = self.qualified_name()?;
+        self.expect_keyword("ON")?;
+        let table = self.identifier()?;
+
+        self.expect_symbol("(")?;
+        let mut columns = vec![];
+        loop {
+            let name = self.identifier()?;
+            let collation = if self.eat_keyword("COLLATE") {
+                Some(self.identifier()?)
+            } else {
+                None
+            };
+            let descending = if self.eat_keyword("DESC") {
+                true
+            } else {
+                self.eat_keyword("ASC");
+                false
+            };
+            if !self.peek_symbol(",") && !self.peek_symbol(")") {
+                return Err(Error::UnsupportedFeature("indexes on expressions".to_string()).into());
+            }
+            columns.push(IndexedColumn {
+                name,
+                collation,
+                descending,
+            });
+            if !self.eat_symbol(",") {
+                break;
+            }
+        }
+        self.expect_symbol(")")?;
+
+        let partial = self.eat_keyword("WHERE");
+        if partial {
+            while self.peek().is_some() && !self.peek_symbol(";") {
+                self.pos += 1;
+            }
+        }
+
+        Ok(CreateIndex {
+            schema,
+            name,
+            table,
+            unique,
+            columns,
+            partial,
+        })
+    }
+}
+
+fn number(text: &str, negative: bool) -> Result<Expr> {
+    let text = if negative {
+        format!("-{}", text)
+    } else {
+        text.to_string()
+    };
+    // Integers too large for 64 bits are read as reals, as in SQLite
+    if let Ok(i) = text.parse::<i64>() {
+        return Ok(Expr::Literal(Column::Integer(i)));
+    }
+    match text.parse::<f64>() {
+        Ok(r) => Ok(Expr::Literal(Column::Real(r))),
+        Err(_) => bail!("malformed numeric literal: {}", text),
+    }
+}
@@ -0,0 +1,246 @@
+use super::syntax_error;
+use anyhow::Result;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenKind {
+    /// A bare word: either a keyword or an unquoted identifier
+    Ident(String),
+    /// An identifier written as `"name"`, `[name]` or `` `name` ``
+    QuotedIdent(String),
+    String(String),
+    /// A blob literal, `X'hex digits'`
+    Blob(Vec<u8>),
+    Number(String),
+    /// Punctuation and operators
+    Symbol(&'static str),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    pub kind: TokenKind,
+    /// Byte range of the token in the source text
+    pub start: usize,
+    pub end: usize,
+}
+
+// Longest first, so that e.g. `<=` wins over `<`
+const SYMBOLS: &[&str] = &[
+    "<=", ">=", "==", "!=", "<>", "||", "<<", ">>", "(", ")", ",", ";", ".", "*", "+", "-", "/",
+    "%", "=", "<", ">", "&", "|", "~",
+];
+
+pub fn tokenize(sql: &str) -> Result<Vec<Token>> {
+    let bytes = sql.as_bytes();
+    let mut tokens = vec![];
+    let mut i = 0;
+
+    while i < sql.len() {
+        let c = sql[i..].chars().next().unwrap();
+        let start = i;
+
+        if c.is_whitespace() {
+            i += c.len_utf8();
+            continue;
+        }
+        if sql[i..].starts_with("--") {
+            i = sql[i..].find('\n').map_or(sql.len(), |n| i + n + 1);
+            continue;
+        }
+        if sql[i..].starts_with("/*") {
+            i = sql[i + 2..].find("*/").map_or(sql.len(), |n| i + 2 + n + 2);
+            continue;
+        }
+
+        let kind = match c {
+            '\'' => {
+                let (text, end) = quoted(sql, i, '\'')?;
+                i = end;
+                TokenKind::String(text)
+            }
+            'x' | 'X' if bytes.get(i + 1) == Some(&b'\'') => {
+                let (hex, end) = quoted(sql, i + 1, '\'')?;
+                i = end;
+                let digits = hex
+                    .chars()
+                    .map(|c| c.to_digit(16))
+                    .collect::<Option<Vec<_>>>()
+                    .filter(|digits| digits.len() % 2 == 0);
+                let Some(digits) = digits else {
+                    return Err(unrecognized(&sql[start..i], start));
+                };
+                TokenKind::Blob(digits.chunks(2).map(|d| (d[0] * 16 + d[1]) as u8).collect())
+            }
+            '"' | '`' => {
+                let (text, end) = quoted(sql, i, c)?;
+                i = end;
+                TokenKind::QuotedIdent(text)
+            }
+            '[' => {
+                let Some(n) = sql[i..].find(']') else {
+                    return Err(unrecognized(&sql[i..], i));
+                };
+                i += n + 1;
+                TokenKind::QuotedIdent(sql[start + 1..i - 1].to_string())
+            }
+            c if c.is_ascii_digit()
+                || (c == '.' && bytes.get(i + 1).map_or(false, u8::is_ascii_digit)) =>
+            {
+                i = number_end(bytes, i);
+                if bytes
+                    .get(i)
+                    .map_or(false, |b| b.is_ascii_alphabetic() || *b == b'_')
+                {
+                    return Err(unrecognized(&sql[start..=i], start));
+                }
+                TokenKind::Number(sql[start..i].to_string())
+            }
+            c if c.is_alphabetic() || c == '_' || !c.is_ascii() => {
+                i += sql[i..]
+                    .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '$' || !c.is_ascii()))
+                    .unwrap_or(sql.len() - i);
+                TokenKind::Ident(sql[start..i].to_string())
+            }
+            _ => {
+                let Some(symbol) = SYMBOLS.iter().find(|s| sql[i..].starts_with(*s)) else {
+                    return Err(unrecognized(&c.to_string(), i));
+                };
+                i += symbol.len();
+                TokenKind::Symbol(symbol)
+            }
+        };
+
+        tokens.push(Token {
+            kind,
+            start,
+            end: i,
+        });
+    }
+
+    Ok(tokens)
+}
+
+/// Reads a token enclosed in `quote`, where a doubled quote stands for
+/// itself. Returns the unescaped text and the offset just past the token.
+fn quoted(sql: &str, start: usize, quote: char) -> Result<(String, usize)> {
+    let mut text = String::new();
+    let mut chars = sql[start + 1..].char_indices();
+    while let Some((n, c)) = chars.next() {
+        if c == quote {
+            if sql[start + 1 + n + 1..].starts_with(quote) {
+                chars.next();
+            } else {
+                return Ok((text, start + 1 + n + 1));
+            }
+        }
+        text.push(c);
+    }
+    Err(unrecognized(&sql[start..], start))
+}
+
+fn unrecognized(token: &str, offset: usize) -> anyhow::Error {
+    syntax_error(format!("unrecognized token: \"{}\"", token), offset)
+}
+
+fn number_end(bytes: &[u8], mut i: usize) -> usize {
+    let digits = |mut i: usize| {
+        while bytes.get(i).map_or(false, u8::is_ascii_digit) {
+            i += 1;
+        }
+        i
+    };
+
+    i = digits(i);
+    if bytes.get(i) == Some(&b'.') {
+        i = digits(i + 1);
+    }
+    if matches!(bytes.get(i), Some(b'e' | b'E')) {
+        let mut j = i + 1;
+        if matches!(bytes.get(j), Some(b'+' | b'-')) {
+            j += 1;
+        }
+        if bytes.get(j).map_or(false, u8::is_ascii_digit) {
+            i = digits(j);
+        }
+    }
+    i
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Error;
+
+    fn kinds(sql: &str) -> Vec<TokenKind> {
+        tokenize(sql).unwrap().into_iter().map(|t| t.kind).collect()
+    }
+
+    fn error_offset(sql: &str) -> usize {
+        match tokenize(sql).unwrap_err().downcast::<Error>() {
+            Ok(Error::ParseError { offset, .. }) => offset,
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn words_numbers_and_symbols() {
+        assert_eq!(
+            kinds("SELECT a1, _b FROM t WHERE x<=1.5e3 AND y<>.5"),
+            [
+                TokenKind::Ident("SELECT".to_string()),
+                TokenKind::Ident("a1".to_string()),
+                TokenKind::Symbol(","),
+                TokenKind::Ident("_b".to_string()),
+                TokenKind::Ident("FROM".to_string()),
+                TokenKind::Ident("t".to_string()),
+                TokenKind::Ident("WHERE".to_string()),
+                TokenKind::Ident("x".to_string()),
+                TokenKind::Symbol("<="),
+                TokenKind::Number("1.5e3".to_string()),
+                TokenKind::Ident("AND".to_string()),
+                TokenKind::Ident("y".to_string()),
+                TokenKind::Symbol("<>"),
+                TokenKind::Number(".5".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(
+            kinds(r#"'it''s' "a ""b""" [c d] `e` x'00fF'"#),
+            [
+                TokenKind::String("it's".to_string()),
+                TokenKind::QuotedIdent("a \"b\"".to_string()),
+                TokenKind::QuotedIdent("c d".to_string()),
+                TokenKind::QuotedIdent("e".to_string()),
+                TokenKind::Blob(vec![0x00, 0xff]),
+            ]
+        );
+    }
+
+    #[test]
+    fn comments_are_skipped() {
+        assert_eq!(
+            kinds("a -- b\n/* c */ d /* e"),
+            [
+                TokenKind::Ident("a".to_string()),
+                TokenKind::Ident("d".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn offsets() {
+        let tokens = tokenize("  ab || 'c'").unwrap();
+        let ranges = tokens.iter().map(|t| (t.start, t.end)).collect::<Vec<_>>();
+        assert_eq!(ranges, [(2, 4), (5, 7), (8, 11)]);
+    }
+
+    #[test]
+    fn unrecognized_tokens() {
+        assert_eq!(error_offset("a 'unterminated"), 2);
+        assert_eq!(error_offset("x'abc'"), 0);
+        assert_eq!(error_offset("1 12abc"), 2);
+        assert_eq!(error_offset("a ? b"), 2);
+    }
+}
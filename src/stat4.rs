@@ -0,0 +1,161 @@
+//! The index statistics `ANALYZE` stores in `sqlite_stat1` and
+//! `sqlite_stat4`, used to estimate how many rows a range of keys holds.
+
+use crate::btree::{rows, Direction};
+use crate::pager::Pager;
+use crate::record::{self, Format, TableLayout};
+use crate::{Collation, Column, Table};
+use anyhow::Result;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::ops::Bound;
+
+/// What the samples of an index tell about its first column.
+#[derive(Debug, Clone, Default)]
+pub struct IndexStats {
+    /// Number of entries in the index
+    rows: u64,
+    /// One sample per distinct sampled key, in index order
+    samples: Vec<Sample>,
+}
+
+#[derive(Debug, Clone)]
+struct Sample {
+    key: Column,
+    /// Number of entries whose key is less than this one
+    less: u64,
+    /// Number of entries whose key is equal to this one
+    equal: u64,
+}
+
+impl IndexStats {
+    pub fn rows(&self) -> u64 {
+        self.rows
+    }
+
+    /// Estimates the number of entries whose key is within `lower` and
+    /// `upper`, comparing under `collation`.
+    pub fn estimate(
+        &self,
+        lower: Bound<&Column>,
+        upper: Bound<&Column>,
+        collation: Collation,
+    ) -> u64 {
+        let start = match lower {
+            Bound::Unbounded => 0,
+            Bound::Included(key) => self.before(key, false, collation),
+            Bound::Excluded(key) => self.before(key, true, collation),
+        };
+        let end = match upper {
+            Bound::Unbounded => self.rows,
+            Bound::Included(key) => self.before(key, true, collation),
+            Bound::Excluded(key) => self.before(key, false, collation),
+        };
+        end.saturating_sub(start)
+    }
+
+    /// Estimates the number of entries less than `key`, or also equal to it
+    /// with `or_equal`. A key between two samples is taken to be halfway
+    /// between them.
+    fn before(&self, key: &Column, or_equal: bool, collation: Collation) -> u64 {
+        let i = self
+            .samples
+            .partition_point(|s| s.key.compare(key, collation) == Ordering::Less);
+        match self.samples.get(i) {
+            Some(s) if s.key.compare(key, collation) == Ordering::Equal => {
+                if or_equal {
+                    s.less + s.equal
+                } else {
+                    s.less
+                }
+            }
+            next => {
+                let previous = i
+                    .checked_sub(1)
+                    .map_or(0, |i| self.samples[i].less + self.samples[i].equal);
+                let next = next.map_or(self.rows, |s| s.less);
+                (previous + next.max(previous)) / 2
+            }
+        }
+    }
+}
+
+/// Loads the statistics of every index that `sqlite_stat4` has samples for,
+/// by lowercased index name. Without that table there are none.
+pub fn load(
+    pager: &mut Pager,
+    format: Format,
+    tables: &[Table],
+) -> Result<HashMap<String, IndexStats>> {
+    let mut stats: HashMap<String, IndexStats> = HashMap::new();
+    let find = |name: &str| tables.iter().find(|t| t.ty == "table" && t.name == name);
+    let Some(stat4) = find("sqlite_stat4") else {
+        return Ok(stats);
+    };
+
+    // sqlite_stat4(tbl, idx, neq, nlt, ndlt, sample)
+    let page = pager.get_page(stat4.rootpage)?;
+    for values in rows(
+        page,
+        pager,
+        format,
+        Direction::Forward,
+        &TableLayout::default(),
+    )? {
+        let (Some(index), Some(equal), Some(less), Some(Column::Blob(sample))) = (
+            values.get(1).map(text),
+            values.get(2).and_then(first_number),
+            values.get(3).and_then(first_number),
+            values.get(5),
+        ) else {
+            continue;
+        };
+        let key = record::decode(sample, format.encoding).and_then(|key| key.into_iter().next());
+        let Some(key) = key else {
+            continue;
+        };
+        let entry = stats.entry(index.to_lowercase()).or_default();
+        entry.rows = entry.rows.max(less + equal);
+        entry.samples.push(Sample { key, less, equal });
+    }
+    for entry in stats.values_mut() {
+        // Several samples have the same first column when its values repeat
+        entry.samples.sort_by_key(|s| s.less);
+        entry.samples.dedup_by_key(|s| s.less);
+    }
+
+    // sqlite_stat1(tbl, idx, stat), whose stat starts with the number of
+    // entries in the index
+    if let Some(stat1) = find("sqlite_stat1") {
+        let page = pager.get_page(stat1.rootpage)?;
+        for values in rows(
+            page,
+            pager,
+            format,
+            Direction::Forward,
+            &TableLayout::default(),
+        )? {
+            if let (Some(index), Some(rows)) = (
+                values.get(1).map(text),
+                values.get(2).and_then(first_number),
+            ) {
+                if let Some(entry) = stats.get_mut(&index.to_lowercase()) {
+                    entry.rows = entry.rows.max(rows);
+                }
+            }
+        }
+    }
+    Ok(stats)
+}
+
+fn text(value: &Column) -> &str {
+    match value {
+        Column::Text(s) => s,
+        _ => "",
+    }
+}
+
+/// The first of the space-separated numbers of a stat column.
+fn first_number(value: &Column) -> Option<u64> {
+    text(value).split_whitespace().next()?.parse().ok()
+}
@@ -0,0 +1,1042 @@
+//! Standalone commands invoked as `<command> <db> ...`, as opposed to the
+//! `<db> <sql or dot-command>` form.
+
+use crate::btree::{count_rows, index_rows, rows, rows_after, Direction};
+use crate::connection::Connection;
+use crate::db::Options;
+use crate::exec::DistinctKey;
+use crate::expr::{BoundExpr, Scope, ScopeTable};
+use crate::output::{quote_identifier, sql_literal, ColumnInfo, OutputWriter};
+use crate::pager::Pager;
+use crate::record::{Format, TableLayout};
+use crate::{load, sql, vtab};
+use crate::{
+    rowid_alias, table_columns, tables, Affinity, Collation, Column, ColumnDef, Error, Row, Table,
+};
+use anyhow::{Context, Result};
+use itertools::Itertools;
+use std::cmp::Ordering;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::iter;
+use std::mem::size_of;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The pager of the database at `path`, its format, and its schema.
+fn open(path: &str, options: &Options) -> Result<(Pager, Format, Vec<Table>)> {
+    let Connection {
+        mut pager, format, ..
+    } = Connection::open(path, options)?;
+    let tables = tables(&mut pager, format)?;
+    Ok((pager, format, tables))
+}
+
+fn table_rows(
+    path: &str,
+    options: &Options,
+    table_name: &str,
+) -> Result<(Vec<ColumnInfo>, Vec<Row>)> {
+    let (mut pager, format, tables) = open(path, options)?;
+    let Some(table) = tables
+        .iter()
+        .find(|t| t.ty == "table" && t.name == table_name)
+    else {
+        return Err(Error::UnknownTable(table_name.to_string()).into());
+    };
+
+    let columns = table_columns(&table.sql)?;
+    let layout = TableLayout::new(&columns);
+    let columns = columns
+        .into_iter()
+        .map(|c| ColumnInfo {
+            name: c.name,
+            decl_type: c.decl_type,
+            table: Some(table.name.clone()),
+        })
+        .collect();
+    let page = pager.get_page(table.rootpage)?;
+    let rows = rows(page, &mut pager, format, Direction::Forward, &layout)?;
+    Ok((columns, rows))
+}
+
+/// `counts <db>`: every table with its exact number of rows.
+pub fn counts(path: &str, options: &Options, out: &mut dyn OutputWriter) -> Result<()> {
+    let (mut pager, _, tables) = open(path, options)?;
+
+    let columns = ["table", "rows"].map(|name| ColumnInfo {
+        name: name.to_string(),
+        decl_type: None,
+        table: None,
+    });
+    out.begin(&columns)?;
+    // Virtual tables have no b-tree to count
+    for table in tables
+        .iter()
+        .filter(|t| t.ty == "table" && t.rootpage != 0 && !t.name.starts_with("sqlite_"))
+    {
+        if sql::parse_create_table(&table.sql)?.without_rowid {
+            return Err(
+                Error::UnsupportedFeature("reading a WITHOUT ROWID table".to_string()).into(),
+            );
+        }
+        let page = pager.get_page(table.rootpage)?;
+        let count = count_rows(&page, &mut pager)?;
+        out.write_row(&[
+            Column::Text(table.name.clone()),
+            Column::Integer(count as i64),
+        ])?;
+    }
+    out.finish()?;
+    Ok(())
+}
+
+/// `head <db> <table> [-n N]`: the first `n` rows of a table.
+pub fn head(
+    path: &str,
+    options: &Options,
+    table_name: &str,
+    n: usize,
+    out: &mut dyn OutputWriter,
+) -> Result<()> {
+    let (columns, rows) = table_rows(path, options, table_name)?;
+
+    out.begin(&columns)?;
+    for row in rows.into_iter().take(n) {
+        out.write_row(&row)?;
+    }
+    out.finish()?;
+    Ok(())
+}
+
+/// `sample <db> <table> -n N`: `n` rows chosen uniformly at random, shown in
+/// table order.
+pub fn sample(
+    path: &str,
+    options: &Options,
+    table_name: &str,
+    n: usize,
+    out: &mut dyn OutputWriter,
+) -> Result<()> {
+    let (columns, rows) = table_rows(path, options, table_name)?;
+
+    let seed = SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos() as u64;
+    let mut rng = XorShift(seed | 1);
+
+    // Reservoir sampling (Algorithm R), remembering each row's position so
+    // the sample can be put back in table order.
+    let mut reservoir: Vec<(usize, Row)> = Vec::with_capacity(n);
+    for (i, row) in rows.into_iter().enumerate() {
+        if i < n {
+            reservoir.push((i, row));
+        } else {
+            let j = (rng.next() % (i as u64 + 1)) as usize;
+            if j < n {
+                reservoir[j] = (i, row);
+            }
+        }
+    }
+    reservoir.sort_by_key(|(i, _)| *i);
+
+    out.begin(&columns)?;
+    for (_, row) in reservoir {
+        out.write_row(&row)?;
+    }
+    out.finish()?;
+    Ok(())
+}
+
+/// `load <new db> <table> <csv> [--index <columns>]...`: a new database
+/// holding the CSV file at `csv`, or TSV by its extension, as `table`, with
+/// a TEXT column for each field of its header line, and an index on each
+/// comma-separated list of columns of `indexes`.
+pub fn load_csv(path: &str, table: &str, csv: &str, indexes: &[String]) -> Result<()> {
+    let source = vtab::csv(table, Path::new(csv))?;
+    let indexes = indexes
+        .iter()
+        .map(|columns| {
+            let columns = columns.split(',').map(str::trim).collect::<Vec<_>>();
+            format!(
+                "CREATE INDEX {} ON {}({})",
+                quote_identifier(&format!("idx_{}_{}", table, columns.join("_"))),
+                quote_identifier(table),
+                columns.iter().map(|c| quote_identifier(c)).join(", ")
+            )
+        })
+        .collect::<Vec<_>>();
+    load::load(
+        path,
+        source.sql(),
+        &indexes,
+        source.rows()?.into_iter().map(Ok),
+    )
+}
+
+/// `analyze-table <db> <table>`: for each column, the number of NULLs, an
+/// estimate of the number of distinct values, the least and greatest value
+/// and the average length of its text, all from one pass over the table.
+pub fn analyze_table(
+    path: &str,
+    options: &Options,
+    table_name: &str,
+    out: &mut dyn OutputWriter,
+) -> Result<()> {
+    let mut profiles: Vec<Profile> = vec![];
+    let columns = scan(path, options, table_name, &mut |columns, row| {
+        profiles.resize_with(columns.len(), Profile::default);
+        // Rows written before columns were added are short, and NULL past their end
+        let values = row.into_iter().chain(iter::repeat(Column::Null));
+        for ((profile, column), value) in profiles.iter_mut().zip(columns).zip(values) {
+            profile.add(value, column);
+        }
+    })?;
+    profiles.resize_with(columns.len(), Profile::default);
+
+    let names = ["column", "nulls", "distinct", "min", "max", "avg_length"];
+    out.begin(&names.map(|name| ColumnInfo {
+        name: name.to_string(),
+        decl_type: None,
+        table: None,
+    }))?;
+    for (profile, column) in profiles.into_iter().zip(&columns) {
+        out.write_row(&[
+            Column::Text(column.name.clone()),
+            Column::Integer(profile.nulls as i64),
+            Column::Integer(profile.distinct.estimate() as i64),
+            profile.min.unwrap_or(Column::Null),
+            profile.max.unwrap_or(Column::Null),
+            match profile.texts {
+                0 => Column::Null,
+                texts => Column::Real(profile.text_length as f64 / texts as f64),
+            },
+        ])?;
+    }
+    out.finish()?;
+    Ok(())
+}
+
+/// `analyze-table <db> <table> <column> --topk N`: the `k` most frequent
+/// values of a column, with how many rows have each. The counts are kept in
+/// memory up to `memory_limit` bytes, and beyond it in temporary files,
+/// unless `temp_store` is `memory`.
+pub fn top_values(
+    path: &str,
+    options: &Options,
+    table_name: &str,
+    column_name: &str,
+    k: usize,
+    memory_limit: Option<usize>,
+    out: &mut dyn OutputWriter,
+) -> Result<()> {
+    let mut counts = SpillableCounts::new(memory_limit.unwrap_or(TOPK_MEMORY_LIMIT), options);
+    let mut position = None;
+    let mut rows = 0;
+    let mut error = None;
+    let columns = scan(path, options, table_name, &mut |columns, row| {
+        let Some(i) = *position.get_or_insert_with(|| {
+            columns
+                .iter()
+                .position(|c| c.name.eq_ignore_ascii_case(column_name))
+        }) else {
+            return;
+        };
+        rows += 1;
+        if error.is_none() {
+            // Rows written before the column was added don't have it
+            error = counts.add(row.get(i).unwrap_or(&Column::Null)).err();
+        }
+    })?;
+    if !columns
+        .iter()
+        .any(|c| c.name.eq_ignore_ascii_case(column_name))
+    {
+        return Err(Error::UnknownColumn(column_name.to_string()).into());
+    }
+    if let Some(e) = error {
+        return Err(e);
+    }
+
+    let names = ["value", "count", "percent"];
+    out.begin(&names.map(|name| ColumnInfo {
+        name: name.to_string(),
+        decl_type: None,
+        table: None,
+    }))?;
+    for (value, count) in counts.top(k)? {
+        out.write_row(&[
+            value,
+            Column::Integer(count as i64),
+            Column::Real((count as f64 * 10000.0 / rows as f64).round() / 100.0),
+        ])?;
+    }
+    out.finish()?;
+    Ok(())
+}
+
+/// `verify-constraints <db> [table]`: the rows of `table`, or of every
+/// table, with a NULL in a NOT NULL column or that make a CHECK constraint
+/// false, as a database written by a buggy tool may have. CHECK
+/// constraints that can't be evaluated here are listed on stderr as
+/// skipped.
+pub fn verify_constraints(
+    path: &str,
+    options: &Options,
+    table_name: Option<&str>,
+    out: &mut dyn OutputWriter,
+) -> Result<()> {
+    let (mut pager, format, tables) = open(path, options)?;
+    let tables = tables
+        .iter()
+        .filter(|t| t.ty == "table")
+        .filter(|t| table_name.map_or(true, |name| t.name.eq_ignore_ascii_case(name)))
+        .collect::<Vec<_>>();
+    if let (Some(name), []) = (table_name, &tables[..]) {
+        return Err(Error::UnknownTable(name.to_string()).into());
+    }
+
+    let names = ["table", "rowid", "constraint"];
+    out.begin(&names.map(|name| ColumnInfo {
+        name: name.to_string(),
+        decl_type: None,
+        table: None,
+    }))?;
+    for table in tables {
+        let create = sql::parse_create_table(&table.sql)?;
+        let columns = table_columns(&table.sql)?;
+        let not_null = create
+            .columns
+            .iter()
+            .enumerate()
+            .filter(|(_, c)| c.not_null)
+            .map(|(i, c)| (i, format!("NOT NULL {}", c.name)))
+            .collect::<Vec<_>>();
+
+        let scope = [ScopeTable {
+            schema: "main".to_string(),
+            name: table.name.clone(),
+            table: table.name.clone(),
+            columns: columns.clone(),
+        }];
+        let scope = Scope {
+            tables: &scope,
+            ..Default::default()
+        };
+        let mut checks = vec![];
+        for check in &create.checks {
+            let bound = check
+                .expr
+                .as_ref()
+                .context("not understood")
+                .and_then(|expr| BoundExpr::bind(expr, scope));
+            match bound {
+                Ok(expr) => checks.push((
+                    expr,
+                    format!("CHECK {}", check.name.as_deref().unwrap_or(&check.sql)),
+                )),
+                Err(e) => eprintln!("{}: skipped CHECK ({}): {}", table.name, check.sql, e),
+            }
+        }
+        if not_null.is_empty() && checks.is_empty() {
+            continue;
+        }
+
+        let page = pager.get_page(table.rootpage)?;
+        let mut budget = usize::MAX;
+        let rows = rows_after(
+            &page,
+            &mut pager,
+            format,
+            &TableLayout::new(&columns),
+            None,
+            &mut budget,
+        )?;
+        for (row_id, mut row) in rows {
+            // Rows written before columns were added are short
+            row.resize(columns.len(), Column::Null);
+            let failed = not_null
+                .iter()
+                .filter(|(i, _)| row[*i] == Column::Null)
+                .map(|(_, constraint)| constraint)
+                .chain(
+                    checks
+                        .iter()
+                        .filter(|(expr, _)| expr.is_false(&row))
+                        .map(|(_, constraint)| constraint),
+                );
+            for constraint in failed {
+                out.write_row(&[
+                    Column::Text(table.name.clone()),
+                    Column::Integer(row_id as i64),
+                    Column::Text(constraint.clone()),
+                ])?;
+            }
+        }
+    }
+    out.finish()?;
+    Ok(())
+}
+
+/// `find-duplicates <db> [table]`: the groups of rows of `table`, or of
+/// every table, that share a key meant to be unique, with their rowids. The
+/// entries of each UNIQUE index are read in order, where duplicates are
+/// next to each other; keys declared with PRIMARY KEY or UNIQUE are checked
+/// against the table itself, in case their index is missing or out of date.
+/// Keys with a NULL in them are never duplicates.
+pub fn find_duplicates(
+    path: &str,
+    options: &Options,
+    table_name: Option<&str>,
+    out: &mut dyn OutputWriter,
+) -> Result<()> {
+    let (mut pager, format, schema) = open(path, options)?;
+    let tables = schema
+        .iter()
+        .filter(|t| t.ty == "table")
+        .filter(|t| table_name.map_or(true, |name| t.name.eq_ignore_ascii_case(name)))
+        .collect::<Vec<_>>();
+    if let (Some(name), []) = (table_name, &tables[..]) {
+        return Err(Error::UnknownTable(name.to_string()).into());
+    }
+
+    let names = ["table", "constraint", "key", "rowids"];
+    out.begin(&names.map(|name| ColumnInfo {
+        name: name.to_string(),
+        decl_type: None,
+        table: None,
+    }))?;
+    for table in tables {
+        let create = sql::parse_create_table(&table.sql)?;
+        let columns = table_columns(&table.sql)?;
+        let rowid_alias = rowid_alias(&columns);
+        let position = |name: &String| {
+            columns
+                .iter()
+                .position(|c| c.name.eq_ignore_ascii_case(name))
+                .ok_or_else(|| Error::UnknownColumn(name.to_string()).into())
+        };
+        let mut groups: Vec<(String, Vec<Column>, Vec<u64>)> = vec![];
+
+        // The keys declared with the table; an INTEGER PRIMARY KEY is the
+        // rowid, and unique by construction
+        let has_primary_key =
+            !create.primary_key.is_empty() || create.columns.iter().any(|c| c.primary_key);
+        let mut keys = vec![];
+        for (i, key) in create.unique_keys().into_iter().enumerate() {
+            let positions = key.iter().map(position).collect::<Result<Vec<_>>>()?;
+            if rowid_alias.map_or(false, |alias| positions == [alias]) {
+                continue;
+            }
+            let kind = if i == 0 && has_primary_key {
+                "PRIMARY KEY"
+            } else {
+                "UNIQUE"
+            };
+            keys.push((format!("{} ({})", kind, key.join(", ")), positions));
+        }
+        if !keys.is_empty() {
+            let page = pager.get_page(table.rootpage)?;
+            let mut budget = usize::MAX;
+            let layout = TableLayout::new(&columns);
+            let rows = rows_after(&page, &mut pager, format, &layout, None, &mut budget)?;
+            for (constraint, positions) in keys {
+                let mut seen: HashMap<Vec<DistinctKey>, usize> = HashMap::new();
+                let mut found: Vec<(Vec<Column>, Vec<u64>)> = vec![];
+                for (row_id, row) in &rows {
+                    if positions.iter().any(|&i| row[i] == Column::Null) {
+                        continue;
+                    }
+                    let key = positions
+                        .iter()
+                        .map(|&i| DistinctKey::new(&row[i], columns[i].collation))
+                        .collect();
+                    let group = *seen.entry(key).or_insert_with(|| {
+                        found.push((positions.iter().map(|&i| row[i].clone()).collect(), vec![]));
+                        found.len() - 1
+                    });
+                    found[group].1.push(*row_id);
+                }
+                groups.extend(
+                    found
+                        .into_iter()
+                        .filter(|(_, rowids)| rowids.len() > 1)
+                        .map(|(key, rowids)| (constraint.clone(), key, rowids)),
+                );
+            }
+        }
+
+        // UNIQUE indexes, including partial ones, which only hold the rows
+        // they apply to. Those made for constraints have no SQL, and were
+        // checked above.
+        for index in schema
+            .iter()
+            .filter(|t| t.ty == "index" && t.tbl_name.eq_ignore_ascii_case(&table.name))
+        {
+            let Ok(definition) = sql::parse_create_index(&index.sql) else {
+                continue;
+            };
+            if !definition.unique {
+                continue;
+            }
+            let collations = definition
+                .columns
+                .iter()
+                .map(|c| {
+                    let collation = c.collation.as_deref().and_then(Collation::from_name);
+                    Ok(match collation {
+                        Some(collation) => collation,
+                        None => columns[position(&c.name)?].collation,
+                    })
+                })
+                .collect::<Result<Vec<_>>>()?;
+            let page = pager.get_page(index.rootpage)?;
+            let entries = index_rows(&page, &mut pager, format, Direction::Forward)?;
+            let n = collations.len();
+            let mut run: Vec<&Row> = vec![];
+            // An empty entry ends the last run
+            for entry in entries.iter().chain([&vec![]]) {
+                let same = run.first().map_or(false, |first| {
+                    entry.len() > n
+                        && collations.iter().enumerate().all(|(i, &collation)| {
+                            entry[i] != Column::Null
+                                && entry[i].compare(&first[i], collation) == Ordering::Equal
+                        })
+                });
+                if same {
+                    run.push(entry);
+                    continue;
+                }
+                if run.len() > 1 {
+                    let rowids = run
+                        .iter()
+                        .filter_map(|entry| match entry.last() {
+                            Some(Column::Integer(row_id)) => Some(*row_id as u64),
+                            _ => None,
+                        })
+                        .collect();
+                    groups.push((index.name.clone(), run[0][..n].to_vec(), rowids));
+                }
+                run = vec![entry];
+            }
+        }
+
+        for (constraint, key, rowids) in groups {
+            out.write_row(&[
+                Column::Text(table.name.clone()),
+                Column::Text(constraint),
+                Column::Text(key.iter().map(sql_literal).join(", ")),
+                Column::Text(rowids.iter().join(",")),
+            ])?;
+        }
+    }
+    out.finish()?;
+    Ok(())
+}
+
+/// The diagram languages `schema-graph` can write.
+#[derive(Debug, Clone, Copy, Default)]
+pub enum GraphFormat {
+    /// Graphviz
+    #[default]
+    Dot,
+    Mermaid,
+}
+
+impl GraphFormat {
+    pub fn from_name(name: &str) -> Option<GraphFormat> {
+        match name {
+            "dot" => Some(GraphFormat::Dot),
+            "mermaid" => Some(GraphFormat::Mermaid),
+            _ => None,
+        }
+    }
+}
+
+/// `schema-graph <db> [--format dot|mermaid]`: an entity-relationship
+/// diagram of the tables, their columns and the foreign keys between them.
+pub fn schema_graph(
+    path: &str,
+    options: &Options,
+    format: GraphFormat,
+    out: &mut dyn Write,
+) -> Result<()> {
+    let (_, _, tables) = open(path, options)?;
+    let tables = tables
+        .iter()
+        .filter(|t| t.ty == "table" && !t.name.starts_with("sqlite_"))
+        .map(|t| sql::parse_create_table(&t.sql))
+        .collect::<Result<Vec<_>>>()?;
+    let is_foreign_key = |table: &sql::CreateTable, column: &sql::ColumnDef| {
+        table
+            .foreign_keys
+            .iter()
+            .any(|key| key.columns.contains(&column.name))
+    };
+
+    match format {
+        GraphFormat::Dot => {
+            writeln!(out, "digraph schema {{")?;
+            writeln!(out, "    node [shape=record];")?;
+            for table in &tables {
+                let mut label = dot_escape(&table.name) + "|";
+                for column in &table.columns {
+                    label += &dot_escape(&column.name);
+                    if let Some(decl_type) = &column.decl_type {
+                        label += &format!(" {}", dot_escape(decl_type));
+                    }
+                    if column.primary_key {
+                        label += " PK";
+                    }
+                    if is_foreign_key(table, column) {
+                        label += " FK";
+                    }
+                    label += "\\l";
+                }
+                writeln!(
+                    out,
+                    "    {} [label=\"{{{}}}\"];",
+                    dot_id(&table.name),
+                    label
+                )?;
+            }
+            for table in &tables {
+                for key in &table.foreign_keys {
+                    writeln!(
+                        out,
+                        "    {} -> {} [label=\"{}\"];",
+                        dot_id(&table.name),
+                        dot_id(&key.table),
+                        dot_escape(&key.columns.join(", "))
+                    )?;
+                }
+            }
+            writeln!(out, "}}")?;
+        }
+        GraphFormat::Mermaid => {
+            writeln!(out, "erDiagram")?;
+            for table in &tables {
+                writeln!(out, "    {} {{", mermaid_name(&table.name))?;
+                for column in &table.columns {
+                    let keys = [
+                        column.primary_key.then_some("PK"),
+                        is_foreign_key(table, column).then_some("FK"),
+                    ];
+                    let keys = keys.iter().flatten().copied().collect::<Vec<_>>();
+                    writeln!(
+                        out,
+                        "        {} {}{}",
+                        // Every attribute needs a type, and a column may have none
+                        mermaid_name(column.decl_type.as_deref().unwrap_or("ANY")),
+                        mermaid_name(&column.name),
+                        match keys.as_slice() {
+                            [] => String::new(),
+                            keys => format!(" {}", keys.join(", ")),
+                        }
+                    )?;
+                }
+                writeln!(out, "    }}")?;
+            }
+            for table in &tables {
+                for key in &table.foreign_keys {
+                    // Many rows of the table refer to one row of the parent
+                    writeln!(
+                        out,
+                        "    {} }}o--|| {} : \"{}\"",
+                        mermaid_name(&table.name),
+                        mermaid_name(&key.table),
+                        key.columns.join(", ").replace('"', "'")
+                    )?;
+                }
+            }
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// A quoted Graphviz node id.
+fn dot_id(name: &str) -> String {
+    format!("\"{}\"", name.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Escapes the characters that mean something in a Graphviz record label.
+pub fn dot_escape(text: &str) -> String {
+    let mut escaped = String::new();
+    for c in text.chars() {
+        if "\\\"{}|<>".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Mermaid names and types are single words of letters, digits, `_` and `-`,
+/// so anything else, as in `VARCHAR(10)`, becomes `_`.
+fn mermaid_name(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            c if c.is_alphanumeric() || c == '-' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+/// Calls `f` with the columns and each row of a table, reading a batch of
+/// rows at a time so that tables of any size fit in memory. Values of REAL
+/// columns are read as reals. Returns the columns.
+fn scan(
+    path: &str,
+    options: &Options,
+    table_name: &str,
+    f: &mut dyn FnMut(&[ColumnDef], Row),
+) -> Result<Vec<ColumnDef>> {
+    let (mut pager, format, tables) = open(path, options)?;
+    let Some(table) = tables
+        .iter()
+        .find(|t| t.ty == "table" && t.name == table_name)
+    else {
+        return Err(Error::UnknownTable(table_name.to_string()).into());
+    };
+    let columns = table_columns(&table.sql)?;
+    let layout = TableLayout::new(&columns);
+
+    let page = pager.get_page(table.rootpage)?;
+    let mut after = None;
+    loop {
+        let mut budget = SCAN_BATCH_ROWS;
+        let batch = rows_after(&page, &mut pager, format, &layout, after, &mut budget)?;
+        let exhausted = batch.len() < SCAN_BATCH_ROWS;
+        for (row_id, row) in batch {
+            after = Some(row_id);
+            let row = row
+                .into_iter()
+                .zip(&columns)
+                .map(|(value, column)| match (column.affinity(), value) {
+                    (Affinity::Real, Column::Integer(i)) => Column::Real(i as f64),
+                    (_, value) => value,
+                })
+                .collect::<Row>();
+            f(&columns, row);
+        }
+        if exhausted {
+            break;
+        }
+    }
+    Ok(columns)
+}
+
+const SCAN_BATCH_ROWS: usize = 1024;
+
+/// Bytes of counts `--topk` keeps in memory without `--memory-limit`
+const TOPK_MEMORY_LIMIT: usize = 64 << 20;
+
+/// Number of files counts are spilled to, each added up on its own
+const SPILL_PARTITIONS: u64 = 16;
+
+/// Counts of values, kept in a hash map until it grows past a number of
+/// bytes. The map is then written out and emptied, each count to one of
+/// several temporary files by the hash of its value. All the counts of a
+/// value end up in the same file, which can then be added up by itself.
+struct SpillableCounts {
+    counts: HashMap<ValueKey, u64>,
+    /// Approximate size of `counts`
+    bytes: usize,
+    limit: usize,
+    /// Where the files go, or None if `temp_store` keeps the counts in
+    /// memory however many there are
+    spill_dir: Option<PathBuf>,
+    spills: Vec<(PathBuf, BufWriter<File>)>,
+}
+
+/// A value as a hash map key.
+#[derive(Debug, PartialEq, Eq, Hash)]
+enum ValueKey {
+    Null,
+    Integer(i64),
+    Real(u64),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+impl ValueKey {
+    fn new(value: &Column) -> ValueKey {
+        match value {
+            Column::Null => ValueKey::Null,
+            Column::Integer(i) => ValueKey::Integer(*i),
+            Column::Real(r) => ValueKey::Real(r.to_bits()),
+            Column::Text(s) => ValueKey::Text(s.clone()),
+            Column::Blob(b) => ValueKey::Blob(b.clone()),
+        }
+    }
+
+    fn value(self) -> Column {
+        match self {
+            ValueKey::Null => Column::Null,
+            ValueKey::Integer(i) => Column::Integer(i),
+            ValueKey::Real(bits) => Column::Real(f64::from_bits(bits)),
+            ValueKey::Text(s) => Column::Text(s),
+            ValueKey::Blob(b) => Column::Blob(b),
+        }
+    }
+
+    fn size(&self) -> usize {
+        size_of::<(ValueKey, u64)>()
+            + match self {
+                ValueKey::Text(s) => s.len(),
+                ValueKey::Blob(b) => b.len(),
+                _ => 0,
+            }
+    }
+
+    /// Writes the key and `count` to a spill file: a tag byte, the value,
+    /// with text and blobs preceded by their length, and the count.
+    fn write(&self, count: u64, out: &mut impl Write) -> io::Result<()> {
+        match self {
+            ValueKey::Null => out.write_all(&[0])?,
+            ValueKey::Integer(i) => {
+                out.write_all(&[1])?;
+                out.write_all(&i.to_be_bytes())?;
+            }
+            ValueKey::Real(bits) => {
+                out.write_all(&[2])?;
+                out.write_all(&bits.to_be_bytes())?;
+            }
+            ValueKey::Text(s) => {
+                out.write_all(&[3])?;
+                out.write_all(&(s.len() as u64).to_be_bytes())?;
+                out.write_all(s.as_bytes())?;
+            }
+            ValueKey::Blob(b) => {
+                out.write_all(&[4])?;
+                out.write_all(&(b.len() as u64).to_be_bytes())?;
+                out.write_all(b)?;
+            }
+        }
+        out.write_all(&count.to_be_bytes())
+    }
+
+    /// Reads back a key and count written by `write`, or None at the end.
+    fn read(input: &mut impl Read) -> io::Result<Option<(ValueKey, u64)>> {
+        let mut tag = [0];
+        if input.read(&mut tag)? == 0 {
+            return Ok(None);
+        }
+        let mut number = || -> io::Result<u64> {
+            let mut bytes = [0; 8];
+            input.read_exact(&mut bytes)?;
+            Ok(u64::from_be_bytes(bytes))
+        };
+        let key = match tag[0] {
+            0 => ValueKey::Null,
+            1 => ValueKey::Integer(number()? as i64),
+            2 => ValueKey::Real(number()?),
+            tag => {
+                let mut bytes = vec![0; number()? as usize];
+                input.read_exact(&mut bytes)?;
+                match tag {
+                    3 => ValueKey::Text(String::from_utf8_lossy(&bytes).into_owned()),
+                    _ => ValueKey::Blob(bytes),
+                }
+            }
+        };
+        let mut count = [0; 8];
+        input.read_exact(&mut count)?;
+        Ok(Some((key, u64::from_be_bytes(count))))
+    }
+}
+
+impl SpillableCounts {
+    fn new(limit: usize, options: &Options) -> SpillableCounts {
+        SpillableCounts {
+            counts: HashMap::new(),
+            bytes: 0,
+            limit,
+            spill_dir: options.spill_dir(),
+            spills: vec![],
+        }
+    }
+
+    fn add(&mut self, value: &Column) -> Result<()> {
+        let key = ValueKey::new(value);
+        match self.counts.get_mut(&key) {
+            Some(count) => *count += 1,
+            None => {
+                self.bytes += key.size();
+                self.counts.insert(key, 1);
+                if self.bytes > self.limit && self.spill_dir.is_some() {
+                    self.spill()?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the counts in memory out to the spill files, and empties them.
+    fn spill(&mut self) -> Result<()> {
+        if self.spills.is_empty() {
+            let Some(dir) = &self.spill_dir else {
+                return Ok(());
+            };
+            for i in 0..SPILL_PARTITIONS {
+                let path = dir.join(format!("sqlite-topk-{}-{}", std::process::id(), i));
+                let file = File::create(&path)
+                    .with_context(|| format!("cannot create {}", path.display()))?;
+                self.spills.push((path, BufWriter::new(file)));
+            }
+        }
+        for (key, count) in self.counts.drain() {
+            let mut hasher = DefaultHasher::new();
+            key.hash(&mut hasher);
+            let (_, out) = &mut self.spills[(hasher.finish() % SPILL_PARTITIONS) as usize];
+            key.write(count, out)?;
+        }
+        self.bytes = 0;
+        Ok(())
+    }
+
+    /// The `k` values counted most often, most often first, and values
+    /// counted as often in the order they sort in.
+    fn top(mut self, k: usize) -> Result<Vec<(Column, u64)>> {
+        let mut top = vec![];
+        let keep = |counts: HashMap<ValueKey, u64>, top: &mut Vec<(Column, u64)>| {
+            top.extend(counts.into_iter().map(|(key, count)| (key.value(), count)));
+            top.sort_by(|(a, m), (b, n)| n.cmp(m).then_with(|| a.compare(b, Collation::Binary)));
+            top.truncate(k);
+        };
+        if self.spills.is_empty() {
+            keep(std::mem::take(&mut self.counts), &mut top);
+            return Ok(top);
+        }
+        self.spill()?;
+        for (path, out) in &mut self.spills {
+            out.flush()?;
+            let mut input = BufReader::new(File::open(&path)?);
+            let mut counts: HashMap<ValueKey, u64> = HashMap::new();
+            while let Some((key, count)) = ValueKey::read(&mut input)? {
+                *counts.entry(key).or_default() += count;
+            }
+            keep(counts, &mut top);
+        }
+        Ok(top)
+    }
+}
+
+impl Drop for SpillableCounts {
+    fn drop(&mut self) {
+        for (path, _) in &self.spills {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// What `analyze-table` has found out about a column so far.
+#[derive(Default)]
+struct Profile {
+    nulls: u64,
+    distinct: HyperLogLog,
+    min: Option<Column>,
+    max: Option<Column>,
+    /// Number of text values, and their total length in characters
+    texts: u64,
+    text_length: u64,
+}
+
+impl Profile {
+    fn add(&mut self, value: Column, column: &ColumnDef) {
+        if value == Column::Null {
+            self.nulls += 1;
+            return;
+        }
+        self.distinct.add(&value);
+        if let Column::Text(s) = &value {
+            self.texts += 1;
+            self.text_length += s.chars().count() as u64;
+        }
+        let collation = column.collation;
+        if self
+            .min
+            .as_ref()
+            .map_or(true, |min| value.compare(min, collation) == Ordering::Less)
+        {
+            self.min = Some(value.clone());
+        }
+        if self.max.as_ref().map_or(true, |max| {
+            value.compare(max, collation) == Ordering::Greater
+        }) {
+            self.max = Some(value);
+        }
+    }
+}
+
+/// Estimates the number of distinct values added, in a few kilobytes
+/// whatever their number, by the HyperLogLog algorithm.
+struct HyperLogLog {
+    /// For each bucket of hashes, the greatest number of leading zeros plus
+    /// one seen in the rest of the hash
+    registers: Vec<u8>,
+}
+
+/// log2 of the number of registers, which makes the error about 1.6%
+const HYPERLOGLOG_BITS: u32 = 12;
+
+impl Default for HyperLogLog {
+    fn default() -> Self {
+        HyperLogLog {
+            registers: vec![0; 1 << HYPERLOGLOG_BITS],
+        }
+    }
+}
+
+impl HyperLogLog {
+    fn add(&mut self, value: &Column) {
+        let mut hasher = DefaultHasher::new();
+        // Equal values hash the same, 1 and 1.0 included
+        match value {
+            Column::Null => {}
+            Column::Integer(i) => (0u8, *i).hash(&mut hasher),
+            Column::Real(r) if r.fract() == 0.0 && r.abs() < 9.2e18 => {
+                (0u8, *r as i64).hash(&mut hasher)
+            }
+            Column::Real(r) => (1u8, r.to_bits()).hash(&mut hasher),
+            Column::Text(s) => (2u8, s).hash(&mut hasher),
+            Column::Blob(b) => (3u8, b).hash(&mut hasher),
+        }
+        let hash = hasher.finish();
+        let bucket = (hash >> (64 - HYPERLOGLOG_BITS)) as usize;
+        let rank = ((hash << HYPERLOGLOG_BITS) | 1 << (HYPERLOGLOG_BITS - 1)).leading_zeros() + 1;
+        self.registers[bucket] = self.registers[bucket].max(rank as u8);
+    }
+
+    fn estimate(&self) -> u64 {
+        let m = self.registers.len() as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum = self
+            .registers
+            .iter()
+            .map(|&r| 2f64.powi(-(r as i32)))
+            .sum::<f64>();
+        let estimate = alpha * m * m / sum;
+        let empty = self.registers.iter().filter(|&&r| r == 0).count();
+        // Few values leave many registers empty, and are better counted by
+        // how many are
+        if estimate <= 2.5 * m && empty > 0 {
+            (m * (m / empty as f64).ln()).round() as u64
+        } else {
+            estimate.round() as u64
+        }
+    }
+}
+
+struct XorShift(u64);
+
+impl XorShift {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
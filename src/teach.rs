@@ -0,0 +1,101 @@
+//! `--teach`: a walkthrough of the file format as a query reads it, written
+//! to stderr alongside the results. It tells what the header holds, which
+//! pages are read and what kind they are, where their cells start, and what
+//! the serial types of each record decode to.
+
+//!
+//! Each function writes its part unconditionally: the pager and connection
+//! only call them when their `Options` ask for it.
+
+use crate::header::DatabaseHeader;
+use crate::pager;
+use crate::record;
+
+pub fn header(header: &DatabaseHeader) {
+    eprintln!("[header] the first 100 bytes of the file, after the magic string:");
+    eprintln!("[header]   bytes 16-17: page size {}", header.page_size);
+    eprintln!(
+        "[header]   byte 20: {} bytes reserved at the end of each page",
+        header.reserved_space
+    );
+    eprintln!(
+        "[header]   bytes 28-31: {} pages in the database",
+        header.page_count
+    );
+    eprintln!(
+        "[header]   bytes 56-59: text is stored as {}",
+        header.text_encoding.name()
+    );
+    eprintln!("[header]   page 1 holds the schema table, after these 100 bytes");
+}
+
+/// Describes page `page_no` as read: its kind and, for a b-tree page, the
+/// offsets of its cells from the cell pointer array.
+pub fn page(page_no: u32, page: &[u8]) {
+    let start = pager::btree_start(page_no);
+    let header = &page[start..];
+    let (kind, header_size) = match header[0] {
+        0x02 => ("interior index b-tree", 12),
+        0x05 => ("interior table b-tree", 12),
+        0x0a => ("leaf index b-tree", 8),
+        0x0d => ("leaf table b-tree", 8),
+        _ => {
+            eprintln!(
+                "[page {}] not a b-tree page: an overflow page, whose first 4 bytes are the next one's number",
+                page_no
+            );
+            return;
+        }
+    };
+    let number_of_cells = u16::from_be_bytes([header[3], header[4]]) as usize;
+    eprintln!(
+        "[page {}] {} page (type byte 0x{:02x} at offset {}), {} cells",
+        page_no, kind, header[0], start, number_of_cells
+    );
+    if header_size == 12 {
+        eprintln!(
+            "[page {}]   right-most child: page {}",
+            page_no,
+            u32::from_be_bytes([header[8], header[9], header[10], header[11]])
+        );
+    }
+    // The count of a corrupt page may have the array run past its end
+    let mut offsets = (0..number_of_cells)
+        .map_while(|i| {
+            let at = start + header_size + 2 * i;
+            let pointer = page.get(at..at + 2)?;
+            Some(u16::from_be_bytes([pointer[0], pointer[1]]).to_string())
+        })
+        .collect::<Vec<_>>();
+    if offsets.len() < number_of_cells {
+        offsets.push("... past the end of the page".to_string());
+    }
+    eprintln!(
+        "[page {}]   cell pointer array at offset {}: cells at {}",
+        page_no,
+        start + header_size,
+        offsets.join(", ")
+    );
+}
+
+/// Describes a payload too large for its page, of which only `local` of
+/// `length` bytes are kept there.
+pub fn overflow(length: usize, local: usize, first_page: u32) {
+    eprintln!(
+        "[payload] {} bytes, {} on the page and the rest from overflow page {} on",
+        length, local, first_page
+    );
+}
+
+/// Describes `record` by the serial types in its header.
+pub fn record(record: &[u8]) {
+    let Some(fields) = record::fields(record) else {
+        eprintln!("[record] malformed: the header runs past the end of the record");
+        return;
+    };
+    let types = fields
+        .iter()
+        .map(|&(t, _)| format!("{} ({})", t, record::describe(t)))
+        .collect::<Vec<_>>();
+    eprintln!("[record] serial types: {}", types.join(", "));
+}
@@ -0,0 +1,37 @@
+//! What the tests of several modules share.
+
+use crate::db::Database;
+use crate::Row;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+
+/// A database file for test `name` to write, removed once it is done.
+pub struct TempDatabase(pub PathBuf);
+
+impl TempDatabase {
+    pub fn new(name: &str) -> TempDatabase {
+        let path = env::temp_dir().join(format!("test-{}-{}.db", process::id(), name));
+        let _ = fs::remove_file(&path);
+        TempDatabase(path)
+    }
+
+    pub fn filename(&self) -> &str {
+        self.0.to_str().unwrap()
+    }
+
+    pub fn query(&self, sql: &str) -> Vec<Row> {
+        let mut database = Database::open(self.filename()).unwrap();
+        let mut rows = database.query(sql).unwrap();
+        let result = rows.by_ref().collect();
+        rows.finish().unwrap();
+        result
+    }
+}
+
+impl Drop for TempDatabase {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
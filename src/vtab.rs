@@ -0,0 +1,155 @@
+//! Virtual tables: tables whose rows come from elsewhere than the database
+//! file, which queries can read and join like any other. `--vtab name=path`
+//! makes a CSV file (or TSV, by its `.tsv` extension) one, for as long as
+//! the database is open.
+
+use crate::{Column, Row, Table};
+use anyhow::{bail, Context, Result};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+pub trait VirtualTable: fmt::Debug {
+    /// The `CREATE TABLE` statement declaring its columns.
+    fn sql(&self) -> &str;
+
+    /// Every row, in the order the source holds them.
+    fn rows(&self) -> Result<Vec<Row>>;
+}
+
+/// The name and path of a virtual table from a `--vtab` argument,
+/// `name=path`.
+pub fn parse_argument(argument: &str) -> Result<(String, PathBuf)> {
+    let Some((name, path)) = argument.split_once('=') else {
+        bail!("--vtab expects <NAME>=<PATH>, not {}", argument);
+    };
+    if name.is_empty() || path.is_empty() {
+        bail!("--vtab expects <NAME>=<PATH>, not {}", argument);
+    }
+    Ok((name.to_string(), PathBuf::from(path)))
+}
+
+/// The virtual tables `vtabs` names, as entries of the schema of `main`.
+pub fn tables(vtabs: &[(String, PathBuf)]) -> Result<Vec<Table>> {
+    vtabs
+        .iter()
+        .map(|(name, path)| {
+            let csv = csv(name, path)?;
+            Ok(Table {
+                ty: "table".to_string(),
+                name: name.clone(),
+                tbl_name: name.clone(),
+                rootpage: 0,
+                sql: csv.sql().to_string(),
+                vtab: Some(csv),
+            })
+        })
+        .collect()
+}
+
+/// The CSV file at `path`, or TSV by its `.tsv` extension, as the virtual
+/// table `name`.
+pub fn csv(name: &str, path: &Path) -> Result<Rc<dyn VirtualTable>> {
+    Ok(Rc::new(CsvTable::open(name, path)?))
+}
+
+/// A CSV file whose first line names the columns, like SQLite's csv
+/// extension with `header=yes`. Every value is text.
+#[derive(Debug)]
+struct CsvTable {
+    path: PathBuf,
+    separator: char,
+    sql: String,
+}
+
+impl CsvTable {
+    fn open(name: &str, path: &Path) -> Result<CsvTable> {
+        let separator = match path.extension().and_then(|e| e.to_str()) {
+            Some(e) if e.eq_ignore_ascii_case("tsv") => '\t',
+            _ => ',',
+        };
+        let text = read(path)?;
+        let header = parse(&text, separator).into_iter().next();
+        let Some(header) = header.filter(|h| !h.is_empty()) else {
+            bail!("{}: no header line to name the columns", path.display());
+        };
+        let columns = header
+            .iter()
+            .map(|c| format!("{} TEXT", quote(c)))
+            .collect::<Vec<_>>();
+        Ok(CsvTable {
+            path: path.to_path_buf(),
+            separator,
+            sql: format!("CREATE TABLE {}({})", quote(name), columns.join(", ")),
+        })
+    }
+}
+
+impl VirtualTable for CsvTable {
+    fn sql(&self) -> &str {
+        &self.sql
+    }
+
+    fn rows(&self) -> Result<Vec<Row>> {
+        let text = read(&self.path)?;
+        let mut records = parse(&text, self.separator).into_iter();
+        let width = records.next().map_or(0, |header| header.len());
+        // Short lines are padded with NULL, and extra fields dropped
+        Ok(records
+            .map(|record| {
+                let mut row = record.into_iter().map(Column::Text).collect::<Row>();
+                row.resize(width, Column::Null);
+                row
+            })
+            .collect())
+    }
+}
+
+fn read(path: &Path) -> Result<String> {
+    fs::read_to_string(path).with_context(|| format!("cannot read {}", path.display()))
+}
+
+/// Quotes an identifier for SQL.
+fn quote(identifier: &str) -> String {
+    format!("\"{}\"", identifier.replace('"', "\"\""))
+}
+
+/// The records of CSV text as RFC 4180 has them: fields in double quotes
+/// can hold separators, line breaks and `""` for a quote. Blank lines are
+/// skipped.
+fn parse(text: &str, separator: char) -> Vec<Vec<String>> {
+    let mut records = vec![];
+    let mut record = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted => {
+                if chars.peek() == Some(&'"') {
+                    chars.next();
+                    field.push('"');
+                } else {
+                    quoted = false;
+                }
+            }
+            '"' if field.is_empty() => quoted = true,
+            c if quoted => field.push(c),
+            c if c == separator => record.push(std::mem::take(&mut field)),
+            '\r' if chars.peek() == Some(&'\n') => {}
+            '\n' => {
+                if !record.is_empty() || !field.is_empty() {
+                    record.push(std::mem::take(&mut field));
+                    records.push(std::mem::take(&mut record));
+                }
+            }
+            c => field.push(c),
+        }
+    }
+    if !record.is_empty() || !field.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+    records
+}
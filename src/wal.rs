@@ -0,0 +1,255 @@
+//! The write-ahead log: in WAL mode commits are appended to `<db>-wal` as
+//! frames, each a new version of a page, and only copied back into the
+//! database at a checkpoint. Until then the latest committed version of a
+//! page is the one in the WAL, if it has one.
+//! https://www.sqlite.org/fileformat.html#the_write_ahead_log
+
+use anyhow::{bail, Result};
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{prelude::*, ErrorKind, SeekFrom};
+use std::path::Path;
+
+const HEADER_SIZE: usize = 32;
+const FRAME_HEADER_SIZE: usize = 24;
+
+#[derive(Debug)]
+pub struct Wal {
+    file: File,
+    /// Where in the WAL the latest committed version of each page starts
+    frames: HashMap<u32, u64>,
+    version: Version,
+}
+
+/// What a WAL was like when it was read: its length, which every commit
+/// adds frames to, and its header, whose salts change when it starts over
+/// after a checkpoint.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Version {
+    length: u64,
+    header: Vec<u8>,
+}
+
+impl Version {
+    /// The version of the WAL of the database at `path` as it is now, or
+    /// None if it has none.
+    pub fn of(path: &Path) -> Result<Option<Version>> {
+        let file = match File::open(wal_path(path)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let length = file.metadata()?.len();
+        let mut header = vec![];
+        file.take(HEADER_SIZE as u64).read_to_end(&mut header)?;
+        Ok(Some(Version { length, header }))
+    }
+}
+
+/// `<path>-wal`, where the WAL of the database at `path` is.
+fn wal_path(path: &Path) -> OsString {
+    let mut name = path.as_os_str().to_owned();
+    name.push("-wal");
+    name
+}
+
+impl Wal {
+    /// Reads the WAL of the database at `path`, if it has one, for a
+    /// database of `page_size` byte pages.
+    ///
+    /// Frames are only valid as long as their salts match the header's and
+    /// the running checksum over the header and every frame so far matches
+    /// theirs. The first that doesn't ends the log, as does the last commit
+    /// frame: later frames belong to a transaction that never committed.
+    pub fn open(path: &Path, page_size: usize) -> Result<Option<Wal>> {
+        let mut file = match File::open(wal_path(path)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let mut wal = vec![];
+        file.read_to_end(&mut wal)?;
+        let version = Version {
+            length: wal.len() as u64,
+            header: wal[..wal.len().min(HEADER_SIZE)].to_vec(),
+        };
+        let mut frames = HashMap::new();
+        if wal.len() < HEADER_SIZE {
+            return Ok(Some(Wal {
+                file,
+                frames,
+                version,
+            }));
+        }
+
+        let u32_at = |bytes: &[u8], offset: usize| {
+            u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap())
+        };
+        // The magic number's last bit tells the byte order of the checksums
+        let big_endian = match u32_at(&wal, 0) {
+            0x377f0682 => false,
+            0x377f0683 => true,
+            _ => {
+                return Ok(Some(Wal {
+                    file,
+                    frames,
+                    version,
+                }))
+            }
+        };
+        if u32_at(&wal, 8) as usize != page_size {
+            bail!(
+                "the WAL has {} byte pages, but the database {}",
+                u32_at(&wal, 8),
+                page_size
+            );
+        }
+        let salts = &wal[16..24];
+        let mut sum = checksum((0, 0), &wal[..24], big_endian);
+        if sum != (u32_at(&wal, 24), u32_at(&wal, 28)) {
+            return Ok(Some(Wal {
+                file,
+                frames,
+                version,
+            }));
+        }
+
+        let mut uncommitted = vec![];
+        let frame_size = FRAME_HEADER_SIZE + page_size;
+        for (i, frame) in wal[HEADER_SIZE..].chunks_exact(frame_size).enumerate() {
+            if &frame[8..16] != salts {
+                break;
+            }
+            sum = checksum(sum, &frame[..8], big_endian);
+            sum = checksum(sum, &frame[FRAME_HEADER_SIZE..], big_endian);
+            if sum != (u32_at(frame, 16), u32_at(frame, 20)) {
+                break;
+            }
+
+            let offset = (HEADER_SIZE + i * frame_size + FRAME_HEADER_SIZE) as u64;
+            uncommitted.push((u32_at(frame, 0), offset));
+            // Commit frames hold the size of the database after the commit,
+            // and other frames 0
+            if u32_at(frame, 4) != 0 {
+                frames.extend(uncommitted.drain(..));
+            }
+        }
+
+        Ok(Some(Wal {
+            file,
+            frames,
+            version,
+        }))
+    }
+
+    pub fn version(&self) -> &Version {
+        &self.version
+    }
+
+    /// Reads page `page_no` into `page`, returning whether the WAL has a
+    /// version of it.
+    pub fn read_page(&mut self, page_no: u32, page: &mut [u8]) -> Result<bool> {
+        let Some(&offset) = self.frames.get(&page_no) else {
+            return Ok(false);
+        };
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(page)?;
+        Ok(true)
+    }
+
+    /// Whether the WAL has a version of page `page_no`.
+    pub fn has_page(&self, page_no: u32) -> bool {
+        self.frames.contains_key(&page_no)
+    }
+}
+
+/// The WAL checksum of `data`, continuing from `(s0, s1)`.
+fn checksum((mut s0, mut s1): (u32, u32), data: &[u8], big_endian: bool) -> (u32, u32) {
+    let word = |bytes: &[u8]| {
+        let bytes = bytes.try_into().unwrap();
+        if big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        }
+    };
+    for pair in data.chunks_exact(8) {
+        s0 = s0.wrapping_add(word(&pair[..4])).wrapping_add(s1);
+        s1 = s1.wrapping_add(word(&pair[4..])).wrapping_add(s0);
+    }
+    (s0, s1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDatabase;
+    use std::fs;
+
+    const PAGE_SIZE: usize = 512;
+
+    /// A WAL holding `frames`: each a page number, the size of the database
+    /// if the frame commits or else 0, and the byte the page is filled with.
+    fn log(frames: &[(u32, u32, u8)]) -> Vec<u8> {
+        let mut wal = vec![];
+        // Checksums of little-endian words
+        wal.extend(0x377f0682u32.to_be_bytes());
+        wal.extend(3007000u32.to_be_bytes());
+        wal.extend((PAGE_SIZE as u32).to_be_bytes());
+        // Checkpoint sequence number, then the salts
+        wal.extend([0, 0, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8]);
+        let mut sum = checksum((0, 0), &wal, false);
+        wal.extend(sum.0.to_be_bytes());
+        wal.extend(sum.1.to_be_bytes());
+        for &(page_no, size, fill) in frames {
+            let page = vec![fill; PAGE_SIZE];
+            let mut header = [page_no.to_be_bytes(), size.to_be_bytes()].concat();
+            sum = checksum(sum, &header, false);
+            sum = checksum(sum, &page, false);
+            header.extend_from_slice(&wal[16..24]);
+            header.extend(sum.0.to_be_bytes());
+            header.extend(sum.1.to_be_bytes());
+            wal.extend(header);
+            wal.extend(page);
+        }
+        wal
+    }
+
+    /// The byte each page `pages` is filled with in `wal`, or None for those
+    /// it has no version of.
+    fn pages(wal: &mut Wal, pages: &[u32]) -> Vec<Option<u8>> {
+        let mut page = vec![0; PAGE_SIZE];
+        pages
+            .iter()
+            .map(|&page_no| wal.read_page(page_no, &mut page).unwrap().then(|| page[0]))
+            .collect()
+    }
+
+    #[test]
+    fn committed_frames_only() {
+        let database = TempDatabase::new("wal");
+        let path = wal_path(&database.0);
+        // Page 2 is written twice, and page 4 after the last commit
+        let mut wal = log(&[(2, 0, b'a'), (3, 3, b'b'), (2, 3, b'c'), (4, 0, b'd')]);
+        fs::write(&path, &wal).unwrap();
+        let mut read = Wal::open(&database.0, PAGE_SIZE).unwrap().unwrap();
+        assert_eq!(
+            pages(&mut read, &[1, 2, 3, 4]),
+            [None, Some(b'c'), Some(b'b'), None]
+        );
+
+        // A frame whose checksum doesn't match ends the log, commits after
+        // it included
+        let third = HEADER_SIZE + 2 * (FRAME_HEADER_SIZE + PAGE_SIZE) + FRAME_HEADER_SIZE;
+        wal[third] = b'x';
+        fs::write(&path, &wal).unwrap();
+        let mut read = Wal::open(&database.0, PAGE_SIZE).unwrap().unwrap();
+        assert_eq!(pages(&mut read, &[2, 3]), [Some(b'a'), Some(b'b')]);
+
+        // Pages of another size than the database's are an error
+        assert!(Wal::open(&database.0, 1024).is_err());
+        fs::remove_file(&path).unwrap();
+        assert!(Wal::open(&database.0, PAGE_SIZE).unwrap().is_none());
+    }
+}